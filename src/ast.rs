@@ -0,0 +1,224 @@
+//! Typed views of parse trees. [`ast_node!`] declares a type for the nodes
+//! of one rule, with an accessor per child; [`Token`] stands for leaves.
+//!
+//! [`ast_node!`]: crate::ast_node
+
+use std::fmt;
+
+use crate::grammar::Grammar;
+use crate::tree::{RedNode, Tag, TreeAlloc};
+use crate::utils::Span;
+
+/// A typed view of a node, usually declared with [`ast_node!`].
+///
+/// [`ast_node!`]: crate::ast_node
+pub trait AstNode<'a>: Sized {
+    /// `node` as `Self`, or `None` if it's some other kind of node.
+    fn cast(node: RedNode, alloc: &'a TreeAlloc, grammar: &'a Grammar) -> Option<Self>;
+
+    fn syntax(&self) -> &RedNode;
+
+    fn alloc(&self) -> &'a TreeAlloc;
+
+    fn grammar(&self) -> &'a Grammar;
+
+    fn span(&self) -> Span {
+        self.syntax().span(self.alloc())
+    }
+
+    /// The `n`th child, not counting trivia, as a `T`. `None` if there is no
+    /// such child or it isn't a `T`, as when error recovery skipped or
+    /// replaced it. Children are counted as written in the grammar, seeing
+    /// through the rules `opt` and repetitions are lowered to.
+    fn child<T: AstNode<'a>>(&self, n: usize) -> Option<T> {
+        let alloc = self.alloc();
+        let child = (self.syntax().transparent_children(alloc, self.grammar()))
+            .filter(|child| child.green(alloc).tag != Tag::Trivia)
+            .nth(n)?;
+        T::cast(child, alloc, self.grammar())
+    }
+}
+
+/// A leaf holding text: the match of a terminal or of a token rule.
+#[derive(Clone)]
+pub struct Token<'a> {
+    syntax: RedNode,
+    alloc: &'a TreeAlloc,
+    grammar: &'a Grammar,
+}
+
+impl<'a> Token<'a> {
+    pub fn text(&self) -> &'a str {
+        let green = self.syntax.green(self.alloc);
+        green.text.as_deref().unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Token").field(&self.text()).finish()
+    }
+}
+
+impl<'a> AstNode<'a> for Token<'a> {
+    fn cast(node: RedNode, alloc: &'a TreeAlloc, grammar: &'a Grammar) -> Option<Self> {
+        let green = node.green(alloc);
+        let leaf = green.children.is_empty() && green.text.is_some();
+        let token = matches!(green.tag, Tag::Terminal | Tag::Rule(_));
+        (leaf && token).then_some(Token {
+            syntax: node,
+            alloc,
+            grammar,
+        })
+    }
+
+    fn syntax(&self) -> &RedNode {
+        &self.syntax
+    }
+
+    fn alloc(&self) -> &'a TreeAlloc {
+        self.alloc
+    }
+
+    fn grammar(&self) -> &'a Grammar {
+        self.grammar
+    }
+}
+
+/// Declares a typed view of the nodes of a rule, named by a string, with
+/// an accessor per child. Children are matched by position, trivia left
+/// out, and each accessor gives `None` if its child is missing or of
+/// another type. Child types are [`AstNode`]s taking one lifetime, named
+/// without it.
+///
+/// ```
+/// use tree_editor::ast::Token;
+/// use tree_editor::ast_node;
+///
+/// ast_node!(BinaryExpr, "binary_expr", { lhs: Operand, op: Token, rhs: Operand });
+/// ast_node!(Operand, "operand", { value: Token });
+/// ```
+#[macro_export]
+macro_rules! ast_node {
+    ($name:ident, $rule:literal, { $($field:ident : $ty:ident),* $(,)? }) => {
+        #[derive(Clone)]
+        pub struct $name<'a> {
+            syntax: $crate::tree::RedNode,
+            alloc: &'a $crate::tree::TreeAlloc,
+            grammar: &'a $crate::grammar::Grammar,
+        }
+
+        impl<'a> $crate::ast::AstNode<'a> for $name<'a> {
+            fn cast(
+                node: $crate::tree::RedNode,
+                alloc: &'a $crate::tree::TreeAlloc,
+                grammar: &'a $crate::grammar::Grammar,
+            ) -> Option<Self> {
+                let tag = $crate::tree::Tag::Rule(grammar.rule_index($rule)?);
+                (node.green(alloc).tag == tag).then_some($name {
+                    syntax: node,
+                    alloc,
+                    grammar,
+                })
+            }
+
+            fn syntax(&self) -> &$crate::tree::RedNode {
+                &self.syntax
+            }
+
+            fn alloc(&self) -> &'a $crate::tree::TreeAlloc {
+                self.alloc
+            }
+
+            fn grammar(&self) -> &'a $crate::grammar::Grammar {
+                self.grammar
+            }
+        }
+
+        impl ::std::fmt::Debug for $name<'_> {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                f.debug_tuple(stringify!($name)).field(&self.syntax).finish()
+            }
+        }
+
+        impl<'a> $name<'a> {
+            $crate::ast_node!(@fields 0; $($field : $ty),*);
+        }
+    };
+    (@fields $n:expr; ) => {};
+    (@fields $n:expr; $field:ident : $ty:ident $(, $rest:ident : $rest_ty:ident)*) => {
+        pub fn $field(&self) -> Option<$ty<'a>> {
+            $crate::ast::AstNode::child(self, $n)
+        }
+
+        $crate::ast_node!(@fields $n + 1; $($rest : $rest_ty),*);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar_dsl::*;
+    use crate::parser::ParserState;
+    use crate::r;
+    use crate::words::{Matcher, Whitespace};
+
+    fn expr() -> GrammarNode {
+        r!(term) + opt(t('+') + r!(expr))
+    }
+
+    fn term() -> GrammarNode {
+        t(('0'..='9').times(1..)) + opt(t('*') + r!(term))
+    }
+
+    ast_node!(Expr, "expr", { lhs: Term, plus: Token, rhs: Expr });
+    ast_node!(Term, "term", { number: Token, star: Token, rhs: Term });
+
+    impl Expr<'_> {
+        fn eval(&self) -> Option<u64> {
+            let lhs = self.lhs()?.eval()?;
+            match self.plus() {
+                Some(_) => Some(lhs + self.rhs()?.eval()?),
+                None => Some(lhs),
+            }
+        }
+    }
+
+    impl Term<'_> {
+        fn eval(&self) -> Option<u64> {
+            let number = self.number()?.text().parse::<u64>().ok()?;
+            match self.star() {
+                Some(_) => Some(number * self.rhs()?.eval()?),
+                None => Some(number),
+            }
+        }
+    }
+
+    #[test]
+    fn test_typed_eval() {
+        let grammar = Grammar::try_from(r!(expr))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let mut state = ParserState::new(grammar.clone()).with_text("1 + 2 * 3 + 40");
+        assert!(state.parse().is_complete());
+        let (root, alloc) = (state.ast(), state.arena());
+
+        assert!(Expr::cast(root.clone(), alloc, &grammar).is_none());
+        let expr = Expr::cast(root.first_child(alloc).unwrap(), alloc, &grammar).unwrap();
+        assert_eq!(expr.span(), Span::new(0, 14));
+        assert_eq!(expr.plus().unwrap().text(), "+");
+        assert_eq!(expr.eval(), Some(47));
+        let rhs = expr.rhs().unwrap();
+        assert_eq!(rhs.lhs().unwrap().number().unwrap().text(), "2");
+        assert!(rhs.lhs().unwrap().rhs().unwrap().rhs().is_none());
+
+        // Recovery put an error node where the right operand goes.
+        state.set_text("1 + * 3");
+        let (root, alloc) = (state.ast(), state.arena());
+        let expr = Expr::cast(root.first_child(alloc).unwrap(), alloc, &grammar).unwrap();
+        assert_eq!(expr.lhs().unwrap().eval(), Some(1));
+        assert!(expr.plus().is_some());
+        assert!(expr.rhs().is_none());
+        assert_eq!(expr.eval(), None);
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+
+/// Version of the serialized grammar format, the JSON of
+/// [`GrammarSpec`](crate::grammar::GrammarSpec), which every build reads and
+/// writes. Bumped whenever a cached grammar written by an older build can no
+/// longer be read back.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Version of the LSP semantic-token encoding produced by the `lsp` feature.
+pub const LSP_TOKENS_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub format_version: u32,
+    pub lsp: bool,
+    pub regex: bool,
+    pub byte_input: bool,
+    pub capi: bool,
+    pub testing: bool,
+    pub unicode: bool,
+    pub lsp_tokens_version: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingCapability {
+    pub name: String,
+    pub version: &'static str,
+}
+
+impl fmt::Display for MissingCapability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "capability `{}` is not available in this build (version {})",
+            self.name, self.version
+        )
+    }
+}
+
+impl std::error::Error for MissingCapability {}
+
+/// Reports what this build of the crate was compiled with.
+pub fn capabilities() -> Capabilities {
+    let lsp = cfg!(feature = "lsp");
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        format_version: FORMAT_VERSION,
+        lsp,
+        regex: cfg!(feature = "regex"),
+        byte_input: cfg!(feature = "byte-input"),
+        capi: cfg!(feature = "capi"),
+        testing: cfg!(feature = "testing"),
+        unicode: cfg!(feature = "unicode"),
+        lsp_tokens_version: lsp.then_some(LSP_TOKENS_VERSION),
+    }
+}
+
+impl Capabilities {
+    /// Names of the enabled features, using the same spelling as `Cargo.toml`.
+    pub fn features(&self) -> Vec<&'static str> {
+        self.all_features()
+            .into_iter()
+            .filter_map(|(name, enabled)| enabled.then_some(name))
+            .collect()
+    }
+
+    /// Every feature in `Cargo.toml`, and whether it's enabled.
+    fn all_features(&self) -> [(&'static str, bool); 6] {
+        [
+            ("byte-input", self.byte_input),
+            ("capi", self.capi),
+            ("lsp", self.lsp),
+            ("regex", self.regex),
+            ("testing", self.testing),
+            ("unicode", self.unicode),
+        ]
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.features().contains(&name)
+    }
+
+    /// Fails on the first feature in `names` that was not compiled in.
+    pub fn require(&self, names: &[&str]) -> Result<(), MissingCapability> {
+        match names.iter().find(|name| !self.has(name)) {
+            Some(name) => Err(MissingCapability {
+                name: name.to_string(),
+                version: self.version,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// [`Capabilities`] laid out for C callers, from
+/// [`tree_editor_capabilities`]. Features are bits of `features`, and
+/// `lsp_tokens_version` is 0 without the `lsp` feature.
+#[cfg(feature = "capi")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CCapabilities {
+    /// The crate version, NUL-terminated and never freed.
+    pub version: *const std::ffi::c_char,
+    pub format_version: u32,
+    pub features: u32,
+    pub lsp_tokens_version: u32,
+}
+
+/// Bits of [`CCapabilities::features`], one per feature in `Cargo.toml`.
+#[cfg(feature = "capi")]
+pub mod capi_features {
+    pub const BYTE_INPUT: u32 = 1 << 0;
+    pub const CAPI: u32 = 1 << 1;
+    pub const LSP: u32 = 1 << 2;
+    pub const REGEX: u32 = 1 << 3;
+    pub const TESTING: u32 = 1 << 4;
+    pub const UNICODE: u32 = 1 << 5;
+}
+
+/// [`capabilities`] for C callers, and for WASM hosts reading the module's
+/// exports directly.
+#[cfg(feature = "capi")]
+#[unsafe(no_mangle)]
+pub extern "C" fn tree_editor_capabilities() -> CCapabilities {
+    let caps = capabilities();
+    let features = (caps.all_features().into_iter())
+        .enumerate()
+        .filter(|(_, (_, enabled))| *enabled)
+        .fold(0, |bits, (i, _)| bits | 1 << i);
+    CCapabilities {
+        version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast(),
+        format_version: caps.format_version,
+        features,
+        lsp_tokens_version: caps.lsp_tokens_version.unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_match_build() {
+        let caps = capabilities();
+        assert_eq!(caps.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(caps.format_version, FORMAT_VERSION);
+        assert_eq!(caps.lsp, cfg!(feature = "lsp"));
+        assert_eq!(caps.regex, cfg!(feature = "regex"));
+        assert_eq!(caps.byte_input, cfg!(feature = "byte-input"));
+        assert_eq!(caps.testing, cfg!(feature = "testing"));
+        assert_eq!(caps.unicode, cfg!(feature = "unicode"));
+        assert_eq!(caps.lsp_tokens_version.is_some(), caps.lsp);
+        for name in caps.features() {
+            assert!(caps.has(name));
+            assert!(caps.require(&[name]).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_features_match_manifest() {
+        let manifest = include_str!("../Cargo.toml");
+        let section = manifest.split("[features]").nth(1).unwrap();
+        let declared: Vec<_> = (section.lines())
+            .take_while(|line| !line.starts_with('['))
+            .filter_map(|line| line.split_once('=').map(|(name, _)| name.trim()))
+            .collect();
+        let reported: Vec<_> = (capabilities().all_features().into_iter())
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(reported, declared);
+    }
+
+    #[cfg(feature = "capi")]
+    #[test]
+    fn test_c_capabilities() {
+        use super::capi_features::*;
+        let caps = capabilities();
+        let c_caps = tree_editor_capabilities();
+        // SAFETY: the version is a NUL-terminated static string.
+        let version = unsafe { std::ffi::CStr::from_ptr(c_caps.version) };
+        assert_eq!(version.to_str(), Ok(caps.version));
+        assert_eq!(c_caps.format_version, FORMAT_VERSION);
+        assert_eq!(
+            c_caps.lsp_tokens_version,
+            caps.lsp_tokens_version.unwrap_or(0)
+        );
+        let bits = [
+            (BYTE_INPUT, caps.byte_input),
+            (CAPI, caps.capi),
+            (LSP, caps.lsp),
+            (REGEX, caps.regex),
+            (TESTING, caps.testing),
+            (UNICODE, caps.unicode),
+        ];
+        for (bit, enabled) in bits {
+            assert_eq!(c_caps.features & bit != 0, enabled);
+        }
+    }
+
+    #[test]
+    fn test_require_missing() {
+        let mut caps = capabilities();
+        caps.regex = false;
+        let err = caps.require(&["regex"]).unwrap_err();
+        assert_eq!(err.name, "regex");
+        assert!(err.to_string().contains("`regex`"));
+        assert!(caps.require(&["serde"]).is_err());
+    }
+}
@@ -1,29 +1,281 @@
 use core::fmt;
-use std::{collections::HashSet, hash};
+use std::{
+    borrow::Borrow,
+    collections::{BTreeSet, HashMap, HashSet},
+    hash,
+    sync::Arc,
+};
 
 use indexmap::{IndexSet, set::MutableValues};
 
+mod ebnf;
+mod generate;
+mod spec;
+
+pub use ebnf::{EbnfParseError, from_ebnf};
+pub use generate::{GenLimits, Rng, SplitMix64};
+pub use spec::{GrammarSpec, NodeSpec, RuleSpec, SpecError};
+
 use crate::grammar_dsl::*;
+use crate::words::{Matcher, Precedence};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum EvaluationError {
     UndecidableRule(String),
     AlwaysFails,
+    IndirectLeftRecursion(String),
+    /// Direct left recursion the rewrite can't remove, like a
+    /// self-reference leading a nested choice.
+    UnsupportedLeftRecursion(String),
+    UnreachableRule(String),
+    ConflictingRule {
+        name: String,
+    },
+    /// `token` was applied to something other than a rule reference.
+    InvalidToken,
+    /// An expression nests deeper than the limit set for normalization.
+    TooDeep {
+        max_depth: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct GrammarDiagnostic {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub error: EvaluationError,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum GrammarError {
     Placeholder,
-    RuleMismatch { expected: usize },
-    TokenMismatch { expected: String },
+    RuleMismatch {
+        expected: RuleId,
+    },
+    /// `expected` names the terminal, or the rule, that was expected;
+    /// `label`, if the place had one, what to call it instead.
+    TokenMismatch {
+        expected: String,
+        label: Option<&'static str>,
+    },
+}
+
+impl fmt::Display for EvaluationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvaluationError::UndecidableRule(name) => {
+                write!(f, "rule `{}` contains a placeholder", name)
+            }
+            EvaluationError::AlwaysFails => write!(f, "rule can never match"),
+            EvaluationError::IndirectLeftRecursion(name) => {
+                write!(f, "rule `{}` is indirectly left-recursive", name)
+            }
+            EvaluationError::UnsupportedLeftRecursion(name) => write!(
+                f,
+                "rule `{}` is left-recursive other than at the start of an alternative",
+                name
+            ),
+            EvaluationError::UnreachableRule(name) => {
+                write!(f, "rule `{}` is unreachable from START", name)
+            }
+            EvaluationError::ConflictingRule { name } => {
+                write!(f, "rule `{}` is defined with two different bodies", name)
+            }
+            EvaluationError::InvalidToken => {
+                write!(f, "`token` applied to something other than a rule")
+            }
+            EvaluationError::TooDeep { max_depth } => {
+                write!(f, "expression nests deeper than {} levels", max_depth)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EvaluationError {}
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrammarError::Placeholder => write!(f, "unparsed placeholder"),
+            GrammarError::RuleMismatch { expected } => write!(f, "expected rule {}", expected),
+            GrammarError::TokenMismatch {
+                label: Some(label), ..
+            } => write!(f, "expected {}", label),
+            GrammarError::TokenMismatch { expected, .. } => write!(f, "expected {}", expected),
+        }
+    }
+}
+
+impl std::error::Error for GrammarError {}
+
+/// Terminal placed in the FOLLOW set of START; matches `EndOfInput::display()`.
+pub const END_MARKER: &str = "EOF";
+
+/// FIRST sets per rule index, keyed by each terminal's `display()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirstSets {
+    pub nullable: Vec<bool>,
+    pub first: Vec<BTreeSet<String>>,
+}
+
+impl FirstSets {
+    pub fn first(&self, rule: RuleId) -> Option<&BTreeSet<String>> {
+        self.first.get(rule.index())
+    }
+
+    pub fn is_nullable(&self, rule: RuleId) -> bool {
+        self.nullable.get(rule.index()).copied().unwrap_or(false)
+    }
+
+    /// Whether a match of `rule` can begin with the given terminal.
+    pub fn can_start_with(&self, rule: RuleId, matcher: &dyn Matcher) -> bool {
+        self.first
+            .get(rule.index())
+            .is_some_and(|set| set.contains(&matcher.display()))
+    }
+
+    /// FIRST set and nullability of an arbitrary node under these sets.
+    pub fn of_node(&self, node: &NormalizedNode) -> (BTreeSet<String>, bool) {
+        let mut out = BTreeSet::new();
+        let nullable = first_of_node(node, &self.first, &self.nullable, &mut out);
+        (out, nullable)
+    }
+}
+
+/// FOLLOW sets per rule index, keyed by each terminal's `display()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FollowSets {
+    pub follow: Vec<BTreeSet<String>>,
+}
+
+impl FollowSets {
+    pub fn follow(&self, rule: RuleId) -> Option<&BTreeSet<String>> {
+        self.follow.get(rule.index())
+    }
+
+    pub fn can_follow(&self, rule: RuleId, matcher: &dyn Matcher) -> bool {
+        self.follow
+            .get(rule.index())
+            .is_some_and(|set| set.contains(&matcher.display()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    /// Prefixing did not make a colliding rule name unique.
+    DuplicateRule(String),
+    UnknownRule(String),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::DuplicateRule(name) => write!(f, "rule `{}` is defined twice", name),
+            MergeError::UnknownRule(name) => write!(f, "no rule named `{}`", name),
+        }
+    }
 }
 
+impl std::error::Error for MergeError {}
+
 pub type Result<T> = std::result::Result<T, EvaluationError>;
 
-#[derive(Debug)]
+/// Names a rule of a [`Grammar`] by its place in the rule list, as rule
+/// references and [`Tag::Rule`] nodes do. Only grammars hand them out, so
+/// one is never mistaken for a green node id or some other index.
+///
+/// [`Tag::Rule`]: crate::tree::Tag::Rule
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RuleId(u32);
+
+impl RuleId {
+    /// The START rule every grammar has first, wrapping the node it was
+    /// built from.
+    pub(crate) const START: RuleId = RuleId(0);
+
+    pub(crate) fn new(index: usize) -> Self {
+        RuleId(u32::try_from(index).expect("more than u32::MAX rules"))
+    }
+
+    /// The rule's place in [`Grammar::iter`].
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Shown as the bare index, so trees and errors print as they did when
+/// rules were numbered with plain integers.
+impl fmt::Debug for RuleId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for RuleId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Rule {
     pub name: &'static str,
     pub node: NormalizedNode,
+    /// Token rules are matched as a whole: the parse engine records their
+    /// match as one leaf, with no children and no trivia skipping inside.
+    pub token: bool,
+    pub(crate) synthetic: bool,
+    /// What syntax errors call the rule where it was expected, in place of
+    /// the terminals it starts with.
+    pub(crate) label: Option<&'static str>,
+    /// An operator rule of a precedence table: its match is folded into a
+    /// node per operator, and a match without one is its operand's node.
+    pub(crate) fold: bool,
+}
+
+impl Rule {
+    pub fn new(name: &'static str, node: NormalizedNode) -> Self {
+        Rule {
+            name,
+            node,
+            token: false,
+            synthetic: false,
+            label: None,
+            fold: false,
+        }
+    }
+
+    /// A helper rule the grammar made up, for an `opt`, a repetition or a
+    /// left-recursion tail, named after the rule or item it came from:
+    /// `expr_opt`, `item_list`, `expr_tail`.
+    pub(crate) fn synthetic(name: &'static str, node: NormalizedNode) -> Self {
+        Rule {
+            synthetic: true,
+            ..Rule::new(name, node)
+        }
+    }
+
+    /// Whether the grammar made the rule up rather than it being written.
+    /// Synthetic rules are transparent: their nodes only group the
+    /// children of the rule around them, and
+    /// [`RedNode::transparent_children`] splices them into it.
+    ///
+    /// [`RedNode::transparent_children`]: crate::tree::RedNode::transparent_children
+    pub fn is_synthetic(&self) -> bool {
+        self.synthetic
+    }
+
+    /// The label an `expected` node gave the rule, as in "expected a
+    /// statement".
+    pub fn label(&self) -> Option<&'static str> {
+        self.label
+    }
 }
 
 impl hash::Hash for Rule {
@@ -32,6 +284,12 @@ impl hash::Hash for Rule {
     }
 }
 
+impl Borrow<str> for Rule {
+    fn borrow(&self) -> &str {
+        self.name
+    }
+}
+
 impl PartialEq for Rule {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
@@ -40,212 +298,2350 @@ impl PartialEq for Rule {
 
 impl Eq for Rule {}
 
+#[derive(Debug, Clone)]
 pub struct Grammar {
     rules: IndexSet<Rule>,
+    trivia: Option<Arc<dyn Matcher>>,
 }
 
+/// How deep [`Grammar::try_from`] lets expressions nest within a rule body.
+/// Later passes over rule bodies recurse, so this keeps them clear of the
+/// stack's end.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
 impl TryFrom<GrammarNode> for Grammar {
     type Error = EvaluationError;
     fn try_from(node: GrammarNode) -> Result<Self> {
-        let mut rules = IndexSet::new();
-        let start = normalize(node, &mut rules)?;
+        Grammar::try_from_with_max_depth(node, DEFAULT_MAX_DEPTH)
+    }
+}
 
-        // Shift all references by 1 to make room for START at index 0
-        let shifted_rules: IndexSet<Rule> = rules
-            .into_iter()
-            .map(|mut rule| {
-                rule.node = shift_references(rule.node, 1);
-                rule
-            })
-            .collect();
+impl Grammar {
+    /// Like [`Grammar::try_from`], but failing with
+    /// [`EvaluationError::TooDeep`] on expressions nested more than
+    /// `max_depth` levels within a rule body instead of
+    /// [`DEFAULT_MAX_DEPTH`]. Rule references don't count as nesting.
+    pub fn try_from_with_max_depth(node: GrammarNode, max_depth: usize) -> Result<Self> {
+        let mut grammar = Grammar::normalized(node, max_depth)?;
+        grammar.simplify();
+        Ok(grammar)
+    }
 
-        let start_rule = Rule {
-            name: "START",
-            node: shift_references(start, 1),
-        };
+    /// Normalizes `node` without the [`Grammar::simplify`] pass, keeping the
+    /// structure exactly as the DSL built it.
+    pub fn try_from_unsimplified(node: GrammarNode) -> Result<Self> {
+        Grammar::normalized(node, DEFAULT_MAX_DEPTH)
+    }
 
-        let mut final_rules = IndexSet::new();
-        final_rules.insert(start_rule);
-        final_rules.extend(shifted_rules);
+    fn normalized(node: GrammarNode, max_depth: usize) -> Result<Self> {
+        // START goes first, its body filled in once the rules it refers to
+        // have their places.
+        let mut rules = IndexSet::new();
+        rules.insert(Rule::new("START", NormalizedNode::Placeholder));
+        let start = normalize(node, &mut rules, max_depth)?;
+        if let Some(rule) = rules.get_index_mut2(RuleId::START.index()) {
+            rule.node = start;
+        }
 
-        Ok(Grammar { rules: final_rules })
+        Ok(Grammar {
+            rules,
+            trivia: None,
+        })
     }
 }
 
-impl fmt::Display for Grammar {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use NormalizedNode as N;
+impl Grammar {
+    /// Sets the trivia matcher (whitespace, comments) that the parse engine
+    /// tries before and after the start rule and between sequence elements,
+    /// outside of `verbatim` nodes. What it consumes is kept as trivia rather
+    /// than as grammar-visible children.
+    pub fn with_trivia<M: Matcher + 'static>(mut self, matcher: M) -> Self {
+        self.trivia = Some(Arc::new(matcher));
+        self
+    }
 
-        fn needs_paren(node: &NormalizedNode) -> bool {
-            matches!(node, N::Choice(_))
+    pub fn trivia(&self) -> Option<&dyn Matcher> {
+        self.trivia.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn rule(&self, id: RuleId) -> Option<&Rule> {
+        self.rules.get_index(id.index())
+    }
+
+    pub fn rule_name(&self, id: RuleId) -> Option<&'static str> {
+        self.rule(id).map(|rule| rule.name)
+    }
+
+    pub fn rule_index(&self, name: &str) -> Option<RuleId> {
+        self.rules.get_index_of(name).map(RuleId::new)
+    }
+
+    /// The START rule, which wraps the node the grammar was built from.
+    pub fn start(&self) -> RuleId {
+        RuleId::START
+    }
+
+    /// Rules in order, START first.
+    pub fn iter(&self) -> impl Iterator<Item = (RuleId, &Rule)> {
+        (self.rules.iter().enumerate()).map(|(idx, rule)| (RuleId::new(idx), rule))
+    }
+
+    /// Flattens nested sequences and choices, drops empty sequences from
+    /// sequences, drops choice alternatives that can never be reached (exact
+    /// duplicates of earlier ones, or anything after an empty alternative),
+    /// and collapses single-element sequences and choices into their child.
+    /// A choice with an empty alternative thus ends in exactly one, the
+    /// optional form, written `[ X ]`.
+    pub fn simplify(&mut self) {
+        for idx in 0..self.rules.len() {
+            if let Some(rule) = self.rules.get_index_mut2(idx) {
+                let node = std::mem::replace(&mut rule.node, NormalizedNode::Placeholder);
+                rule.node = simplify_node(node);
+            }
         }
+    }
 
-        fn fmt_node(
-            grammar: &Grammar,
-            node: &NormalizedNode,
-            cur_idx: usize,
-            f: &mut fmt::Formatter<'_>,
-        ) -> fmt::Result {
-            match node {
-                N::Terminal(m) => write!(f, "{}", m.display()),
-                N::Reference(idx) => {
-                    let name = grammar
-                        .rules
-                        .get_index(*idx)
-                        .map(|r| r.name)
-                        .unwrap_or("<unknown>");
-                    write!(f, "{}", name)
-                }
-                N::Placeholder => write!(f, "<placeholder>"),
-                N::Sequence(parts) => {
-                    let mut first = true;
-                    for p in parts.iter() {
-                        if !first {
-                            write!(f, " ")?;
-                        }
-                        first = false;
-                        if needs_paren(p) {
-                            write!(f, "(")?;
-                            fmt_node(grammar, p, cur_idx, f)?;
-                            write!(f, ")")?;
-                        } else {
-                            fmt_node(grammar, p, cur_idx, f)?;
-                        }
-                    }
-                    Ok(())
-                }
-                N::Choice(alts) => {
-                    let mut first = true;
-                    for a in alts.iter() {
-                        if !first {
-                            write!(f, " | ")?;
-                        }
-                        first = false;
-                        if matches!(a, N::Sequence(_)) {
-                            write!(f, "(")?;
-                            fmt_node(grammar, a, cur_idx, f)?;
-                            write!(f, ")")?;
-                        } else {
-                            fmt_node(grammar, a, cur_idx, f)?;
-                        }
-                    }
-                    Ok(())
+    /// Reports rules that are unreachable from START (warning), still bodied
+    /// by a placeholder (error), or can never succeed (error).
+    pub fn validate(&self) -> Vec<GrammarDiagnostic> {
+        let mut reachable = vec![false; self.rules.len()];
+        let mut stack = vec![0];
+        while let Some(idx) = stack.pop() {
+            if idx >= reachable.len() || reachable[idx] {
+                continue;
+            }
+            reachable[idx] = true;
+            collect_references(&self.rules[idx].node, &mut stack);
+        }
+
+        let mut productive = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, rule) in self.rules.iter().enumerate() {
+                if !productive[i] && can_succeed(&rule.node, &productive) {
+                    productive[i] = true;
+                    changed = true;
                 }
             }
         }
 
+        let mut diagnostics = Vec::new();
         for (i, rule) in self.rules.iter().enumerate() {
-            write!(f, "{} ::= ", rule.name)?;
-            fmt_node(self, &rule.node, i, f)?;
-            if i + 1 < self.rules.len() {
-                writeln!(f)?;
+            if !reachable[i] {
+                diagnostics.push(GrammarDiagnostic {
+                    rule: rule.name,
+                    severity: Severity::Warning,
+                    error: EvaluationError::UnreachableRule(rule.name.to_string()),
+                });
+            }
+            if contains_placeholder(&rule.node) {
+                diagnostics.push(GrammarDiagnostic {
+                    rule: rule.name,
+                    severity: Severity::Error,
+                    error: EvaluationError::UndecidableRule(rule.name.to_string()),
+                });
+            } else if !productive[i] {
+                diagnostics.push(GrammarDiagnostic {
+                    rule: rule.name,
+                    severity: Severity::Error,
+                    error: EvaluationError::AlwaysFails,
+                });
             }
         }
-        Ok(())
+        diagnostics
     }
-}
 
-fn shift_references(node: NormalizedNode, offset: usize) -> NormalizedNode {
-    use NormalizedNode as N;
-    match node {
-        N::Reference(idx) => N::Reference(idx + offset),
-        N::Choice(nodes) => N::Choice(
-            nodes
+    /// Indices of every rule that can reach itself in leftmost position,
+    /// directly or through other rules and nullable prefixes.
+    pub fn left_recursive_rules(&self) -> Vec<RuleId> {
+        let corners = self.left_corners();
+        (0..self.rules.len())
+            .filter(|&idx| reaches(&corners, idx, idx, None))
+            .map(RuleId::new)
+            .collect()
+    }
+
+    /// Rewrites directly left-recursive rules into right-recursive form.
+    ///
+    /// `A ::= A a | b` becomes `A ::= b A_tail` and `A_tail ::= [ a A_tail ]`.
+    /// Synthesized rules are appended, so indices of existing rules are kept.
+    /// Fails without modifying the grammar if any left recursion is indirect,
+    /// or survives the rewrite, as a self-reference leading a nested choice
+    /// does; the latter is [`EvaluationError::UnsupportedLeftRecursion`].
+    pub fn eliminate_left_recursion(&mut self) -> Result<()> {
+        let corners = self.left_corners();
+        let recursive: Vec<_> = (self.left_recursive_rules().into_iter())
+            .map(RuleId::index)
+            .collect();
+        for &idx in recursive.iter() {
+            let name = self.rules[idx].name;
+            if reaches(&corners, idx, idx, Some(idx)) {
+                return Err(EvaluationError::IndirectLeftRecursion(name.to_string()));
+            }
+            // Only self-edges from leading references can be rewritten
+            if !has_direct_tails(&self.rules[idx].node, idx) {
+                return Err(EvaluationError::UnsupportedLeftRecursion(name.to_string()));
+            }
+        }
+
+        use NormalizedNode as N;
+        let mut rewritten = self.clone();
+        for &idx in recursive.iter() {
+            let name = rewritten.rules[idx].name;
+            let tail_idx = RuleId::new(rewritten.rules.len());
+            let tail_name = rewritten.fresh_name(&format!("{}_tail", name));
+
+            let Some(rule) = rewritten.rules.get_index_mut2(idx) else {
+                continue;
+            };
+            let node = std::mem::replace(&mut rule.node, N::Placeholder);
+            let (tails, bases) = split_left_recursive(node, idx);
+
+            let mut tail_alts: Vec<_> = tails
                 .into_iter()
-                .map(|n| shift_references(n, offset))
-                .collect(),
-        ),
-        N::Sequence(nodes) => N::Sequence(
-            nodes
+                .map(|rest| N::Sequence(rest.into_iter().chain([N::Reference(tail_idx)]).collect()))
+                .collect();
+            tail_alts.push(N::null());
+
+            let mut base_alts: Vec<_> = bases
                 .into_iter()
-                .map(|n| shift_references(n, offset))
-                .collect(),
-        ),
-        n => n,
+                .map(|base| match base {
+                    N::Sequence(mut parts) => {
+                        parts.push(N::Reference(tail_idx));
+                        N::Sequence(parts)
+                    }
+                    base => N::Sequence(vec![base, N::Reference(tail_idx)]),
+                })
+                .collect();
+
+            let body = if base_alts.len() == 1 {
+                base_alts.pop().unwrap()
+            } else {
+                N::Choice(base_alts)
+            };
+            if let Some(rule) = rewritten.rules.get_index_mut2(idx) {
+                rule.node = body;
+            }
+            rewritten
+                .rules
+                .insert(Rule::synthetic(tail_name, N::Choice(tail_alts)));
+        }
+        if let Some(&idx) = rewritten.left_recursive_rules().first() {
+            let name = rewritten.rules[idx.index()].name;
+            return Err(EvaluationError::UnsupportedLeftRecursion(name.to_string()));
+        }
+        *self = rewritten;
+        Ok(())
     }
-}
 
-fn normalize(node: GrammarNode, rules: &mut IndexSet<Rule>) -> Result<NormalizedNode> {
-    normalize_impl(node, rules, &mut HashSet::new())
-}
+    /// For every rule, the rules reachable in leftmost position, tagged with
+    /// whether the reference is literally the first element of an alternative.
+    fn left_corners(&self) -> Vec<Vec<(usize, bool)>> {
+        let nullable = self.nullable_rules();
+        self.rules
+            .iter()
+            .map(|rule| {
+                let mut out = Vec::new();
+                collect_left_corners(&rule.node, &nullable, true, &mut out);
+                out
+            })
+            .collect()
+    }
 
-fn normalize_impl(
-    node: GrammarNode,
-    rules: &mut IndexSet<Rule>,
-    in_progress: &mut HashSet<&'static str>,
-) -> Result<NormalizedNode> {
-    use GrammarNode as G;
-    use NormalizedNode as N;
-    match node {
-        G::Terminal(m) => Ok(N::Terminal(m)),
-        G::Choice(choices) => choices
-            .into_iter()
-            .map(|n| normalize_impl(n, rules, in_progress))
-            .collect::<Result<Vec<_>>>()
-            .map(N::Choice),
-        G::Sequence(seq) => seq
-            .into_iter()
-            .map(|n| normalize_impl(n, rules, in_progress))
-            .collect::<Result<Vec<_>>>()
-            .map(N::Sequence),
-        G::Optional(opt) => Ok(N::Choice(vec![
-            normalize_impl(*opt, rules, in_progress)?,
-            N::null(),
-        ])),
-        G::Reference(f, name) => {
-            let proto = Rule {
-                name,
-                node: N::Placeholder,
-            };
-            // If the rule is already defined, use the existing reference
-            if let Some(idx) = rules.get_index_of(&proto) {
-                Ok(N::Reference(idx))
+    /// Terminals that can begin each rule, computed to a fixed point.
+    pub fn first_sets(&self) -> FirstSets {
+        let mut nullable = vec![false; self.rules.len()];
+        let mut first = vec![BTreeSet::new(); self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, rule) in self.rules.iter().enumerate() {
+                let mut set = BTreeSet::new();
+                let is_nullable = first_of_node(&rule.node, &first, &nullable, &mut set);
+                if is_nullable != nullable[i] || set.len() != first[i].len() {
+                    nullable[i] = is_nullable;
+                    first[i] = set;
+                    changed = true;
+                }
             }
-            // If the rule is currently being processed, we have a cycle - use placeholder
-            else if in_progress.contains(name) {
-                Ok(N::Reference(rules.len()))
+        }
+        FirstSets { nullable, first }
+    }
+
+    /// Terminals that can directly follow each rule; START is followed by
+    /// [`END_MARKER`].
+    pub fn follow_sets(&self) -> FollowSets {
+        let first = self.first_sets();
+        let mut follow = vec![BTreeSet::new(); self.rules.len()];
+        if let Some(start) = follow.first_mut() {
+            start.insert(END_MARKER.to_string());
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, rule) in self.rules.iter().enumerate() {
+                let trailer = follow[i].clone();
+                changed |= add_follow(&rule.node, &trailer, &first, &mut follow);
             }
-            // Otherwise, define the rule
-            else {
-                let idx = rules.len();
-                rules.insert(Rule {
-                    name,
-                    node: N::Placeholder,
-                });
-                in_progress.insert(name);
-                let node = normalize_impl(f(), rules, in_progress)?;
-                in_progress.remove(name);
-                // Update the placeholder rule with the actual normalized node
-                if let Some(rule) = rules.get_index_mut2(idx) {
-                    rule.node = node;
+        }
+        FollowSets { follow }
+    }
+
+    /// Fixed-point computation of which rules can match the empty string.
+    pub(crate) fn nullable_rules(&self) -> Vec<bool> {
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, rule) in self.rules.iter().enumerate() {
+                if !nullable[i] && is_nullable_node(&rule.node, &nullable) {
+                    nullable[i] = true;
+                    changed = true;
                 }
-                Ok(N::Reference(idx))
             }
         }
-        _ => unimplemented!(),
+        nullable
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::r;
 
-    #[test]
-    fn test_normalize_terminal() {
-        fn a() -> GrammarNode {
-            t("a") + r!(b)
+    /// Appends the rules of `other`, re-indexing its references. Rules of
+    /// `other` whose names already exist here, including its START, are
+    /// renamed to `prefix` followed by the original name.
+    pub fn merge(
+        mut self,
+        other: Grammar,
+        prefix: &str,
+    ) -> std::result::Result<Grammar, MergeError> {
+        let offset = self.rules.len();
+        for rule in other.rules {
+            let name = if self.rule_index(rule.name).is_some() {
+                leak_name(format!("{}{}", prefix, rule.name))
+            } else {
+                rule.name
+            };
+            let inserted = self.rules.insert(Rule {
+                name,
+                node: shift_references(rule.node, offset),
+                ..rule
+            });
+            if !inserted {
+                return Err(MergeError::DuplicateRule(name.to_string()));
+            }
         }
+        Ok(self)
+    }
 
-        fn b() -> GrammarNode {
-            t("b") + r!(c)
+    /// Adds a reference to rule `alternative` as the last alternative of rule `name`.
+    pub fn extend_rule(
+        &mut self,
+        name: &str,
+        alternative: &str,
+    ) -> std::result::Result<(), MergeError> {
+        let index_of = |name: &str| {
+            self.rule_index(name)
+                .ok_or_else(|| MergeError::UnknownRule(name.to_string()))
+        };
+        let target = index_of(name)?;
+        let alt = index_of(alternative)?;
+        if let Some(rule) = self.rules.get_index_mut2(target.index()) {
+            let node = std::mem::replace(&mut rule.node, NormalizedNode::Placeholder);
+            rule.node = match node {
+                NormalizedNode::Choice(mut alts) => {
+                    alts.push(NormalizedNode::Reference(alt));
+                    NormalizedNode::Choice(alts)
+                }
+                node => NormalizedNode::Choice(vec![node, NormalizedNode::Reference(alt)]),
+            };
         }
+        Ok(())
+    }
 
-        fn c() -> GrammarNode {
-            t("c") + r!(a)
+    /// Replaces references to small rules with copies of their bodies, then
+    /// drops the inlined rules and compacts the remaining indices.
+    ///
+    /// A rule is inlined when its body is a terminal or a sequence of at most
+    /// `max_size` nodes and it cannot reach itself. START is never inlined,
+    /// nor are token rules, whose match must stay one leaf without trivia.
+    /// Returns the number of rules removed.
+    pub fn inline_trivial_rules(&mut self, max_size: usize) -> usize {
+        use NormalizedNode as N;
+        let inlinable: Vec<bool> = self
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(idx, rule)| {
+                idx != 0
+                    && match &rule.node {
+                        N::Terminal(_) => true,
+                        N::Sequence(_) => node_size(&rule.node) <= max_size,
+                        _ => false,
+                    }
+                    && rule.label.is_none()
+                    && !rule.fold
+                    && !rule.token
+                    && !self.reaches_rule(idx, idx)
+            })
+            .collect();
+        if !inlinable.contains(&true) {
+            return 0;
         }
 
-        let grammar = Grammar::try_from(a()).unwrap();
-        println!("{:#?}", grammar.rules);
+        // Inlined bodies may reference other inlined rules, so substitute until
+        // nothing changes; this terminates because none of them are recursive
+        let bodies: Vec<Option<NormalizedNode>> = self
+            .rules
+            .iter()
+            .zip(inlinable.iter())
+            .map(|(rule, &inline)| inline.then(|| rule.node.clone()))
+            .collect();
+        let mut rules = std::mem::take(&mut self.rules);
+        for idx in 0..rules.len() {
+            if let Some(rule) = rules.get_index_mut2(idx) {
+                while let Some(next) = inline_references(&rule.node, &bodies) {
+                    rule.node = next;
+                }
+            }
+        }
+
+        let mut remap = Vec::with_capacity(rules.len());
+        let mut next = 0;
+        for &inline in inlinable.iter() {
+            remap.push(next);
+            if !inline {
+                next += 1;
+            }
+        }
+        let removed = rules.len() - next;
+        self.rules = rules
+            .into_iter()
+            .zip(inlinable)
+            .filter(|(_, inline)| !inline)
+            .map(|(mut rule, _)| {
+                rule.node = remap_references(rule.node, &remap);
+                rule
+            })
+            .collect();
+        removed
+    }
+
+    /// Whether `to` is reachable from the body of `from` through references.
+    fn reaches_rule(&self, from: usize, to: usize) -> bool {
+        let mut seen = vec![false; self.rules.len()];
+        let mut stack = Vec::new();
+        collect_references(&self.rules[from].node, &mut stack);
+        while let Some(idx) = stack.pop() {
+            if idx == to {
+                return true;
+            }
+            if idx < seen.len() && !seen[idx] {
+                seen[idx] = true;
+                collect_references(&self.rules[idx].node, &mut stack);
+            }
+        }
+        false
+    }
+
+    /// For each rule, its body if it's a synthetic rule that can be written
+    /// out in place of references to it: one that doesn't reach itself
+    /// through synthetic rules alone, as a repetition does, and has no
+    /// label to lose.
+    pub(crate) fn inlined_bodies(&self) -> Vec<Option<&NormalizedNode>> {
+        let synthetic = |idx: usize| self.rules.get_index(idx).is_some_and(|rule| rule.synthetic);
+        let cycles = |from: usize| {
+            let mut seen = vec![false; self.rules.len()];
+            let mut stack = Vec::new();
+            collect_references(&self.rules[from].node, &mut stack);
+            while let Some(idx) = stack.pop() {
+                if idx == from {
+                    return true;
+                }
+                if synthetic(idx) && !seen[idx] {
+                    seen[idx] = true;
+                    collect_references(&self.rules[idx].node, &mut stack);
+                }
+            }
+            false
+        };
+        (self.rules.iter().enumerate())
+            .map(|(idx, rule)| {
+                let inlined = rule.synthetic && rule.label.is_none() && !cycles(idx);
+                inlined.then_some(&rule.node)
+            })
+            .collect()
+    }
+
+    /// Returns `base`, or `base` with a numeric suffix if a rule already uses it.
+    fn fresh_name(&self, base: &str) -> &'static str {
+        let taken = |name: &str| self.rule_index(name).is_some();
+        let mut name = base.to_string();
+        let mut n = 1;
+        while taken(&name) {
+            n += 1;
+            name = format!("{}{}", base, n);
+        }
+        leak_name(name)
+    }
+}
+
+/// Rule names are `&'static str`; names synthesized at runtime are leaked,
+/// which is bounded by the number of rules a grammar ever creates.
+pub(crate) fn leak_name(name: String) -> &'static str {
+    Box::leak(name.into_boxed_str())
+}
+
+/// Adds the terminals that can begin `node` to `out`, returning whether
+/// `node` is nullable.
+fn first_of_node(
+    node: &NormalizedNode,
+    first: &[BTreeSet<String>],
+    nullable: &[bool],
+    out: &mut BTreeSet<String>,
+) -> bool {
+    use NormalizedNode as N;
+    match node {
+        N::Terminal(m) => {
+            out.insert(m.display());
+            m.is_nullable()
+        }
+        N::Reference(idx) => {
+            if let Some(set) = first.get(idx.index()) {
+                out.extend(set.iter().cloned());
+            }
+            nullable.get(idx.index()).copied().unwrap_or(false)
+        }
+        N::Sequence(parts) => parts
+            .iter()
+            .all(|part| first_of_node(part, first, nullable, out)),
+        N::Choice(alts) => {
+            let mut any_nullable = false;
+            for alt in alts {
+                any_nullable |= first_of_node(alt, first, nullable, out);
+            }
+            any_nullable
+        }
+        N::Verbatim(inner) => first_of_node(inner, first, nullable, out),
+        N::Placeholder => false,
+    }
+}
+
+/// Propagates `trailer`, the terminals that can follow `node`, into the
+/// FOLLOW sets of the rules referenced inside it. Returns whether any set grew.
+fn add_follow(
+    node: &NormalizedNode,
+    trailer: &BTreeSet<String>,
+    first: &FirstSets,
+    follow: &mut [BTreeSet<String>],
+) -> bool {
+    use NormalizedNode as N;
+    match node {
+        N::Reference(idx) => match follow.get_mut(idx.index()) {
+            Some(set) => {
+                let before = set.len();
+                set.extend(trailer.iter().cloned());
+                set.len() != before
+            }
+            None => false,
+        },
+        N::Choice(alts) => {
+            let mut changed = false;
+            for alt in alts {
+                changed |= add_follow(alt, trailer, first, follow);
+            }
+            changed
+        }
+        N::Sequence(parts) => {
+            let mut changed = false;
+            let mut trailer = trailer.clone();
+            for part in parts.iter().rev() {
+                changed |= add_follow(part, &trailer, first, follow);
+                let (part_first, part_nullable) = first.of_node(part);
+                if part_nullable {
+                    trailer.extend(part_first);
+                } else {
+                    trailer = part_first;
+                }
+            }
+            changed
+        }
+        N::Verbatim(inner) => add_follow(inner, trailer, first, follow),
+        N::Terminal(_) | N::Placeholder => false,
+    }
+}
+
+fn collect_references(node: &NormalizedNode, out: &mut Vec<usize>) {
+    use NormalizedNode as N;
+    match node {
+        N::Reference(idx) => out.push(idx.index()),
+        N::Choice(nodes) | N::Sequence(nodes) => {
+            for n in nodes {
+                collect_references(n, out);
+            }
+        }
+        N::Verbatim(inner) => collect_references(inner, out),
+        N::Terminal(_) | N::Placeholder => {}
+    }
+}
+
+fn contains_placeholder(node: &NormalizedNode) -> bool {
+    use NormalizedNode as N;
+    match node {
+        N::Placeholder => true,
+        N::Choice(nodes) | N::Sequence(nodes) => nodes.iter().any(contains_placeholder),
+        N::Verbatim(inner) => contains_placeholder(inner),
+        N::Terminal(_) | N::Reference(_) => false,
+    }
+}
+
+/// Whether `node` can match some input, given which rules are known to.
+fn can_succeed(node: &NormalizedNode, productive: &[bool]) -> bool {
+    use NormalizedNode as N;
+    match node {
+        N::Terminal(_) => true,
+        N::Reference(idx) => productive.get(idx.index()).copied().unwrap_or(false),
+        N::Sequence(parts) => parts.iter().all(|p| can_succeed(p, productive)),
+        N::Choice(alts) => alts.iter().any(|a| can_succeed(a, productive)),
+        N::Verbatim(inner) => can_succeed(inner, productive),
+        N::Placeholder => false,
+    }
+}
+
+pub(crate) fn is_nullable_node(node: &NormalizedNode, nullable: &[bool]) -> bool {
+    use NormalizedNode as N;
+    match node {
+        N::Terminal(m) => m.is_nullable(),
+        N::Reference(idx) => nullable.get(idx.index()).copied().unwrap_or(false),
+        N::Sequence(parts) => parts.iter().all(|p| is_nullable_node(p, nullable)),
+        N::Choice(alts) => alts.iter().any(|a| is_nullable_node(a, nullable)),
+        N::Verbatim(inner) => is_nullable_node(inner, nullable),
+        N::Placeholder => false,
+    }
+}
+
+fn collect_left_corners(
+    node: &NormalizedNode,
+    nullable: &[bool],
+    leading: bool,
+    out: &mut Vec<(usize, bool)>,
+) {
+    use NormalizedNode as N;
+    match node {
+        N::Reference(idx) => out.push((idx.index(), leading)),
+        N::Choice(alts) => {
+            for alt in alts {
+                collect_left_corners(alt, nullable, leading, out);
+            }
+        }
+        N::Sequence(parts) => {
+            let mut leading = leading;
+            for part in parts {
+                collect_left_corners(part, nullable, leading, out);
+                if !is_nullable_node(part, nullable) {
+                    break;
+                }
+                leading = false;
+            }
+        }
+        // Not literally leading, so left recursion through it is never rewritten
+        N::Verbatim(inner) => collect_left_corners(inner, nullable, false, out),
+        N::Terminal(_) | N::Placeholder => {}
+    }
+}
+
+/// Whether `to` is reachable from `from` through left corners. When `skip_direct`
+/// names a rule, its leading self-references are ignored.
+fn reaches(
+    corners: &[Vec<(usize, bool)>],
+    from: usize,
+    to: usize,
+    skip_direct: Option<usize>,
+) -> bool {
+    let mut seen = vec![false; corners.len()];
+    let mut stack = vec![from];
+    while let Some(cur) = stack.pop() {
+        for &(next, leading) in corners[cur].iter() {
+            if leading && cur == next && skip_direct == Some(cur) {
+                continue;
+            }
+            if next == to {
+                return true;
+            }
+            if next < seen.len() && !seen[next] {
+                seen[next] = true;
+                stack.push(next);
+            }
+        }
+    }
+    false
+}
+
+/// Whether the rule has at least one alternative of the form `A rest...` and
+/// none that are the bare self-reference `A ::= A`.
+fn has_direct_tails(node: &NormalizedNode, idx: usize) -> bool {
+    use NormalizedNode as N;
+    let alts: Vec<&NormalizedNode> = match node {
+        N::Choice(alts) => alts.iter().collect(),
+        node => vec![node],
+    };
+    let mut found = false;
+    for alt in alts {
+        match alt {
+            N::Reference(r) if r.index() == idx => return false,
+            N::Sequence(parts) if matches!(parts.first(), Some(N::Reference(r)) if r.index() == idx) =>
+            {
+                if parts.len() == 1 {
+                    return false;
+                }
+                found = true;
+            }
+            _ => {}
+        }
+    }
+    found
+}
+
+/// Splits a rule body into the tails of its directly left-recursive
+/// alternatives and its remaining base alternatives.
+fn split_left_recursive(
+    node: NormalizedNode,
+    idx: usize,
+) -> (Vec<Vec<NormalizedNode>>, Vec<NormalizedNode>) {
+    use NormalizedNode as N;
+    let alts = match node {
+        N::Choice(alts) => alts,
+        node => vec![node],
+    };
+    let mut tails = Vec::new();
+    let mut bases = Vec::new();
+    for alt in alts {
+        match alt {
+            N::Sequence(mut parts) if matches!(parts.first(), Some(N::Reference(r)) if r.index() == idx) =>
+            {
+                parts.remove(0);
+                tails.push(parts);
+            }
+            alt => bases.push(alt),
+        }
+    }
+    (tails, bases)
+}
+
+/// One rule per line, as `name ::= body`. Synthetic rules are written out
+/// where they're referenced, giving the grammar as written, unless they
+/// repeat; the alternate flag, `{:#}`, shows them all as rules.
+impl fmt::Display for Grammar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use NormalizedNode as N;
+
+        let inlined = match f.alternate() {
+            true => vec![None; self.rules.len()],
+            false => self.inlined_bodies(),
+        };
+
+        /// `node`, or the body it stands for if it refers to an inlined rule.
+        fn shown<'a>(
+            node: &'a NormalizedNode,
+            inlined: &[Option<&'a NormalizedNode>],
+        ) -> &'a NormalizedNode {
+            let mut node = node;
+            while let N::Reference(idx) = node {
+                match inlined.get(idx.index()) {
+                    Some(Some(body)) => node = body,
+                    _ => break,
+                }
+            }
+            node
+        }
+
+        /// The alternatives of a choice written `[ ... ]`, one whose last
+        /// alternative is empty.
+        fn optional(node: &NormalizedNode) -> Option<&[NormalizedNode]> {
+            match node {
+                N::Choice(alts) if alts.len() > 1 && alts.last() == Some(&N::null()) => {
+                    Some(&alts[..alts.len() - 1])
+                }
+                _ => None,
+            }
+        }
+
+        fn needs_paren(node: &NormalizedNode) -> bool {
+            match node {
+                N::Choice(_) => optional(node).is_none(),
+                N::Terminal(m) => m.precedence() < Precedence::Sequence,
+                _ => false,
+            }
+        }
+
+        fn fmt_node(
+            grammar: &Grammar,
+            inlined: &[Option<&NormalizedNode>],
+            node: &NormalizedNode,
+            f: &mut fmt::Formatter<'_>,
+        ) -> fmt::Result {
+            match shown(node, inlined) {
+                N::Terminal(m) => write!(f, "{}", m.display()),
+                N::Reference(idx) => {
+                    let name = grammar.rule_name(*idx).unwrap_or("<unknown>");
+                    write!(f, "{}", name)
+                }
+                N::Placeholder => write!(f, "<placeholder>"),
+                N::Verbatim(inner) => {
+                    write!(f, "verbatim(")?;
+                    fmt_node(grammar, inlined, inner, f)?;
+                    write!(f, ")")
+                }
+                N::Sequence(parts) => {
+                    let mut first = true;
+                    for p in parts.iter() {
+                        if !first {
+                            write!(f, " ")?;
+                        }
+                        first = false;
+                        if needs_paren(shown(p, inlined)) {
+                            write!(f, "(")?;
+                            fmt_node(grammar, inlined, p, f)?;
+                            write!(f, ")")?;
+                        } else {
+                            fmt_node(grammar, inlined, p, f)?;
+                        }
+                    }
+                    Ok(())
+                }
+                node @ N::Choice(alts) => {
+                    if let Some(alts) = optional(node) {
+                        write!(f, "[ ")?;
+                        match alts {
+                            [alt] => fmt_node(grammar, inlined, alt, f)?,
+                            _ => fmt_node(grammar, inlined, &N::Choice(alts.to_vec()), f)?,
+                        }
+                        return write!(f, " ]");
+                    }
+                    let mut first = true;
+                    for a in alts.iter() {
+                        if !first {
+                            write!(f, " | ")?;
+                        }
+                        first = false;
+                        let is_sequence = match shown(a, inlined) {
+                            N::Sequence(_) => true,
+                            N::Terminal(m) => m.precedence() < Precedence::Atom,
+                            _ => false,
+                        };
+                        if is_sequence {
+                            write!(f, "(")?;
+                            fmt_node(grammar, inlined, a, f)?;
+                            write!(f, ")")?;
+                        } else {
+                            fmt_node(grammar, inlined, a, f)?;
+                        }
+                    }
+                    Ok(())
+                }
+            }
+        }
+
+        let shown_rules = (self.rules.iter().zip(&inlined))
+            .filter(|(_, inlined)| inlined.is_none())
+            .map(|(rule, _)| rule);
+        for (i, rule) in shown_rules.enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if rule.token {
+                write!(f, "@token ")?;
+            }
+            write!(f, "{} ::= ", rule.name)?;
+            fmt_node(self, &inlined, &rule.node, f)?;
+        }
+        Ok(())
+    }
+}
+
+fn simplify_node(node: NormalizedNode) -> NormalizedNode {
+    use NormalizedNode as N;
+    match node {
+        N::Sequence(parts) => {
+            let mut flat = Vec::with_capacity(parts.len());
+            for part in parts.into_iter().map(simplify_node) {
+                match part {
+                    N::Sequence(inner) => flat.extend(inner),
+                    part => flat.push(part),
+                }
+            }
+            if flat.len() == 1 {
+                flat.pop().unwrap()
+            } else {
+                N::Sequence(flat)
+            }
+        }
+        N::Choice(alts) => {
+            let mut flat: Vec<NormalizedNode> = Vec::with_capacity(alts.len());
+            'alts: for alt in alts.into_iter().map(simplify_node) {
+                let inner = match alt {
+                    N::Choice(inner) => inner,
+                    alt => vec![alt],
+                };
+                for alt in inner {
+                    if flat.last() == Some(&N::null()) {
+                        break 'alts;
+                    }
+                    if !flat.contains(&alt) {
+                        flat.push(alt);
+                    }
+                }
+            }
+            if flat.len() == 1 {
+                flat.pop().unwrap()
+            } else {
+                N::Choice(flat)
+            }
+        }
+        N::Verbatim(inner) => N::Verbatim(Box::new(simplify_node(*inner))),
+        node => node,
+    }
+}
+
+fn node_size(node: &NormalizedNode) -> usize {
+    use NormalizedNode as N;
+    match node {
+        N::Choice(nodes) | N::Sequence(nodes) => 1 + nodes.iter().map(node_size).sum::<usize>(),
+        N::Verbatim(inner) => 1 + node_size(inner),
+        _ => 1,
+    }
+}
+
+/// One substitution pass of `bodies` into `node`, or `None` if `node`
+/// references none of them. Inlined sequences are spliced into enclosing ones.
+fn inline_references(
+    node: &NormalizedNode,
+    bodies: &[Option<NormalizedNode>],
+) -> Option<NormalizedNode> {
+    use NormalizedNode as N;
+    match node {
+        N::Reference(idx) => bodies.get(idx.index()).cloned().flatten(),
+        N::Choice(nodes) | N::Sequence(nodes) => {
+            let replaced: Vec<_> = nodes.iter().map(|n| inline_references(n, bodies)).collect();
+            if replaced.iter().all(Option::is_none) {
+                return None;
+            }
+            let nodes = nodes
+                .iter()
+                .zip(replaced)
+                .map(|(n, r)| r.unwrap_or_else(|| n.clone()));
+            Some(match node {
+                N::Choice(_) => N::Choice(nodes.collect()),
+                _ => N::Sequence(
+                    nodes
+                        .flat_map(|n| match n {
+                            N::Sequence(parts) => parts,
+                            n => vec![n],
+                        })
+                        .collect(),
+                ),
+            })
+        }
+        N::Verbatim(inner) => inline_references(inner, bodies).map(|n| N::Verbatim(Box::new(n))),
+        N::Terminal(_) | N::Placeholder => None,
+    }
+}
+
+fn remap_references(node: NormalizedNode, remap: &[usize]) -> NormalizedNode {
+    use NormalizedNode as N;
+    match node {
+        N::Reference(idx) => N::Reference(RuleId::new(
+            remap.get(idx.index()).copied().unwrap_or(idx.index()),
+        )),
+        N::Choice(nodes) => N::Choice(
+            nodes
+                .into_iter()
+                .map(|n| remap_references(n, remap))
+                .collect(),
+        ),
+        N::Sequence(nodes) => N::Sequence(
+            nodes
+                .into_iter()
+                .map(|n| remap_references(n, remap))
+                .collect(),
+        ),
+        N::Verbatim(inner) => N::Verbatim(Box::new(remap_references(*inner, remap))),
+        n => n,
+    }
+}
+
+fn shift_references(node: NormalizedNode, offset: usize) -> NormalizedNode {
+    use NormalizedNode as N;
+    match node {
+        N::Reference(idx) => N::Reference(RuleId::new(idx.index() + offset)),
+        N::Choice(nodes) => N::Choice(
+            nodes
+                .into_iter()
+                .map(|n| shift_references(n, offset))
+                .collect(),
+        ),
+        N::Sequence(nodes) => N::Sequence(
+            nodes
+                .into_iter()
+                .map(|n| shift_references(n, offset))
+                .collect(),
+        ),
+        N::Verbatim(inner) => N::Verbatim(Box::new(shift_references(*inner, offset))),
+        n => n,
+    }
+}
+
+/// Bookkeeping shared across one normalization run.
+#[derive(Default)]
+struct Normalizer {
+    /// Every rule function seen under each name, to detect colliding names.
+    seen: HashMap<&'static str, Vec<RuleFn>>,
+    /// Bodies of colliding rule functions, compared once all rules are defined.
+    pending: Vec<(usize, NormalizedNode)>,
+    /// The rules whose bodies are being normalized, innermost last. Rules
+    /// synthesized on the way are named after the innermost.
+    enclosing: Vec<&'static str>,
+    /// Every instance of a parameterized rule, by function and normalized
+    /// argument.
+    instances: Vec<(RuleFn1, NormalizedNode, RuleId)>,
+    /// Every precedence table lowered, with the node it was lowered to.
+    climbs: Vec<(ClimbKey, NormalizedNode)>,
+}
+
+/// A precedence table by name, normalized atom and operators, and the
+/// precedence and associativity of each operator.
+type ClimbKey = (
+    &'static str,
+    NormalizedNode,
+    Vec<NormalizedNode>,
+    Vec<(u32, Assoc)>,
+);
+
+/// A step of normalization. Nodes are taken apart onto a work stack rather
+/// than by recursion, so deep nesting costs heap instead of call stack; the
+/// steps pushed under a node's children put their results back together.
+enum Work {
+    /// Normalize the node, `depth` levels into a rule body.
+    Node(GrammarNode, usize),
+    Choice(usize),
+    Sequence(usize),
+    Optional,
+    Some,
+    Many,
+    Verbatim,
+    Token,
+    /// The argument of this application of a parameterized rule is done.
+    Apply(RuleFn1, &'static str),
+    /// The atom and the operators of a precedence table are done.
+    Climb(&'static str, Vec<(u32, Assoc)>),
+    /// The node an `expected` label is given to is done.
+    Expected(&'static str),
+    /// The body of the rule just defined at this index is done.
+    Define(usize),
+    /// The body of another function named like the rule at this index is
+    /// done.
+    Compare(usize),
+}
+
+fn normalize(
+    node: GrammarNode,
+    rules: &mut IndexSet<Rule>,
+    max_depth: usize,
+) -> Result<NormalizedNode> {
+    let mut ctx = Normalizer {
+        enclosing: vec!["START"],
+        ..Normalizer::default()
+    };
+    let mut work = vec![Work::Node(node, 0)];
+    let start = normalize_impl(&mut work, rules, &mut ctx, max_depth);
+    // An error leaves nodes unvisited, maybe nested too deep to drop.
+    for step in work {
+        if let Work::Node(node, _) = step {
+            dismantle(node);
+        }
+    }
+    let start = start?;
+    for (idx, body) in ctx.pending {
+        let rule = &rules[idx];
+        if rule.node != body {
+            return Err(EvaluationError::ConflictingRule {
+                name: rule.name.to_string(),
+            });
+        }
+    }
+    Ok(start)
+}
+
+fn normalize_impl(
+    work: &mut Vec<Work>,
+    rules: &mut IndexSet<Rule>,
+    ctx: &mut Normalizer,
+    max_depth: usize,
+) -> Result<NormalizedNode> {
+    use GrammarNode as G;
+    use NormalizedNode as N;
+    // Normalized nodes waiting for their parent, innermost last.
+    let mut done: Vec<NormalizedNode> = Vec::new();
+    let last = |done: &mut Vec<NormalizedNode>| done.pop().unwrap_or(N::Placeholder);
+    while let Some(step) = work.pop() {
+        let (node, depth) = match step {
+            Work::Node(node, depth) => (node, depth),
+            Work::Choice(n) | Work::Sequence(n) => {
+                let nodes = done.split_off(done.len() - n);
+                done.push(match step {
+                    Work::Choice(_) => N::Choice(nodes),
+                    _ => N::Sequence(nodes),
+                });
+                continue;
+            }
+            Work::Optional => {
+                let node = last(&mut done);
+                let name = format!("{}_opt", ctx.enclosing());
+                let idx = make_rule(rules, &name, (true, None), |_| {
+                    N::Choice(vec![node.clone(), N::null()])
+                });
+                done.push(N::Reference(idx));
+                continue;
+            }
+            Work::Some | Work::Many => {
+                let node = last(&mut done);
+                let name = format!("{}_list", ctx.item_name(&node, rules));
+                let list = make_rule(rules, &name, (true, None), |idx| {
+                    let more = N::Sequence(vec![node.clone(), N::Reference(idx)]);
+                    N::Choice(vec![more, N::null()])
+                });
+                done.push(match step {
+                    Work::Some => N::Sequence(vec![node, N::Reference(list)]),
+                    _ => N::Reference(list),
+                });
+                continue;
+            }
+            Work::Expected(label) => {
+                let node = last(&mut done);
+                let name = format!("{}_expected", ctx.item_name(&node, rules));
+                let idx = make_rule(rules, &name, (true, Some(label)), |_| node.clone());
+                done.push(N::Reference(idx));
+                continue;
+            }
+            Work::Verbatim => {
+                let node = last(&mut done);
+                done.push(N::Verbatim(Box::new(node)));
+                continue;
+            }
+            Work::Token => {
+                let Some(N::Reference(idx)) = done.last() else {
+                    return Err(EvaluationError::InvalidToken);
+                };
+                if rules[idx.index()].synthetic {
+                    return Err(EvaluationError::InvalidToken);
+                }
+                if let Some(rule) = rules.get_index_mut2(idx.index()) {
+                    rule.token = true;
+                }
+                continue;
+            }
+            Work::Define(idx) => {
+                // Update the placeholder rule with the actual normalized node
+                let body = last(&mut done);
+                if let Some(rule) = rules.get_index_mut2(idx) {
+                    rule.node = body;
+                }
+                ctx.enclosing.pop();
+                done.push(N::Reference(RuleId::new(idx)));
+                continue;
+            }
+            Work::Compare(idx) => {
+                let body = last(&mut done);
+                ctx.enclosing.pop();
+                ctx.pending.push((idx, body));
+                done.push(N::Reference(RuleId::new(idx)));
+                continue;
+            }
+            Work::Apply(f, name) => {
+                // Instances are shared by argument, so one applied to the
+                // argument it was instantiated with, as in its own body,
+                // finds its placeholder
+                let key = last(&mut done);
+                let instance = (ctx.instances.iter())
+                    .find(|(g, arg, _)| std::ptr::fn_addr_eq(f, *g) && *arg == key);
+                if let Some(&(_, _, idx)) = instance {
+                    done.push(N::Reference(idx));
+                    continue;
+                }
+                let base = format!("{}<{}>", name, describe_argument(&key, rules));
+                let mut instance_name = base.clone();
+                let mut n = 1;
+                while rules.contains(instance_name.as_str()) {
+                    n += 1;
+                    instance_name = format!("{}{}", base, n);
+                }
+                let instance_name = leak_name(instance_name);
+                let idx = rules.len();
+                rules.insert(Rule::new(instance_name, N::Placeholder));
+                let body = f(GrammarNode::Normalized(key.clone()));
+                ctx.instances.push((f, key, RuleId::new(idx)));
+                ctx.enclosing.push(instance_name);
+                work.extend([Work::Define(idx), Work::Node(body, 0)]);
+                continue;
+            }
+            Work::Climb(name, table) => {
+                let operators = done.split_off(done.len() - table.len());
+                let atom = last(&mut done);
+                // A table normalized again, as in the body of a rule
+                // compared with another, gets the rules it got before
+                let key = (name, atom, operators, table);
+                let known = ctx.climbs.iter().find(|(other, _)| *other == key);
+                let node = match known {
+                    Some((_, node)) => node.clone(),
+                    None => {
+                        let (name, atom, operators, table) = &key;
+                        let node = climb(rules, name, atom.clone(), operators, table);
+                        ctx.climbs.push((key, node.clone()));
+                        node
+                    }
+                };
+                done.push(node);
+                continue;
+            }
+        };
+        if depth > max_depth {
+            work.push(Work::Node(node, depth));
+            return Err(EvaluationError::TooDeep { max_depth });
+        }
+        let children = |nodes: Vec<GrammarNode>| {
+            (nodes.into_iter().rev()).map(move |node| Work::Node(node, depth + 1))
+        };
+        match node {
+            G::Terminal(m) => done.push(N::Terminal(m)),
+            G::Choice(nodes) => {
+                work.push(Work::Choice(nodes.len()));
+                work.extend(children(nodes));
+            }
+            G::Sequence(nodes) => {
+                work.push(Work::Sequence(nodes.len()));
+                work.extend(children(nodes));
+            }
+            G::Optional(inner) => work.extend([Work::Optional, Work::Node(*inner, depth + 1)]),
+            G::Some(inner) => work.extend([Work::Some, Work::Node(*inner, depth + 1)]),
+            G::Many(inner) => work.extend([Work::Many, Work::Node(*inner, depth + 1)]),
+            G::Verbatim(inner) => work.extend([Work::Verbatim, Work::Node(*inner, depth + 1)]),
+            G::Expected(label, inner) => {
+                work.extend([Work::Expected(label), Work::Node(*inner, depth + 1)]);
+            }
+            G::Token(inner) => work.extend([Work::Token, Work::Node(*inner, depth + 1)]),
+            G::Apply(f, name, argument) => {
+                work.extend([Work::Apply(f, name), Work::Node(*argument, depth + 1)]);
+            }
+            G::Normalized(node) => done.push(node),
+            G::Climb(name, atom, table) => {
+                let (operators, table): (Vec<_>, Vec<_>) = (table.into_iter())
+                    .map(|(operator, precedence, assoc)| (operator, (precedence, assoc)))
+                    .unzip();
+                work.push(Work::Climb(name, table));
+                work.extend(children([vec![*atom], operators].concat()));
+            }
+            G::Reference(f, name) => {
+                // A rule made up earlier may have taken the name; it gives
+                // the name up
+                if rules.get(name).is_some_and(|rule| rule.synthetic) {
+                    rename_synthetic(rules, name);
+                }
+                // If the rule is already defined, use the existing reference
+                if let Some(idx) = rules.get_index_of(name) {
+                    let fns = ctx.seen.entry(name).or_default();
+                    // A different function under the same name must produce the same body
+                    if fns.iter().any(|&g| std::ptr::fn_addr_eq(f, g)) {
+                        done.push(N::Reference(RuleId::new(idx)));
+                    } else {
+                        fns.push(f);
+                        ctx.enclosing.push(name);
+                        work.extend([Work::Compare(idx), Work::Node(f(), 0)]);
+                    }
+                }
+                // Otherwise, define the rule; references to it from its own
+                // body find the placeholder
+                else {
+                    let idx = rules.len();
+                    rules.insert(Rule::new(name, N::Placeholder));
+                    ctx.seen.entry(name).or_default().push(f);
+                    ctx.enclosing.push(name);
+                    work.extend([Work::Define(idx), Work::Node(f(), 0)]);
+                }
+            }
+        }
+    }
+    Ok(last(&mut done))
+}
+
+impl Normalizer {
+    fn enclosing(&self) -> &'static str {
+        self.enclosing.last().copied().unwrap_or("START")
+    }
+
+    /// What to call a repetition of `item`: the rule it refers to, or else
+    /// the rule it's in.
+    fn item_name(&self, item: &NormalizedNode, rules: &IndexSet<Rule>) -> &'static str {
+        match item {
+            NormalizedNode::Reference(idx) if !rules[idx.index()].synthetic => {
+                rules[idx.index()].name
+            }
+            _ => self.enclosing(),
+        }
+    }
+}
+
+/// A short rendering of the argument of a parameterized rule, for naming
+/// its instance.
+fn describe_argument(node: &NormalizedNode, rules: &IndexSet<Rule>) -> String {
+    use NormalizedNode as N;
+    let all = |nodes: &[NormalizedNode], separator| {
+        (nodes.iter())
+            .map(|node| describe_argument(node, rules))
+            .collect::<Vec<_>>()
+            .join(separator)
+    };
+    match node {
+        N::Terminal(matcher) => matcher.display(),
+        N::Reference(idx) => rules[idx.index()].name.to_string(),
+        N::Sequence(parts) if parts.is_empty() => String::from("()"),
+        N::Sequence(parts) => all(parts, " "),
+        N::Choice(alternatives) => format!("({})", all(alternatives, " | ")),
+        N::Verbatim(inner) => describe_argument(inner, rules),
+        N::Placeholder => String::from("?"),
+    }
+}
+
+/// The rules of a precedence table over `atom`, and the node matching an
+/// expression of them. Each precedence gets a folded rule, `{name}_{p} ::=
+/// head {name}_{p}_tail`, whose head is the next tighter rule, or `atom`,
+/// or a prefix operator applied to the rule itself, and whose synthetic
+/// tail repeats binary operators and their right operands and postfix
+/// operators, then ends in at most one right-associative operator applied
+/// to the rule itself. None of them is left-recursive, and each refers to
+/// the tighter rules through the next one only, so an expression is read
+/// in one pass however many precedences there are; folding the match
+/// gives the tree its shape.
+fn climb(
+    rules: &mut IndexSet<Rule>,
+    name: &'static str,
+    atom: NormalizedNode,
+    operators: &[NormalizedNode],
+    table: &[(u32, Assoc)],
+) -> NormalizedNode {
+    use NormalizedNode as N;
+    let mut precedences: Vec<_> = table.iter().map(|&(precedence, _)| precedence).collect();
+    precedences.sort_unstable();
+    precedences.dedup();
+    let fresh = |rules: &IndexSet<Rule>, base: String| {
+        let mut candidate = base.clone();
+        let mut n = 1;
+        while rules.contains(candidate.as_str()) {
+            n += 1;
+            candidate = format!("{}{}", base, n);
+        }
+        leak_name(candidate)
+    };
+    let mut next = atom;
+    for &precedence in precedences.iter().rev() {
+        let of = |assoc| {
+            let ops: Vec<_> = (table.iter().zip(operators))
+                .filter(|&(&entry, _)| entry == (precedence, assoc))
+                .map(|(_, operator)| operator.clone())
+                .collect();
+            match ops.len() {
+                0 => None,
+                1 => ops.into_iter().next(),
+                _ => Some(N::Choice(ops)),
+            }
+        };
+        let level = RuleId::new(rules.len());
+        let level_name = fresh(rules, format!("{}_{}", name, precedence));
+        rules.insert(Rule {
+            fold: true,
+            ..Rule::new(level_name, N::Placeholder)
+        });
+
+        let mut steps = Vec::new();
+        let tail = RuleId::new(rules.len());
+        if let Some(op) = of(Assoc::Left) {
+            steps.push(N::Sequence(vec![op, next.clone(), N::Reference(tail)]));
+        }
+        if let Some(op) = of(Assoc::Postfix) {
+            steps.push(N::Sequence(vec![op, N::Reference(tail)]));
+        }
+        if let Some(op) = of(Assoc::Right) {
+            steps.push(N::Sequence(vec![op, N::Reference(level)]));
+        }
+        let head = match of(Assoc::Prefix) {
+            Some(op) => N::Choice(vec![N::Sequence(vec![op, N::Reference(level)]), next]),
+            None => next,
+        };
+        let body = match steps.is_empty() {
+            true => head,
+            false => {
+                steps.push(N::null());
+                let tail_name = fresh(rules, format!("{}_tail", level_name));
+                rules.insert(Rule::synthetic(tail_name, N::Choice(steps)));
+                N::Sequence(vec![head, N::Reference(tail)])
+            }
+        };
+        if let Some(rule) = rules.get_index_mut2(level.index()) {
+            rule.node = body;
+        }
+        next = N::Reference(level);
+    }
+    next
+}
+
+/// A rule named `name`, or `name` with a numeric suffix, whose body `body`
+/// gives given the rule's own id, synthetic and labeled as `kind` says. An
+/// earlier rule made here with the same body and kind is reused, so a rule
+/// normalized twice makes its helpers once.
+fn make_rule(
+    rules: &mut IndexSet<Rule>,
+    name: &str,
+    kind: (bool, Option<&'static str>),
+    body: impl Fn(RuleId) -> NormalizedNode,
+) -> RuleId {
+    let (synthetic, label) = kind;
+    let mut candidate = name.to_string();
+    let mut n = 1;
+    while let Some((idx, rule)) = rules.get_full(candidate.as_str()) {
+        let idx = RuleId::new(idx);
+        if (rule.synthetic, rule.label) == kind && rule.node == body(idx) {
+            return idx;
+        }
+        n += 1;
+        candidate = format!("{}{}", name, n);
+    }
+    let idx = RuleId::new(rules.len());
+    let rule = Rule::new(leak_name(candidate), body(idx));
+    rules.insert(Rule {
+        synthetic,
+        label,
+        ..rule
+    });
+    idx
+}
+
+/// Moves the synthetic rule named `name` to a fresh name, keeping its
+/// place, so a written rule can have the name.
+fn rename_synthetic(rules: &mut IndexSet<Rule>, name: &str) {
+    let Some((idx, rule)) = rules.get_full(name) else {
+        return;
+    };
+    let mut n = 1;
+    let fresh = loop {
+        n += 1;
+        let candidate = format!("{}{}", name, n);
+        if !rules.contains(candidate.as_str()) {
+            break leak_name(candidate);
+        }
+    };
+    let rule = Rule {
+        name: fresh,
+        ..rule.clone()
+    };
+    let _ = rules.replace_index(idx, rule);
+}
+
+/// Drops `node` a level at a time, however deep it nests.
+fn dismantle(node: GrammarNode) {
+    use GrammarNode as G;
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        match node {
+            G::Choice(nodes) | G::Sequence(nodes) => stack.extend(nodes),
+            G::Climb(_, atom, table) => {
+                stack.push(*atom);
+                stack.extend(table.into_iter().map(|(operator, _, _)| operator));
+            }
+            G::Optional(inner)
+            | G::Verbatim(inner)
+            | G::Token(inner)
+            | G::Some(inner)
+            | G::Many(inner)
+            | G::Expected(_, inner)
+            | G::Apply(_, _, inner) => stack.push(*inner),
+            G::Terminal(_) | G::Reference(..) | G::Normalized(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{r, r1};
+
+    #[test]
+    fn test_normalize_terminal() {
+        fn a() -> GrammarNode {
+            t("a") + r!(b)
+        }
+
+        fn b() -> GrammarNode {
+            t("b") + r!(c)
+        }
+
+        fn c() -> GrammarNode {
+            t("c") + r!(a)
+        }
+
+        let grammar = Grammar::try_from(a()).unwrap();
+        println!("{:#?}", grammar.rules);
+    }
+
+    #[test]
+    fn test_eliminate_left_recursion() {
+        fn expr() -> GrammarNode {
+            (r!(expr) + t("+") + r!(term)) | r!(term)
+        }
+
+        fn term() -> GrammarNode {
+            t("1") | t("2") | t("3")
+        }
+
+        let mut grammar = Grammar::try_from(r!(expr)).unwrap();
+        assert_eq!(grammar.left_recursive_rules(), vec![RuleId::new(1)]);
+        let before = grammar.rules.len();
+
+        grammar.eliminate_left_recursion().unwrap();
+        assert!(grammar.left_recursive_rules().is_empty());
+        assert_eq!(grammar.rules.len(), before + 1);
+        assert_eq!(grammar.rules[1].name, "expr");
+        assert_eq!(grammar.rules[2].name, "term");
+        assert_eq!(
+            grammar.to_string(),
+            "START ::= expr\n\
+             expr ::= term expr_tail\n\
+             term ::= \"1\" | \"2\" | \"3\"\n\
+             expr_tail ::= [ \"+\" term expr_tail ]"
+        );
+
+        // Operands come in order, each `+` with the term to its right, and
+        // the tails fall away in the transparent view.
+        let text = "1+2+3";
+        let mut state = crate::parser::ParserState::new(grammar.clone()).with_text(text);
+        assert!(state.parse().is_complete());
+        let render = |transparent| {
+            let options = crate::tree::SexprOptions {
+                transparent,
+                ..Default::default()
+            };
+            crate::tree::render_sexpr(state.ast(), state.arena(), &grammar, text, options)
+        };
+        assert_eq!(
+            render(false),
+            r#"(START (expr (term "1") (expr_tail "+" (term "2") (expr_tail "+" (term "3") (expr_tail)))))"#
+        );
+        assert_eq!(
+            render(true),
+            r#"(START (expr (term "1") "+" (term "2") "+" (term "3")))"#
+        );
+    }
+
+    #[test]
+    fn test_left_recursion_in_nested_choice_rejected() {
+        fn a() -> GrammarNode {
+            (r!(a) + t("x")) | ((r!(a) | t("b")) + t("c")) | t("d")
+        }
+
+        let mut grammar = Grammar::try_from(r!(a)).unwrap();
+        let before = grammar.to_string();
+        assert!(matches!(
+            grammar.eliminate_left_recursion(),
+            Err(EvaluationError::UnsupportedLeftRecursion(name)) if name == "a"
+        ));
+        assert_eq!(grammar.to_string(), before);
+        assert_eq!(grammar.left_recursive_rules(), [RuleId::new(1)]);
+    }
+
+    #[test]
+    fn test_indirect_left_recursion_rejected() {
+        fn a() -> GrammarNode {
+            (r!(b) + t("x")) | t("y")
+        }
+
+        fn b() -> GrammarNode {
+            r!(a) + t("z")
+        }
+
+        let mut grammar = Grammar::try_from(r!(a)).unwrap();
+        assert_eq!(grammar.left_recursive_rules(), [1, 2].map(RuleId::new));
+        let before = grammar.to_string();
+        assert!(matches!(
+            grammar.eliminate_left_recursion(),
+            Err(EvaluationError::IndirectLeftRecursion(_))
+        ));
+        assert_eq!(grammar.to_string(), before);
+    }
+
+    #[test]
+    fn test_validate() {
+        fn list() -> GrammarNode {
+            t("[") + r!(items) + t("]")
+        }
+
+        // No base case: `items` can never finish matching
+        fn items() -> GrammarNode {
+            t("x") + r!(items)
+        }
+
+        fn never() -> GrammarNode {
+            choice([])
+        }
+
+        fn unused() -> GrammarNode {
+            t("u") + r!(never)
+        }
+
+        let mut grammar = Grammar::try_from(r!(list)).unwrap();
+        assert!(grammar.validate().iter().all(|d| d.rule != "unused"));
+
+        let extra = Grammar::try_from(r!(unused)).unwrap();
+        for rule in extra.rules.into_iter().skip(1) {
+            grammar.rules.insert(Rule {
+                node: shift_references(rule.node, 2),
+                ..rule
+            });
+        }
+
+        let diagnostics = grammar.validate();
+        let summary: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.rule, d.severity, format!("{:?}", d.error)))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("START", Severity::Error, "AlwaysFails".to_string()),
+                ("list", Severity::Error, "AlwaysFails".to_string()),
+                ("items", Severity::Error, "AlwaysFails".to_string()),
+                (
+                    "unused",
+                    Severity::Warning,
+                    "UnreachableRule(\"unused\")".to_string()
+                ),
+                ("unused", Severity::Error, "AlwaysFails".to_string()),
+                (
+                    "never",
+                    Severity::Warning,
+                    "UnreachableRule(\"never\")".to_string()
+                ),
+                ("never", Severity::Error, "AlwaysFails".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_conflicting_rule_names() {
+        mod first {
+            use crate::grammar_dsl::*;
+            use crate::r;
+
+            pub fn list() -> GrammarNode {
+                r!(item) + opt(t(",") + r!(list))
+            }
+
+            pub fn item() -> GrammarNode {
+                t("a")
+            }
+        }
+
+        mod second {
+            use crate::grammar_dsl::*;
+            use crate::r;
+
+            pub fn list() -> GrammarNode {
+                r!(item) + t(";")
+            }
+
+            pub fn item() -> GrammarNode {
+                t("b")
+            }
+        }
+
+        fn root() -> GrammarNode {
+            r!(first::list) | r!(second::list)
+        }
+
+        let err = Grammar::try_from(r!(root)).err().unwrap();
+        assert!(matches!(err, EvaluationError::ConflictingRule { name } if name == "item"));
+
+        // A different function with an identical body is not a conflict
+        fn item() -> GrammarNode {
+            t("a")
+        }
+        fn local() -> GrammarNode {
+            r!(first::list) | r!(item)
+        }
+        let grammar = Grammar::try_from(r!(local)).unwrap();
+        assert_eq!(grammar.rules.iter().filter(|r| r.name == "item").count(), 1);
+    }
+
+    #[test]
+    fn test_first_follow_sets() {
+        fn expr() -> GrammarNode {
+            r!(term) + opt(t("+") + r!(expr))
+        }
+
+        fn term() -> GrammarNode {
+            r!(factor) + opt(t("*") + r!(term))
+        }
+
+        fn factor() -> GrammarNode {
+            (t("(") + r!(expr) + t(")")) | t("n")
+        }
+
+        let set =
+            |items: &[&str]| -> BTreeSet<String> { items.iter().map(|s| s.to_string()).collect() };
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        let first = grammar.first_sets();
+        for idx in 0..4 {
+            assert_eq!(
+                first.first(RuleId::new(idx)),
+                Some(&set(&["\"(\"", "\"n\""]))
+            );
+            assert!(!first.is_nullable(RuleId::new(idx)));
+        }
+        assert!(first.can_start_with(RuleId::new(3), &"n"));
+        assert!(!first.can_start_with(RuleId::new(3), &"+"));
+
+        let follow = grammar.follow_sets();
+        assert_eq!(follow.follow(RuleId::new(0)), Some(&set(&["EOF"])));
+        assert_eq!(follow.follow(RuleId::new(1)), Some(&set(&["EOF", "\")\""])));
+        assert_eq!(
+            follow.follow(RuleId::new(2)),
+            Some(&set(&["EOF", "\")\"", "\"+\""]))
+        );
+        assert_eq!(
+            follow.follow(RuleId::new(3)),
+            Some(&set(&["EOF", "\")\"", "\"*\"", "\"+\""]))
+        );
+        assert!(follow.can_follow(RuleId::new(3), &"*"));
+    }
+
+    #[test]
+    fn test_merge() {
+        mod base {
+            use crate::grammar_dsl::*;
+            use crate::r;
+
+            pub fn expr() -> GrammarNode {
+                r!(literal) | r!(ident)
+            }
+
+            pub fn literal() -> GrammarNode {
+                t("0") | t("1")
+            }
+
+            pub fn ident() -> GrammarNode {
+                t("x")
+            }
+        }
+
+        mod ext {
+            use crate::grammar_dsl::*;
+            use crate::r;
+
+            pub fn string() -> GrammarNode {
+                t("'") + r!(ident) + t("'")
+            }
+
+            pub fn ident() -> GrammarNode {
+                t("y")
+            }
+        }
+
+        let grammar = Grammar::try_from(r!(base::expr)).unwrap();
+        let other = Grammar::try_from(r!(ext::string)).unwrap();
+        let mut merged = grammar.merge(other, "ext_").unwrap();
+        merged.extend_rule("literal", "ext_START").unwrap();
+        assert_eq!(
+            merged.to_string(),
+            "START ::= base::expr\n\
+             base::expr ::= literal | ident\n\
+             literal ::= \"0\" | \"1\" | ext_START\n\
+             ident ::= \"x\"\n\
+             ext_START ::= ext::string\n\
+             ext::string ::= \"'\" ext_ident \"'\"\n\
+             ext_ident ::= \"y\""
+        );
+        assert_eq!(
+            merged.extend_rule("missing", "ident"),
+            Err(MergeError::UnknownRule("missing".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_inline_trivial_rules() {
+        fn list() -> GrammarNode {
+            r!(item) + opt(r!(separator) + r!(list))
+        }
+
+        fn item() -> GrammarNode {
+            t("a") | r!(nested)
+        }
+
+        fn nested() -> GrammarNode {
+            r!(open) + r!(list) + t("]")
+        }
+
+        fn open() -> GrammarNode {
+            t("[")
+        }
+
+        fn separator() -> GrammarNode {
+            r!(comma) + r!(space)
+        }
+
+        fn comma() -> GrammarNode {
+            t(",")
+        }
+
+        fn space() -> GrammarNode {
+            t(" ")
+        }
+
+        let mut grammar = Grammar::try_from(r!(list)).unwrap();
+        assert_eq!(grammar.rules.len(), 9);
+        assert_eq!(grammar.inline_trivial_rules(3), 4);
+        assert_eq!(grammar.rules.len(), 5);
+        assert_eq!(
+            grammar.to_string(),
+            "START ::= list\n\
+             list ::= item [ \",\" \" \" list ]\n\
+             item ::= \"a\" | nested\n\
+             nested ::= \"[\" list \"]\""
+        );
+        assert!(grammar.validate().is_empty());
+    }
+
+    #[test]
+    fn test_simplify() {
+        fn root() -> GrammarNode {
+            seq([t("a"), seq([t("b"), seq([])]), choice([t("c")])])
+                + choice([
+                    t("x"),
+                    choice([t("y"), t("x")]),
+                    choice([t("z"), seq([])]),
+                    t("unreachable"),
+                ])
+        }
+
+        let grammar = Grammar::try_from_unsimplified(root()).unwrap();
+        assert_eq!(
+            grammar.to_string(),
+            "START ::= \"a\" \"b\"  (\"c\") (\"x\" | \"y\" | \"x\" | [ \"z\" ] | \"unreachable\")"
+        );
+
+        let grammar = Grammar::try_from(root()).unwrap();
+        assert_eq!(
+            grammar.to_string(),
+            "START ::= \"a\" \"b\" \"c\" [ \"x\" | \"y\" | \"z\" ]"
+        );
+        let NormalizedNode::Sequence(parts) = &grammar.rules[0].node else {
+            panic!("expected a sequence");
+        };
+        assert_eq!(parts.len(), 4);
+    }
+
+    #[test]
+    fn test_simplify_optional() {
+        use NormalizedNode as N;
+
+        let optionals = [
+            choice([t("x"), seq([]), seq([])]),
+            choice([t("x"), t("x"), seq([]), t("y")]),
+            choice([choice([t("x"), seq([])]), seq([])]),
+            choice([seq([choice([t("x"), seq([])])]), t("y")]),
+        ];
+        for node in optionals {
+            let grammar = Grammar::try_from(node).unwrap();
+            assert_eq!(grammar.to_string(), "START ::= [ \"x\" ]");
+            assert_eq!(grammar.to_ebnf(), "START ::= [ \"x\" ]");
+            let NormalizedNode::Choice(alts) = &grammar.rules[0].node else {
+                panic!("expected a choice");
+            };
+            assert_eq!(alts.as_slice(), [N::Terminal(Arc::new("x")), N::null()]);
+        }
+
+        let grammar = Grammar::try_from(choice([seq([]), t("x")])).unwrap();
+        assert_eq!(grammar.rules[0].node, N::null());
+    }
+
+    #[test]
+    fn test_accessors() {
+        fn expr() -> GrammarNode {
+            r!(atom) + opt(t("+") + r!(expr))
+        }
+
+        fn atom() -> GrammarNode {
+            t("1")
+        }
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        assert_eq!(grammar.len(), 4);
+        assert_eq!(grammar.rule_index("START"), Some(grammar.start()));
+        assert_eq!(grammar.rule_index("atom").map(RuleId::index), Some(2));
+        assert_eq!(grammar.rule_index("missing"), None);
+        assert_eq!(grammar.rule(RuleId::new(1)).map(|r| r.name), Some("expr"));
+        assert!(grammar.rule(RuleId::new(4)).is_none());
+        let names: Vec<_> = grammar.iter().map(|(i, r)| (i.index(), r.name)).collect();
+        assert_eq!(
+            names,
+            vec![(0, "START"), (1, "expr"), (2, "atom"), (3, "expr_opt")]
+        );
+        assert_eq!(
+            grammar.rule(grammar.start()).map(|r| &r.node),
+            Some(&NormalizedNode::Reference(RuleId::new(1)))
+        );
+    }
+
+    #[test]
+    fn test_normalize_deep_nesting() {
+        // Built in a loop, as `+` would have flattened it.
+        let nested = |depth: usize| {
+            (0..depth).fold(t("a"), |node, _| GrammarNode::Sequence(vec![t("b"), node]))
+        };
+        let too_deep = Err(EvaluationError::TooDeep {
+            max_depth: DEFAULT_MAX_DEPTH,
+        });
+        assert_eq!(Grammar::try_from(nested(100_000)).map(|_| ()), too_deep);
+        let options = (0..100_000).fold(t("a"), |node, _| opt(node));
+        assert_eq!(Grammar::try_from(options).map(|_| ()), too_deep);
+
+        let grammar = Grammar::try_from(nested(200)).unwrap();
+        let text = "b".repeat(200) + "a";
+        assert!(crate::parser::parse(&grammar, &text).is_complete());
+        assert_eq!(
+            Grammar::try_from(nested(600)).map(|_| ()),
+            Err(EvaluationError::TooDeep { max_depth: 256 })
+        );
+        assert!(Grammar::try_from_with_max_depth(nested(600), 1000).is_ok());
+
+        // Wide is fine at any width.
+        let literals = choice((0..2_000).map(|i| t(format!("<{i}>"))));
+        let grammar = Grammar::try_from(literals).unwrap();
+        assert!(crate::parser::parse(&grammar, "<1999>").is_complete());
+    }
+
+    #[test]
+    fn test_rule_ids_resolve() {
+        fn expr() -> GrammarNode {
+            r!(atom) + opt(t("+") + r!(expr))
+        }
+
+        fn atom() -> GrammarNode {
+            t("1")
+        }
+
+        // START takes its place before the rules, so nothing is shifted after.
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        assert_eq!(grammar.start().index(), 0);
+        assert_eq!(
+            grammar.to_string(),
+            "START ::= expr\nexpr ::= atom [ \"+\" expr ]\natom ::= \"1\""
+        );
+
+        let mut state = crate::parser::ParserState::new(grammar.clone()).with_text("1+1");
+        assert!(state.parse().is_complete());
+        let names: Vec<_> = (state.ast().matching_descendants(state.arena(), |_| true))
+            .filter_map(|node| match node.green(state.arena()).tag {
+                crate::tree::Tag::Rule(id) => grammar.rule_name(id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "START", "expr", "atom", "expr_opt", "expr", "atom", "expr_opt"
+            ]
+        );
+
+        // Merged rules refer to each other by their new ids.
+        let merged = Grammar::try_from(t("x"))
+            .unwrap()
+            .merge(grammar, "g_")
+            .unwrap();
+        assert_eq!(merged.rule_index("g_START").map(RuleId::index), Some(1));
+        assert_eq!(
+            merged.to_string(),
+            "START ::= \"x\"\ng_START ::= expr\nexpr ::= atom [ \"+\" expr ]\natom ::= \"1\""
+        );
+    }
+
+    #[test]
+    fn test_clone_grammar() {
+        fn expr() -> GrammarNode {
+            r!(atom) + opt(t('+') + r!(expr))
+        }
+
+        fn atom() -> GrammarNode {
+            t("1") | t("2")
+        }
+
+        let node = r!(expr);
+        let grammar = Grammar::try_from(node.clone()).unwrap();
+        let expected = grammar.to_string();
+        let mut copy = grammar.clone();
+        drop(grammar);
+        assert_eq!(copy.to_string(), expected);
+
+        copy.extend_rule("atom", "expr").unwrap();
+        assert_ne!(copy.to_string(), expected);
+        assert_eq!(Grammar::try_from(node).unwrap().to_string(), expected);
+    }
+
+    #[test]
+    fn test_verbatim_and_trivia() {
+        fn call() -> GrammarNode {
+            t("f") + t("(") + r!(string) + t(")")
+        }
+
+        fn string() -> GrammarNode {
+            verbatim(t("\"") + r!(chars) + t("\""))
+        }
+
+        fn chars() -> GrammarNode {
+            opt(t("a") + r!(chars))
+        }
+
+        let grammar = Grammar::try_from(r!(call)).unwrap();
+        assert!(grammar.trivia().is_none());
+        let grammar = grammar.with_trivia(' ');
+        assert_eq!(
+            grammar.trivia().map(|m| m.display()),
+            Some("' '".to_string())
+        );
+        assert_eq!(
+            grammar.rule(RuleId::new(2)).map(|r| &r.node),
+            Some(&NormalizedNode::Verbatim(Box::new(
+                NormalizedNode::Sequence(vec![
+                    NormalizedNode::Terminal(Arc::new("\"")),
+                    NormalizedNode::Reference(RuleId::new(3)),
+                    NormalizedNode::Terminal(Arc::new("\"")),
+                ])
+            )))
+        );
+        assert!(
+            grammar
+                .to_string()
+                .contains(r#"string ::= verbatim(""" chars """)"#)
+        );
+        assert!(grammar.validate().is_empty());
+    }
+
+    #[test]
+    fn test_composite_terminal_display() {
+        let grammar =
+            Grammar::try_from((t("a".then('b').times(1..)) + t('x'.or('y'))) | t('z'.then('w')))
+                .unwrap();
+        assert_eq!(
+            grammar.to_string().trim_end(),
+            r#"START ::= (("a" 'b')+ ('x' | 'y')) | ('z' 'w')"#
+        );
+    }
+
+    #[test]
+    fn test_token_rules() {
+        fn assign() -> GrammarNode {
+            token(r!(ident)) + t("=") + r!(ident)
+        }
+
+        fn ident() -> GrammarNode {
+            t("x") + opt(r!(ident))
+        }
+
+        let grammar = Grammar::try_from(r!(assign)).unwrap();
+        let tokens: Vec<_> = grammar.iter().map(|(_, r)| (r.name, r.token)).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                ("START", false),
+                ("assign", false),
+                ("ident", true),
+                ("ident_opt", false)
+            ]
+        );
+        assert!(grammar.to_string().contains("\n@token ident ::= "));
+
+        let spec = grammar.to_spec().unwrap();
+        let restored =
+            Grammar::from_spec(GrammarSpec::from_json(&spec.to_json()).unwrap()).unwrap();
+        assert!(restored.rule(RuleId::new(2)).unwrap().token);
+
+        assert!(matches!(
+            Grammar::try_from(token(t("x"))),
+            Err(EvaluationError::InvalidToken)
+        ));
+    }
+
+    #[test]
+    fn test_token_rules_not_inlined() {
+        fn kw() -> GrammarNode {
+            t("a") + t("b")
+        }
+
+        let mut grammar = Grammar::try_from(token(r!(kw)) + t(';'))
+            .unwrap()
+            .with_trivia(' ');
+        assert_eq!(grammar.inline_trivial_rules(4), 0);
+        assert!(grammar.to_string().starts_with("START ::= kw ';'\n"));
+        let parse = |text: &str| {
+            let state = crate::parser::ParserState::new(grammar.clone()).with_text(text);
+            state.parse_full().is_complete()
+        };
+        assert!(parse("ab ;"));
+        assert!(!parse("a b;"));
+    }
+
+    #[test]
+    fn test_synthetic_rules() {
+        fn list() -> GrammarNode {
+            t('[') + opt(r!(items)) + t(']')
+        }
+
+        fn items() -> GrammarNode {
+            r!(item) + many(t(',') + r!(item))
+        }
+
+        fn item() -> GrammarNode {
+            some(r!(letter))
+        }
+
+        fn letter() -> GrammarNode {
+            t('a'..='z')
+        }
+
+        let grammar = Grammar::try_from(r!(list)).unwrap();
+        let synthetic: Vec<_> = (grammar.iter())
+            .filter(|(_, rule)| rule.is_synthetic())
+            .map(|(_, rule)| rule.name)
+            .collect();
+        assert_eq!(synthetic, ["letter_list", "items_list", "list_opt"]);
+        // Shown as written, with only the repetitions as rules of their own.
+        let shown = grammar.to_string();
+        assert!(shown.contains("list ::= '[' [ items ] ']'\n"));
+        assert!(!shown.contains("list_opt"));
+        assert!(shown.contains("\nitems_list ::= [ ',' item items_list ]"));
+        let all = format!("{grammar:#}");
+        assert!(all.contains("list ::= '[' list_opt ']'\n"));
+        assert!(all.ends_with("\nlist_opt ::= [ items ]"));
+
+        let mut state = crate::parser::ParserState::new(grammar.clone()).with_text("[ab,c]");
+        assert!(state.parse().is_complete());
+        let (root, alloc) = (state.ast(), state.arena());
+        let names = |node: &crate::tree::RedNode| -> Vec<_> {
+            (node.transparent_children(alloc, &grammar))
+                .map(|child| match child.green(alloc).tag {
+                    crate::tree::Tag::Rule(id) => grammar.rule_name(id).unwrap(),
+                    _ => "token",
+                })
+                .collect()
+        };
+        let list = root.first_child(alloc).unwrap();
+        assert_eq!(names(&list), ["token", "items", "token"]);
+        let items = list.transparent_children(alloc, &grammar).nth(1).unwrap();
+        assert_eq!(names(&items), ["item", "token", "item"]);
+        let item = items.first_child(alloc).unwrap();
+        assert_eq!(names(&item), ["letter", "letter"]);
+        // Plain iteration sees the synthetic nodes.
+        assert_eq!(list.child_count(alloc), 3);
+        assert_eq!(item.child_count(alloc), 2);
+
+        // A written rule takes the name from a synthetic one.
+        fn expr() -> GrammarNode {
+            opt(t('-')) + r!(expr_opt)
+        }
+
+        fn expr_opt() -> GrammarNode {
+            t('x')
+        }
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        let rule = |name| grammar.rule(grammar.rule_index(name).unwrap()).unwrap();
+        assert!(!rule("expr_opt").is_synthetic());
+        assert!(rule("expr_opt2").is_synthetic());
+        assert_eq!(
+            grammar.to_string(),
+            "START ::= expr\nexpr ::= [ '-' ] expr_opt\nexpr_opt ::= 'x'"
+        );
+    }
+
+    #[test]
+    fn test_parameterized_rules() {
+        fn signature() -> GrammarNode {
+            r1!(paren_list, r!(name)) + t("->") + r1!(paren_list, r!(number))
+        }
+
+        fn paren_list(item: GrammarNode) -> GrammarNode {
+            t('(') + r1!(list, item) + t(')')
+        }
+
+        // Refers to itself with the same argument, which finds the instance
+        // being defined.
+        fn list(item: GrammarNode) -> GrammarNode {
+            item.clone() + opt(t(',') + r1!(list, item))
+        }
+
+        fn name() -> GrammarNode {
+            t('a'..='z')
+        }
+
+        fn number() -> GrammarNode {
+            t('0'..='9') | r1!(paren_list, r!(number))
+        }
+
+        let grammar = Grammar::try_from(r!(signature)).unwrap();
+        let instances: Vec<_> = (grammar.iter())
+            .filter(|(_, rule)| !rule.is_synthetic())
+            .map(|(_, rule)| rule.name)
+            .filter(|name| name.contains('<'))
+            .collect();
+        assert_eq!(
+            instances,
+            [
+                "paren_list<name>",
+                "list<name>",
+                "paren_list<number>",
+                "list<number>"
+            ]
+        );
+        assert_eq!(
+            grammar.to_string(),
+            "START ::= signature\n\
+             signature ::= paren_list<name> \"->\" paren_list<number>\n\
+             name ::= ['a'-'z']\n\
+             paren_list<name> ::= '(' list<name> ')'\n\
+             list<name> ::= name [ ',' list<name> ]\n\
+             number ::= ['0'-'9'] | paren_list<number>\n\
+             paren_list<number> ::= '(' list<number> ')'\n\
+             list<number> ::= number [ ',' list<number> ]"
+        );
+        assert!(crate::parser::parse(&grammar, "(a,b)->(1,(2,3))").is_complete());
+        assert!(!crate::parser::parse(&grammar, "(a,1)->(1)").is_complete());
+    }
+
+    #[test]
+    fn test_prec_climb() {
+        use crate::tree::{SexprOptions, render_sexpr};
+
+        fn expr() -> GrammarNode {
+            let table = [
+                (t('+'), 1, Assoc::Left),
+                (t('-'), 1, Assoc::Left),
+                (t('*'), 2, Assoc::Left),
+                (t('-'), 3, Assoc::Prefix),
+                (t('^'), 4, Assoc::Right),
+                (t('!'), 5, Assoc::Postfix),
+            ];
+            prec_climb("binary", r!(atom), table)
+        }
+
+        fn atom() -> GrammarNode {
+            t('0'..='9') | t('a'..='z') | (t('(') + r!(expr) + t(')'))
+        }
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        let operators: Vec<_> = (grammar.iter())
+            .filter(|(_, rule)| !rule.is_synthetic())
+            .map(|(_, rule)| rule.name)
+            .filter(|name| name.starts_with("binary"))
+            .collect();
+        assert_eq!(
+            operators,
+            ["binary_5", "binary_4", "binary_3", "binary_2", "binary_1"]
+        );
+        let sexpr = |text: &str| {
+            let mut state = crate::parser::ParserState::new(grammar.clone()).with_text(text);
+            assert!(state.parse().is_complete(), "{text:?} doesn't parse");
+            let options = SexprOptions::default();
+            render_sexpr(state.ast(), state.arena(), &grammar, text, options)
+        };
+        assert_eq!(
+            sexpr("1+2*3"),
+            r#"(START (expr (binary_1 (atom "1") "+" (binary_2 (atom "2") "*" (atom "3")))))"#
+        );
+        assert_eq!(
+            sexpr("1-2-3"),
+            r#"(START (expr (binary_1 (binary_1 (atom "1") "-" (atom "2")) "-" (atom "3"))))"#
+        );
+        assert_eq!(
+            sexpr("2^3^4"),
+            r#"(START (expr (binary_4 (atom "2") "^" (binary_4 (atom "3") "^" (atom "4")))))"#
+        );
+        assert_eq!(
+            sexpr("-x*y"),
+            r#"(START (expr (binary_2 (binary_3 "-" (atom "x")) "*" (atom "y"))))"#
+        );
+        assert_eq!(
+            sexpr("--n!"),
+            r#"(START (expr (binary_3 "-" (binary_3 "-" (binary_5 (atom "n") "!")))))"#
+        );
+        // Operands standing alone get no operator node.
+        assert_eq!(
+            sexpr("(x)"),
+            r#"(START (expr (atom "(" (expr (atom "x")) ")")))"#
+        );
+    }
+
+    #[test]
+    fn test_prec_climb_many_levels() {
+        fn expr() -> GrammarNode {
+            let table = "+-*/%&|<>~"
+                .chars()
+                .zip(1..)
+                .map(|(op, precedence)| (t(op), precedence, Assoc::Left));
+            prec_climb("binary", t('a'..='z') | (t('(') + r!(expr) + t(')')), table)
+        }
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        assert!(grammar.left_recursive_rules().is_empty());
+        let text =
+            "a+b-c*d/e%f&g|h<i>j~k+(a-b)*c/(d%e&f)|g<h>(i~j)+k-a*b/c%d&e|f<g>h~i+j-k*a/b%c&d+e";
+        assert_eq!(text.len(), 81);
+        let steps = |text: &str| {
+            let mut state = crate::parser::ParserState::new(grammar.clone()).with_text(text);
+            assert!(state.parse().is_complete());
+            state.stats().steps
+        };
+        // Each byte is read through every level about once.
+        let once = steps(text);
+        assert!(once < 2_500, "{once} steps");
+        let twice = steps(&format!("{text}+{text}"));
+        assert!(twice < once * 2 + 100, "{twice} steps for twice the text");
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = EvaluationError::ConflictingRule {
+            name: "item".into(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "rule `item` is defined with two different bodies"
+        );
+        assert_eq!(
+            EvaluationError::InvalidToken.to_string(),
+            "`token` applied to something other than a rule"
+        );
+        let err = GrammarError::TokenMismatch {
+            expected: "')'".into(),
+            label: None,
+        };
+        assert_eq!(err.to_string(), "expected ')'");
+        let labeled = GrammarError::TokenMismatch {
+            expected: "')'".into(),
+            label: Some("a closing paren"),
+        };
+        assert_eq!(labeled.to_string(), "expected a closing paren");
+        let _: Box<dyn std::error::Error> = Box::new(err);
     }
 }
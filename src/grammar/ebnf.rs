@@ -0,0 +1,685 @@
+//! EBNF export and import.
+//!
+//! The dialect is one rule per line, `name ::= expression`, where
+//!
+//! - terminals are quoted with `"..."`, or `'...'` when the text contains a
+//!   double quote but no single one. Inside either, `\\` escapes the
+//!   quote, a backslash, `\n`, `\r` and `\t`, and `\u{..}` any other
+//!   char, so every terminal is one literal, e.g. `"a\"b'c"`;
+//! - sequencing is whitespace and alternation is `|`, which binds loosest;
+//! - `( ... )` groups, and `()` is the empty sequence;
+//! - `[ ... ]` is an optional part, emitted for a choice whose last
+//!   alternative is empty;
+//! - terminals without a fixed literal are written as special sequences
+//!   `? display ?`;
+//! - rule names are reduced to `[A-Za-z0-9_]`, with a numeric suffix added in
+//!   rule order when two names would collide;
+//! - `verbatim` markers, token rules and the grammar's trivia matcher are not
+//!   represented, and synthetic rules that don't reach themselves are
+//!   written out where they're referenced.
+//!
+//! [`from_ebnf`] reads the same dialect, plus `{ ... }` for zero or more
+//! repetitions, which is lowered to a synthesized `<rule>_rep` rule. Adjacent
+//! terminals stay separate terminals. The first rule defined becomes the
+//! start rule.
+
+use std::fmt;
+
+use super::*;
+
+impl Grammar {
+    /// Exports the grammar as EBNF text; see the module docs for the dialect.
+    pub fn to_ebnf(&self) -> String {
+        let names = self.ebnf_names();
+        let inlined = self.inlined_bodies();
+        let ctx = Names {
+            names: &names,
+            inlined: &inlined,
+        };
+        (self.rules.iter().zip(&names).zip(&inlined))
+            .filter(|(_, inlined)| inlined.is_none())
+            .map(|((rule, name), _)| {
+                let mut line = format!("{} ::= ", name);
+                write_node(&rule.node, &ctx, false, &mut line);
+                line
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn ebnf_names(&self) -> Vec<String> {
+        let mut used = HashSet::new();
+        self.rules
+            .iter()
+            .map(|rule| {
+                let base = sanitize_name(rule.name);
+                let mut name = base.clone();
+                let mut n = 1;
+                while !used.insert(name.clone()) {
+                    n += 1;
+                    name = format!("{}_{}", base, n);
+                }
+                name
+            })
+            .collect()
+    }
+}
+
+fn sanitize_name(name: &str) -> String {
+    let mut out: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !out.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        out.insert(0, '_');
+    }
+    out
+}
+
+/// Quotes `text` as one EBNF terminal, escaping what can't appear in it
+/// as is.
+pub(crate) fn quote_literal(text: &str) -> String {
+    let quote = if text.contains('"') && !text.contains('\'') {
+        '\''
+    } else {
+        '"'
+    };
+    let mut out = String::from(quote);
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push(quote);
+    out
+}
+
+/// How to write references: by the rule's name, or by its body for
+/// synthetic rules [`Grammar::inlined_bodies`] gives.
+struct Names<'a> {
+    names: &'a [String],
+    inlined: &'a [Option<&'a NormalizedNode>],
+}
+
+fn write_node(node: &NormalizedNode, names: &Names, in_seq: bool, out: &mut String) {
+    use NormalizedNode as N;
+    match node {
+        N::Reference(idx) if let Some(Some(body)) = names.inlined.get(idx.index()) => {
+            write_node(body, names, in_seq, out)
+        }
+        N::Terminal(m) => match m.literal() {
+            Some(text) => out.push_str(&quote_literal(&text)),
+            None => {
+                out.push_str("? ");
+                out.push_str(&m.display().replace('?', ""));
+                out.push_str(" ?");
+            }
+        },
+        N::Reference(idx) => match names.names.get(idx.index()) {
+            Some(name) => out.push_str(name),
+            None => out.push_str("? unknown ?"),
+        },
+        N::Placeholder => out.push_str("? placeholder ?"),
+        N::Verbatim(inner) => write_node(inner, names, in_seq, out),
+        N::Sequence(parts) if parts.is_empty() => out.push_str("()"),
+        N::Sequence(parts) => {
+            for (i, part) in parts.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                write_node(part, names, true, out);
+            }
+        }
+        N::Choice(alts) if alts.is_empty() => out.push_str("? never ?"),
+        N::Choice(alts) if alts.len() > 1 && alts.last() == Some(&N::null()) => {
+            out.push_str("[ ");
+            write_alternatives(&alts[..alts.len() - 1], names, out);
+            out.push_str(" ]");
+        }
+        N::Choice(alts) => {
+            if in_seq && alts.len() > 1 {
+                out.push_str("( ");
+                write_alternatives(alts, names, out);
+                out.push_str(" )");
+            } else {
+                write_alternatives(alts, names, out);
+            }
+        }
+    }
+}
+
+fn write_alternatives(alts: &[NormalizedNode], names: &Names, out: &mut String) {
+    for (i, alt) in alts.iter().enumerate() {
+        if i > 0 {
+            out.push_str(" | ");
+        }
+        write_node(alt, names, false, out);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EbnfParseError {
+    pub line: usize,
+    pub column: usize,
+    pub token: String,
+    pub message: String,
+}
+
+impl fmt::Display for EbnfParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {} (found `{}`)",
+            self.line, self.column, self.message, self.token
+        )
+    }
+}
+
+impl std::error::Error for EbnfParseError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Literal(String),
+    Special(String),
+    Define,
+    Bar,
+    Open(char),
+    Close(char),
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Literal(text) => write!(f, "{}", quote_literal(text)),
+            Token::Special(text) => write!(f, "? {} ?", text),
+            Token::Define => write!(f, "::="),
+            Token::Bar => write!(f, "|"),
+            Token::Open(c) | Token::Close(c) => write!(f, "{}", c),
+            Token::Eof => write!(f, "end of input"),
+        }
+    }
+}
+
+struct Lexed {
+    token: Token,
+    line: usize,
+    column: usize,
+}
+
+fn lex(src: &str) -> std::result::Result<Vec<Lexed>, EbnfParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+    let (mut line, mut column) = (1, 1);
+
+    macro_rules! bump {
+        () => {{
+            let c = chars.next();
+            if c == Some('\n') {
+                line += 1;
+                column = 1;
+            } else if c.is_some() {
+                column += 1;
+            }
+            c
+        }};
+    }
+
+    while let Some(&c) = chars.peek() {
+        let (start_line, start_column) = (line, column);
+        let error = |token: String, message: &str| EbnfParseError {
+            line: start_line,
+            column: start_column,
+            token,
+            message: message.to_string(),
+        };
+        let token = match c {
+            c if c.is_whitespace() || c == ';' => {
+                bump!();
+                continue;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    name.push(c);
+                    bump!();
+                }
+                Token::Ident(name)
+            }
+            '?' => {
+                bump!();
+                let mut text = String::new();
+                loop {
+                    match bump!() {
+                        Some('?') => break,
+                        Some(other) => text.push(other),
+                        None => return Err(error(format!("?{}", text), "unterminated")),
+                    }
+                }
+                Token::Special(text.trim().to_string())
+            }
+            '"' | '\'' => {
+                bump!();
+                let mut text = String::new();
+                loop {
+                    match bump!() {
+                        Some(end) if end == c => break,
+                        Some('\\') => {
+                            let escaped = match bump!() {
+                                Some('n') => Some('\n'),
+                                Some('r') => Some('\r'),
+                                Some('t') => Some('\t'),
+                                Some(c @ ('\\' | '"' | '\'')) => Some(c),
+                                Some('u') if chars.peek() == Some(&'{') => {
+                                    bump!();
+                                    let mut hex = String::new();
+                                    while let Some(c) = bump!() {
+                                        if c == '}' {
+                                            break;
+                                        }
+                                        hex.push(c);
+                                    }
+                                    u32::from_str_radix(&hex, 16)
+                                        .ok()
+                                        .filter(|_| hex.chars().all(|c| c.is_ascii_hexdigit()))
+                                        .and_then(char::from_u32)
+                                }
+                                _ => None,
+                            };
+                            match escaped {
+                                Some(escaped) => text.push(escaped),
+                                None => {
+                                    return Err(error(format!("{}{}", c, text), "invalid escape"));
+                                }
+                            }
+                        }
+                        Some(other) => text.push(other),
+                        None => return Err(error(format!("{}{}", c, text), "unterminated")),
+                    }
+                }
+                Token::Literal(text)
+            }
+            ':' => {
+                let mut text = String::new();
+                while text.len() < 3 {
+                    match bump!() {
+                        Some(c) => text.push(c),
+                        None => break,
+                    }
+                }
+                if text != "::=" {
+                    return Err(error(text, "expected `::=`"));
+                }
+                Token::Define
+            }
+            '|' => {
+                bump!();
+                Token::Bar
+            }
+            '(' | '[' | '{' => {
+                bump!();
+                Token::Open(c)
+            }
+            ')' | ']' | '}' => {
+                bump!();
+                Token::Close(c)
+            }
+            c => return Err(error(c.to_string(), "unexpected character")),
+        };
+        tokens.push(Lexed {
+            token,
+            line: start_line,
+            column: start_column,
+        });
+    }
+    tokens.push(Lexed {
+        token: Token::Eof,
+        line,
+        column,
+    });
+    Ok(tokens)
+}
+
+/// A parsed rule body whose references are still names.
+enum Expr {
+    Literal(String),
+    Rule(usize, usize, String),
+    Never,
+    Sequence(Vec<Expr>),
+    Choice(Vec<Expr>),
+    Repeat(Box<Expr>),
+}
+
+struct EbnfParser {
+    tokens: Vec<Lexed>,
+    pos: usize,
+}
+
+impl EbnfParser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].token
+    }
+
+    fn starts_rule(&self) -> bool {
+        matches!(self.peek(), Token::Ident(_))
+            && matches!(
+                self.tokens.get(self.pos + 1).map(|t| &t.token),
+                Some(Token::Define)
+            )
+    }
+
+    fn error(&self, message: &str) -> EbnfParseError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, pos: usize, message: &str) -> EbnfParseError {
+        let lexed = &self.tokens[pos];
+        EbnfParseError {
+            line: lexed.line,
+            column: lexed.column,
+            token: lexed.token.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn rule(&mut self) -> std::result::Result<(String, Expr), EbnfParseError> {
+        let Token::Ident(name) = self.peek().clone() else {
+            return Err(self.error("expected a rule name"));
+        };
+        self.pos += 1;
+        if *self.peek() != Token::Define {
+            return Err(self.error("expected `::=`"));
+        }
+        self.pos += 1;
+        let body = self.alternation()?;
+        match self.peek() {
+            Token::Eof | Token::Ident(_) => Ok((name, body)),
+            _ => Err(self.error("expected a new rule")),
+        }
+    }
+
+    fn alternation(&mut self) -> std::result::Result<Expr, EbnfParseError> {
+        let mut alts = vec![self.sequence()?];
+        while *self.peek() == Token::Bar {
+            self.pos += 1;
+            alts.push(self.sequence()?);
+        }
+        Ok(if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            Expr::Choice(alts)
+        })
+    }
+
+    fn sequence(&mut self) -> std::result::Result<Expr, EbnfParseError> {
+        let mut parts = Vec::new();
+        loop {
+            if self.starts_rule() {
+                break;
+            }
+            let lexed = &self.tokens[self.pos];
+            let part = match lexed.token.clone() {
+                Token::Ident(name) => {
+                    self.pos += 1;
+                    Expr::Rule(lexed.line, lexed.column, name)
+                }
+                Token::Literal(text) => {
+                    self.pos += 1;
+                    Expr::Literal(text)
+                }
+                Token::Special(text) if text == "never" => {
+                    self.pos += 1;
+                    Expr::Never
+                }
+                Token::Special(_) => return Err(self.error("unsupported special sequence")),
+                Token::Open(open) => {
+                    self.pos += 1;
+                    let inner = if *self.peek() == Token::Close(')') && open == '(' {
+                        Expr::Sequence(vec![])
+                    } else {
+                        self.alternation()?
+                    };
+                    let close = match open {
+                        '(' => ')',
+                        '[' => ']',
+                        _ => '}',
+                    };
+                    if *self.peek() != Token::Close(close) {
+                        return Err(self.error(&format!("expected `{}`", close)));
+                    }
+                    self.pos += 1;
+                    match open {
+                        '(' => inner,
+                        '[' => Expr::Choice(vec![inner, Expr::Sequence(vec![])]),
+                        _ => Expr::Repeat(Box::new(inner)),
+                    }
+                }
+                _ => break,
+            };
+            match part {
+                Expr::Sequence(inner) => parts.extend(inner),
+                part => parts.push(part),
+            }
+        }
+        Ok(if parts.len() == 1 {
+            parts.pop().unwrap()
+        } else {
+            Expr::Sequence(parts)
+        })
+    }
+}
+
+/// Builds a grammar from EBNF text in the dialect written by
+/// [`Grammar::to_ebnf`]; see the module docs.
+pub fn from_ebnf(src: &str) -> std::result::Result<Grammar, EbnfParseError> {
+    let mut parser = EbnfParser {
+        tokens: lex(src)?,
+        pos: 0,
+    };
+    let mut defs = Vec::new();
+    while *parser.peek() != Token::Eof {
+        let at = parser.pos;
+        let (name, body) = parser.rule()?;
+        if defs.iter().any(|(n, _)| *n == name) {
+            return Err(parser.error_at(at, "rule defined twice"));
+        }
+        defs.push((name, body));
+    }
+    if defs.is_empty() {
+        return Err(parser.error("expected at least one rule"));
+    }
+
+    let mut grammar = Grammar {
+        rules: defs
+            .iter()
+            .map(|(name, _)| Rule::new(leak_name(name.clone()), NormalizedNode::Placeholder))
+            .collect(),
+        trivia: None,
+    };
+    for (idx, (name, body)) in defs.into_iter().enumerate() {
+        let node = lower(body, &name, &mut grammar)?;
+        if let Some(rule) = grammar.rules.get_index_mut2(idx) {
+            rule.node = node;
+        }
+    }
+    Ok(grammar)
+}
+
+fn lower(
+    expr: Expr,
+    rule: &str,
+    grammar: &mut Grammar,
+) -> std::result::Result<NormalizedNode, EbnfParseError> {
+    use NormalizedNode as N;
+    Ok(match expr {
+        Expr::Literal(text) => N::Terminal(std::sync::Arc::new(text)),
+        Expr::Rule(line, column, name) => match grammar.rule_index(&name) {
+            Some(idx) => N::Reference(idx),
+            None => {
+                return Err(EbnfParseError {
+                    line,
+                    column,
+                    token: name,
+                    message: String::from("undefined rule"),
+                });
+            }
+        },
+        Expr::Never => N::Choice(vec![]),
+        Expr::Sequence(parts) => N::Sequence(
+            parts
+                .into_iter()
+                .map(|p| lower(p, rule, grammar))
+                .collect::<std::result::Result<_, _>>()?,
+        ),
+        Expr::Choice(alts) => N::Choice(
+            alts.into_iter()
+                .map(|a| lower(a, rule, grammar))
+                .collect::<std::result::Result<_, _>>()?,
+        ),
+        Expr::Repeat(inner) => {
+            let idx = RuleId::new(grammar.rules.len());
+            let name = grammar.fresh_name(&format!("{}_rep", rule));
+            grammar.rules.insert(Rule::synthetic(name, N::Placeholder));
+            let inner = lower(*inner, rule, grammar)?;
+            if let Some(rep) = grammar.rules.get_index_mut2(idx.index()) {
+                rep.node = N::Choice(vec![N::Sequence(vec![inner, N::Reference(idx)]), N::null()]);
+            }
+            N::Reference(idx)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r;
+
+    #[test]
+    fn test_quote_literal() {
+        assert_eq!(quote_literal("ab"), "\"ab\"");
+        assert_eq!(quote_literal("\""), "'\"'");
+        assert_eq!(quote_literal("|"), "\"|\"");
+        assert_eq!(quote_literal("a\"b'c"), "\"a\\\"b'c\"");
+        assert_eq!(quote_literal(""), "\"\"");
+        assert_eq!(quote_literal("a\nb\\"), "\"a\\nb\\\\\"");
+        assert_eq!(quote_literal("\u{7}"), "\"\\u{7}\"");
+    }
+
+    #[test]
+    fn test_to_ebnf() {
+        fn expr() -> GrammarNode {
+            r!(term) + opt(t("|") + r!(expr))
+        }
+
+        fn term() -> GrammarNode {
+            (t('"') + r!(inner::text) + t('"')) | t("it's \"x\"")
+        }
+
+        mod inner {
+            use crate::grammar_dsl::*;
+
+            pub fn text() -> GrammarNode {
+                t("a") | (t("b") + (t("c") | t("d")))
+            }
+        }
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        assert_eq!(
+            grammar.to_ebnf(),
+            "START ::= expr\n\
+             expr ::= term [ \"|\" expr ]\n\
+             term ::= '\"' inner__text '\"' | \"it's \\\"x\\\"\"\n\
+             inner__text ::= \"a\" | \"b\" ( \"c\" | \"d\" )"
+        );
+    }
+
+    #[test]
+    fn test_ebnf_round_trip() {
+        fn expr() -> GrammarNode {
+            r!(term) + opt((t("+") | t("-")) + r!(expr))
+        }
+
+        fn term() -> GrammarNode {
+            (t('(') + r!(expr) + t(')')) | t("x") | t("say \"it's\"")
+        }
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        let text = grammar.to_ebnf();
+        let parsed = from_ebnf(&text).unwrap();
+        assert_eq!(parsed.to_ebnf(), text);
+    }
+
+    #[test]
+    fn test_ebnf_literals() {
+        let grammar = from_ebnf("s ::= \"a\" \"b\"").unwrap();
+        assert_eq!(grammar.rules.len(), 1);
+        let NormalizedNode::Sequence(parts) = &grammar.rules[0].node else {
+            panic!("a sequence of two terminals");
+        };
+        assert_eq!(parts.len(), 2);
+        assert_eq!(grammar.to_ebnf(), "s ::= \"a\" \"b\"");
+    }
+
+    #[test]
+    fn test_ebnf_escapes() {
+        for text in [
+            "\"",
+            "'",
+            "a\"b'c",
+            "\\",
+            "\\\"",
+            "line\r\n\tend",
+            "\u{0}\u{7f}",
+        ] {
+            let src = format!("s ::= {}", quote_literal(text));
+            let grammar = from_ebnf(&src).unwrap();
+            match &grammar.rules[0].node {
+                NormalizedNode::Terminal(m) => assert_eq!(m.literal().as_deref(), Some(text)),
+                node => panic!("{src} read back as {node:?}"),
+            }
+            assert_eq!(grammar.to_ebnf(), src);
+        }
+
+        let err = from_ebnf("s ::= \"a\\q\"").unwrap_err();
+        assert_eq!(err.message, "invalid escape");
+    }
+
+    #[test]
+    fn test_from_ebnf_repetition() {
+        let grammar = from_ebnf("list ::= \"[\" { item } \"]\"\nitem ::= \"a\" | \"b\"").unwrap();
+        assert_eq!(
+            grammar.to_ebnf(),
+            "list ::= \"[\" list_rep \"]\"\n\
+             item ::= \"a\" | \"b\"\n\
+             list_rep ::= [ item list_rep ]"
+        );
+    }
+
+    #[test]
+    fn test_from_ebnf_errors() {
+        let err = from_ebnf("a ::= \"x\"\nb ::= a c").unwrap_err();
+        assert_eq!((err.line, err.column, err.token.as_str()), (2, 9, "c"));
+        assert_eq!(err.message, "undefined rule");
+
+        let err = from_ebnf("a ::= ( \"x\" ]").unwrap_err();
+        assert_eq!((err.line, err.column, err.token.as_str()), (1, 13, "]"));
+
+        let err = from_ebnf("a ::= \"x").unwrap_err();
+        assert_eq!(err.message, "unterminated");
+        assert_eq!(err.to_string(), "1:7: unterminated (found `\"x`)");
+    }
+}
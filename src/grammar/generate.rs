@@ -0,0 +1,288 @@
+//! Random sentences of a grammar, for fuzzing and property tests.
+//!
+//! Each choice picks an alternative at random and each terminal gives its
+//! [`Matcher::sample`]. A depth budget, counted in rule references, makes
+//! recursion end: once an alternative would need more references than the
+//! budget has left, the one needing the fewest is taken instead.
+
+use super::*;
+
+/// A source of random numbers, so callers can plug in whatever generator
+/// they use. [`SplitMix64`] is a small seeded one.
+pub trait Rng {
+    fn next_u64(&mut self) -> u64;
+
+    /// A number in `0..n`, for `n > 0`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// The SplitMix64 generator: fast, seeded, and reproducible across
+/// platforms. Not for anything needing unpredictable numbers.
+#[derive(Debug, Clone)]
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+}
+
+impl Rng for SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Budgets for [`Grammar::generate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenLimits {
+    /// How deep rule references may nest before choices go for the
+    /// alternative that ends soonest.
+    pub max_depth: usize,
+    /// The length in bytes past which choices go for the alternative that
+    /// ends soonest. The sentence still gets finished, so it can run over.
+    pub max_len: usize,
+}
+
+impl Default for GenLimits {
+    fn default() -> Self {
+        GenLimits {
+            max_depth: 32,
+            max_len: 1024,
+        }
+    }
+}
+
+impl Grammar {
+    /// A random sentence of the grammar, or `None` if every way to finish
+    /// one goes through a terminal with no sample. Alternatives that
+    /// would need such a terminal are never picked.
+    ///
+    /// Outside token rules, terminals are separated by a sample of the
+    /// trivia matcher, so adjacent tokens don't run together. The sentence
+    /// is built from the grammar alone, so an ordered choice that would
+    /// take an earlier alternative on it, or a greedy repetition swallowing
+    /// what follows, can keep it from parsing back.
+    pub fn generate(&self, rng: &mut impl Rng, limits: GenLimits) -> Option<String> {
+        let heights = self.heights();
+        heights[self.start().index()]?;
+        let separator = self.trivia().and_then(sample).unwrap_or_default();
+        let mut generator = Generator {
+            grammar: self,
+            rng,
+            limits,
+            heights,
+            separator,
+            out: String::new(),
+            in_token: false,
+            glued: false,
+        };
+        generator.node(&NormalizedNode::Reference(self.start()), 0)?;
+        Some(generator.out)
+    }
+
+    /// The fewest nested references it takes to finish each rule, or `None`
+    /// for rules that can't be finished with sampleable terminals.
+    fn heights(&self) -> Vec<Option<usize>> {
+        let mut heights = vec![None; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (idx, rule) in self.rules.iter().enumerate() {
+                let height = height(&rule.node, &heights);
+                if height.is_some_and(|height| heights[idx].is_none_or(|old| height < old)) {
+                    heights[idx] = height;
+                    changed = true;
+                }
+            }
+        }
+        heights
+    }
+}
+
+/// `matcher`'s sample, if it really matches it.
+fn sample(matcher: &dyn Matcher) -> Option<String> {
+    let text = matcher.sample()?;
+    (matcher.try_match(&text, 0) == Some(text.len())).then_some(text)
+}
+
+/// The fewest nested references it takes to finish `node`, given the
+/// heights of rules known so far.
+fn height(node: &NormalizedNode, heights: &[Option<usize>]) -> Option<usize> {
+    use NormalizedNode as N;
+    match node {
+        N::Terminal(matcher) => sample(matcher.as_ref()).map(|_| 0),
+        N::Reference(idx) => Some(heights.get(idx.index()).copied().flatten()? + 1),
+        N::Sequence(parts) => parts
+            .iter()
+            .try_fold(0, |max, part| Some(max.max(height(part, heights)?))),
+        N::Choice(alternatives) => alternatives
+            .iter()
+            .filter_map(|alternative| height(alternative, heights))
+            .min(),
+        N::Verbatim(inner) => height(inner, heights),
+        N::Placeholder => None,
+    }
+}
+
+struct Generator<'a, R> {
+    grammar: &'a Grammar,
+    rng: &'a mut R,
+    limits: GenLimits,
+    heights: Vec<Option<usize>>,
+    separator: String,
+    out: String,
+    /// Inside a token rule, where no trivia goes between terminals.
+    in_token: bool,
+    /// The next terminal follows the last one with no separator.
+    glued: bool,
+}
+
+impl<R: Rng> Generator<'_, R> {
+    fn node(&mut self, node: &NormalizedNode, depth: usize) -> Option<()> {
+        use NormalizedNode as N;
+        match node {
+            N::Terminal(matcher) => {
+                let text = sample(matcher.as_ref())?;
+                self.push(&text);
+            }
+            N::Reference(idx) => {
+                let rule = self.grammar.rule(*idx)?;
+                let enters_token = rule.token && !self.in_token;
+                if enters_token {
+                    self.in_token = true;
+                    self.glued = false;
+                }
+                self.node(&rule.node, depth + 1)?;
+                if enters_token {
+                    self.in_token = false;
+                    self.glued = false;
+                }
+            }
+            N::Sequence(parts) => {
+                for part in parts {
+                    self.node(part, depth)?;
+                }
+            }
+            N::Choice(alternatives) => {
+                let heights: Vec<_> = (alternatives.iter())
+                    .filter_map(|alternative| {
+                        Some((height(alternative, &self.heights)?, alternative))
+                    })
+                    .collect();
+                let fits: Vec<_> = (heights.iter())
+                    .filter(|(height, _)| depth + height <= self.limits.max_depth)
+                    .collect();
+                let alternative = match fits.is_empty() || self.out.len() >= self.limits.max_len {
+                    false => fits[self.rng.below(fits.len())].1,
+                    true => heights.iter().min_by_key(|(height, _)| *height)?.1,
+                };
+                self.node(alternative, depth)?;
+            }
+            N::Verbatim(inner) => self.node(inner, depth)?,
+            N::Placeholder => return None,
+        }
+        Some(())
+    }
+
+    fn push(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if !self.glued && !self.out.is_empty() {
+            self.out.push_str(&self.separator);
+        }
+        self.out.push_str(text);
+        self.glued = self.in_token;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ParserState;
+    use crate::r;
+    use crate::words::{Identifier, Whitespace};
+
+    fn stmts() -> GrammarNode {
+        r!(stmt) + opt(r!(stmts))
+    }
+
+    fn stmt() -> GrammarNode {
+        token(r!(name)) + t('=') + r!(expr) + t(';')
+    }
+
+    fn expr() -> GrammarNode {
+        r!(term) + opt((t('+') | t('-')) + r!(expr))
+    }
+
+    fn term() -> GrammarNode {
+        token(r!(number)) | token(r!(name)) | (t('(') + r!(expr) + t(')'))
+    }
+
+    fn name() -> GrammarNode {
+        t(Identifier)
+    }
+
+    fn number() -> GrammarNode {
+        t('1'..='9') + opt(r!(number))
+    }
+
+    #[test]
+    fn test_generated_sentences_parse() {
+        let grammar = Grammar::try_from(r!(stmts))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let limits = GenLimits {
+            max_depth: 12,
+            max_len: 200,
+        };
+        let mut state = ParserState::new(grammar.clone());
+        let mut seen = HashSet::new();
+        for seed in 0..200 {
+            let text = grammar
+                .generate(&mut SplitMix64::new(seed), limits)
+                .unwrap();
+            state.set_text(&text);
+            assert!(state.parse().is_complete(), "{text:?} doesn't parse");
+            seen.insert(text);
+        }
+        assert!(seen.len() > 100, "only {} distinct sentences", seen.len());
+        // Same seed, same sentence.
+        let again = |seed| grammar.generate(&mut SplitMix64::new(seed), limits);
+        assert_eq!(again(7), again(7));
+    }
+
+    #[derive(Debug)]
+    struct Opaque;
+
+    impl Matcher for Opaque {
+        fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+            input.get(at..)?.starts_with('a').then_some(1)
+        }
+
+        fn is_nullable(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_generate_without_samples() {
+        let mut rng = SplitMix64::new(0);
+        let grammar = Grammar::try_from(t(Opaque) + t('b')).unwrap();
+        assert_eq!(grammar.generate(&mut rng, GenLimits::default()), None);
+
+        // The unsampleable alternative is never taken.
+        let grammar = Grammar::try_from(t(Opaque) | t('b')).unwrap();
+        for _ in 0..10 {
+            let text = grammar.generate(&mut rng, GenLimits::default());
+            assert_eq!(text.as_deref(), Some("b"));
+        }
+    }
+}
@@ -0,0 +1,714 @@
+//! Serializable form of a normalized grammar.
+//!
+//! A [`GrammarSpec`] mirrors the rule set with terminals replaced by their
+//! [`TerminalSpec`], so grammars made only of built-in matchers can be cached
+//! as JSON and rebuilt without running the DSL again.
+
+use std::{fmt, sync::Arc};
+
+use super::*;
+use crate::FORMAT_VERSION;
+use crate::json::{Json, JsonError};
+use crate::words::{CharClass, TerminalSpec};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrammarSpec {
+    pub format_version: u32,
+    pub rules: Vec<RuleSpec>,
+    pub trivia: Option<TerminalSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleSpec {
+    pub name: String,
+    pub node: NodeSpec,
+    pub token: bool,
+    pub synthetic: bool,
+    pub label: Option<String>,
+    pub fold: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeSpec {
+    Terminal(TerminalSpec),
+    Choice(Vec<NodeSpec>),
+    Sequence(Vec<NodeSpec>),
+    Reference(usize),
+    Verbatim(Box<NodeSpec>),
+    Placeholder,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecError {
+    /// A terminal is a custom matcher with no [`TerminalSpec`]; carries its display.
+    UnsupportedTerminal(String),
+    UnsupportedVersion {
+        found: u32,
+        expected: u32,
+    },
+    InvalidReference(usize),
+    Json(JsonError),
+    Malformed(String),
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecError::UnsupportedTerminal(display) => {
+                write!(f, "terminal {} cannot be serialized", display)
+            }
+            SpecError::UnsupportedVersion { found, expected } => write!(
+                f,
+                "grammar format version {} is not supported (expected {})",
+                found, expected
+            ),
+            SpecError::InvalidReference(idx) => write!(f, "reference to missing rule {}", idx),
+            SpecError::Json(err) => write!(f, "{}", err),
+            SpecError::Malformed(what) => write!(f, "malformed grammar: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SpecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SpecError::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl Grammar {
+    pub fn to_spec(&self) -> std::result::Result<GrammarSpec, SpecError> {
+        Ok(GrammarSpec {
+            format_version: FORMAT_VERSION,
+            rules: self
+                .rules
+                .iter()
+                .map(|rule| {
+                    Ok(RuleSpec {
+                        name: rule.name.to_string(),
+                        node: node_to_spec(&rule.node)?,
+                        token: rule.token,
+                        synthetic: rule.synthetic,
+                        label: rule.label.map(String::from),
+                        fold: rule.fold,
+                    })
+                })
+                .collect::<std::result::Result<_, SpecError>>()?,
+            trivia: match &self.trivia {
+                Some(m) => Some(
+                    m.spec()
+                        .ok_or_else(|| SpecError::UnsupportedTerminal(m.display()))?,
+                ),
+                None => None,
+            },
+        })
+    }
+
+    pub fn from_spec(spec: GrammarSpec) -> std::result::Result<Grammar, SpecError> {
+        if spec.format_version != FORMAT_VERSION {
+            return Err(SpecError::UnsupportedVersion {
+                found: spec.format_version,
+                expected: FORMAT_VERSION,
+            });
+        }
+        let len = spec.rules.len();
+        let mut rules = IndexSet::with_capacity(len);
+        for rule in spec.rules {
+            let name = leak_name(rule.name);
+            let node = node_from_spec(rule.node, len)?;
+            if !rules.insert(Rule {
+                name,
+                node,
+                token: rule.token,
+                synthetic: rule.synthetic,
+                label: rule.label.map(leak_name),
+                fold: rule.fold,
+            }) {
+                return Err(SpecError::Malformed(format!(
+                    "rule `{}` defined twice",
+                    name
+                )));
+            }
+        }
+        Ok(Grammar {
+            rules,
+            trivia: spec.trivia.map(|t| Arc::from(t.into_matcher())),
+        })
+    }
+}
+
+fn node_to_spec(node: &NormalizedNode) -> std::result::Result<NodeSpec, SpecError> {
+    use NormalizedNode as N;
+    let all = |nodes: &[NormalizedNode]| {
+        nodes
+            .iter()
+            .map(node_to_spec)
+            .collect::<std::result::Result<Vec<_>, _>>()
+    };
+    Ok(match node {
+        N::Terminal(m) => NodeSpec::Terminal(
+            m.spec()
+                .ok_or_else(|| SpecError::UnsupportedTerminal(m.display()))?,
+        ),
+        N::Choice(alts) => NodeSpec::Choice(all(alts)?),
+        N::Sequence(parts) => NodeSpec::Sequence(all(parts)?),
+        N::Reference(idx) => NodeSpec::Reference(idx.index()),
+        N::Verbatim(inner) => NodeSpec::Verbatim(Box::new(node_to_spec(inner)?)),
+        N::Placeholder => NodeSpec::Placeholder,
+    })
+}
+
+fn node_from_spec(spec: NodeSpec, len: usize) -> std::result::Result<NormalizedNode, SpecError> {
+    use NormalizedNode as N;
+    let all = |specs: Vec<NodeSpec>| {
+        specs
+            .into_iter()
+            .map(|s| node_from_spec(s, len))
+            .collect::<std::result::Result<Vec<_>, _>>()
+    };
+    Ok(match spec {
+        NodeSpec::Terminal(t) => N::Terminal(Arc::from(t.into_matcher())),
+        NodeSpec::Choice(alts) => N::Choice(all(alts)?),
+        NodeSpec::Sequence(parts) => N::Sequence(all(parts)?),
+        NodeSpec::Reference(idx) if idx < len => N::Reference(RuleId::new(idx)),
+        NodeSpec::Reference(idx) => return Err(SpecError::InvalidReference(idx)),
+        NodeSpec::Verbatim(inner) => N::Verbatim(Box::new(node_from_spec(*inner, len)?)),
+        NodeSpec::Placeholder => N::Placeholder,
+    })
+}
+
+impl GrammarSpec {
+    pub fn to_json(&self) -> String {
+        Json::object([
+            ("format_version", Json::Int(self.format_version as i64)),
+            (
+                "rules",
+                Json::Array(
+                    self.rules
+                        .iter()
+                        .map(|rule| {
+                            Json::object([
+                                ("name", Json::String(rule.name.clone())),
+                                ("node", node_json(&rule.node)),
+                                ("token", Json::Bool(rule.token)),
+                                ("synthetic", Json::Bool(rule.synthetic)),
+                                ("label", rule.label.clone().map_or(Json::Null, Json::String)),
+                                ("fold", Json::Bool(rule.fold)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "trivia",
+                self.trivia.as_ref().map_or(Json::Null, terminal_json),
+            ),
+        ])
+        .to_string()
+    }
+
+    pub fn from_json(src: &str) -> std::result::Result<GrammarSpec, SpecError> {
+        let value = Json::parse(src).map_err(SpecError::Json)?;
+        let format_version = int_field(&value, "format_version")? as u32;
+        let rules = array_field(&value, "rules")?
+            .iter()
+            .map(|rule| {
+                Ok(RuleSpec {
+                    name: str_field(rule, "name")?.to_string(),
+                    node: node_from_json(field(rule, "node")?)?,
+                    token: rule.get("token") == Some(&Json::Bool(true)),
+                    synthetic: rule.get("synthetic") == Some(&Json::Bool(true)),
+                    label: rule.get("label").and_then(Json::as_str).map(String::from),
+                    fold: rule.get("fold") == Some(&Json::Bool(true)),
+                })
+            })
+            .collect::<std::result::Result<_, SpecError>>()?;
+        let trivia = match value.get("trivia") {
+            None | Some(Json::Null) => None,
+            Some(trivia) => Some(terminal_from_json(trivia)?),
+        };
+        Ok(GrammarSpec {
+            format_version,
+            rules,
+            trivia,
+        })
+    }
+}
+
+fn node_json(node: &NodeSpec) -> Json {
+    let items = |nodes: &[NodeSpec]| Json::Array(nodes.iter().map(node_json).collect());
+    match node {
+        NodeSpec::Terminal(t) => Json::object([
+            ("kind", Json::String("terminal".into())),
+            ("terminal", terminal_json(t)),
+        ]),
+        NodeSpec::Choice(alts) => Json::object([
+            ("kind", Json::String("choice".into())),
+            ("items", items(alts)),
+        ]),
+        NodeSpec::Sequence(parts) => Json::object([
+            ("kind", Json::String("sequence".into())),
+            ("items", items(parts)),
+        ]),
+        NodeSpec::Reference(idx) => Json::object([
+            ("kind", Json::String("ref".into())),
+            ("rule", Json::Int(*idx as i64)),
+        ]),
+        NodeSpec::Verbatim(inner) => Json::object([
+            ("kind", Json::String("verbatim".into())),
+            ("inner", node_json(inner)),
+        ]),
+        NodeSpec::Placeholder => Json::object([("kind", Json::String("placeholder".into()))]),
+    }
+}
+
+fn terminal_json(spec: &TerminalSpec) -> Json {
+    let kind = |k: &str| ("kind", Json::String(k.into()));
+    match spec {
+        TerminalSpec::Str(text) => {
+            Json::object([kind("str"), ("text", Json::String(text.clone()))])
+        }
+        TerminalSpec::Char(c) => {
+            Json::object([kind("char"), ("char", Json::String(c.to_string()))])
+        }
+        TerminalSpec::StartOfInput => Json::object([kind("sof")]),
+        TerminalSpec::EndOfInput => Json::object([kind("eof")]),
+        TerminalSpec::StartOfLine => Json::object([kind("bol")]),
+        TerminalSpec::EndOfLine => Json::object([kind("eol")]),
+        TerminalSpec::Alternative(a, b) => Json::object([
+            kind("alt"),
+            ("left", terminal_json(a)),
+            ("right", terminal_json(b)),
+        ]),
+        TerminalSpec::Sequence(a, b) => Json::object([
+            kind("seq"),
+            ("left", terminal_json(a)),
+            ("right", terminal_json(b)),
+        ]),
+        TerminalSpec::Repeat { inner, min, max } => Json::object([
+            kind("repeat"),
+            ("inner", terminal_json(inner)),
+            ("min", Json::Int(*min as i64)),
+            ("max", max.map_or(Json::Null, |n| Json::Int(n as i64))),
+        ]),
+        TerminalSpec::CharRange(start, end) => Json::object([
+            kind("range"),
+            ("start", Json::String(start.to_string())),
+            ("end", Json::String(end.to_string())),
+        ]),
+        TerminalSpec::AnyChar => Json::object([kind("any")]),
+        TerminalSpec::NoneOf(chars) => Json::object([
+            kind("none_of"),
+            ("chars", Json::String(chars.iter().collect())),
+        ]),
+        TerminalSpec::OneOf(chars) => Json::object([
+            kind("one_of"),
+            ("chars", Json::String(chars.iter().collect())),
+        ]),
+        #[cfg(feature = "regex")]
+        TerminalSpec::Regex(pattern) => {
+            Json::object([kind("regex"), ("pattern", Json::String(pattern.clone()))])
+        }
+        TerminalSpec::Integer {
+            allow_sign,
+            allow_underscores,
+            radix,
+        } => Json::object([
+            kind("integer"),
+            ("sign", Json::Bool(*allow_sign)),
+            ("underscores", Json::Bool(*allow_underscores)),
+            ("radix", Json::Int(*radix as i64)),
+        ]),
+        TerminalSpec::Float {
+            allow_exponent,
+            require_fraction,
+        } => Json::object([
+            kind("float"),
+            ("exponent", Json::Bool(*allow_exponent)),
+            ("require_fraction", Json::Bool(*require_fraction)),
+        ]),
+        TerminalSpec::QuotedString {
+            quote,
+            escape,
+            allow_newlines,
+        } => Json::object([
+            kind("string"),
+            ("quote", Json::String(quote.to_string())),
+            (
+                "escape",
+                escape.map_or(Json::Null, |c| Json::String(c.to_string())),
+            ),
+            ("newlines", Json::Bool(*allow_newlines)),
+        ]),
+        TerminalSpec::LineComment(prefix) => Json::object([
+            kind("line_comment"),
+            ("prefix", Json::String(prefix.clone())),
+        ]),
+        TerminalSpec::BlockComment {
+            open,
+            close,
+            nested,
+            allow_unterminated,
+        } => Json::object([
+            kind("block_comment"),
+            ("open", Json::String(open.clone())),
+            ("close", Json::String(close.clone())),
+            ("nested", Json::Bool(*nested)),
+            ("unterminated", Json::Bool(*allow_unterminated)),
+        ]),
+        TerminalSpec::Identifier => Json::object([kind("ident")]),
+        TerminalSpec::Whitespace {
+            include_newlines,
+            min,
+        } => Json::object([
+            kind("whitespace"),
+            ("newlines", Json::Bool(*include_newlines)),
+            ("min", Json::Int(*min as i64)),
+        ]),
+        TerminalSpec::Until {
+            delimiter,
+            inclusive,
+            or_end_of_input,
+        } => Json::object([
+            kind("until"),
+            ("delimiter", Json::String(delimiter.clone())),
+            ("inclusive", Json::Bool(*inclusive)),
+            ("or_eof", Json::Bool(*or_end_of_input)),
+        ]),
+        TerminalSpec::CharClass(name) => {
+            Json::object([kind("class"), ("name", Json::String(name.clone()))])
+        }
+        TerminalSpec::LazyRepeat { inner, min, max } => Json::object([
+            kind("lazy_repeat"),
+            ("inner", terminal_json(inner)),
+            ("min", Json::Int(*min as i64)),
+            ("max", max.map_or(Json::Null, |n| Json::Int(n as i64))),
+        ]),
+        #[cfg(feature = "byte-input")]
+        TerminalSpec::Byte(b) => Json::object([kind("byte"), ("value", Json::Int(*b as i64))]),
+        #[cfg(feature = "byte-input")]
+        TerminalSpec::ByteString(bytes) => Json::object([
+            kind("byte_string"),
+            (
+                "bytes",
+                Json::Array(bytes.iter().map(|&b| Json::Int(b as i64)).collect()),
+            ),
+        ]),
+        #[cfg(feature = "byte-input")]
+        TerminalSpec::AnyBytes(n) => {
+            Json::object([kind("any_bytes"), ("len", Json::Int(*n as i64))])
+        }
+        #[cfg(feature = "byte-input")]
+        TerminalSpec::LengthPrefixed { width, big_endian } => Json::object([
+            kind("length_prefixed"),
+            ("width", Json::Int(*width as i64)),
+            ("big_endian", Json::Bool(*big_endian)),
+        ]),
+        TerminalSpec::Keywords(words) => Json::object([
+            kind("keywords"),
+            (
+                "words",
+                Json::Array(words.iter().map(|w| Json::String(w.clone())).collect()),
+            ),
+        ]),
+    }
+}
+
+fn field<'a>(value: &'a Json, key: &str) -> std::result::Result<&'a Json, SpecError> {
+    value
+        .get(key)
+        .ok_or_else(|| SpecError::Malformed(format!("missing field `{}`", key)))
+}
+
+fn str_field<'a>(value: &'a Json, key: &str) -> std::result::Result<&'a str, SpecError> {
+    field(value, key)?
+        .as_str()
+        .ok_or_else(|| SpecError::Malformed(format!("field `{}` must be a string", key)))
+}
+
+fn char_field(value: &Json, key: &str) -> std::result::Result<char, SpecError> {
+    let mut chars = str_field(value, key)?.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(SpecError::Malformed(format!(
+            "field `{}` must be one character",
+            key
+        ))),
+    }
+}
+
+fn optional_int_field(value: &Json, key: &str) -> std::result::Result<Option<usize>, SpecError> {
+    match field(value, key)? {
+        Json::Null => Ok(None),
+        _ => int_field(value, key).map(Some),
+    }
+}
+
+#[cfg(feature = "byte-input")]
+fn byte_value(value: &Json) -> std::result::Result<u8, SpecError> {
+    value
+        .as_int()
+        .and_then(|n| u8::try_from(n).ok())
+        .ok_or_else(|| SpecError::Malformed("bytes must be integers in 0..=255".into()))
+}
+
+fn bool_field(value: &Json, key: &str) -> std::result::Result<bool, SpecError> {
+    match field(value, key)? {
+        Json::Bool(b) => Ok(*b),
+        _ => Err(SpecError::Malformed(format!(
+            "field `{}` must be a boolean",
+            key
+        ))),
+    }
+}
+
+fn int_field(value: &Json, key: &str) -> std::result::Result<usize, SpecError> {
+    field(value, key)?
+        .as_int()
+        .and_then(|n| usize::try_from(n).ok())
+        .ok_or_else(|| {
+            SpecError::Malformed(format!("field `{}` must be a non-negative integer", key))
+        })
+}
+
+fn array_field<'a>(value: &'a Json, key: &str) -> std::result::Result<&'a [Json], SpecError> {
+    field(value, key)?
+        .as_array()
+        .ok_or_else(|| SpecError::Malformed(format!("field `{}` must be an array", key)))
+}
+
+fn node_from_json(value: &Json) -> std::result::Result<NodeSpec, SpecError> {
+    let items = |value: &Json| {
+        array_field(value, "items")?
+            .iter()
+            .map(node_from_json)
+            .collect::<std::result::Result<Vec<_>, _>>()
+    };
+    Ok(match str_field(value, "kind")? {
+        "terminal" => NodeSpec::Terminal(terminal_from_json(field(value, "terminal")?)?),
+        "choice" => NodeSpec::Choice(items(value)?),
+        "sequence" => NodeSpec::Sequence(items(value)?),
+        "ref" => NodeSpec::Reference(int_field(value, "rule")?),
+        "verbatim" => NodeSpec::Verbatim(Box::new(node_from_json(field(value, "inner")?)?)),
+        "placeholder" => NodeSpec::Placeholder,
+        other => {
+            return Err(SpecError::Malformed(format!(
+                "unknown node kind `{}`",
+                other
+            )));
+        }
+    })
+}
+
+fn terminal_from_json(value: &Json) -> std::result::Result<TerminalSpec, SpecError> {
+    let boxed = |key: &str| Ok::<_, SpecError>(Box::new(terminal_from_json(field(value, key)?)?));
+    Ok(match str_field(value, "kind")? {
+        "str" => TerminalSpec::Str(str_field(value, "text")?.to_string()),
+        "char" => TerminalSpec::Char(char_field(value, "char")?),
+        "range" => TerminalSpec::CharRange(char_field(value, "start")?, char_field(value, "end")?),
+        "sof" => TerminalSpec::StartOfInput,
+        "eof" => TerminalSpec::EndOfInput,
+        "bol" => TerminalSpec::StartOfLine,
+        "eol" => TerminalSpec::EndOfLine,
+        "alt" => TerminalSpec::Alternative(boxed("left")?, boxed("right")?),
+        "seq" => TerminalSpec::Sequence(boxed("left")?, boxed("right")?),
+        "repeat" => TerminalSpec::Repeat {
+            inner: boxed("inner")?,
+            min: int_field(value, "min")?,
+            max: optional_int_field(value, "max")?,
+        },
+        "lazy_repeat" => TerminalSpec::LazyRepeat {
+            inner: boxed("inner")?,
+            min: int_field(value, "min")?,
+            max: optional_int_field(value, "max")?,
+        },
+        "any" => TerminalSpec::AnyChar,
+        "none_of" => TerminalSpec::NoneOf(str_field(value, "chars")?.chars().collect()),
+        "one_of" => TerminalSpec::OneOf(str_field(value, "chars")?.chars().collect()),
+        #[cfg(feature = "regex")]
+        "regex" => {
+            let pattern = str_field(value, "pattern")?;
+            crate::words::Regex::new(pattern)
+                .map_err(|e| SpecError::Malformed(format!("invalid regex: {}", e)))?;
+            TerminalSpec::Regex(pattern.to_string())
+        }
+        "integer" => TerminalSpec::Integer {
+            allow_sign: bool_field(value, "sign")?,
+            allow_underscores: bool_field(value, "underscores")?,
+            radix: match int_field(value, "radix")? {
+                radix @ 2..=36 => radix as u32,
+                _ => return Err(SpecError::Malformed("`radix` must be in 2..=36".into())),
+            },
+        },
+        "float" => TerminalSpec::Float {
+            allow_exponent: bool_field(value, "exponent")?,
+            require_fraction: bool_field(value, "require_fraction")?,
+        },
+        "string" => TerminalSpec::QuotedString {
+            quote: char_field(value, "quote")?,
+            escape: match field(value, "escape")? {
+                Json::Null => None,
+                _ => Some(char_field(value, "escape")?),
+            },
+            allow_newlines: bool_field(value, "newlines")?,
+        },
+        "line_comment" => TerminalSpec::LineComment(str_field(value, "prefix")?.to_string()),
+        "block_comment" => {
+            let open = str_field(value, "open")?;
+            let close = str_field(value, "close")?;
+            if open.is_empty() || close.is_empty() {
+                return Err(SpecError::Malformed(
+                    "comment delimiters must not be empty".into(),
+                ));
+            }
+            TerminalSpec::BlockComment {
+                open: open.to_string(),
+                close: close.to_string(),
+                nested: bool_field(value, "nested")?,
+                allow_unterminated: bool_field(value, "unterminated")?,
+            }
+        }
+        "ident" => TerminalSpec::Identifier,
+        "whitespace" => TerminalSpec::Whitespace {
+            include_newlines: bool_field(value, "newlines")?,
+            min: int_field(value, "min")?,
+        },
+        "until" => {
+            let delimiter = str_field(value, "delimiter")?;
+            if delimiter.is_empty() {
+                return Err(SpecError::Malformed("`delimiter` must not be empty".into()));
+            }
+            TerminalSpec::Until {
+                delimiter: delimiter.to_string(),
+                inclusive: bool_field(value, "inclusive")?,
+                or_end_of_input: bool_field(value, "or_eof")?,
+            }
+        }
+        "class" => {
+            let name = str_field(value, "name")?;
+            if CharClass::builtin(name).is_none() {
+                return Err(SpecError::Malformed(format!(
+                    "unknown char class `{}`",
+                    name
+                )));
+            }
+            TerminalSpec::CharClass(name.to_string())
+        }
+        #[cfg(feature = "byte-input")]
+        "byte" => TerminalSpec::Byte(byte_value(field(value, "value")?)?),
+        #[cfg(feature = "byte-input")]
+        "byte_string" => TerminalSpec::ByteString(
+            array_field(value, "bytes")?
+                .iter()
+                .map(byte_value)
+                .collect::<std::result::Result<_, _>>()?,
+        ),
+        #[cfg(feature = "byte-input")]
+        "any_bytes" => TerminalSpec::AnyBytes(int_field(value, "len")?),
+        #[cfg(feature = "byte-input")]
+        "length_prefixed" => {
+            let width = int_field(value, "width")?;
+            if !matches!(width, 1 | 2 | 4) {
+                return Err(SpecError::Malformed("`width` must be 1, 2 or 4".into()));
+            }
+            TerminalSpec::LengthPrefixed {
+                width,
+                big_endian: bool_field(value, "big_endian")?,
+            }
+        }
+        "keywords" => TerminalSpec::Keywords(
+            array_field(value, "words")?
+                .iter()
+                .map(|w| {
+                    w.as_str().map(str::to_string).ok_or_else(|| {
+                        SpecError::Malformed("field `words` must contain strings".into())
+                    })
+                })
+                .collect::<std::result::Result<_, _>>()?,
+        ),
+        other => {
+            return Err(SpecError::Malformed(format!(
+                "unknown terminal kind `{}`",
+                other
+            )));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r;
+    use crate::words::*;
+
+    #[test]
+    fn test_spec_json_round_trip() {
+        fn expr() -> GrammarNode {
+            r!(atom) + opt(t('+') + r!(expr)) + t(EndOfInput)
+        }
+
+        fn atom() -> GrammarNode {
+            t("x\"y")
+                | t("1".or('2').times(1..=3))
+                | t(Keywords::new(["if", "in"]))
+                | t('α'..='ω')
+                | t(HEX_DIGIT)
+                | t(AnyChar.then(none_of("\"\\")))
+                | t(one_of("+-"))
+                | t(Until::new("*/").inclusive())
+                | t(Integer::with_radix(16).signed())
+                | t(Float::new())
+                | t(QuotedString::new('"'))
+                | t(LineComment("#"))
+                | t(Identifier)
+                | t(AnyChar.times_lazy(1..=4).then(';'))
+                | t(StartOfLine.then(EndOfLine))
+                | t(Whitespace::new().horizontal())
+                | t(BlockComment::new("(*", "*)").nested())
+        }
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap().with_trivia(' ');
+        let json = grammar.to_spec().unwrap().to_json();
+        let restored = Grammar::from_spec(GrammarSpec::from_json(&json).unwrap()).unwrap();
+        assert_eq!(restored.to_string(), grammar.to_string());
+        assert_eq!(restored.to_spec().unwrap().to_json(), json);
+    }
+
+    #[test]
+    fn test_spec_errors() {
+        #[derive(Debug)]
+        struct Custom;
+
+        impl crate::words::Matcher for Custom {
+            fn try_match(&self, _input: &str, _at: usize) -> Option<usize> {
+                None
+            }
+            fn display(&self) -> String {
+                String::from("CUSTOM")
+            }
+            fn is_nullable(&self) -> bool {
+                false
+            }
+        }
+
+        let grammar = Grammar::try_from(t("a") + t(Custom)).unwrap();
+        assert_eq!(
+            grammar.to_spec(),
+            Err(SpecError::UnsupportedTerminal("CUSTOM".to_string()))
+        );
+
+        let json = r#"{"format_version":99,"rules":[]}"#;
+        assert!(matches!(
+            Grammar::from_spec(GrammarSpec::from_json(json).unwrap()),
+            Err(SpecError::UnsupportedVersion { found: 99, .. })
+        ));
+        let json =
+            r#"{"format_version":1,"rules":[{"name":"START","node":{"kind":"ref","rule":3}}]}"#;
+        assert!(matches!(
+            Grammar::from_spec(GrammarSpec::from_json(json).unwrap()),
+            Err(SpecError::InvalidReference(3))
+        ));
+    }
+}
@@ -1,17 +1,56 @@
 use std::ops;
+use std::sync::Arc;
 
+use crate::grammar::RuleId;
 use crate::words::Matcher;
 
 pub type RuleFn = fn() -> GrammarNode;
 
+/// A rule taking a node as a parameter, like `paren_list(item)`.
+pub type RuleFn1 = fn(GrammarNode) -> GrammarNode;
+
+#[derive(Debug, Clone)]
 pub enum GrammarNode {
-    Terminal(Box<dyn Matcher>),
+    Terminal(Arc<dyn Matcher>),
     Choice(Vec<GrammarNode>),
     Sequence(Vec<GrammarNode>),
     Reference(RuleFn, &'static str),
+    /// A parameterized rule applied to an argument. Each distinct argument
+    /// gets a rule of its own, named like `paren_list<expr>`.
+    Apply(RuleFn1, &'static str, Box<GrammarNode>),
+    /// A node normalized already: the argument as a parameterized rule's
+    /// function is given it.
+    Normalized(NormalizedNode),
     Optional(Box<GrammarNode>),
+    /// Disables implicit trivia skipping inside the node, including in the
+    /// rules it references.
+    Verbatim(Box<GrammarNode>),
+    /// Marks the referenced rule as a token rule.
+    Token(Box<GrammarNode>),
     Some(Box<GrammarNode>),
     Many(Box<GrammarNode>),
+    /// A node syntax errors call by a label rather than by its terminals.
+    Expected(&'static str, Box<GrammarNode>),
+    /// Operators over an atom, with a rule per precedence named after the
+    /// first field. See [`prec_climb`].
+    Climb(
+        &'static str,
+        Box<GrammarNode>,
+        Vec<(GrammarNode, u32, Assoc)>,
+    ),
+}
+
+/// How an operator of [`prec_climb`] takes its operands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    /// Binary, grouping `a - b - c` as `(a - b) - c`.
+    Left,
+    /// Binary, grouping `a ^ b ^ c` as `a ^ (b ^ c)`.
+    Right,
+    /// Unary, before its operand.
+    Prefix,
+    /// Unary, after its operand.
+    Postfix,
 }
 
 impl GrammarNode {
@@ -20,15 +59,38 @@ impl GrammarNode {
     }
 }
 
-#[derive(Debug)]
+/// Terminals are shared behind `Arc`, as in `GrammarNode`, so rule bodies can
+/// be copied cheaply.
+#[derive(Debug, Clone)]
 pub enum NormalizedNode {
-    Terminal(Box<dyn Matcher>),
+    Terminal(Arc<dyn Matcher>),
     Choice(Vec<NormalizedNode>),
     Sequence(Vec<NormalizedNode>),
-    Reference(usize),
+    Reference(RuleId),
+    Verbatim(Box<NormalizedNode>),
     Placeholder,
 }
 
+/// Structural equality. Terminals compare by their `Debug` representation,
+/// which distinguishes matcher types as well as their contents.
+impl PartialEq for NormalizedNode {
+    fn eq(&self, other: &Self) -> bool {
+        use NormalizedNode as N;
+        match (self, other) {
+            (N::Terminal(a), N::Terminal(b)) => {
+                Arc::ptr_eq(a, b) || format!("{:?}", a) == format!("{:?}", b)
+            }
+            (N::Choice(a), N::Choice(b)) | (N::Sequence(a), N::Sequence(b)) => a == b,
+            (N::Reference(a), N::Reference(b)) => a == b,
+            (N::Verbatim(a), N::Verbatim(b)) => a == b,
+            (N::Placeholder, N::Placeholder) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for NormalizedNode {}
+
 impl NormalizedNode {
     pub fn is_reference(&self) -> bool {
         matches!(self, NormalizedNode::Reference(_))
@@ -40,7 +102,14 @@ impl NormalizedNode {
 
 #[inline]
 pub fn t<M: Matcher + 'static>(matcher: M) -> GrammarNode {
-    GrammarNode::Terminal(Box::new(matcher))
+    GrammarNode::Terminal(Arc::new(matcher))
+}
+
+/// A terminal matching one token of the given kind, for grammars run over
+/// token streams.
+#[inline]
+pub fn tok<K: PartialEq + std::fmt::Debug + Send + Sync + 'static>(kind: K) -> GrammarNode {
+    t(crate::words::TokenKind(kind))
 }
 
 #[inline]
@@ -48,6 +117,11 @@ pub fn r(rule: RuleFn, name: &'static str) -> GrammarNode {
     GrammarNode::Reference(rule, name)
 }
 
+#[inline]
+pub fn r1(rule: RuleFn1, name: &'static str, argument: impl Into<GrammarNode>) -> GrammarNode {
+    GrammarNode::Apply(rule, name, Box::new(argument.into()))
+}
+
 #[inline]
 pub fn choice(nodes: impl IntoIterator<Item = GrammarNode>) -> GrammarNode {
     GrammarNode::Choice(nodes.into_iter().collect())
@@ -63,6 +137,77 @@ pub fn opt(node: impl Into<GrammarNode>) -> GrammarNode {
     GrammarNode::Optional(Box::new(node.into()))
 }
 
+/// One or more `node`s, one after another.
+#[inline]
+pub fn some(node: impl Into<GrammarNode>) -> GrammarNode {
+    GrammarNode::Some(Box::new(node.into()))
+}
+
+/// Zero or more `node`s, one after another.
+#[inline]
+pub fn many(node: impl Into<GrammarNode>) -> GrammarNode {
+    GrammarNode::Many(Box::new(node.into()))
+}
+
+/// `node`, called `label` by syntax errors where it was expected, as in
+/// "expected a statement" rather than a list of the terminals a statement
+/// can start with. The label stands for `node` only where it was expected
+/// and nothing of it matched; an error further into it still names what
+/// was missing there. Where labels nest, the innermost wins.
+#[inline]
+pub fn expected(label: &'static str, node: impl Into<GrammarNode>) -> GrammarNode {
+    GrammarNode::Expected(label, Box::new(node.into()))
+}
+
+/// Expressions of `atom` and the operators in `table`, each given with its
+/// precedence, higher binding tighter, and how it takes its operands.
+///
+/// Each precedence gets a rule named `{name}_{precedence}`, so the tree
+/// has a node per operator applied, like `(expr_1 1 + (expr_2 2 * 3))`
+/// for `1+2*3`, and none for operands standing alone. The rules are read
+/// left to right and folded into that shape as they finish, with no left
+/// recursion, so an expression takes time linear in its length. A prefix operator's
+/// operand, and a right-associative one's right operand, may use
+/// operators of the same precedence; other operands only tighter ones.
+///
+/// ```
+/// use tree_editor::grammar::Grammar;
+/// use tree_editor::grammar_dsl::*;
+///
+/// let expr = prec_climb(
+///     "expr",
+///     t('0'..='9'),
+///     [
+///         (t('+'), 1, Assoc::Left),
+///         (t('*'), 2, Assoc::Left),
+///         (t('-'), 3, Assoc::Prefix),
+///     ],
+/// );
+/// let grammar = Grammar::try_from(expr).unwrap();
+/// assert!(tree_editor::parser::parse(&grammar, "-1+2*3").is_complete());
+/// ```
+pub fn prec_climb(
+    name: &'static str,
+    atom: impl Into<GrammarNode>,
+    table: impl IntoIterator<Item = (GrammarNode, u32, Assoc)>,
+) -> GrammarNode {
+    GrammarNode::Climb(name, Box::new(atom.into()), table.into_iter().collect())
+}
+
+/// Matches `node` without skipping the grammar's trivia between its parts,
+/// e.g. for string literals where whitespace is significant.
+#[inline]
+pub fn verbatim(node: impl Into<GrammarNode>) -> GrammarNode {
+    GrammarNode::Verbatim(Box::new(node.into()))
+}
+
+/// Marks a rule as a token: its whole match becomes one leaf in the tree.
+/// `rule` must be a reference, e.g. `token(r!(ident))`.
+#[inline]
+pub fn token(rule: GrammarNode) -> GrammarNode {
+    GrammarNode::Token(Box::new(rule))
+}
+
 #[macro_export]
 macro_rules! r {
     ($rule_fn:expr) => {
@@ -70,6 +215,14 @@ macro_rules! r {
     };
 }
 
+/// A reference to a parameterized rule, e.g. `r1!(paren_list, r!(expr))`.
+#[macro_export]
+macro_rules! r1 {
+    ($rule_fn:expr, $argument:expr) => {
+        $crate::grammar_dsl::r1($rule_fn, stringify!($rule_fn), $argument)
+    };
+}
+
 impl ops::Add for GrammarNode {
     type Output = GrammarNode;
 
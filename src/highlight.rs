@@ -0,0 +1,223 @@
+//! Highlight classes for ranges of a parsed text, as an editor asks for
+//! them: which class each piece of the range has, by the rules it was
+//! parsed as.
+
+use std::collections::HashMap;
+
+use crate::grammar::{Grammar, RuleId};
+use crate::parser::ParserState;
+use crate::tree::{RedNode, Tag};
+use crate::utils::Span;
+
+#[cfg(feature = "lsp")]
+mod lsp;
+
+#[cfg(feature = "lsp")]
+pub use lsp::{
+    SemanticTokens, SemanticTokensDelta, SemanticTokensEdit, semantic_tokens,
+    semantic_tokens_delta, semantic_tokens_range,
+};
+
+/// Which class the text of each rule gets, and the class of text error
+/// recovery skipped. Classes are whatever the caller picks, an enum or
+/// strings.
+#[derive(Debug, Clone)]
+pub struct HighlightMap<C> {
+    rules: HashMap<RuleId, C>,
+    error: C,
+    /// Every class, each once, in the order first given.
+    classes: Vec<C>,
+}
+
+impl<C: Clone + PartialEq> HighlightMap<C> {
+    pub fn new(error: C) -> Self {
+        HighlightMap {
+            rules: HashMap::new(),
+            classes: vec![error.clone()],
+            error,
+        }
+    }
+
+    /// Gives the text of `rule` in `grammar` the class `class`, unless a
+    /// rule under it has a class of its own.
+    ///
+    /// # Panics
+    ///
+    /// If `grammar` has no rule named `rule`.
+    pub fn with_rule(mut self, grammar: &Grammar, rule: &str, class: C) -> Self {
+        let idx = grammar
+            .rule_index(rule)
+            .unwrap_or_else(|| panic!("no rule named `{rule}`"));
+        if !self.classes.contains(&class) {
+            self.classes.push(class.clone());
+        }
+        self.rules.insert(idx, class);
+        self
+    }
+
+    /// Every class the map gives out, each once: the error class, then the
+    /// others in the order they were first given to a rule. The order
+    /// depends only on how the map was built, so it can serve as a legend
+    /// numbering the classes.
+    pub fn legend(&self) -> &[C] {
+        &self.classes
+    }
+
+    /// The class of the text under a node tagged `tag`, given the class of
+    /// the text under its parent. Text under an error node is an error all
+    /// the way down.
+    fn class(&self, tag: &Tag, parent: Option<&C>) -> Option<C> {
+        match tag {
+            _ if parent == Some(&self.error) => parent.cloned(),
+            Tag::Error(_) => Some(self.error.clone()),
+            Tag::Rule(idx) => self.rules.get(idx).or(parent).cloned(),
+            Tag::Terminal | Tag::Trivia => parent.cloned(),
+        }
+    }
+}
+
+/// The classes of the text in `range` of the tree `state` last parsed,
+/// in text order: the class of the innermost rule with one over each
+/// token, cut to `range`. Adjacent pieces of the same class are merged.
+/// Trivia and tokens outside any rule with a class are left out.
+///
+/// Only the smallest subtree covering `range` is looked into, and under
+/// it only what intersects `range`.
+pub fn highlight<C: Clone + PartialEq>(
+    state: &ParserState,
+    range: Span,
+    map: &HighlightMap<C>,
+) -> Vec<(Span, C)> {
+    let alloc = state.arena();
+    let top = state.ast().covering_node_for_span(alloc, range);
+    // The class the covering node's parents give it, from the root down.
+    let mut ancestors: Vec<&RedNode> = top.ancestors().collect();
+    ancestors.reverse();
+    let inherited = ancestors.into_iter().fold(None, |class, node| {
+        map.class(&node.green(alloc).tag, class.as_ref())
+    });
+
+    let mut out: Vec<(Span, C)> = Vec::new();
+    let mut stack = vec![(top, inherited)];
+    while let Some((node, parent)) = stack.pop() {
+        let span = node.span(alloc);
+        if !span.intersects(range) {
+            continue;
+        }
+        let green = node.green(alloc);
+        let class = map.class(&green.tag, parent.as_ref());
+        if !green.children.is_empty() {
+            let children: Vec<_> = node.children(alloc).collect();
+            stack.extend(
+                children
+                    .into_iter()
+                    .rev()
+                    .map(|child| (child, class.clone())),
+            );
+            continue;
+        }
+        let Some(class) = class else { continue };
+        let span = Span::new(span.start.max(range.start), span.end.min(range.end));
+        if green.tag == Tag::Trivia || span.is_empty() {
+            continue;
+        }
+        match out.last_mut() {
+            Some((last, last_class)) if last.end == span.start && *last_class == class => {
+                last.end = span.end;
+            }
+            _ => out.push((span, class)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar_dsl::*;
+    use crate::r;
+    use crate::words::{Identifier, Matcher, Whitespace};
+
+    fn stmts() -> GrammarNode {
+        r!(stmt) + opt(r!(stmts))
+    }
+
+    fn stmt() -> GrammarNode {
+        token(r!(name)) + t('=') + r!(value) + t(';')
+    }
+
+    fn value() -> GrammarNode {
+        token(r!(number)) | (t('-') + token(r!(number)))
+    }
+
+    fn name() -> GrammarNode {
+        t(Identifier)
+    }
+
+    fn number() -> GrammarNode {
+        t(('0'..='9').times(1..))
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Class {
+        Name,
+        Number,
+        Operator,
+        Error,
+    }
+
+    #[test]
+    fn test_highlight() {
+        let grammar = Grammar::try_from(r!(stmts))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let map = HighlightMap::new(Class::Error)
+            .with_rule(&grammar, "stmt", Class::Operator)
+            .with_rule(&grammar, "name", Class::Name)
+            .with_rule(&grammar, "number", Class::Number);
+        let mut state = ParserState::new(grammar).with_text("a = 1;\nbc =-23;\n");
+        assert!(state.parse().is_complete());
+        let spans = |state: &ParserState, start, end| -> Vec<_> {
+            highlight(state, Span::new(start, end), &map)
+                .into_iter()
+                .map(|(span, class)| (span.start, span.end, class))
+                .collect()
+        };
+
+        use Class::*;
+        assert_eq!(
+            spans(&state, 0, 16),
+            [
+                (0, 1, Name),
+                (2, 3, Operator),
+                (4, 5, Number),
+                (5, 6, Operator),
+                (7, 9, Name),
+                // `=` and `-` run together.
+                (10, 12, Operator),
+                (12, 14, Number),
+                (14, 15, Operator),
+            ]
+        );
+        // Cut to the range, from inside the second statement.
+        assert_eq!(
+            spans(&state, 8, 13),
+            [(8, 9, Name), (10, 12, Operator), (12, 13, Number)]
+        );
+
+        state.set_text("a = 1;\nb = ?;\n");
+        assert_eq!(
+            spans(&state, 0, 14),
+            [
+                (0, 1, Name),
+                (2, 3, Operator),
+                (4, 5, Number),
+                (5, 6, Operator),
+                (7, 8, Name),
+                (9, 10, Operator),
+                (11, 12, Error),
+                (12, 13, Operator),
+            ]
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! Highlights in the LSP semantic-token wire format.
+
+use super::{HighlightMap, highlight};
+use crate::parser::ParserState;
+use crate::positions::{Position, PositionEncoding, PositionIndex};
+use crate::utils::Span;
+
+/// The semantic tokens of a whole text, as in an LSP `SemanticTokens`
+/// result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemanticTokens {
+    /// Names the state of the text the tokens were taken from, for a
+    /// later [`semantic_tokens_delta`].
+    pub result_id: String,
+    /// `(deltaLine, deltaStart, length, tokenType, tokenModifiers)` per
+    /// token, each position relative to the token before. The token type
+    /// is an index into [`HighlightMap::legend`].
+    pub data: Vec<u32>,
+}
+
+/// Replaces `delete_count` numbers of the previous data from `start` on by
+/// `data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemanticTokensEdit {
+    pub start: u32,
+    pub delete_count: u32,
+    pub data: Vec<u32>,
+}
+
+/// How to get from one [`SemanticTokens`] to the next, as in an LSP
+/// `SemanticTokensDelta` result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemanticTokensDelta {
+    pub result_id: String,
+    pub edits: Vec<SemanticTokensEdit>,
+}
+
+/// The highlights of the whole text `state` last parsed, encoded as
+/// semantic tokens of the text it was parsed from. Characters are counted in `encoding`, UTF-16 unless the
+/// client negotiated something else. A token spanning lines is split into
+/// one token per line, since not every client takes multi-line tokens.
+pub fn semantic_tokens<C: Clone + PartialEq>(
+    state: &ParserState,
+    map: &HighlightMap<C>,
+    encoding: PositionEncoding,
+) -> SemanticTokens {
+    let range = Span::new(0, state.parsed_text().len());
+    SemanticTokens {
+        result_id: state.version().to_string(),
+        data: semantic_tokens_range(state, range, map, encoding),
+    }
+}
+
+/// The semantic tokens of `range` only, as for an LSP range request.
+/// Positions are still relative to the start of the text. Like the tree,
+/// they are of the text last parsed, not of edits since.
+pub fn semantic_tokens_range<C: Clone + PartialEq>(
+    state: &ParserState,
+    range: Span,
+    map: &HighlightMap<C>,
+    encoding: PositionEncoding,
+) -> Vec<u32> {
+    let text = state.parsed_text();
+    let index = PositionIndex::new(text, encoding);
+    let mut data = Vec::new();
+    let mut last = Position::new(0, 0);
+    for (span, class) in highlight(state, range, map) {
+        let token_type = (map.legend().iter())
+            .position(|legend| *legend == class)
+            .expect("highlight gives out legend classes only") as u32;
+        // A tree of tokens or bytes has no text to take tokens from.
+        let Some(covered) = text.get(span.start..span.end) else {
+            continue;
+        };
+        let mut start = span.start;
+        for piece in covered.split('\n') {
+            let line = piece.strip_suffix('\r').unwrap_or(piece);
+            if !line.is_empty() {
+                let at = index.position(start);
+                let length: usize = line.chars().map(|c| encoding.len(c)).sum();
+                let delta_start = match at.line == last.line {
+                    true => at.character - last.character,
+                    false => at.character,
+                };
+                data.extend([
+                    (at.line - last.line) as u32,
+                    delta_start as u32,
+                    length as u32,
+                    token_type,
+                    0,
+                ]);
+                last = at;
+            }
+            start += piece.len() + 1;
+        }
+    }
+    data
+}
+
+/// The edits from `previous` to `current`, if `previous` is the result the
+/// client names by `previous_result_id`. `None` means the client holds
+/// some other result, and needs all of `current` instead.
+///
+/// The edit is a single one, replacing what lies between the common start
+/// and the common end of the two.
+pub fn semantic_tokens_delta(
+    previous: &SemanticTokens,
+    previous_result_id: &str,
+    current: &SemanticTokens,
+) -> Option<SemanticTokensDelta> {
+    if previous.result_id != previous_result_id {
+        return None;
+    }
+    let (old, new) = (&previous.data, &current.data);
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = (old[prefix..].iter().rev())
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let edits = match old == new {
+        true => Vec::new(),
+        false => vec![SemanticTokensEdit {
+            start: prefix as u32,
+            delete_count: (old.len() - prefix - suffix) as u32,
+            data: new[prefix..new.len() - suffix].to_vec(),
+        }],
+    };
+    Some(SemanticTokensDelta {
+        result_id: current.result_id.clone(),
+        edits,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::Grammar;
+    use crate::grammar_dsl::*;
+    use crate::parser::{Edit, Parser};
+    use crate::r;
+    use crate::words::{Identifier, Matcher, QuotedString, Whitespace};
+    use std::sync::mpsc;
+
+    fn stmts() -> GrammarNode {
+        r!(stmt) + opt(r!(stmts))
+    }
+
+    fn stmt() -> GrammarNode {
+        token(r!(name)) + t('=') + (token(r!(number)) | token(r!(string))) + t(';')
+    }
+
+    fn name() -> GrammarNode {
+        t(Identifier)
+    }
+
+    fn number() -> GrammarNode {
+        t(('0'..='9').times(1..))
+    }
+
+    fn string() -> GrammarNode {
+        t(QuotedString::new('"').allow_newlines())
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Class {
+        Operator,
+        Name,
+        String,
+        Number,
+        Error,
+    }
+
+    #[test]
+    fn test_semantic_tokens() {
+        let grammar = Grammar::try_from(r!(stmts))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let map = HighlightMap::new(Class::Error)
+            .with_rule(&grammar, "stmt", Class::Operator)
+            .with_rule(&grammar, "name", Class::Name)
+            .with_rule(&grammar, "string", Class::String)
+            .with_rule(&grammar, "number", Class::Number);
+        use Class::*;
+        assert_eq!(map.legend(), [Error, Operator, Name, String, Number]);
+
+        let mut state = ParserState::new(grammar).with_text("a = \"😀\";\nbc = \"x\ny😀\";\n");
+        assert!(state.parse().is_complete());
+        let tokens = semantic_tokens(&state, &map, PositionEncoding::Utf16);
+        #[rustfmt::skip]
+        assert_eq!(tokens.data, [
+            0, 0, 1, 2, 0, // a
+            0, 2, 1, 1, 0, // =
+            0, 2, 4, 3, 0, // "😀", the emoji two units long
+            0, 4, 1, 1, 0, // ;
+            1, 0, 2, 2, 0, // bc
+            0, 3, 1, 1, 0, // =
+            0, 2, 2, 3, 0, // "x, first line of the string
+            1, 0, 4, 3, 0, // y😀", second line
+            0, 4, 1, 1, 0, // ;
+        ]);
+        // Counted in chars instead, the emoji is one long.
+        let chars = semantic_tokens(&state, &map, PositionEncoding::Utf32);
+        assert_eq!(chars.data[10..15], [0, 2, 3, 3, 0]);
+        assert_eq!(chars.data[35..40], [1, 0, 3, 3, 0]);
+
+        // From the `=` of the second statement to just before the emoji.
+        #[rustfmt::skip]
+        assert_eq!(
+            semantic_tokens_range(&state, Span::new(15, 21), &map, PositionEncoding::Utf16),
+            [
+                1, 3, 1, 1, 0,
+                0, 2, 2, 3, 0,
+                1, 0, 1, 3, 0,
+            ]
+        );
+
+        state.set_text("abc = \"😀\";\nbc = \"x\ny😀\";\n");
+        let next = semantic_tokens(&state, &map, PositionEncoding::Utf16);
+        assert_ne!(next.result_id, tokens.result_id);
+        let delta = semantic_tokens_delta(&tokens, &tokens.result_id, &next).unwrap();
+        assert_eq!(delta.result_id, next.result_id);
+        assert_eq!(
+            delta.edits,
+            [SemanticTokensEdit {
+                start: 2,
+                delete_count: 5,
+                data: vec![3, 2, 0, 0, 4],
+            }]
+        );
+        assert_eq!(semantic_tokens_delta(&tokens, "stale", &next), None);
+        let same = semantic_tokens_delta(&next, &next.result_id, &next).unwrap();
+        assert!(same.edits.is_empty());
+    }
+
+    #[test]
+    fn test_semantic_tokens_before_reparse() {
+        let grammar = Grammar::try_from(r!(stmts))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let map = HighlightMap::new(Class::Error)
+            .with_rule(&grammar, "stmt", Class::Operator)
+            .with_rule(&grammar, "name", Class::Name)
+            .with_rule(&grammar, "string", Class::String)
+            .with_rule(&grammar, "number", Class::Number);
+        let (sender, receiver) = mpsc::channel();
+        let mut parser = Parser::new_with_text(grammar, receiver, "ab = 1;\ncd = 2;\n");
+        let tokens = semantic_tokens(parser.state(), &map, PositionEncoding::Utf16);
+
+        // Until a reparse, the tokens stay those of the text parsed, even
+        // with a char inside a token and the text cut short since.
+        sender
+            .send(Edit::Insert {
+                position: 1,
+                new_text: "é".to_string(),
+            })
+            .unwrap();
+        sender
+            .send(Edit::Delete {
+                span: Span::new(9, 18),
+            })
+            .unwrap();
+        for _ in 0..2 {
+            parser.receive_edits().unwrap();
+            let stale = semantic_tokens(parser.state(), &map, PositionEncoding::Utf16);
+            assert_eq!(stale.data, tokens.data);
+        }
+
+        assert!(parser.parse_now().is_complete());
+        let tokens = semantic_tokens(parser.state(), &map, PositionEncoding::Utf16);
+        #[rustfmt::skip]
+        assert_eq!(tokens.data, [
+            0, 0, 3, 2, 0, // aéb
+            0, 4, 1, 1, 0, // =
+            0, 2, 1, 4, 0, // 1
+            0, 1, 1, 1, 0, // ;
+        ]);
+    }
+}
@@ -1,15 +1,28 @@
+pub mod ast;
+mod capabilities;
 mod core;
 pub mod grammar;
 pub mod grammar_dsl;
+pub mod highlight;
+pub mod json;
 pub mod parser;
+pub mod positions;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod tree;
 pub mod utils;
 pub mod words;
 
+pub use parser::parse;
+
+#[cfg(feature = "capi")]
+pub use capabilities::{CCapabilities, capi_features, tree_editor_capabilities};
+pub use capabilities::{
+    Capabilities, FORMAT_VERSION, LSP_TOKENS_VERSION, MissingCapability, capabilities,
+};
+
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn it_works() {}
 }
@@ -1,17 +1,348 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
 use std::sync::{
-    Arc,
-    mpsc::{Receiver, RecvError},
+    Arc, OnceLock,
+    atomic::{AtomicBool, Ordering},
+    mpsc::{Receiver, RecvError, RecvTimeoutError, TryRecvError},
 };
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use concurrent_queue::ConcurrentQueue;
+use crate::{
+    grammar::{Grammar, RuleId},
+    positions::{Position, PositionIndex},
+    tree::*,
+    utils::{LineCol, LineIndex, Span},
+    words::TokenInput,
+};
+
+mod diagnostic;
+mod engine;
+mod rope;
 
-use crate::{grammar::Grammar, tree::*, utils::Span};
+pub use diagnostic::Diagnostic;
+use engine::{Change, Engine, Failure, Input, ParseCache, Reused, Stop};
+pub use engine::{ParseOptions, ParseStats, ReuseStats};
+use rope::Rope;
 
+/// A change to the text. Spans and positions are byte offsets, which must
+/// fall on char boundaries; [`char_to_byte`] converts from char indices.
+///
+/// [`char_to_byte`]: crate::utils::char_to_byte
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Edit {
-    Update { span: Span, new_text: String },
-    Insert { position: usize, new_text: String },
-    Delete { span: Span },
+    Update {
+        span: Span,
+        new_text: String,
+    },
+    Insert {
+        position: usize,
+        new_text: String,
+    },
+    Delete {
+        span: Span,
+    },
+    /// Edits applied in order, each to the text the one before it left,
+    /// as LSP applies the changes of one notification. Either all of them
+    /// fit and are applied, or none is.
+    Batch(Vec<Edit>),
+}
+
+/// Which side of text inserted exactly at a span boundary the boundary
+/// ends up on, for [`Edit::map_span`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bias {
+    /// The boundary sticks to the text on its left, staying before the
+    /// inserted text.
+    Left,
+    /// The boundary sticks to the text on its right, moving past the
+    /// inserted text.
+    Right,
+}
+
+impl Edit {
+    /// Replaces the text between two LSP positions, or `None` if either
+    /// isn't in the text `index` was built from.
+    pub fn from_lsp_range(
+        index: &PositionIndex,
+        start: Position,
+        end: Position,
+        new_text: impl Into<String>,
+    ) -> Option<Edit> {
+        let span = Span::new(index.offset(start)?, index.offset(end)?);
+        let new_text = new_text.into();
+        Some(match (span.is_empty(), new_text.is_empty()) {
+            (true, _) => Edit::Insert {
+                position: span.start,
+                new_text,
+            },
+            (false, true) => Edit::Delete { span },
+            (false, false) => Edit::Update { span, new_text },
+        })
+    }
+
+    /// Inserts text at an LSP position, or `None` if it isn't in the text
+    /// `index` was built from.
+    pub fn from_lsp_position(
+        index: &PositionIndex,
+        position: Position,
+        new_text: impl Into<String>,
+    ) -> Option<Edit> {
+        Some(Edit::Insert {
+            position: index.offset(position)?,
+            new_text: new_text.into(),
+        })
+    }
+
+    /// How much longer the edit makes the text, negative if shorter.
+    pub fn len_delta(&self) -> isize {
+        match self {
+            Edit::Update { span, new_text } => new_text.len() as isize - span.len() as isize,
+            Edit::Insert { new_text, .. } => new_text.len() as isize,
+            Edit::Delete { span } => -(span.len() as isize),
+            Edit::Batch(edits) => edits.iter().map(Edit::len_delta).sum(),
+        }
+    }
+
+    /// Where `span` of the text before the edit is in the text after it.
+    /// Spans before the edit stay put and spans after it shift. A span
+    /// that the edit removes part of keeps what's left, without any
+    /// replacement text, and one it removes entirely, or that is empty and
+    /// inside the removed text, maps to `None`. A boundary at an insertion
+    /// goes to the side `bias` says; a span containing a replacement
+    /// grows or shrinks with it.
+    pub fn map_span(&self, span: Span, bias: Bias) -> Option<Span> {
+        if let Edit::Batch(edits) = self {
+            return Edit::map_span_through(edits, span, bias);
+        }
+        let Change {
+            start,
+            old_end,
+            new_end,
+        } = self.change();
+        let shift = |at: usize| at - old_end + new_end;
+        if start == old_end {
+            let map = |at: usize| {
+                if at < start || (at == start && bias == Bias::Left) {
+                    at
+                } else {
+                    shift(at)
+                }
+            };
+            return Some(Span::new(map(span.start), map(span.end)));
+        }
+        if span.end <= start {
+            Some(span)
+        } else if span.start >= old_end {
+            Some(Span::new(shift(span.start), shift(span.end)))
+        } else if span.start >= start && span.end <= old_end {
+            None
+        } else if span.start < start {
+            let end = if span.end > old_end {
+                shift(span.end)
+            } else {
+                start
+            };
+            Some(Span::new(span.start, end))
+        } else {
+            Some(Span::new(new_end, shift(span.end)))
+        }
+    }
+
+    /// Maps `span` through `edits` applied in order, as
+    /// [`Edit::map_span`] does for one.
+    pub fn map_span_through(edits: &[Edit], span: Span, bias: Bias) -> Option<Span> {
+        edits
+            .iter()
+            .try_fold(span, |span, edit| edit.map_span(span, bias))
+    }
+
+    fn change(&self) -> Change {
+        let (span, new_len) = match self {
+            Edit::Update { span, new_text } => (*span, new_text.len()),
+            Edit::Insert { position, new_text } => {
+                (Span::new(*position, *position), new_text.len())
+            }
+            Edit::Delete { span } => (*span, 0),
+            Edit::Batch(edits) => {
+                let mut changes = edits.iter().map(Edit::change);
+                let first = changes.next().unwrap_or(Change {
+                    start: 0,
+                    old_end: 0,
+                    new_end: 0,
+                });
+                return changes.fold(first, |acc, next| {
+                    // `acc` spans `acc.start..acc.new_end` of the text `next`
+                    // applies to; cover both in the text before either.
+                    let end = acc.new_end.max(next.old_end);
+                    Change {
+                        start: acc.start.min(next.start),
+                        old_end: acc.old_end + (end - acc.new_end),
+                        new_end: end - next.old_end + next.new_end,
+                    }
+                });
+            }
+        };
+        Change {
+            start: span.start,
+            old_end: span.end,
+            new_end: span.start + new_len,
+        }
+    }
+
+    /// Applies the edit to `text`, unless it doesn't fit, returning the
+    /// edit that undoes it.
+    pub(crate) fn apply(&self, text: &mut Rope) -> Result<Edit, ParserError> {
+        let inverse = match self {
+            Edit::Update { span, new_text } => {
+                is_valid_span(text, *span)?;
+                let old_text = text.slice(*span);
+                text.replace(*span, new_text);
+                Edit::Update {
+                    span: Span::new_len(span.start, new_text.len()),
+                    new_text: old_text,
+                }
+            }
+            Edit::Insert { position, new_text } => {
+                is_valid_position(text, *position)?;
+                text.replace(Span::new(*position, *position), new_text);
+                Edit::Delete {
+                    span: Span::new_len(*position, new_text.len()),
+                }
+            }
+            Edit::Delete { span } => {
+                is_valid_span(text, *span)?;
+                let old_text = text.slice(*span);
+                text.replace(*span, "");
+                Edit::Insert {
+                    position: span.start,
+                    new_text: old_text,
+                }
+            }
+            Edit::Batch(edits) => {
+                let mut edited = text.clone();
+                let mut inverses = edits
+                    .iter()
+                    .map(|edit| edit.apply(&mut edited))
+                    .collect::<Result<Vec<_>, _>>()?;
+                *text = edited;
+                inverses.reverse();
+                Edit::Batch(inverses)
+            }
+        };
+        Ok(inverse)
+    }
+}
+
+fn is_valid_span(text: &Rope, span: Span) -> Result<(), ParserError> {
+    if span.start > span.end || span.end > text.len() {
+        return Err(ParserError::SpanOutOfBounds {
+            expected: span,
+            actual: Span {
+                start: 0,
+                end: text.len(),
+            },
+        });
+    }
+    is_char_boundary(text, span.start)?;
+    is_char_boundary(text, span.end)
+}
+
+fn is_valid_position(text: &Rope, position: usize) -> Result<(), ParserError> {
+    if position > text.len() {
+        return Err(ParserError::PositionOutOfBounds {
+            expected: Span {
+                start: 0,
+                end: text.len(),
+            },
+            actual: position,
+        });
+    }
+    is_char_boundary(text, position)
+}
+
+fn is_char_boundary(text: &Rope, position: usize) -> Result<(), ParserError> {
+    if text.is_char_boundary(position) {
+        Ok(())
+    } else {
+        Err(ParserError::NotCharBoundary { position })
+    }
+}
+
+/// The text a state parses. Edits go to the rope, copying the chunks they
+/// touch, and to the line index; both also shift the offsets they keep
+/// after the edit, one per chunk and per line. Parses and snapshots read
+/// the text joined into one string, which is built when the first of them
+/// after an edit asks for it, never by the edit itself.
+#[derive(Default)]
+struct Document {
+    text: Rope,
+    joined: OnceLock<Arc<String>>,
+    /// Line starts of `text`, kept up to date by each edit.
+    lines: LineIndex,
+    /// Bumped on each change to `text`.
+    version: u64,
+}
+
+impl Document {
+    fn apply(&mut self, edit: &Edit) -> Result<Edit, ParserError> {
+        let inverse = edit.apply(&mut self.text)?;
+        self.index_lines(edit);
+        self.joined = OnceLock::new();
+        self.version += 1;
+        Ok(inverse)
+    }
+
+    fn index_lines(&mut self, edit: &Edit) {
+        match edit {
+            Edit::Update { span, new_text } => self.lines.edit(*span, new_text),
+            Edit::Insert { position, new_text } => {
+                self.lines.edit(Span::new(*position, *position), new_text);
+            }
+            Edit::Delete { span } => self.lines.edit(*span, ""),
+            Edit::Batch(edits) => edits.iter().for_each(|edit| self.index_lines(edit)),
+        }
+    }
+
+    /// The position of byte `offset`, as [`LineIndex::line_col`] gives it.
+    fn line_col(&self, offset: usize) -> LineCol {
+        let mut offset = offset.min(self.text.len());
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = self.lines.line_of(offset);
+        let start = self.lines.line_start(line).unwrap_or(0);
+        LineCol {
+            line: line + 1,
+            col: self.text.slice(Span::new(start, offset)).chars().count() + 1,
+        }
+    }
+
+    /// The byte offset of `line_col`, if the text has that line and the
+    /// line has that column. The column after a line's last char, where its
+    /// line break is, counts.
+    fn offset(&self, line_col: LineCol) -> Option<usize> {
+        let line = line_col.line.checked_sub(1)?;
+        let start = self.lines.line_start(line)?;
+        let end = self
+            .lines
+            .line_start(line + 1)
+            .map_or(self.text.len(), |next| next - 1);
+        let column = line_col.col.checked_sub(1)?;
+        let text = self.text.slice(Span::new(start, end));
+        text.char_indices()
+            .map(|(at, _)| at)
+            .chain(std::iter::once(text.len()))
+            .nth(column)
+            .map(|at| start + at)
+    }
+
+    fn joined(&self) -> Arc<String> {
+        self.joined
+            .get_or_init(|| Arc::new(self.text.to_string()))
+            .clone()
+    }
 }
 
 #[derive(Clone)]
@@ -19,46 +350,905 @@ pub struct ParserState {
     grammar: Arc<Grammar>,
     arena: Arc<TreeAlloc>,
     ast: Arc<RedNode>,
-    text: Arc<parking_lot::RwLock<String>>,
+    /// The text `ast` was parsed from.
+    parsed: Arc<String>,
+    document: Arc<parking_lot::RwLock<Document>>,
+    /// Rule results of the last parse, for reuse by the next reparse.
+    cache: Arc<ParseCache>,
+    reuse_stats: ReuseStats,
+    /// What the last parse built rather than reused, from
+    /// [`Engine::rebuilt`].
+    rebuilt: Vec<Span>,
+    stats: ParseStats,
+    total_stats: ParseStats,
+    options: ParseOptions,
+    diagnostics: Vec<Diagnostic>,
+    cancel: CancellationToken,
+    /// Subtrees known to hold no error nodes, which collecting diagnostics
+    /// can skip.
+    clean: Arc<HashSet<GreenId>>,
+    /// Reuse is unsound under left recursion, where a rule's result
+    /// depends on which rules are already being evaluated.
+    incremental: bool,
+    /// Set while [`Parser::feed`] appends to the text, which may go on
+    /// past its end until [`Parser::finish`].
+    open_ended: bool,
+}
+
+/// Cancels a parse in progress from another thread. Clones share the
+/// flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Makes the parse in progress, or the next one if none is, give up
+    /// with [`ParserError::Cancelled`].
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Whether it was cancelled, resetting it for the next parse.
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
 }
 
+/// What [`ParserState::run`] found.
+struct Run {
+    result: ParserResult,
+    /// The text parsed.
+    text: Arc<String>,
+    cache: ParseCache,
+    /// What the parse reused, for recovering from it.
+    reused: Reused,
+    reuse_stats: ReuseStats,
+    rebuilt: Vec<Span>,
+    stats: ParseStats,
+}
+
+/// The outcome of a parse.
 pub enum ParserResult {
+    /// The text matches the grammar; the root of its tree.
     Complete(Arc<RedNode>),
+    /// The text doesn't match, and the tree kept is one recovered from the
+    /// errors, with [`Tag::Error`] nodes over the text that didn't fit.
+    /// `error` is the [`ParserError::SyntaxError`] at the farthest point
+    /// the parse got to.
+    Recovered {
+        root: Arc<RedNode>,
+        diagnostics: Vec<Diagnostic>,
+        error: ParserError,
+    },
+    /// No tree came of the parse.
     Incomplete(ParserError),
 }
 
+impl ParserResult {
+    pub fn is_complete(&self) -> bool {
+        matches!(self, ParserResult::Complete(_))
+    }
+
+    /// The root of the tree built, complete or recovered.
+    pub fn root(&self) -> Option<&Arc<RedNode>> {
+        match self {
+            ParserResult::Complete(root) | ParserResult::Recovered { root, .. } => Some(root),
+            ParserResult::Incomplete(_) => None,
+        }
+    }
+
+    pub fn error(&self) -> Option<&ParserError> {
+        match self {
+            ParserResult::Complete(_) => None,
+            ParserResult::Recovered { error, .. } | ParserResult::Incomplete(error) => Some(error),
+        }
+    }
+}
+
+/// Parses `text` with `grammar` once, recovering from syntax errors as
+/// [`ParserState::parse`] does. The result comes with the state that made
+/// it, whose arena the nodes of its tree live in:
+///
+/// ```
+/// use tree_editor::grammar::Grammar;
+/// use tree_editor::grammar_dsl::*;
+/// use tree_editor::r;
+/// use tree_editor::words::Matcher;
+///
+/// fn expr() -> GrammarNode {
+///     r!(term) + opt(t('+') + r!(expr))
+/// }
+///
+/// fn term() -> GrammarNode {
+///     t(('0'..='9').times(1..)) + opt(t('*') + r!(term))
+/// }
+///
+/// let grammar = Grammar::try_from(r!(expr)).unwrap();
+/// assert!(!tree_editor::parse(&grammar, "1+*3").is_complete());
+///
+/// let parsed = tree_editor::parse(&grammar, "1+2*3");
+/// assert!(parsed.is_complete());
+/// assert_eq!(
+///     parsed.state.dump_tree(),
+///     "\
+/// START 0..5
+///   expr 0..5
+///     term 0..1
+///       \"1\"
+///       term_opt 1..1
+///     expr_opt 1..5
+///       \"+\"
+///       expr 2..5
+///         term 2..5
+///           \"2\"
+///           term_opt 3..5
+///             \"*\"
+///             term 4..5
+///               \"3\"
+///               term_opt 5..5
+///         expr_opt 5..5
+/// "
+/// );
+/// ```
+pub fn parse(grammar: &Grammar, text: &str) -> Parsed {
+    let mut state = ParserState::new(grammar.clone()).with_text(text);
+    let result = state.parse();
+    Parsed { state, result }
+}
+
+/// A parse from [`parse`]: its result, and the state holding the text and
+/// the arena the result's tree lives in.
+pub struct Parsed {
+    pub state: ParserState,
+    pub result: ParserResult,
+}
+
+impl Parsed {
+    pub fn is_complete(&self) -> bool {
+        self.result.is_complete()
+    }
+
+    /// The root of the tree built, complete or recovered, to be walked in
+    /// [`ParserState::arena`] of `state`.
+    pub fn root(&self) -> Option<&Arc<RedNode>> {
+        self.result.root()
+    }
+
+    pub fn error(&self) -> Option<&ParserError> {
+        self.result.error()
+    }
+}
+
 impl ParserState {
     pub fn new(grammar: Grammar) -> Self {
+        let incremental = grammar.left_recursive_rules().is_empty();
         let arena = TreeAlloc::new();
         let placeholder_id = arena.new_placeholder(0);
         Self {
             grammar: Arc::new(grammar),
             arena: Arc::new(arena),
-            ast: Arc::new(RedNode {
-                parent: None,
-                green: placeholder_id,
-                offset: 0,
-            }),
-            text: Arc::new(parking_lot::RwLock::new(String::new())),
+            ast: Arc::new(RedNode::root(placeholder_id)),
+            parsed: Arc::default(),
+            document: Arc::default(),
+            cache: Arc::default(),
+            reuse_stats: ReuseStats::default(),
+            rebuilt: Vec::new(),
+            stats: ParseStats::default(),
+            total_stats: ParseStats::default(),
+            options: ParseOptions::default(),
+            diagnostics: Vec::new(),
+            cancel: CancellationToken::default(),
+            clean: Arc::default(),
+            incremental,
+            open_ended: false,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.current_text().to_string()
+    }
+
+    /// How many times the text has changed, by an edit or
+    /// [`ParserState::with_text`].
+    pub fn version(&self) -> u64 {
+        self.document.read().version
+    }
+
+    /// Replaces the text the state parses.
+    pub fn with_text(self, text: impl Into<String>) -> Self {
+        let text = text.into();
+        let mut document = self.document.write();
+        document.text = Rope::from(text.as_str());
+        document.lines = LineIndex::new(&text);
+        document.joined = OnceLock::from(Arc::new(text));
+        document.version += 1;
+        drop(document);
+        self
+    }
+
+    /// The 1-based line and column of byte `offset`, found with a line
+    /// index kept up to date by edits. Offsets inside a char or past the
+    /// end are clamped to the preceding boundary.
+    pub fn offset_to_line_col(&self, offset: usize) -> LineCol {
+        self.document.read().line_col(offset)
+    }
+
+    /// The byte offset of a 1-based line and column, or `None` if the text
+    /// has no such line or the line no such column. A line's columns run up
+    /// to the one after its last char, where its line break is.
+    pub fn line_col_to_offset(&self, line_col: LineCol) -> Option<usize> {
+        self.document.read().offset(line_col)
+    }
+
+    /// Replaces the whole text, as an LSP client does when it resyncs the
+    /// document, and parses it from scratch.
+    pub fn set_text(&mut self, text: impl Into<String>) -> ParserResult {
+        let edit = self.replace_all(text.into());
+        self.apply(&edit)
+            .expect("replacing the whole text always fits");
+        self.parse()
+    }
+
+    /// An edit replacing the whole text with `text`.
+    fn replace_all(&self, text: String) -> Edit {
+        Edit::Update {
+            span: Span::new(0, self.document.read().text.len()),
+            new_text: text,
+        }
+    }
+
+    /// The current text and version with the tree of the last parse, which
+    /// stay as they are while the state moves on. The tree may be from an
+    /// older version if the text was edited since.
+    pub fn snapshot(&self) -> ParseSnapshot {
+        let document = self.document.read();
+        ParseSnapshot {
+            version: document.version,
+            text: document.joined(),
+            ast: self.ast.clone(),
+            arena: self.arena.clone(),
+        }
+    }
+
+    fn apply(&self, edit: &Edit) -> Result<Edit, ParserError> {
+        self.document.write().apply(edit)
+    }
+
+    /// The current text of `span`, cut down to fit the text and to fall on
+    /// char boundaries.
+    pub(crate) fn text_in(&self, span: Span) -> String {
+        let document = self.document.read();
+        let text = &document.text;
+        let floor = |mut offset: usize| {
+            offset = offset.min(text.len());
+            while !text.is_char_boundary(offset) {
+                offset -= 1;
+            }
+            offset
+        };
+        let end = floor(span.end);
+        text.slice(Span::new(floor(span.start).min(end), end))
+    }
+
+    /// The text the tree of the last parse was built from, which lags
+    /// behind [`ParserState::text`] while edits wait for a reparse. Empty
+    /// if the tree is of tokens or bytes.
+    pub fn parsed_text(&self) -> &str {
+        &self.parsed
+    }
+
+    /// The current text, which stays valid after later edits.
+    fn current_text(&self) -> Arc<String> {
+        self.document.read().joined()
+    }
+
+    /// Sets how parses are run.
+    pub fn with_options(mut self, options: ParseOptions) -> Self {
+        self.options = ParseOptions {
+            memoize: options.memoize && self.incremental,
+            ..options
+        };
+        self
+    }
+
+    /// Parses the whole text with the START rule. The result is
+    /// `Incomplete` unless START matches all of it.
+    pub fn parse_full(&self) -> ParserResult {
+        self.run(ParseCache::new(), &|| false).result
+    }
+
+    /// Parses the whole text like [`ParserState::parse_full`], keeping the
+    /// tree as [`ParserState::ast`]. If the text doesn't match, the tree
+    /// kept is one recovered from the errors, with [`Tag::Error`] nodes
+    /// over the text that didn't fit, and the result is
+    /// [`ParserResult::Recovered`]. A parse that runs out of the budget
+    /// in [`ParseOptions`] or is cancelled keeps the previous tree.
+    pub fn parse(&mut self) -> ParserResult {
+        self.commit(ParseCache::new(), &|| false)
+    }
+
+    /// Parses `tokens` from an external lexer instead of the text, with a
+    /// grammar whose terminals are token matchers such as
+    /// [`TokenKind`](crate::words::TokenKind). Positions and widths in the
+    /// tree count tokens, and a syntax error's `line_col` is line 1 at the
+    /// 1-based index of the token. The tree is kept as [`ParserState::ast`]
+    /// only if all of `tokens` matches: token streams aren't recovered
+    /// from, nor reparsed after edits.
+    pub fn parse_tokens(&mut self, tokens: &dyn TokenInput) -> ParserResult {
+        self.parse_input(Input::Tokens(tokens))
+    }
+
+    /// Parses binary input instead of the text, like
+    /// [`ParserState::parse_tokens`] but with byte matchers such as
+    /// [`LengthPrefixed`](crate::words::LengthPrefixed). Positions and
+    /// widths in the tree count bytes.
+    #[cfg(feature = "byte-input")]
+    pub fn parse_bytes(&mut self, bytes: &[u8]) -> ParserResult {
+        self.parse_input(Input::Bytes(bytes))
+    }
+
+    fn parse_input(&mut self, input: Input) -> ParserResult {
+        let cancel = self.cancel.clone();
+        let interrupt = || cancel.take();
+        let mut engine = Engine::over(&self.grammar, &self.arena, input)
+            .with_options(self.options)
+            .interruptible(&interrupt);
+        let outcome = engine.parse_full();
+        self.stats = engine.stats();
+        self.total_stats.merge(self.stats);
+        let error = match outcome {
+            Ok(green) => {
+                self.ast = Arc::new(RedNode::root(green));
+                self.parsed = Arc::default();
+                self.diagnostics.clear();
+                // What's cached and rebuilt is of the text, not this tree.
+                self.cache = Arc::new(ParseCache::new());
+                self.reuse_stats = ReuseStats::default();
+                self.rebuilt = Vec::new();
+                self.debug_check_tree();
+                return ParserResult::Complete(self.ast.clone());
+            }
+            Err(_) if let Some(stop) = engine.stopped() => match stop {
+                Stop::Budget { steps, elapsed } => ParserError::BudgetExceeded { steps, elapsed },
+                Stop::Interrupted => ParserError::Cancelled,
+            },
+            Err(failure) => ParserError::SyntaxError {
+                offset: failure.pos,
+                line_col: LineCol {
+                    line: 1,
+                    col: failure.pos + 1,
+                },
+                expected: failure.expected(),
+                labels: failure.labels(),
+                rules: failure.rules(),
+            },
+        };
+        ParserResult::Incomplete(error)
+    }
+
+    /// Applies `edit` to the text and parses it again. Rule results of the
+    /// previous parse that the edit can't have changed are reused instead
+    /// of evaluated, so the tree is the same as a full parse would give,
+    /// with the same `GreenId`s. See [`ParserState::reuse_stats`] for how
+    /// much was reused. Fails, changing nothing, if the edit doesn't fit
+    /// the text.
+    pub fn reparse(&mut self, edit: &Edit) -> Result<ParserResult, ParserError> {
+        self.apply(edit)?;
+        Ok(self.reparse_applied(edit))
+    }
+
+    /// Like [`ParserState::reparse`], for an edit already applied.
+    pub(crate) fn reparse_applied(&mut self, edit: &Edit) -> ParserResult {
+        self.reparse_interruptible(edit, &|| false)
+    }
+
+    /// A token that cancels this state's parses, and those of its clones,
+    /// from another thread.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Like [`ParserState::reparse`], but gives up with
+    /// [`ParserError::Cancelled`] once `interrupt` returns `true`.
+    fn reparse_interruptible(&mut self, edit: &Edit, interrupt: &dyn Fn() -> bool) -> ParserResult {
+        let reusable = if self.incremental {
+            engine::carry_over(&self.cache, &self.current_text(), edit.change())
+        } else {
+            ParseCache::new()
+        };
+        self.commit(reusable, interrupt)
+    }
+
+    /// Node counts of the tree from the last [`ParserState::parse`] or
+    /// [`ParserState::reparse`].
+    pub fn reuse_stats(&self) -> ReuseStats {
+        self.reuse_stats
+    }
+
+    /// The syntax errors recovered from in the tree from the last
+    /// [`ParserState::parse`] or [`ParserState::reparse`], one per
+    /// [`Tag::Error`] node. Empty after a complete parse.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    fn commit(&mut self, reusable: ParseCache, interrupt: &dyn Fn() -> bool) -> ParserResult {
+        let cancel = self.cancel.clone();
+        let interrupt = || cancel.take() || interrupt();
+        let run = self.run(reusable, &interrupt);
+        let result = run.result;
+        let mut cache = run.cache;
+        self.reuse_stats = run.reuse_stats;
+        self.rebuilt = run.rebuilt;
+        self.stats = run.stats;
+        match &result {
+            ParserResult::Complete(root) => {
+                self.ast = root.clone();
+                self.parsed = run.text;
+                self.diagnostics.clear();
+            }
+            ParserResult::Incomplete(ParserError::SyntaxError { .. }) => {
+                let text = run.text;
+                let (recovered, stats) = engine::recover(
+                    &self.grammar,
+                    &self.arena,
+                    &text,
+                    &mut cache,
+                    run.reused,
+                    self.options,
+                    &interrupt,
+                );
+                self.stats.merge(stats);
+                self.cache = Arc::new(cache);
+                self.total_stats.merge(self.stats);
+                let recovered = match recovered {
+                    Ok(recovered) => recovered,
+                    Err(Stop::Interrupted) => {
+                        return ParserResult::Incomplete(ParserError::Cancelled);
+                    }
+                    Err(Stop::Budget { steps, elapsed }) => {
+                        let error = ParserError::BudgetExceeded { steps, elapsed };
+                        return ParserResult::Incomplete(error);
+                    }
+                };
+                let green = recovered.root;
+                self.reuse_stats = recovered.reuse_stats;
+                self.rebuilt = recovered.rebuilt;
+                let clean = Arc::make_mut(&mut self.clean);
+                self.diagnostics =
+                    diagnostic::collect(&self.grammar, &self.arena, &text, green, clean);
+                self.ast = Arc::new(RedNode::root(green));
+                self.parsed = text;
+                self.debug_check_tree();
+                let ParserResult::Incomplete(error) = result else {
+                    unreachable!()
+                };
+                return ParserResult::Recovered {
+                    root: self.ast.clone(),
+                    diagnostics: self.diagnostics.clone(),
+                    error,
+                };
+            }
+            ParserResult::Incomplete(_) | ParserResult::Recovered { .. } => {}
+        }
+        self.cache = Arc::new(cache);
+        self.total_stats.merge(self.stats);
+        self.debug_check_tree();
+        result
+    }
+
+    /// Panics in debug builds if the tree kept is malformed, which would be
+    /// a bug in building or reusing it.
+    fn debug_check_tree(&self) {
+        if cfg!(debug_assertions)
+            && let Err(error) = self.arena.check_invariants(self.ast.green)
+        {
+            panic!("malformed tree after parsing: {error}");
         }
     }
 
+    fn run(&self, reusable: ParseCache, interrupt: &dyn Fn() -> bool) -> Run {
+        let text = self.current_text();
+        let mut engine = Engine::new(&self.grammar, &self.arena, &text)
+            .reusing(reusable)
+            .with_options(self.options)
+            .interruptible(interrupt)
+            .open_ended(self.open_ended);
+        let outcome = engine.parse_full();
+        let mut stats = engine.stats();
+        let (result, reuse_stats, rebuilt) = match outcome {
+            Ok(green) => {
+                let root = RedNode::root(green);
+                let reuse_stats = engine.reuse_stats(green);
+                let rebuilt = engine.rebuilt(green);
+                (ParserResult::Complete(Arc::new(root)), reuse_stats, rebuilt)
+            }
+            Err(_) if let Some(stop) = engine.stopped() => {
+                let error = match stop {
+                    Stop::Budget { steps, elapsed } => {
+                        ParserError::BudgetExceeded { steps, elapsed }
+                    }
+                    Stop::Interrupted => ParserError::Cancelled,
+                };
+                let result = ParserResult::Incomplete(error);
+                (result, ReuseStats::default(), Vec::new())
+            }
+            Err(_) if engine.pending() => {
+                let result = ParserResult::Incomplete(ParserError::Pending);
+                (result, ReuseStats::default(), Vec::new())
+            }
+            Err(failure) => {
+                // Failures inside reused results went unrecorded, so if they
+                // may reach as far, a parse reusing nothing finds the
+                // farthest one.
+                let error = if engine.hides_failure(failure.pos) {
+                    let mut fresh = Engine::new(&self.grammar, &self.arena, &text)
+                        .with_options(self.options)
+                        .interruptible(interrupt);
+                    let outcome = fresh.parse_full();
+                    stats.merge(fresh.stats());
+                    match outcome {
+                        Err(farthest) if fresh.stopped().is_none() => self.syntax_error(&farthest),
+                        Err(_) if fresh.stopped() == Some(Stop::Interrupted) => {
+                            ParserError::Cancelled
+                        }
+                        _ => self.syntax_error(&failure),
+                    }
+                } else {
+                    self.syntax_error(&failure)
+                };
+                let result = ParserResult::Incomplete(error);
+                (result, ReuseStats::default(), Vec::new())
+            }
+        };
+        let reused = engine.take_reused();
+        let cache = engine.into_cache();
+        Run {
+            result,
+            text,
+            reused,
+            cache,
+            reuse_stats,
+            rebuilt,
+            stats,
+        }
+    }
+
+    fn syntax_error(&self, failure: &Failure) -> ParserError {
+        ParserError::SyntaxError {
+            offset: failure.pos,
+            line_col: self.offset_to_line_col(failure.pos),
+            expected: failure.expected(),
+            labels: failure.labels(),
+            rules: failure.rules(),
+        }
+    }
+
+    /// Counts of what the last parse did, including recovering from any
+    /// syntax errors.
+    pub fn stats(&self) -> ParseStats {
+        self.stats
+    }
+
+    /// Counts of what every parse of this state, and the state it was
+    /// cloned from, did together.
+    pub fn total_stats(&self) -> ParseStats {
+        self.total_stats
+    }
+
+    pub fn ast(&self) -> &RedNode {
+        &self.ast
+    }
+
+    /// The tree from the last parse, one node per line indented by depth:
+    /// rules by name and span, terminals by the text they matched. Trivia
+    /// is left out.
+    pub fn dump_tree(&self) -> String {
+        let text = self.current_text();
+        let mut out = String::new();
+        let mut stack = vec![(self.ast.green, self.ast.offset, 0)];
+        while let Some((id, offset, depth)) = stack.pop() {
+            let node = self.arena.get_node(id);
+            let span = Span::new_len(offset, node.width);
+            let indent = "  ".repeat(depth);
+            match &node.tag {
+                Tag::Rule(idx) => {
+                    let name = self.grammar.rule_name(*idx).unwrap_or("?");
+                    out += &format!("{indent}{name} {}..{}\n", span.start, span.end);
+                    let mut at = span.end;
+                    for &child in node.children.iter().rev() {
+                        at -= self.arena.get_node(child).width;
+                        stack.push((child, at, depth + 1));
+                    }
+                }
+                Tag::Terminal => {
+                    out += &format!("{indent}{:?}\n", &text[span.start..span.end]);
+                }
+                Tag::Trivia => {}
+                Tag::Error(_) => {
+                    out += &format!("{indent}ERROR {:?}\n", &text[span.start..span.end]);
+                }
+            }
+        }
+        out
+    }
+
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    pub fn arena(&self) -> &TreeAlloc {
+        &self.arena
+    }
+
+    /// Counts the memory the state takes. The arena is shared with the
+    /// state's clones and snapshots, and counted in full.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let document = self.document.read();
+        let joined = document.joined.get().map_or(0, |text| text.len());
+        MemoryUsage {
+            arena: self.arena.memory_usage(),
+            text_bytes: document.text.len() + joined,
+            line_index_bytes: document.lines.line_count() * size_of::<usize>(),
+            cache_bytes: engine::cache_bytes(&self.cache),
+        }
+    }
+
+    /// Moves the tree and the rule results kept for reparsing to a new
+    /// arena, leaving behind the nodes earlier parses built that neither
+    /// uses any more. Returns how many nodes were left behind. Snapshots
+    /// taken before keep the old arena, and stay valid.
+    pub fn collect_garbage(&mut self) -> usize {
+        let roots: Vec<_> = std::iter::once(self.ast.green)
+            .chain(engine::cache_roots(&self.cache))
+            .collect();
+        let (arena, map) = self.arena.compact(&roots);
+        let dropped = self.arena.len() - arena.len();
+        let green = map.get(self.ast.green).expect("the tree is kept");
+        self.ast = Arc::new(RedNode::root(green));
+        self.cache = Arc::new(engine::remap_cache(&self.cache, &map));
+        self.clean = Arc::new(self.clean.iter().filter_map(|&id| map.get(id)).collect());
+        self.arena = Arc::new(arena);
+        dropped
+    }
+}
+
+/// Roughly how much memory a [`ParserState`] takes, from
+/// [`ParserState::memory_usage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub arena: AllocStats,
+    /// The text, along with the joined copy parses read if there is one.
+    pub text_bytes: usize,
+    pub line_index_bytes: usize,
+    /// The rule results kept for reparsing.
+    pub cache_bytes: usize,
+}
+
+impl MemoryUsage {
+    pub fn total_bytes(&self) -> usize {
+        self.arena.total_bytes() + self.text_bytes + self.line_index_bytes + self.cache_bytes
+    }
+}
+
+/// A [`ParserState`] as it was at one version, from
+/// [`ParserState::snapshot`].
+#[derive(Clone)]
+pub struct ParseSnapshot {
+    version: u64,
+    text: Arc<String>,
+    ast: Arc<RedNode>,
+    arena: Arc<TreeAlloc>,
+}
+
+impl ParseSnapshot {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
     pub fn ast(&self) -> &RedNode {
         &self.ast
     }
+
+    pub fn arena(&self) -> &TreeAlloc {
+        &self.arena
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum ParserError {
     LostConnection(RecvError),
-    SpanOutOfBounds { expected: Span, actual: Span },
-    PositionOutOfBounds { expected: Span, actual: usize },
+    SpanOutOfBounds {
+        expected: Span,
+        actual: Span,
+    },
+    PositionOutOfBounds {
+        expected: Span,
+        actual: usize,
+    },
+    /// An edit's byte offset falls inside a multibyte char.
+    NotCharBoundary {
+        position: usize,
+    },
+    /// The text doesn't match the grammar. `offset` is the farthest
+    /// position a terminal was tried at, `expected` the displays of the
+    /// terminals tried there and `rules` the rules they were tried in,
+    /// outermost first. Terminals tried at the start of a labeled rule are
+    /// left out of `expected` for its label in `labels`.
+    SyntaxError {
+        offset: usize,
+        line_col: LineCol,
+        expected: Vec<String>,
+        labels: Vec<&'static str>,
+        rules: Vec<RuleId>,
+    },
+    /// The parse ran out of the budget in [`ParseOptions`] and gave up.
+    BudgetExceeded {
+        steps: u64,
+        elapsed: Duration,
+    },
+    /// The parse was cancelled, by a [`CancellationToken`] or because a
+    /// [`Parser`] received another edit. The previous tree is kept.
+    Cancelled,
+    /// The parse got to the end of text still being fed to a [`Parser`]
+    /// without finding an error, so it can't tell yet whether the text
+    /// matches. The previous tree is kept.
+    Pending,
 }
 
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParserError::LostConnection(_) => write!(f, "every edit sender was dropped"),
+            ParserError::SpanOutOfBounds { expected, actual } => write!(
+                f,
+                "span {}..{} is out of bounds of the text, {}..{}",
+                expected.start, expected.end, actual.start, actual.end
+            ),
+            ParserError::PositionOutOfBounds { expected, actual } => write!(
+                f,
+                "position {} is out of bounds of the text, {}..{}",
+                actual, expected.start, expected.end
+            ),
+            ParserError::NotCharBoundary { position } => {
+                write!(f, "position {} is inside a char", position)
+            }
+            ParserError::SyntaxError {
+                line_col,
+                expected,
+                labels,
+                ..
+            } if !labels.is_empty() => {
+                // Labels read as prose: "a statement, an expression or ';'"
+                let mut items: Vec<&str> = labels.clone();
+                items.extend(expected.iter().map(String::as_str));
+                let last = items.pop().unwrap_or_default();
+                match items.is_empty() {
+                    true => write!(f, "syntax error at {}: expected {}", line_col, last),
+                    false => write!(
+                        f,
+                        "syntax error at {}: expected {} or {}",
+                        line_col,
+                        items.join(", "),
+                        last
+                    ),
+                }
+            }
+            ParserError::SyntaxError {
+                line_col, expected, ..
+            } => match expected.as_slice() {
+                [] => write!(f, "syntax error at {}", line_col),
+                [one] => write!(f, "syntax error at {}: expected {}", line_col, one),
+                many => write!(
+                    f,
+                    "syntax error at {}: expected one of {}",
+                    line_col,
+                    many.join(", ")
+                ),
+            },
+            ParserError::BudgetExceeded { steps, elapsed } => {
+                write!(f, "parse gave up after {} steps and {:?}", steps, elapsed)
+            }
+            ParserError::Cancelled => write!(f, "parse was cancelled"),
+            ParserError::Pending => write!(f, "parse needs more input"),
+        }
+    }
+}
+
+impl std::error::Error for ParserError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParserError::LostConnection(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// How often a spawned parser waiting for edits checks for shutdown.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(20);
+
+/// A [`Parser`] running on its own thread, from [`Parser::spawn`].
+pub struct ParserHandle {
+    thread: JoinHandle<ParserState>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ParserHandle {
+    /// Stops the parser after the edit it is working on, if any, and
+    /// returns its final state.
+    pub fn shutdown(self) -> ParserState {
+        self.stop.store(true, Ordering::Relaxed);
+        self.join()
+    }
+
+    /// Waits for the parser to stop, which it does once every sender is
+    /// dropped, and returns its final state.
+    pub fn join(self) -> ParserState {
+        match self.thread.join() {
+            Ok(state) => state,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// How many edits a [`Parser`] can undo unless set otherwise.
+const DEFAULT_HISTORY_DEPTH: usize = 100;
+
+/// The edits that undo and redo the latest ones, most recent last.
+#[derive(Default)]
+struct History {
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    depth: usize,
+}
+
+impl History {
+    fn push_undo(&mut self, inverse: Edit) {
+        if self.undo.len() == self.depth {
+            self.undo.pop_front();
+        }
+        if self.depth > 0 {
+            self.undo.push_back(inverse);
+        }
+    }
+}
+
+/// What an edit changed, passed to observers added with
+/// [`Parser::add_observer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeSummary {
+    /// The edit reparsed, a batch if edits arriving during a reparse were
+    /// folded into it.
+    pub edit: Edit,
+    /// The text version after the edit.
+    pub version: u64,
+    /// Covers `changed_nodes`, or is empty where the edit is if the
+    /// reparse built nothing new.
+    pub reparsed_span: Span,
+    /// The largest subtrees the reparse built rather than reused, in text
+    /// order. Subtrees reused as they were, even if they moved, aren't
+    /// included.
+    pub changed_nodes: Vec<Span>,
+}
+
+type ChangeObserver = Box<dyn Fn(&ParserState, &ChangeSummary) + Send + Sync>;
+
 pub struct Parser {
     state: ParserState,
     receiver: Receiver<Edit>,
     observer: Box<dyn Fn(&ParserState) + Send + Sync>,
+    observers: Vec<ChangeObserver>,
+    history: RefCell<History>,
+    /// How long [`Parser::run`] waits for more edits before reparsing.
+    debounce: Duration,
 }
 
 impl Parser {
@@ -67,9 +1257,39 @@ impl Parser {
             state: ParserState::new(grammar),
             receiver,
             observer: Box::new(|_state| {}),
+            observers: Vec::new(),
+            history: RefCell::new(History {
+                depth: DEFAULT_HISTORY_DEPTH,
+                ..History::default()
+            }),
+            debounce: Duration::ZERO,
         }
     }
 
+    /// A parser for a document that starts out as `text`, parsed before
+    /// this returns.
+    pub fn new_with_text(
+        grammar: Grammar,
+        receiver: Receiver<Edit>,
+        text: impl Into<String>,
+    ) -> Self {
+        let mut parser = Parser::new(grammar, receiver);
+        parser.state = parser.state.with_text(text);
+        parser.state.parse();
+        parser
+    }
+
+    /// Replaces the whole text and parses it from scratch, then calls the
+    /// observer. The replacement can be undone like an edit.
+    pub fn set_text(&mut self, text: impl Into<String>) -> ParserResult {
+        let edit = self.state.replace_all(text.into());
+        self.apply(&edit)
+            .expect("replacing the whole text always fits");
+        let result = self.state.parse();
+        self.notify(edit);
+        result
+    }
+
     pub fn set_observer<F>(&mut self, observer: F)
     where
         F: Fn(&ParserState) + Send + Sync + 'static,
@@ -77,56 +1297,1085 @@ impl Parser {
         self.observer = Box::new(observer);
     }
 
+    /// Adds an observer called with what changed after each reparse,
+    /// alongside the one from [`Parser::set_observer`] and any others
+    /// added.
+    pub fn add_observer<F>(&mut self, observer: F)
+    where
+        F: Fn(&ParserState, &ChangeSummary) + Send + Sync + 'static,
+    {
+        self.observers.push(Box::new(observer));
+    }
+
+    fn notify(&self, edit: Edit) {
+        let changed_nodes = self.state.rebuilt.clone();
+        let reparsed_span = match (changed_nodes.first(), changed_nodes.last()) {
+            (Some(first), Some(last)) => Span::new(first.start, last.end),
+            _ => {
+                let start = edit.change().start;
+                Span::new(start, start)
+            }
+        };
+        let summary = ChangeSummary {
+            edit,
+            version: self.state.version(),
+            reparsed_span,
+            changed_nodes,
+        };
+        (self.observer)(&self.state);
+        for observer in &self.observers {
+            observer(&self.state, &summary);
+        }
+    }
+
+    /// Appends `chunk` to the text, for input that arrives in pieces, and
+    /// reparses, calling the observers. Until [`Parser::finish`] the text
+    /// is taken to go on past its end: rules that reach the end are
+    /// evaluated again when more arrives rather than failing, and the
+    /// result is [`ParserError::Pending`] unless an error turns up before
+    /// the end.
+    pub fn feed(&mut self, chunk: &str) -> ParserResult {
+        let edit = Edit::Insert {
+            position: self.state.document.read().text.len(),
+            new_text: chunk.to_string(),
+        };
+        self.apply(&edit).expect("appending always fits");
+        self.state.open_ended = true;
+        let result = self.state.reparse_applied(&edit);
+        self.notify(edit);
+        result
+    }
+
+    /// Marks the end of the text fed with [`Parser::feed`], so
+    /// [`EndOfInput`] can match there, and reparses, calling the
+    /// observers.
+    ///
+    /// [`EndOfInput`]: crate::words::EndOfInput
+    pub fn finish(&mut self) -> ParserResult {
+        self.state.open_ended = false;
+        let edit = Edit::Insert {
+            position: self.state.document.read().text.len(),
+            new_text: String::new(),
+        };
+        let result = self.state.reparse_applied(&edit);
+        self.notify(edit);
+        result
+    }
+
+    /// Makes [`Parser::run`] wait after an edit until no other arrives for
+    /// `quiet`, then reparse the edits received as one batch, so a burst of
+    /// keystrokes is reparsed and observed once. Zero, the default,
+    /// reparses after each edit.
+    pub fn set_debounce(&mut self, quiet: Duration) {
+        self.debounce = quiet;
+    }
+
+    /// Sets how many of the latest edits can be undone, forgetting older
+    /// ones beyond it. Zero turns history off.
+    pub fn set_history_depth(&mut self, depth: usize) {
+        let history = self.history.get_mut();
+        let excess = history.undo.len().saturating_sub(depth);
+        history.undo.drain(..excess);
+        history.depth = depth;
+    }
+
+    /// Undoes the latest edit not yet undone, reparses and calls the
+    /// observers. A batch is undone as a whole. Returns `None` if there is
+    /// nothing to undo.
+    pub fn undo(&mut self) -> Option<ParserResult> {
+        let inverse = self.history.get_mut().undo.pop_back()?;
+        let redo = self.state.apply(&inverse).ok()?;
+        self.history.get_mut().redo.push(redo);
+        let result = self.state.reparse_applied(&inverse);
+        self.notify(inverse);
+        Some(result)
+    }
+
+    /// Applies the edit last undone again, reparses and calls the
+    /// observers. Returns `None` if nothing was undone since the last edit.
+    pub fn redo(&mut self) -> Option<ParserResult> {
+        let edit = self.history.get_mut().redo.pop()?;
+        let inverse = self.state.apply(&edit).ok()?;
+        self.history.get_mut().push_undo(inverse);
+        let result = self.state.reparse_applied(&edit);
+        self.notify(edit);
+        Some(result)
+    }
+
+    /// See [`ParserState::collect_garbage`].
+    pub fn collect_garbage(&mut self) -> usize {
+        self.state.collect_garbage()
+    }
+
+    pub fn state(&self) -> &ParserState {
+        &self.state
+    }
+
+    /// Reparses after `edit`, as returned by [`Parser::receive_edits`] or
+    /// [`Parser::try_receive_edits`], and so already applied, on this
+    /// thread, and calls the observers. Edits from
+    /// [`Parser::drain_edits`] go in an [`Edit::Batch`]. Reuses what the
+    /// edit can't have changed, as [`ParserState::reparse`] does.
+    pub fn reparse_received(&mut self, edit: &Edit) -> ParserResult {
+        let result = self.state.reparse_applied(edit);
+        self.notify(edit.clone());
+        result
+    }
+
+    /// Parses the current text, with every edit received so far applied,
+    /// on this thread, and calls the observers.
+    pub fn parse_now(&mut self) -> ParserResult {
+        let result = self.state.parse();
+        self.notify(Edit::Batch(Vec::new()));
+        result
+    }
+
+    /// Applies edits and reparses until every sender is dropped, calling
+    /// the observers after each reparse. A reparse still running when the
+    /// next edit arrives is cancelled, and that edit applied and reparsed
+    /// in its place. Edits that don't fit the text are skipped, and bursts
+    /// can be batched with [`Parser::set_debounce`]. Returns the final
+    /// state.
+    pub fn run(self) -> ParserState {
+        self.run_until(&AtomicBool::new(false))
+    }
+
+    /// Runs [`Parser::run`] on a new thread.
+    pub fn spawn(self) -> ParserHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            std::thread::spawn(move || self.run_until(&stop))
+        };
+        ParserHandle { thread, stop }
+    }
+
+    fn run_until(mut self, stop: &AtomicBool) -> ParserState {
+        while !stop.load(Ordering::Relaxed) {
+            let edit = match self.receiver.recv_timeout(SHUTDOWN_POLL) {
+                Ok(edit) => edit,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if self.apply(&edit).is_ok() {
+                let edit = self.debounce(edit, stop);
+                self.reparse_settled(edit);
+            }
+        }
+        self.state
+    }
+
+    /// Applies the edits following `edit` until none arrives for the
+    /// debounce period, returning them with it as one. Stopping or losing
+    /// the senders ends the wait early; the edits applied so far still
+    /// need their reparse.
+    fn debounce(&self, edit: Edit, stop: &AtomicBool) -> Edit {
+        let mut applied = vec![edit];
+        let mut deadline = Instant::now() + self.debounce;
+        while !stop.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match self
+                .receiver
+                .recv_timeout((deadline - now).min(SHUTDOWN_POLL))
+            {
+                Ok(edit) => {
+                    if self.apply(&edit).is_ok() {
+                        applied.push(edit);
+                    }
+                    deadline = Instant::now() + self.debounce;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        match applied.len() {
+            1 => applied.remove(0),
+            _ => Edit::Batch(applied),
+        }
+    }
+
+    /// Reparses after `edit`, giving up to apply the next one if it
+    /// arrives first, until a parse finishes and the observers are called.
+    fn reparse_settled(&mut self, mut edit: Edit) {
+        let mut applied = vec![edit.clone()];
+        loop {
+            let next = Cell::new(None);
+            let interrupt = || match self.receiver.try_recv() {
+                Ok(edit) => {
+                    next.set(Some(edit));
+                    true
+                }
+                Err(_) => false,
+            };
+            self.state.reparse_interruptible(&edit, &interrupt);
+            match next.into_inner() {
+                Some(next) => {
+                    // An edit that doesn't fit is skipped, but the text still
+                    // needs the parse it cut short.
+                    edit = match self.apply(&next) {
+                        Ok(()) => {
+                            applied.push(next.clone());
+                            next
+                        }
+                        Err(_) => Edit::Batch(Vec::new()),
+                    };
+                }
+                None => break,
+            }
+        }
+        let edit = match applied.len() {
+            1 => applied.remove(0),
+            _ => Edit::Batch(applied),
+        };
+        self.notify(edit);
+    }
+
     pub fn receive_edits(&self) -> Result<Edit, ParserError> {
         let edit = self.receiver.recv().map_err(ParserError::LostConnection)?;
-        let text = &self.state.text;
-        match &edit {
-            Edit::Update { span, new_text } => {
-                self.is_valid_span(*span)?;
-                let mut text = text.write();
-                text.replace_range(span.start..span.end, new_text);
+        self.apply(&edit)?;
+        Ok(edit)
+    }
+
+    /// Like [`Parser::receive_edits`], but returns `None` rather than
+    /// waiting if no edit is queued.
+    pub fn try_receive_edits(&self) -> Result<Option<Edit>, ParserError> {
+        let edit = match self.receiver.try_recv() {
+            Ok(edit) => edit,
+            Err(TryRecvError::Empty) => return Ok(None),
+            Err(TryRecvError::Disconnected) => return Err(ParserError::LostConnection(RecvError)),
+        };
+        self.apply(&edit)?;
+        Ok(Some(edit))
+    }
+
+    /// Receives and applies every edit queued so far, without waiting, so
+    /// they can be reparsed at once as an [`Edit::Batch`]. Edits that don't
+    /// fit the text are skipped. Fails only if no edit was queued and every
+    /// sender is dropped.
+    pub fn drain_edits(&self) -> Result<Vec<Edit>, ParserError> {
+        let mut edits = Vec::new();
+        loop {
+            match self.receiver.try_recv() {
+                Ok(edit) => {
+                    if self.apply(&edit).is_ok() {
+                        edits.push(edit);
+                    }
+                }
+                Err(TryRecvError::Disconnected) if edits.is_empty() => {
+                    return Err(ParserError::LostConnection(RecvError));
+                }
+                Err(_) => return Ok(edits),
             }
-            Edit::Insert { position, new_text } => {
-                self.is_valid_position(*position)?;
-                let mut text = text.write();
-                text.insert_str(*position, new_text);
+        }
+    }
+
+    /// Applies a received edit, recording it so it can be undone.
+    fn apply(&self, edit: &Edit) -> Result<(), ParserError> {
+        let inverse = self.state.apply(edit)?;
+        let mut history = self.history.borrow_mut();
+        history.push_undo(inverse);
+        history.redo.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::grammar_dsl::*;
+    use crate::r;
+    use crate::utils::char_to_byte;
+    use crate::words::{EndOfInput, Identifier, Matcher, Whitespace};
+
+    fn grammar() -> Grammar {
+        Grammar::try_from(t(('a'..='z').times(..))).unwrap()
+    }
+
+    fn insert(position: usize, text: &str) -> Edit {
+        Edit::Insert {
+            position,
+            new_text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_spawn_until_disconnected() {
+        let (sender, receiver) = mpsc::channel();
+        let mut parser = Parser::new(grammar(), receiver);
+        let seen = Arc::new(AtomicUsize::new(0));
+        parser.set_observer({
+            let seen = seen.clone();
+            move |state| {
+                assert!(matches!(state.parse_full(), ParserResult::Complete(_)));
+                seen.fetch_add(1, Ordering::SeqCst);
             }
-            Edit::Delete { span } => {
-                self.is_valid_span(*span)?;
-                let mut text = text.write();
-                text.replace_range(span.start..span.end, "");
+        });
+        let handle = parser.spawn();
+        sender.send(insert(0, "ac")).unwrap();
+        sender.send(insert(1, "b")).unwrap();
+        // Out of bounds: skipped without notifying the observer.
+        sender.send(insert(9, "x")).unwrap();
+        sender
+            .send(Edit::Delete {
+                span: Span::new(0, 1),
+            })
+            .unwrap();
+        drop(sender);
+
+        let state = handle.join();
+        assert_eq!(seen.load(Ordering::SeqCst), 3);
+        assert_eq!(state.text(), "bc");
+        assert_eq!(state.arena().get_node(state.ast().green).width, 2);
+    }
+
+    #[test]
+    fn test_shutdown_with_sender_alive() {
+        let (sender, receiver) = mpsc::channel();
+        let handle = Parser::new(grammar(), receiver).spawn();
+        sender.send(insert(0, "abc")).unwrap();
+        // The edit may or may not have been applied before shutdown.
+        let text = handle.shutdown().text();
+        assert!(text.is_empty() || text == "abc");
+        drop(sender);
+    }
+
+    #[test]
+    fn test_debounce_bursts() {
+        let (sender, receiver) = mpsc::channel();
+        let mut parser = Parser::new(grammar(), receiver);
+        parser.set_debounce(Duration::from_millis(30));
+        let seen = Arc::new(AtomicUsize::new(0));
+        parser.set_observer({
+            let seen = seen.clone();
+            move |_state| {
+                seen.fetch_add(1, Ordering::SeqCst);
             }
+        });
+        let handle = parser.spawn();
+        for i in 0..100 {
+            sender.send(insert(i, "a")).unwrap();
         }
-        Ok(edit)
+        drop(sender);
+
+        let state = handle.join();
+        assert!(seen.load(Ordering::SeqCst) < 10);
+        assert_eq!(state.text(), "a".repeat(100));
+        let ParserResult::Complete(full) = state.parse_full() else {
+            panic!("the text should parse");
+        };
+        assert_eq!(state.ast().green, full.green);
     }
 
-    fn is_valid_span(&self, span: Span) -> Result<(), ParserError> {
-        let text = self.state.text.read();
-        if span.end <= text.len() {
-            Ok(())
-        } else {
-            Err(ParserError::SpanOutOfBounds {
-                expected: span,
-                actual: Span {
-                    start: 0,
-                    end: text.len(),
+    #[test]
+    fn test_run_on_current_thread() {
+        let (sender, receiver) = mpsc::channel();
+        sender.send(insert(0, "xy")).unwrap();
+        drop(sender);
+        assert_eq!(Parser::new(grammar(), receiver).run().text(), "xy");
+    }
+
+    #[test]
+    fn test_batch_reparses_once() {
+        let (sender, receiver) = mpsc::channel();
+        let mut parser = Parser::new(grammar(), receiver);
+        let seen = Arc::new(AtomicUsize::new(0));
+        parser.set_observer({
+            let seen = seen.clone();
+            move |state| {
+                assert_eq!(state.text(), "xabcd");
+                seen.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        // Position 3 is only in bounds after the first insertion.
+        let batch = Edit::Batch(vec![insert(0, "abc"), insert(3, "d"), insert(0, "x")]);
+        sender.send(batch).unwrap();
+        // The second edit doesn't fit, so the first isn't applied either.
+        sender
+            .send(Edit::Batch(vec![insert(0, "y"), insert(7, "z")]))
+            .unwrap();
+        drop(sender);
+
+        let state = parser.run();
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+        assert_eq!(state.arena().get_node(state.ast().green).width, 5);
+    }
+
+    #[test]
+    fn test_try_receive_and_drain() {
+        let (sender, receiver) = mpsc::channel();
+        let parser = Parser::new(grammar(), receiver);
+        assert!(matches!(parser.try_receive_edits(), Ok(None)));
+        assert!(matches!(parser.drain_edits().as_deref(), Ok([])));
+
+        sender.send(insert(0, "a")).unwrap();
+        assert_eq!(parser.try_receive_edits().unwrap(), Some(insert(0, "a")));
+        for (position, text) in [(1, "b"), (9, "x"), (2, "c")] {
+            sender.send(insert(position, text)).unwrap();
+        }
+        assert_eq!(
+            parser.drain_edits().unwrap(),
+            [insert(1, "b"), insert(2, "c")]
+        );
+        assert_eq!(parser.state().text(), "abc");
+
+        sender.send(insert(3, "d")).unwrap();
+        drop(sender);
+        assert_eq!(parser.drain_edits().unwrap(), [insert(3, "d")]);
+        assert!(matches!(
+            parser.drain_edits(),
+            Err(ParserError::LostConnection(_))
+        ));
+        assert!(matches!(
+            parser.try_receive_edits(),
+            Err(ParserError::LostConnection(_))
+        ));
+        assert_eq!(parser.state().text(), "abcd");
+    }
+
+    /// A rule per letter, so parsing a long word takes many steps.
+    fn letters() -> GrammarNode {
+        t('a'..='z') + opt(r!(letters))
+    }
+
+    #[test]
+    fn test_cancel_keeps_previous_tree() {
+        let grammar = Grammar::try_from(opt(r!(letters))).unwrap();
+        let mut state = ParserState::new(grammar).with_text("ab");
+        assert!(matches!(state.parse(), ParserResult::Complete(_)));
+        let green = state.ast().green;
+
+        state = state.with_text("a".repeat(2000));
+        state.cancellation_token().cancel();
+        assert!(matches!(
+            state.parse(),
+            ParserResult::Incomplete(ParserError::Cancelled)
+        ));
+        assert_eq!(state.ast().green, green);
+        assert!(!state.cancellation_token().is_cancelled());
+
+        assert!(matches!(state.parse(), ParserResult::Complete(_)));
+        assert_eq!(state.arena().get_node(state.ast().green).width, 2000);
+    }
+
+    #[test]
+    fn test_edit_cancels_reparse() {
+        let (sender, receiver) = mpsc::channel();
+        let grammar = Grammar::try_from(opt(r!(letters))).unwrap();
+        let mut parser = Parser::new(grammar, receiver);
+        let seen = Arc::new(AtomicUsize::new(0));
+        parser.set_observer({
+            let seen = seen.clone();
+            move |state| {
+                assert_eq!(state.text().len(), 5001);
+                seen.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        // The second edit is already queued when the first is reparsed, so
+        // that reparse is cancelled and only the second one finishes.
+        sender.send(insert(0, &"a".repeat(5000))).unwrap();
+        sender.send(insert(2500, "b")).unwrap();
+        drop(sender);
+
+        let state = parser.run();
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+        assert_eq!(state.text()[2500..2501], *"b");
+        assert_eq!(state.arena().get_node(state.ast().green).width, 5001);
+    }
+
+    #[test]
+    fn test_snapshot_survives_edits() {
+        let (sender, receiver) = mpsc::channel();
+        let mut parser = Parser::new(grammar(), receiver);
+        sender.send(insert(0, "xy")).unwrap();
+        let edit = parser.receive_edits().unwrap();
+        parser.state.reparse_applied(&edit);
+        let snapshot = parser.state().snapshot();
+        assert_eq!(snapshot.version(), 1);
+
+        sender.send(insert(0, "abc")).unwrap();
+        sender
+            .send(Edit::Delete {
+                span: Span::new(0, 1),
+            })
+            .unwrap();
+        sender.send(insert(2, "d")).unwrap();
+        drop(sender);
+        let state = parser.run();
+
+        assert_eq!(snapshot.text(), "xy");
+        assert_eq!(snapshot.arena().get_node(snapshot.ast().green).width, 2);
+        assert_eq!(state.version(), 4);
+        let live = state.snapshot();
+        assert_eq!((live.version(), live.text()), (4, "bcdxy"));
+        assert_eq!(live.arena().get_node(live.ast().green).width, 5);
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let (sender, receiver) = mpsc::channel();
+        let grammar = Grammar::try_from(opt(r!(letters))).unwrap();
+        let mut parser = Parser::new(grammar, receiver);
+        sender.send(insert(0, "hello")).unwrap();
+        let edit = parser.receive_edits().unwrap();
+        parser.state.reparse_applied(&edit);
+        let (text, green) = (parser.state().text(), parser.state().ast().green);
+
+        let edits = [
+            insert(5, "world"),
+            Edit::Delete {
+                span: Span::new(1, 3),
+            },
+            Edit::Batch(vec![
+                Edit::Update {
+                    span: Span::new(0, 2),
+                    new_text: "j".to_string(),
                 },
+                insert(0, "ab"),
+            ]),
+        ];
+        for edit in edits {
+            sender.send(edit).unwrap();
+            let edit = parser.receive_edits().unwrap();
+            parser.state.reparse_applied(&edit);
+        }
+        assert_eq!(parser.state().text(), "abjoworld");
+
+        for _ in 0..3 {
+            assert!(matches!(parser.undo(), Some(ParserResult::Complete(_))));
+        }
+        assert_eq!(parser.state().text(), text);
+        assert_eq!(parser.state().ast().green, green);
+
+        parser.redo();
+        assert_eq!(parser.state().text(), "helloworld");
+        // A new edit can't be redone past.
+        sender.send(insert(0, "x")).unwrap();
+        parser.receive_edits().unwrap();
+        assert!(parser.redo().is_none());
+
+        parser.set_history_depth(1);
+        assert!(parser.undo().is_some());
+        assert!(parser.undo().is_none());
+        assert_eq!(parser.state().text(), "helloworld");
+    }
+
+    #[test]
+    fn test_edit_inside_char() {
+        let (sender, receiver) = mpsc::channel();
+        let parser = Parser::new(grammar(), receiver);
+        sender.send(insert(0, "café")).unwrap();
+        parser.receive_edits().unwrap();
+
+        // `é` is bytes 3..5.
+        sender
+            .send(Edit::Update {
+                span: Span::new(4, 5),
+                new_text: "e".to_string(),
+            })
+            .unwrap();
+        assert!(matches!(
+            parser.receive_edits(),
+            Err(ParserError::NotCharBoundary { position: 4 })
+        ));
+        sender.send(insert(4, "x")).unwrap();
+        assert!(parser.receive_edits().is_err());
+        assert_eq!(parser.state().text(), "café");
+
+        let span = Span::new(char_to_byte("café", 3).unwrap(), "café".len());
+        sender
+            .send(Edit::Update {
+                span,
+                new_text: "e".to_string(),
             })
+            .unwrap();
+        parser.receive_edits().unwrap();
+        assert_eq!(parser.state().text(), "cafe");
+    }
+
+    #[test]
+    fn test_open_and_resync_text() {
+        let (_sender, receiver) = mpsc::channel();
+        let grammar = Grammar::try_from(opt(r!(letters))).unwrap();
+        let mut parser = Parser::new_with_text(grammar, receiver, "abc");
+        let ParserResult::Complete(full) = parser.state().parse_full() else {
+            panic!("\"abc\" should parse");
+        };
+        assert_eq!(parser.state().ast().green, full.green);
+        assert_eq!(parser.state().version(), 1);
+
+        let seen = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        parser.set_observer({
+            let seen = seen.clone();
+            move |state| seen.lock().push(state.text())
+        });
+        assert!(matches!(parser.set_text("wxyz"), ParserResult::Complete(_)));
+        assert_eq!(*seen.lock(), ["wxyz"]);
+        assert_eq!(parser.state().version(), 2);
+        let ast = parser.state().ast();
+        assert_ne!(ast.green, full.green);
+        assert_eq!(parser.state().arena().get_node(ast.green).width, 4);
+
+        parser.undo();
+        assert_eq!(*seen.lock(), ["wxyz", "abc"]);
+        assert_eq!(parser.state().ast().green, full.green);
+
+        let mut state = parser.state().clone();
+        assert!(matches!(
+            state.set_text("!"),
+            ParserResult::Recovered { .. }
+        ));
+        assert_eq!(state.version(), 4);
+    }
+
+    #[test]
+    fn test_map_span() {
+        let text = "one [two] three";
+        let marked = Span::new(4, 9);
+        let update = |start, end, text: &str| Edit::Update {
+            span: Span::new(start, end),
+            new_text: text.to_string(),
+        };
+        let delete = |start, end| Edit::Delete {
+            span: Span::new(start, end),
+        };
+        // Each edit with what the marked text is after it, if any.
+        let cases = [
+            (insert(0, "zero "), Bias::Left, Some("[two]")),
+            (insert(4, "<"), Bias::Left, Some("<[two]")),
+            (insert(4, "<"), Bias::Right, Some("[two]")),
+            (insert(9, ">"), Bias::Left, Some("[two]")),
+            (insert(9, ">"), Bias::Right, Some("[two]>")),
+            (insert(6, "-"), Bias::Left, Some("[t-wo]")),
+            (update(10, 15, "3"), Bias::Left, Some("[two]")),
+            (update(3, 5, "_("), Bias::Left, Some("two]")),
+            (update(8, 10, ")_"), Bias::Left, Some("[two")),
+            (update(5, 8, "2"), Bias::Left, Some("[2]")),
+            (delete(2, 12), Bias::Left, None),
+            (delete(4, 9), Bias::Right, None),
+            (
+                Edit::Batch(vec![insert(0, "zero "), delete(0, 10)]),
+                Bias::Left,
+                Some("two]"),
+            ),
+        ];
+        for (edit, bias, expected) in cases {
+            let mut rope = Rope::from(text);
+            edit.apply(&mut rope).unwrap();
+            let edited = rope.to_string();
+            assert_eq!(
+                edited.len() as isize - text.len() as isize,
+                edit.len_delta(),
+                "{edit:?}"
+            );
+            let mapped = edit.map_span(marked, bias);
+            assert_eq!(
+                mapped.map(|span| &edited[span.start..span.end]),
+                expected,
+                "{edit:?}"
+            );
         }
+
+        let empty = Span::new(6, 6);
+        assert_eq!(delete(5, 7).map_span(empty, Bias::Left), None);
+        assert_eq!(delete(6, 8).map_span(empty, Bias::Left), Some(empty));
+        let edits = [insert(0, "ab"), delete(0, 1)];
+        assert_eq!(
+            Edit::map_span_through(&edits, marked, Bias::Left),
+            Some(Span::new(5, 10))
+        );
     }
 
-    fn is_valid_position(&self, position: usize) -> Result<(), ParserError> {
-        let text = self.state.text.read();
-        if position <= text.len() {
-            Ok(())
-        } else {
-            Err(ParserError::PositionOutOfBounds {
-                expected: Span {
-                    start: 0,
-                    end: text.len(),
+    #[test]
+    fn test_line_col_after_edits() {
+        let (sender, receiver) = mpsc::channel();
+        let parser = Parser::new_with_text(grammar(), receiver, "ab\r\ncd\r\n");
+        let edits = [
+            // Adds lines, splitting the first.
+            insert(1, "\nxé\n"),
+            // Joins two lines.
+            Edit::Delete {
+                span: Span::new(3, 8),
+            },
+            Edit::Batch(vec![insert(0, "\r\n"), insert(6, "\n")]),
+        ];
+        for edit in edits {
+            sender.send(edit).unwrap();
+            parser.receive_edits().unwrap();
+            let state = parser.state();
+            let text = state.text();
+            for offset in 0..=text.len() + 1 {
+                let expected = crate::utils::line_col(&text, offset);
+                assert_eq!(state.offset_to_line_col(offset), expected, "{text:?}");
+                if text.is_char_boundary(offset) {
+                    assert_eq!(state.line_col_to_offset(expected), Some(offset));
+                }
+            }
+        }
+        let state = parser.state();
+        assert_eq!(state.text(), "\r\na\nx\n\ncd\r\n");
+        let at = |line, col| state.line_col_to_offset(LineCol { line, col });
+        // The `\r` is the last column of its line.
+        assert_eq!(at(1, 2), Some(1));
+        assert_eq!(at(1, 3), None);
+        assert_eq!(at(6, 1), Some(11));
+        assert_eq!(at(7, 1), None);
+        assert_eq!(at(0, 1), None);
+    }
+
+    fn program() -> GrammarNode {
+        r!(stmt) + opt(r!(program))
+    }
+
+    fn stmt() -> GrammarNode {
+        t(Identifier) + t('=') + t(('0'..='9').times(1..)) + t(';')
+    }
+
+    #[test]
+    fn test_change_observers() {
+        let (sender, receiver) = mpsc::channel();
+        let grammar = Grammar::try_from(r!(program))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let text = "a = 1;\nb = 2;\nc = 3;\n";
+        let mut parser = Parser::new_with_text(grammar, receiver, text);
+        let summaries = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        for _ in 0..2 {
+            let summaries = summaries.clone();
+            parser.add_observer(move |_state, summary| summaries.lock().push(summary.clone()));
+        }
+        let edit = Edit::Update {
+            span: Span::new(11, 12),
+            new_text: "22".to_string(),
+        };
+        sender.send(edit.clone()).unwrap();
+        drop(sender);
+        let state = parser.run();
+
+        let summaries = summaries.lock();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0], summaries[1]);
+        let summary = &summaries[0];
+        assert_eq!((&summary.edit, summary.version), (&edit, 2));
+        // Only the second statement was built again.
+        assert_eq!(summary.changed_nodes, [Span::new(7, 14)]);
+        assert_eq!(
+            &state.text()[summary.reparsed_span.start..summary.reparsed_span.end],
+            "b = 22;"
+        );
+    }
+
+    #[test]
+    fn test_stats_second_parse_shares_nodes() {
+        let grammar = Grammar::try_from(r!(program))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let mut state = ParserState::new(grammar)
+            .with_text("a = 1;\nb = 2;\nc = 3;\n")
+            .with_options(ParseOptions {
+                timings: true,
+                ..ParseOptions::default()
+            });
+        state.parse();
+        let first = state.stats();
+        assert!(first.nodes_allocated > 0);
+        assert!(first.duration.is_some());
+        assert!(first.steps > 0 && first.max_depth > 0);
+
+        state.parse();
+        let second = state.stats();
+        assert_eq!(second.nodes_allocated, 0);
+        assert_eq!(second.dedup_rate(), Some(1.0));
+        assert_eq!(second.steps, first.steps);
+        assert_eq!(second.memo_hit_rate(), None);
+        let total = state.total_stats();
+        assert_eq!(total.steps, first.steps * 2);
+        assert_eq!(
+            total.nodes_deduplicated,
+            first.nodes_deduplicated + second.nodes_deduplicated
+        );
+    }
+
+    #[test]
+    fn test_error_display() {
+        let mut state = ParserState::new(grammar()).with_text("abcD");
+        let ParserResult::Recovered { error, .. } = state.parse() else {
+            panic!("\"D\" doesn't match");
+        };
+        assert_eq!(error.to_string(), "syntax error at 1:4: expected EOF");
+        let errors = [
+            (
+                ParserError::SpanOutOfBounds {
+                    expected: Span::new(2, 9),
+                    actual: Span::new(0, 5),
+                },
+                "span 2..9 is out of bounds of the text, 0..5",
+            ),
+            (
+                ParserError::NotCharBoundary { position: 3 },
+                "position 3 is inside a char",
+            ),
+            (
+                ParserError::BudgetExceeded {
+                    steps: 10,
+                    elapsed: Duration::from_millis(5),
                 },
-                actual: position,
+                "parse gave up after 10 steps and 5ms",
+            ),
+        ];
+        for (error, display) in errors {
+            assert_eq!(error.to_string(), display);
+        }
+
+        let error: Box<dyn std::error::Error> = Box::new(ParserError::LostConnection(RecvError));
+        assert_eq!(error.to_string(), "every edit sender was dropped");
+        assert!(error.source().is_some());
+        assert_eq!(ParserError::Cancelled, ParserError::Cancelled,);
+    }
+
+    #[test]
+    fn test_parse_now() {
+        let (sender, receiver) = mpsc::channel();
+        let mut parser = Parser::new(Grammar::try_from(r!(letters)).unwrap(), receiver);
+        let seen = Arc::new(AtomicUsize::new(0));
+        parser.set_observer({
+            let seen = seen.clone();
+            move |_state| {
+                seen.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        sender.send(insert(0, "ab1")).unwrap();
+        parser.receive_edits().unwrap();
+        let result = parser.parse_now();
+        assert!(!result.is_complete());
+        let ParserResult::Recovered {
+            root, diagnostics, ..
+        } = result
+        else {
+            panic!("\"ab1\" should be recovered from");
+        };
+        assert_eq!(root.green, parser.state().ast().green);
+        assert_eq!(diagnostics, parser.state().diagnostics());
+        assert_eq!(seen.load(Ordering::Relaxed), 1);
+
+        let grammar = Grammar::try_from(r!(letters)).unwrap();
+        assert!(crate::parse(&grammar, "abc").is_complete());
+        let result = crate::parse(&grammar, "");
+        assert!(result.root().is_some());
+        assert!(matches!(
+            result.error(),
+            Some(ParserError::SyntaxError { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_public_reparse() {
+        let grammar = || {
+            Grammar::try_from(r!(program))
+                .unwrap()
+                .with_trivia(Whitespace::new())
+        };
+        let text = "alpha = 1;\nbeta = 22;\ngamma = 333;\n";
+        let edit = Edit::Insert {
+            position: text.len(),
+            new_text: "delta = 4;\n".to_string(),
+        };
+        let expected = format!("{text}delta = 4;\n");
+
+        let mut state = ParserState::new(grammar()).with_text(text);
+        assert!(state.parse().is_complete());
+        assert!(state.reparse(&edit).unwrap().is_complete());
+        assert_eq!(state.text(), expected);
+        assert!(state.reuse_stats().reused > 0);
+        let bad = Edit::Delete {
+            span: Span::new(0, 1000),
+        };
+        assert!(state.reparse(&bad).is_err());
+        assert_eq!(state.text(), expected);
+
+        let (sender, receiver) = mpsc::channel();
+        let mut parser = Parser::new_with_text(grammar(), receiver, text);
+        sender.send(edit).unwrap();
+        let edit = parser.receive_edits().unwrap();
+        assert!(parser.reparse_received(&edit).is_complete());
+        assert_eq!(parser.state().text(), expected);
+        assert!(parser.state().reuse_stats().reused > 0);
+    }
+
+    #[test]
+    fn test_feed_in_chunks() {
+        let grammar = || {
+            Grammar::try_from(r!(program))
+                .unwrap()
+                .with_trivia(Whitespace::new())
+        };
+        let text = "alpha = 1;\nbeta = 22;\n  gamma=333 ;\ndelta = 4444;\n";
+        let (_sender, receiver) = mpsc::channel();
+        let mut parser = Parser::new(grammar(), receiver);
+        for chunk in text.as_bytes().chunks(7) {
+            let chunk = std::str::from_utf8(chunk).unwrap();
+            assert!(matches!(
+                parser.feed(chunk),
+                ParserResult::Incomplete(ParserError::Pending)
+            ));
+        }
+        assert_eq!(parser.state().text(), text);
+        assert!(matches!(parser.finish(), ParserResult::Complete(_)));
+        assert!(parser.state().reuse_stats().reused > 0);
+
+        let ParserResult::Complete(full) = parser.state().parse_full() else {
+            panic!("{text:?} should parse");
+        };
+        assert_eq!(parser.state().ast().green, full.green);
+        let mut one_shot = ParserState::new(grammar()).with_text(text);
+        one_shot.parse();
+        assert_eq!(parser.state().dump_tree(), one_shot.dump_tree());
+
+        // An error before the end is found without waiting for the rest.
+        let (_sender, receiver) = mpsc::channel();
+        let mut parser = Parser::new(grammar(), receiver);
+        assert!(matches!(
+            parser.feed("a = 1;\n2 = b"),
+            ParserResult::Recovered { .. }
+        ));
+
+        let (_sender, receiver) = mpsc::channel();
+        let grammar = Grammar::try_from(r!(letters) + t(EndOfInput)).unwrap();
+        let mut parser = Parser::new(grammar, receiver);
+        assert!(matches!(
+            parser.feed("ab"),
+            ParserResult::Incomplete(ParserError::Pending)
+        ));
+        assert!(matches!(parser.finish(), ParserResult::Complete(_)));
+    }
+
+    #[test]
+    fn test_node_spans_and_text() {
+        let grammar = Grammar::try_from(r!(program))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let mut state = ParserState::new(grammar).with_text("ab = 12;\ncd = 3;");
+        assert!(state.parse().is_complete());
+        let root = state.ast().clone();
+        let arena = state.arena();
+        assert_eq!(root.span(arena), Span::new(0, 16));
+
+        let program = root.nth_child(arena, 0).unwrap();
+        let stmt = program.nth_child(arena, 0).unwrap();
+        assert_eq!(stmt.text(&state), "ab = 12;");
+        let number = stmt
+            .children(arena)
+            .filter(|child| child.green(arena).tag == Tag::Terminal)
+            .nth(2)
+            .unwrap();
+        assert_eq!(number.text(&state), "12");
+
+        let mut stack = vec![root.clone()];
+        while let Some(node) = stack.pop() {
+            let span = node.span(arena);
+            for child in node.children(arena) {
+                let inner = child.span(arena);
+                assert!(span.start <= inner.start && inner.end <= span.end);
+                stack.push(child);
+            }
+        }
+
+        // After an edit the node's span covers whatever is there now.
+        state
+            .apply(&Edit::Delete {
+                span: Span::new(2, 16),
             })
+            .unwrap();
+        assert_eq!(stmt.text(&state), "ab");
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut state =
+            ParserState::new(Grammar::try_from(r!(program)).unwrap()).with_text("a=1;b=2;");
+        assert!(state.parse().is_complete());
+        let snapshot = state.snapshot();
+        for i in 0..20 {
+            let edit = Edit::Insert {
+                position: 2,
+                new_text: i.to_string(),
+            };
+            state.apply(&edit).unwrap();
+            assert!(state.reparse_applied(&edit).is_complete());
+        }
+        let before = state.arena().len();
+        let tree = state.dump_tree();
+
+        let dropped = state.collect_garbage();
+        assert!(dropped > 0);
+        assert_eq!(state.arena().len(), before - dropped);
+        assert_eq!(state.dump_tree(), tree);
+        assert_eq!(snapshot.text(), "a=1;b=2;");
+        assert_eq!(snapshot.arena().len(), before);
+
+        // Reparsing still reuses what was kept.
+        let edit = Edit::Insert {
+            position: state.text().len(),
+            new_text: "c=3;".into(),
+        };
+        state.apply(&edit).unwrap();
+        assert!(state.reparse_applied(&edit).is_complete());
+        assert!(state.reuse_stats().reused > 0);
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let grammar = Grammar::try_from(r!(program)).unwrap();
+        let mut state = ParserState::new(grammar).with_text("a=1;b=2;");
+        assert!(state.parse().is_complete());
+        let usage = state.memory_usage();
+        assert_eq!(usage.text_bytes, 2 * 8);
+        assert!(usage.arena.nodes > 0 && usage.cache_bytes > 0);
+
+        let mut grown = usage;
+        for i in 0..20 {
+            let edit = Edit::Insert {
+                position: 2,
+                new_text: i.to_string(),
+            };
+            state.apply(&edit).unwrap();
+            assert!(state.reparse_applied(&edit).is_complete());
+            let usage = state.memory_usage();
+            assert!(usage.arena.total_bytes() > grown.arena.total_bytes());
+            grown = usage;
+        }
+        state.collect_garbage();
+        let collected = state.memory_usage();
+        assert!(collected.arena.total_bytes() < grown.arena.total_bytes());
+        assert_eq!(collected.text_bytes, grown.text_bytes);
+    }
+
+    #[test]
+    fn test_many_small_edits() {
+        let line = format!("{}\n", "x = 1;".repeat(170));
+        let text = line.repeat((1 << 20) / line.len());
+        let grammar = Grammar::try_from(r!(program)).unwrap();
+        let state = ParserState::new(grammar).with_text(text.as_str());
+        let started = std::time::Instant::now();
+        for i in 0..10_000 {
+            let edit = Edit::Insert {
+                position: (i * 97) % text.len() / line.len() * line.len(),
+                new_text: "y".to_string(),
+            };
+            state.apply(&edit).unwrap();
+            // Nothing joins the text until a parse or snapshot reads it.
+            assert!(state.document.read().joined.get().is_none());
         }
+        let elapsed = started.elapsed();
+        assert!(elapsed < Duration::from_secs(10), "{elapsed:?}");
+        let joined = state.current_text();
+        assert_eq!(joined.len(), text.len() + 10_000);
+        assert_eq!(*joined, state.document.read().text.to_string());
+        assert!(Arc::ptr_eq(&joined, &state.current_text()));
     }
 }
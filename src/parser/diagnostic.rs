@@ -0,0 +1,223 @@
+//! Syntax diagnostics read off the [`Tag::Error`] nodes of a recovered tree.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::{
+    grammar::{FirstSets, Grammar, GrammarError, RuleId},
+    tree::{GreenId, Tag, TreeAlloc},
+    utils::Span,
+};
+
+/// A syntax error the parser recovered from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The text skipped over, empty if something is missing.
+    pub span: Span,
+    /// Displays of the terminals that could have matched at the start of
+    /// `span`, sorted, or the label of the place if it had one.
+    pub expected: Vec<String>,
+    /// The skipped text, or the char after an empty span; `None` at the
+    /// end of input.
+    pub found: Option<String>,
+    /// The innermost rule the error occurred in.
+    pub rule: Option<RuleId>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.expected.as_slice() {
+            [] => write!(f, "unexpected ")?,
+            [one] => write!(f, "expected {one}, found ")?,
+            many => write!(f, "expected one of {}, found ", many.join(", "))?,
+        }
+        match &self.found {
+            Some(found) => write!(f, "{found:?}"),
+            None => write!(f, "end of input"),
+        }
+    }
+}
+
+/// Collects a diagnostic for each error node under `root`, in text order.
+/// Subtrees in `clean` are known to hold no errors and are skipped; the
+/// error-free subtrees found are added to it, so after a reparse only the
+/// nodes it built are visited.
+pub(crate) fn collect(
+    grammar: &Grammar,
+    arena: &TreeAlloc,
+    text: &str,
+    root: GreenId,
+    clean: &mut HashSet<GreenId>,
+) -> Vec<Diagnostic> {
+    let mut first_sets: Option<FirstSets> = None;
+    let mut diagnostics = Vec::new();
+    // Entries are (node, offset, enclosing rule, diagnostics before it),
+    // the last `None` until the node's children are done.
+    let mut stack = vec![(root, 0, None, None)];
+    while let Some((id, offset, rule, before)) = stack.pop() {
+        if let Some(before) = before {
+            if diagnostics.len() == before {
+                clean.insert(id);
+            }
+            continue;
+        }
+        if clean.contains(&id) {
+            continue;
+        }
+        let node = arena.get_node(id);
+        let span = Span::new_len(offset, node.width);
+        match &node.tag {
+            Tag::Error(error) => {
+                let expected = match error {
+                    GrammarError::TokenMismatch {
+                        label: Some(label), ..
+                    } => vec![label.to_string()],
+                    GrammarError::TokenMismatch { expected, .. } => vec![expected.clone()],
+                    GrammarError::RuleMismatch { expected } => first_sets
+                        .get_or_insert_with(|| grammar.first_sets())
+                        .first(*expected)
+                        .map(|first| first.iter().cloned().collect())
+                        .unwrap_or_default(),
+                    GrammarError::Placeholder => vec![],
+                };
+                let found = match &text[span.start..span.end] {
+                    "" => text[span.start..].chars().next().map(String::from),
+                    skipped => Some(skipped.to_string()),
+                };
+                diagnostics.push(Diagnostic {
+                    span,
+                    expected,
+                    found,
+                    rule,
+                });
+            }
+            Tag::Rule(idx) => {
+                stack.push((id, offset, rule, Some(diagnostics.len())));
+                let mut at = offset + node.width;
+                for &child in node.children.iter().rev() {
+                    at -= arena.get_node(child).width;
+                    stack.push((child, at, Some(*idx), None));
+                }
+            }
+            Tag::Terminal | Tag::Trivia => {
+                clean.insert(id);
+            }
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar_dsl::*;
+    use crate::parser::{ParserResult, ParserState};
+    use crate::r;
+    use crate::words::Matcher;
+
+    fn expr() -> GrammarNode {
+        r!(atom) + opt(t('+') + r!(expr))
+    }
+
+    fn atom() -> GrammarNode {
+        t(('0'..='9').times(1..)) | (t('(') + r!(expr) + t(')'))
+    }
+
+    #[test]
+    fn test_missing_close_paren() {
+        let grammar = Grammar::try_from(r!(expr)).unwrap().with_trivia(' ');
+        let mut state = ParserState::new(grammar).with_text("(1 + 2");
+        let ParserResult::Recovered { diagnostics, .. } = state.parse() else {
+            panic!("\"(1 + 2\" is missing a ')'");
+        };
+        assert_eq!(diagnostics, state.diagnostics());
+        let atom = state.grammar().rule_index("atom");
+        assert_eq!(
+            state.diagnostics(),
+            [Diagnostic {
+                span: Span::new(6, 6),
+                expected: vec!["')'".to_string()],
+                found: None,
+                rule: atom,
+            }]
+        );
+        assert_eq!(
+            state.diagnostics()[0].to_string(),
+            "expected ')', found end of input"
+        );
+
+        state = state.with_text("(1 + 2)");
+        assert!(matches!(state.parse(), ParserResult::Complete(_)));
+        assert!(state.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_expected_labels() {
+        // The same grammar with and without labels.
+        fn program(labeled: bool) -> GrammarNode {
+            let label = |label, node| match labeled {
+                true => expected(label, node),
+                false => node,
+            };
+            let expr = label("an expression", t('a'..='z') | (t('(') + r!(expr) + t(')')));
+            let stmt = (t("let") + t('a'..='z') + t('=') + expr.clone() + t(';'))
+                | (t('{') + many(expr.clone() + t(';')) + t('}'))
+                | (expr + t(';'));
+            some(label("a statement", stmt))
+        }
+
+        fn expr() -> GrammarNode {
+            t('a'..='z') | (t('(') + r!(expr) + t(')'))
+        }
+
+        let messages = |labeled| {
+            let grammar = Grammar::try_from(program(labeled))
+                .unwrap()
+                .with_trivia(' ');
+            ["}", "let x = ;", "{ (y; }"].map(|text| {
+                let mut state = ParserState::new(grammar.clone()).with_text(text);
+                let error = state.parse().error().unwrap().to_string();
+                let diagnostics: Vec<_> = (state.diagnostics().iter())
+                    .map(ToString::to_string)
+                    .collect();
+                (error, diagnostics)
+            })
+        };
+        assert_eq!(
+            messages(false),
+            [
+                (
+                    "syntax error at 1:1: expected one of \"let\", '(', '{', ['a'-'z']".into(),
+                    vec!["expected \"let\", found \"}\"".to_string()]
+                ),
+                (
+                    "syntax error at 1:9: expected one of '(', ['a'-'z']".into(),
+                    vec!["expected ['a'-'z'], found \";\"".to_string()]
+                ),
+                (
+                    "syntax error at 1:5: expected ')'".into(),
+                    vec!["expected ')', found \";\"".to_string()]
+                ),
+            ]
+        );
+        // Where nothing of a labeled node matched, its label stands for it;
+        // past its start, what's missing is still named.
+        assert_eq!(
+            messages(true),
+            [
+                (
+                    "syntax error at 1:1: expected a statement or an expression".into(),
+                    vec!["expected a statement, found \"}\"".to_string()]
+                ),
+                (
+                    "syntax error at 1:9: expected an expression".into(),
+                    vec!["expected an expression, found \";\"".to_string()]
+                ),
+                (
+                    "syntax error at 1:5: expected ')'".into(),
+                    vec!["expected ')', found \";\"".to_string()]
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,2004 @@
+//! The recursive-descent interpreter that evaluates a normalized grammar
+//! against text, or a token stream, and builds green nodes.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::{
+    grammar::{END_MARKER, Grammar, GrammarError, RuleId},
+    grammar_dsl::NormalizedNode,
+    tree::{CompactionMap, GreenId, Staging, Tag, TreeAlloc},
+    utils::Span,
+    words::{EndOfInput, Matcher, TokenInput},
+};
+
+/// A rule evaluation, keyed by rule index, position and verbatim mode.
+type RuleKey = (RuleId, usize, bool);
+
+/// Successful rule evaluations of one parse, kept so a reparse after an
+/// edit can reuse the ones the edit can't have changed.
+pub(crate) type ParseCache = HashMap<RuleKey, CacheEntry>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CacheEntry {
+    green: GreenId,
+    /// End of the text read while evaluating, including failed
+    /// alternatives; `usize::MAX` if some terminal couldn't say.
+    reach: usize,
+    /// Nodes in the subtree, the root included.
+    size: usize,
+    /// The farthest position a terminal failed at while evaluating.
+    failed: Option<usize>,
+    /// Whether it was carried over from the previous parse, rather than
+    /// found earlier in this one; see [`recover`].
+    carried: bool,
+}
+
+/// Whether the evaluation `key` stands for, which gave `entry`, read `at`.
+fn reads(&(_, pos, _): &RuleKey, entry: &CacheEntry, at: usize) -> bool {
+    (pos..=entry.reach).contains(&at)
+}
+
+/// Nodes a parse reused from the previous one, by position, with their
+/// subtree sizes.
+pub(crate) type Reused = HashMap<(usize, GreenId), usize>;
+
+/// How many nodes of a parse result were reused from the previous parse
+/// rather than built again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReuseStats {
+    pub nodes: usize,
+    pub reused: usize,
+}
+
+/// Settings for how a parse is run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Remember each rule evaluation of a parse, failures included, so no
+    /// rule is evaluated twice at the same position. This keeps heavy
+    /// backtracking linear at the cost of memory. Ignored for
+    /// left-recursive grammars and while recovering from errors.
+    pub memoize: bool,
+    /// Give up after evaluating this many grammar nodes.
+    pub max_steps: Option<u64>,
+    /// Give up after running this long. The clock is only read every
+    /// thousand steps or so.
+    pub max_duration: Option<Duration>,
+    /// Time parses, for [`ParseStats::duration`].
+    pub timings: bool,
+}
+
+/// Counts of what parsing did, for tuning grammars.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseStats {
+    /// Time spent, if [`ParseOptions::timings`] is set.
+    pub duration: Option<Duration>,
+    /// Grammar nodes evaluated.
+    pub steps: u64,
+    /// Tree nodes kept that were added to the arena. Nodes of choice
+    /// alternatives given up on, and others left out of the tree, never
+    /// reach it.
+    pub nodes_allocated: u64,
+    /// Tree nodes kept that the arena already held, and so shared.
+    pub nodes_deduplicated: u64,
+    /// Rule evaluations looked up in, and found in, the memo table of
+    /// [`ParseOptions::memoize`].
+    pub memo_lookups: u64,
+    pub memo_hits: u64,
+    /// Choice alternatives tried after an earlier one failed.
+    pub backtracks: u64,
+    /// The most grammar nodes waiting on a part of them at once.
+    pub max_depth: usize,
+}
+
+impl ParseStats {
+    /// The share of memo lookups that found a result, if there were any.
+    pub fn memo_hit_rate(&self) -> Option<f64> {
+        (self.memo_lookups > 0).then(|| self.memo_hits as f64 / self.memo_lookups as f64)
+    }
+
+    /// The share of tree nodes kept that the arena already held, if any
+    /// were kept.
+    pub fn dedup_rate(&self) -> Option<f64> {
+        let built = self.nodes_allocated + self.nodes_deduplicated;
+        (built > 0).then(|| self.nodes_deduplicated as f64 / built as f64)
+    }
+
+    /// Adds the counts of `other`, keeping the larger depth.
+    pub(crate) fn merge(&mut self, other: ParseStats) {
+        self.duration = match (self.duration, other.duration) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        };
+        self.steps += other.steps;
+        self.nodes_allocated += other.nodes_allocated;
+        self.nodes_deduplicated += other.nodes_deduplicated;
+        self.memo_lookups += other.memo_lookups;
+        self.memo_hits += other.memo_hits;
+        self.backtracks += other.backtracks;
+        self.max_depth = self.max_depth.max(other.max_depth);
+    }
+}
+
+/// How many steps a parse takes between checks of
+/// [`ParseOptions::max_duration`] and for interruption.
+const CHECK_STEPS: u64 = 1024;
+
+/// Why a parse gave up before finishing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stop {
+    /// It ran out of the budget in [`ParseOptions`].
+    Budget { steps: u64, elapsed: Duration },
+    /// It was interrupted.
+    Interrupted,
+}
+
+/// An edit in the terms a reparse needs: the replaced range `start..old_end`
+/// of the old text is `start..new_end` of the new one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Change {
+    pub(crate) start: usize,
+    pub(crate) old_end: usize,
+    pub(crate) new_end: usize,
+}
+
+/// Keeps the entries of `cache`, built for the text before `change`, that
+/// still hold for `text`, the text after it. An entry before the change
+/// holds if nothing it read was edited. One after the change holds if its
+/// position, and the char before it for line anchors, are past the edit;
+/// everything it could read from there on is unchanged, only shifted.
+pub(crate) fn carry_over(cache: &ParseCache, text: &str, change: Change) -> ParseCache {
+    let shift = |n: usize| n + change.new_end - change.old_end;
+    cache
+        .iter()
+        .filter_map(|(&(idx, pos, verbatim), &entry)| {
+            let carried = true;
+            if entry.reach <= change.start {
+                return Some(((idx, pos, verbatim), CacheEntry { carried, ..entry }));
+            }
+            if pos < change.old_end {
+                return None;
+            }
+            let pos = shift(pos);
+            let before = text.get(..pos)?.chars().next_back()?;
+            if pos - before.len_utf8() < change.new_end {
+                return None;
+            }
+            let reach = match entry.reach {
+                usize::MAX => usize::MAX,
+                reach => shift(reach),
+            };
+            let failed = entry.failed.map(shift);
+            let entry = CacheEntry {
+                reach,
+                failed,
+                carried,
+                ..entry
+            };
+            Some(((idx, pos, verbatim), entry))
+        })
+        .collect()
+}
+
+/// The trees the entries of `cache` hold on to.
+pub(crate) fn cache_roots(cache: &ParseCache) -> impl Iterator<Item = GreenId> + '_ {
+    cache.values().map(|entry| entry.green)
+}
+
+/// Roughly the memory `cache` takes.
+pub(crate) fn cache_bytes(cache: &ParseCache) -> usize {
+    cache.capacity() * size_of::<(RuleKey, CacheEntry)>()
+}
+
+/// `cache` with its trees moved to where `map` put them. Entries whose tree
+/// wasn't kept are dropped.
+pub(crate) fn remap_cache(cache: &ParseCache, map: &CompactionMap) -> ParseCache {
+    cache
+        .iter()
+        .filter_map(|(&key, &entry)| {
+            let green = map.get(entry.green)?;
+            Some((key, CacheEntry { green, ..entry }))
+        })
+        .collect()
+}
+
+/// Recovery passes [`recover`] makes before settling for the tree it has.
+const MAX_RECOVERIES: usize = 32;
+
+/// Parses `text`, which doesn't match the grammar, into a tree anyway, with
+/// [`Tag::Error`] nodes over the text that didn't fit. Fails if a pass
+/// runs out of the budget in `options` or is interrupted. Also returns
+/// what the passes did together.
+///
+/// Each pass is a full parse where a sequence that has consumed input and
+/// then fails at one of the recovery points skips ahead to where the
+/// failing element, or the one after it, matches again. Text START leaves
+/// over is skipped up to where START matches again. A pass that gets stuck
+/// at a new position adds it as a recovery point for the next.
+///
+/// The passes share `cache`, which starts with the results of the parse
+/// that failed, `reused` being what that parse reused of the one before.
+/// A result that read none of the recovery points is the same in every
+/// pass, so only those reading a new one are dropped from it. Once done,
+/// `cache` keeps only results that read no recovery point, which a parse
+/// that doesn't recover would also get.
+pub(crate) fn recover(
+    grammar: &Grammar,
+    arena: &TreeAlloc,
+    text: &str,
+    cache: &mut ParseCache,
+    mut reused: Reused,
+    options: ParseOptions,
+    interrupt: &dyn Fn() -> bool,
+) -> (Result<Recovered, Stop>, ParseStats) {
+    let mut stats = ParseStats::default();
+    let mut recover_at = HashSet::new();
+    loop {
+        let mut engine = Engine::new(grammar, arena, text)
+            .reusing(std::mem::take(cache))
+            .with_options(options)
+            .interruptible(interrupt);
+        engine.reused = reused;
+        engine.recover_at = recover_at.clone();
+        let (root, stuck) = engine.parse_recovering();
+        let recovered = match stuck {
+            _ if engine.stopped.is_some() => None,
+            Some(pos) if recover_at.len() < MAX_RECOVERIES && recover_at.insert(pos) => {
+                stats.merge(engine.stats());
+                reused = engine.take_reused();
+                *cache = engine.into_cache();
+                cache.retain(|key, entry| !reads(key, entry, pos));
+                continue;
+            }
+            _ => {
+                let root = engine.promote(root);
+                Some(Recovered {
+                    root,
+                    reuse_stats: engine.reuse_stats(root),
+                    rebuilt: engine.rebuilt(root),
+                })
+            }
+        };
+        stats.merge(engine.stats());
+        let stopped = engine.stopped;
+        *cache = engine.into_cache();
+        cache.retain(|key, entry| !recover_at.iter().any(|&at| reads(key, entry, at)));
+        let recovered =
+            recovered.ok_or_else(|| stopped.expect("only a stopped pass recovers nothing"));
+        return (recovered, stats);
+    }
+}
+
+/// The tree [`recover`] settled on, with what it reused of the parse
+/// before the one that failed.
+pub(crate) struct Recovered {
+    pub(crate) root: GreenId,
+    pub(crate) reuse_stats: ReuseStats,
+    pub(crate) rebuilt: Vec<Span>,
+}
+
+/// The error recorded for text skipped in place of `node`.
+fn expected(node: &NormalizedNode) -> GrammarError {
+    use NormalizedNode as N;
+    match node {
+        N::Terminal(matcher) => GrammarError::TokenMismatch {
+            expected: matcher.display(),
+            label: None,
+        },
+        N::Reference(idx) => GrammarError::RuleMismatch { expected: *idx },
+        N::Sequence(nodes) | N::Choice(nodes) => {
+            nodes.first().map_or(GrammarError::Placeholder, expected)
+        }
+        N::Verbatim(inner) => expected(inner),
+        N::Placeholder => GrammarError::Placeholder,
+    }
+}
+
+/// A rule being evaluated, linked to the one it was entered from.
+struct Frame {
+    rule: RuleId,
+    /// The label of the innermost labeled rule among this and the ones
+    /// around it, with the position it was entered at.
+    label: Option<(&'static str, usize)>,
+    outer: Option<Rc<Frame>>,
+}
+
+impl Drop for Frame {
+    /// Unlinks the frames this was the last holder of one by one, so
+    /// deeply nested rules don't drop recursively.
+    fn drop(&mut self) {
+        let mut outer = self.outer.take();
+        while let Some(frame) = outer {
+            outer = match Rc::try_unwrap(frame) {
+                Ok(mut frame) => frame.outer.take(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+/// The farthest position a parse tried a terminal at without a match,
+/// with the terminals tried there and the rules they were tried in.
+/// Terminals tried at the start of a labeled rule are counted as its
+/// label instead.
+#[derive(Default)]
+pub(crate) struct Failure<'a> {
+    pub(crate) pos: usize,
+    tried: Vec<&'a dyn Matcher>,
+    labels: Vec<&'static str>,
+    rules: Option<Rc<Frame>>,
+}
+
+impl<'a> Failure<'a> {
+    fn at(pos: usize) -> Self {
+        Failure {
+            pos,
+            ..Failure::default()
+        }
+    }
+
+    /// Records `matcher` failing at `pos`, in the rules `rules`, or the
+    /// labeled rule it was the start of failing if `label` is given. Only
+    /// the first failure at the farthest position keeps its rules; the
+    /// terminals and labels of all of them are merged.
+    fn record(
+        &mut self,
+        pos: usize,
+        matcher: &'a dyn Matcher,
+        label: Option<&'static str>,
+        rules: &Option<Rc<Frame>>,
+    ) {
+        if pos > self.pos || (self.tried.is_empty() && self.labels.is_empty()) {
+            self.pos = pos;
+            self.tried.clear();
+            self.labels.clear();
+            self.rules = rules.clone();
+        }
+        if pos != self.pos {
+            return;
+        }
+        match label {
+            Some(label) if !self.labels.contains(&label) => self.labels.push(label),
+            Some(_) => {}
+            None => self.tried.push(matcher),
+        }
+    }
+
+    /// Displays of the terminals tried outside labeled rules, sorted and
+    /// without duplicates.
+    pub(crate) fn expected(&self) -> Vec<String> {
+        let displays: BTreeSet<String> = self.tried.iter().map(|m| m.display()).collect();
+        displays.into_iter().collect()
+    }
+
+    /// Labels of the rules that failed at their start, in the order they
+    /// were tried.
+    pub(crate) fn labels(&self) -> Vec<&'static str> {
+        self.labels.clone()
+    }
+
+    /// Indices of the rules being evaluated, outermost first.
+    pub(crate) fn rules(&self) -> Vec<RuleId> {
+        let mut rules = Vec::new();
+        let mut frame = self.rules.as_deref();
+        while let Some(Frame { rule, outer, .. }) = frame {
+            rules.push(*rule);
+            frame = outer.as_deref();
+        }
+        rules.reverse();
+        rules
+    }
+}
+
+/// A node whose evaluation waits on one of its parts.
+enum Task<'a> {
+    /// Element `i` of a sequence that started at `pos` is being evaluated
+    /// at `end`, after the trivia at `trivia` in the list that started at
+    /// `before`. On a `retry` the element is evaluated again after an
+    /// error was skipped.
+    Sequence {
+        parts: &'a [NormalizedNode],
+        i: usize,
+        pos: usize,
+        end: usize,
+        before: usize,
+        trivia: Range<usize>,
+        mark: usize,
+        verbatim: bool,
+        retry: bool,
+    },
+    /// Alternative `i` is being evaluated, on nodes staged from `mark`.
+    Choice {
+        alternatives: &'a [NormalizedNode],
+        i: usize,
+        pos: usize,
+        mark: usize,
+        verbatim: bool,
+    },
+    Rule(RuleTask<'a>),
+}
+
+/// A rule being evaluated, with what to restore once it is done.
+struct RuleTask<'a> {
+    node: &'a NormalizedNode,
+    pos: usize,
+    key: RuleKey,
+    token: bool,
+    memoize: bool,
+    outer_reach: usize,
+    outer_failed: Option<usize>,
+    outer_rules: Option<Rc<Frame>>,
+}
+
+/// The best result so far of a left-recursive rule being grown at a
+/// position, which its left-recursive calls there return instead of
+/// evaluating it again.
+#[derive(Clone, Copy)]
+struct Seed {
+    green: Option<GreenId>,
+    /// Whether the current evaluation returned the seed.
+    used: bool,
+}
+
+/// What a resumed task does next.
+enum Flow<'a> {
+    Eval(&'a NormalizedNode, usize, bool),
+    Done(Option<usize>),
+}
+
+/// What an [`Engine`] reads: text, the tokens of an external lexer, which
+/// only token matchers such as [`TokenKind`](crate::words::TokenKind)
+/// match, or binary input. Positions and widths count bytes of text and
+/// binary input and tokens of a stream.
+#[derive(Clone, Copy)]
+pub(crate) enum Input<'a> {
+    Text(&'a str),
+    Tokens(&'a dyn TokenInput),
+    #[cfg(feature = "byte-input")]
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Input<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Input::Text(text) => text.len(),
+            Input::Tokens(tokens) => tokens.len(),
+            #[cfg(feature = "byte-input")]
+            Input::Bytes(bytes) => bytes.len(),
+        }
+    }
+
+    fn try_match(&self, matcher: &dyn Matcher, at: usize) -> Option<usize> {
+        match self {
+            Input::Text(text) => matcher.try_match(text, at),
+            Input::Tokens(tokens) => matcher.try_match_tokens(*tokens, at),
+            #[cfg(feature = "byte-input")]
+            Input::Bytes(bytes) => matcher.try_match_bytes(bytes, at),
+        }
+    }
+
+    fn lookahead_end(&self, matcher: &dyn Matcher, at: usize) -> Option<usize> {
+        match self {
+            Input::Text(text) => matcher.lookahead_end(text, at),
+            _ => None,
+        }
+    }
+
+    /// Whether a match can start or end at `at`.
+    fn is_boundary(&self, at: usize) -> bool {
+        match self {
+            Input::Text(text) => text.is_char_boundary(at),
+            _ => at <= self.len(),
+        }
+    }
+
+    /// The text of `span`, which a leaf over it holds.
+    fn text(&self, span: Range<usize>) -> Option<&'a str> {
+        match self {
+            Input::Text(text) => Some(&text[span]),
+            _ => None,
+        }
+    }
+}
+
+/// One parse of `text`. Choices are ordered: the first alternative that
+/// matches wins, and a failing alternative's children are discarded.
+///
+/// Nodes are built in a [`Staging`] over the arena, and only the tree kept
+/// is promoted into it.
+pub(crate) struct Engine<'a> {
+    grammar: &'a Grammar,
+    staging: Staging<'a>,
+    /// Staged nodes below this may be held by `cache` or `seeds`, so a
+    /// failing alternative doesn't discard them.
+    pinned: usize,
+    input: Input<'a>,
+    /// Rules entered but not yet finished, by position and verbatim mode.
+    /// Re-entering one without consuming input would recurse forever, so
+    /// that attempt fails instead, unless the rule is being grown.
+    active: HashSet<RuleKey>,
+    /// Which rules are left-recursive.
+    left_recursive: Vec<bool>,
+    /// Left-recursive rules being grown, see [`Engine::exit_rule`].
+    seeds: HashMap<RuleKey, Seed>,
+    /// Results carried over from the previous parse.
+    reusable: ParseCache,
+    /// Results of this parse.
+    cache: ParseCache,
+    /// End of the text read by the innermost rule being evaluated.
+    reach: usize,
+    /// The farthest position a terminal failed at in the innermost rule
+    /// being evaluated.
+    failed_at: Option<usize>,
+    /// The farthest failure inside reused results, which `failure` misses.
+    hidden: Option<usize>,
+    /// For [`Engine::reuse_stats`].
+    reused: Reused,
+    /// Subtree sizes of the rule nodes produced or reused so far.
+    sizes: HashMap<GreenId, usize>,
+    /// Rules being evaluated, innermost first.
+    rules: Option<Rc<Frame>>,
+    failure: Failure<'a>,
+    /// Positions where a failing sequence recovers; see [`recover`].
+    recover_at: HashSet<usize>,
+    /// Set while looking for where to resume after an error, which must
+    /// not recover itself.
+    probing: bool,
+    options: ParseOptions,
+    /// Rule evaluations of this parse that failed, with their reach and
+    /// farthest failure, when memoizing.
+    failed: HashMap<RuleKey, (usize, Option<usize>)>,
+    /// Nodes evaluated so far.
+    steps: u64,
+    /// Counts of what the parse did, other than `steps`.
+    stats: ParseStats,
+    started: Instant,
+    /// Asked every so often whether to give up.
+    interrupt: Option<&'a dyn Fn() -> bool>,
+    stopped: Option<Stop>,
+    /// Whether more text may follow, see [`Engine::open_ended`].
+    open_ended: bool,
+    /// Set once a terminal needed text past the end of an open-ended
+    /// input.
+    pending: bool,
+}
+
+impl<'a> Engine<'a> {
+    pub(crate) fn new(grammar: &'a Grammar, arena: &'a TreeAlloc, text: &'a str) -> Self {
+        Engine::over(grammar, arena, Input::Text(text))
+    }
+
+    /// A parse of `input`, which [`Engine::new`] is for text.
+    pub(crate) fn over(grammar: &'a Grammar, arena: &'a TreeAlloc, input: Input<'a>) -> Self {
+        let mut left_recursive = vec![false; grammar.len()];
+        for idx in grammar.left_recursive_rules() {
+            left_recursive[idx.index()] = true;
+        }
+        Engine {
+            grammar,
+            staging: Staging::new(arena),
+            pinned: 0,
+            input,
+            active: HashSet::new(),
+            left_recursive,
+            seeds: HashMap::new(),
+            reusable: ParseCache::new(),
+            cache: ParseCache::new(),
+            reach: 0,
+            failed_at: None,
+            hidden: None,
+            reused: Reused::new(),
+            sizes: HashMap::new(),
+            rules: None,
+            failure: Failure::default(),
+            recover_at: HashSet::new(),
+            probing: false,
+            options: ParseOptions::default(),
+            failed: HashMap::new(),
+            steps: 0,
+            stats: ParseStats::default(),
+            started: Instant::now(),
+            interrupt: None,
+            stopped: None,
+            open_ended: false,
+            pending: false,
+        }
+    }
+
+    /// Reuses the results in `cache`, which must hold for this text; see
+    /// [`carry_over`].
+    pub(crate) fn reusing(mut self, cache: ParseCache) -> Self {
+        self.reusable = cache;
+        self
+    }
+
+    /// Runs with `options`. Memoization must only be asked for if the
+    /// grammar isn't left-recursive.
+    pub(crate) fn with_options(mut self, options: ParseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Gives up once `interrupt` returns `true`. It's asked every thousand
+    /// steps or so.
+    pub(crate) fn interruptible(mut self, interrupt: &'a dyn Fn() -> bool) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Parses text that more may be appended to, if `open_ended`. A terminal or trivia
+    /// that reads past the end, or can't say how far it reads, fails as
+    /// pending, and the parse never matches all the text; whether it hit
+    /// the end is told by [`Engine::pending`]. Results that read past the
+    /// end aren't carried over an append, so they're evaluated again once
+    /// the text is longer.
+    pub(crate) fn open_ended(mut self, open_ended: bool) -> Self {
+        self.open_ended = open_ended;
+        self
+    }
+
+    /// Whether a parse of open-ended text got to the end, so it might
+    /// still match once more text arrives.
+    pub(crate) fn pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Why the parse gave up, if it did.
+    pub(crate) fn stopped(&self) -> Option<Stop> {
+        self.stopped
+    }
+
+    /// What the parse reused of the previous one, for [`recover`] to go on
+    /// from.
+    pub(crate) fn take_reused(&mut self) -> Reused {
+        std::mem::take(&mut self.reused)
+    }
+
+    /// Whether reused results may have failed at `pos` or past it, so the
+    /// failure the parse recorded may not be the farthest, or miss some of
+    /// what was tried there.
+    pub(crate) fn hides_failure(&self, pos: usize) -> bool {
+        self.hidden.is_some_and(|hidden| hidden >= pos)
+    }
+
+    /// What the parse did so far.
+    pub(crate) fn stats(&self) -> ParseStats {
+        ParseStats {
+            duration: self.options.timings.then(|| self.started.elapsed()),
+            steps: self.steps,
+            ..self.stats
+        }
+    }
+
+    /// Stages a node.
+    fn alloc(&mut self, tag: Tag, children: Vec<GreenId>, width: usize) -> GreenId {
+        self.staging.alloc(tag, children, width)
+    }
+
+    /// Stages a leaf holding the text of `span`, like [`Engine::alloc`].
+    /// Leaves over tokens or bytes hold only their width.
+    fn token(&mut self, tag: Tag, span: Range<usize>) -> GreenId {
+        match self.input.text(span.clone()) {
+            Some(text) => self.staging.alloc_token(tag, text),
+            None => self.staging.alloc(tag, vec![], span.len()),
+        }
+    }
+
+    /// Discards the nodes staged since `mark`, unless pinned.
+    fn discard(&mut self, mark: usize) {
+        self.staging.truncate(mark.max(self.pinned));
+    }
+
+    /// Promotes the tree under `root` into the arena, counting whether the
+    /// arena already held its nodes, and returns its arena id.
+    fn promote(&mut self, root: GreenId) -> GreenId {
+        let root = self.staging.promote(root);
+        self.stats.nodes_allocated = self.staging.allocated;
+        self.stats.nodes_deduplicated = self.staging.deduplicated;
+        root
+    }
+
+    /// Counts a step, checking it against the budget and for interruption.
+    /// Once the parse has stopped every step fails, so it unwinds without
+    /// matching.
+    fn step(&mut self) -> bool {
+        self.steps += 1;
+        if self.stopped.is_some() {
+            return false;
+        }
+        let options = self.options;
+        let check = self.steps.is_multiple_of(CHECK_STEPS);
+        let over_steps = options.max_steps.is_some_and(|max| self.steps > max);
+        let over_time = check
+            && options
+                .max_duration
+                .is_some_and(|max| self.started.elapsed() > max);
+        if over_steps || over_time {
+            self.stopped = Some(Stop::Budget {
+                steps: self.steps,
+                elapsed: self.started.elapsed(),
+            });
+        } else if check && self.interrupt.is_some_and(|interrupt| interrupt()) {
+            self.stopped = Some(Stop::Interrupted);
+        }
+        self.stopped.is_none()
+    }
+
+    /// Parses the START rule, with trivia on both sides. Returns the root
+    /// node, or the farthest failure if START doesn't match all the text.
+    pub(crate) fn parse_full(&mut self) -> Result<GreenId, Failure<'a>> {
+        let grammar = self.grammar;
+        let start = grammar.rule(grammar.start()).ok_or_else(Failure::default)?;
+        self.enter(RuleId::START, 0);
+        let mut children = Vec::new();
+        let pos = self.skip_trivia(0, false, &mut children);
+        let Some(pos) = self.eval(&start.node, pos, false, &mut children) else {
+            return Err(std::mem::take(&mut self.failure));
+        };
+        let pos = self.skip_trivia(pos, false, &mut children);
+        if pos < self.input.len() || self.past_end(&EndOfInput, pos) {
+            self.fail(pos, &EndOfInput);
+            return Err(std::mem::take(&mut self.failure));
+        }
+        let root = self.alloc(Tag::Rule(RuleId::START), children, pos);
+        Ok(self.promote(root))
+    }
+
+    /// Parses the START rule like [`Engine::parse_full`], but always builds
+    /// a root: text START can't match becomes [`Tag::Error`] nodes. Also
+    /// returns the farthest failure that stopped START, unless it already
+    /// is a recovery point. The root is left staged, for [`recover`] to
+    /// promote once it settles on it.
+    fn parse_recovering(&mut self) -> (GreenId, Option<usize>) {
+        let len = self.input.len();
+        let Some(start) = self.grammar.rule(RuleId::START) else {
+            let error = Tag::Error(GrammarError::RuleMismatch {
+                expected: RuleId::START,
+            });
+            return (self.token(error, 0..len), None);
+        };
+        self.enter(RuleId::START, 0);
+        let mut children = Vec::new();
+        let mut stuck = None;
+        let mut pos = self.skip_trivia(0, false, &mut children);
+        loop {
+            self.failure = Failure::at(pos);
+            self.hidden = None;
+            let error = match self.eval(&start.node, pos, false, &mut children) {
+                Some(end) => {
+                    pos = self.skip_trivia(end, false, &mut children);
+                    GrammarError::TokenMismatch {
+                        expected: END_MARKER.to_string(),
+                        label: None,
+                    }
+                }
+                None => self.skipped(&start.node, pos),
+            };
+            if pos >= len {
+                break;
+            }
+            self.fail(pos, &EndOfInput);
+            let farthest = self.failure.pos.max(self.hidden.unwrap_or(0));
+            if stuck.is_none() && !self.recover_at.contains(&farthest) {
+                stuck = Some(farthest);
+            }
+            let input = self.input;
+            let resume = (pos + 1..len)
+                .filter(|&at| input.is_boundary(at))
+                .find(|&at| {
+                    self.probe(&start.node, at, false)
+                        .is_some_and(|end| end > at)
+                })
+                .unwrap_or(len);
+            children.push(self.token(Tag::Error(error), pos..resume));
+            pos = resume;
+        }
+        (self.alloc(Tag::Rule(RuleId::START), children, len), stuck)
+    }
+
+    /// The results of this parse, plus those it was given to reuse, for the
+    /// next reparse. After a parse that kept a tree, only results in it are
+    /// kept; after one that didn't, all are, promoted into the arena.
+    pub(crate) fn into_cache(mut self) -> ParseCache {
+        let committed = self.staging.allocated + self.staging.deduplicated > 0;
+        if !committed {
+            // In the order they were built, so ids don't depend on the
+            // cache's.
+            let mut staged: Vec<_> = (self.cache.values())
+                .map(|entry| entry.green)
+                .filter(|&green| Staging::is_staged(green))
+                .collect();
+            staged.sort_unstable();
+            for green in staged {
+                self.staging.promote(green);
+            }
+        }
+        let mut cache = std::mem::take(&mut self.cache);
+        cache.retain(|_, entry| match self.staging.promoted(entry.green) {
+            Some(green) => {
+                entry.green = green;
+                true
+            }
+            None => false,
+        });
+        cache.extend(self.reusable);
+        cache
+    }
+
+    /// Counts the nodes under `root`, and how many of them sit in subtrees
+    /// that were reused rather than evaluated.
+    pub(crate) fn reuse_stats(&self, root: GreenId) -> ReuseStats {
+        let mut stats = ReuseStats::default();
+        let mut stack = vec![(root, 0)];
+        while let Some((id, pos)) = stack.pop() {
+            if let Some(&size) = self.reused.get(&(pos, id)) {
+                stats.nodes += size;
+                stats.reused += size;
+                continue;
+            }
+            stats.nodes += 1;
+            let mut at = pos;
+            for &child in &self.staging.get_node(id).children {
+                stack.push((child, at));
+                at += self.staging.get_node(child).width;
+            }
+        }
+        stats
+    }
+
+    /// The spans of the largest subtrees under `root` that this parse built
+    /// entirely, holding nothing reused, in text order. Trivia between
+    /// reused subtrees is left out, being built again only because the
+    /// nodes around it were.
+    pub(crate) fn rebuilt(&self, root: GreenId) -> Vec<Span> {
+        let mut spans = Vec::new();
+        // Whether each node finished so far is entirely new, children
+        // before their parent.
+        let mut fresh = Vec::new();
+        let mut stack = vec![(root, 0, false)];
+        while let Some((id, pos, expanded)) = stack.pop() {
+            let node = self.staging.get_node(id);
+            if !expanded {
+                if self.reused.contains_key(&(pos, id)) {
+                    fresh.push(false);
+                    continue;
+                }
+                stack.push((id, pos, true));
+                let mut at = pos + node.width;
+                for &child in node.children.iter().rev() {
+                    at -= self.staging.get_node(child).width;
+                    stack.push((child, at, false));
+                }
+                continue;
+            }
+            let children = fresh.split_off(fresh.len() - node.children.len());
+            let all_fresh = children.iter().all(|&fresh| fresh);
+            if !all_fresh {
+                let mut at = pos;
+                for (&child, fresh) in node.children.iter().zip(children) {
+                    let child = self.staging.get_node(child);
+                    if fresh && child.tag != Tag::Trivia {
+                        spans.push(Span::new_len(at, child.width));
+                    }
+                    at += child.width;
+                }
+            } else if stack.is_empty() {
+                spans.push(Span::new_len(pos, node.width));
+            }
+            fresh.push(all_fresh);
+        }
+        spans.sort_by_key(|span| span.start);
+        spans
+    }
+
+    /// Records that a terminal was run at `pos`.
+    fn read(&mut self, matcher: &dyn Matcher, pos: usize) {
+        let end = self.input.lookahead_end(matcher, pos).unwrap_or(usize::MAX);
+        self.reach = self.reach.max(end);
+    }
+
+    /// Whether `matcher` run at `pos` of open-ended text reads past its
+    /// end, marking the parse pending if so.
+    fn past_end(&mut self, matcher: &dyn Matcher, pos: usize) -> bool {
+        let past = self.open_ended
+            && (self.input.lookahead_end(matcher, pos)).is_none_or(|end| end > self.input.len());
+        self.pending |= past;
+        past
+    }
+
+    /// Evaluates `node` at `pos`, appending the nodes it builds to
+    /// `children`. On failure `children` is left as it was.
+    ///
+    /// Nodes waiting on a part of them are kept on a heap stack of
+    /// [`Task`]s rather than the call stack, so nesting in the input is
+    /// bounded only by memory.
+    fn eval(
+        &mut self,
+        node: &'a NormalizedNode,
+        pos: usize,
+        verbatim: bool,
+        children: &mut Vec<GreenId>,
+    ) -> Option<usize> {
+        use NormalizedNode as N;
+        // Children being built, one list per rule entered on top of the
+        // caller's.
+        let mut lists = vec![std::mem::take(children)];
+        let mut tasks = Vec::new();
+        let mut next = Some((node, pos, verbatim));
+        let mut result = None;
+        loop {
+            while let Some((node, pos, verbatim)) = next.take() {
+                if !self.step() {
+                    *children = std::mem::take(&mut lists[0]);
+                    return None;
+                }
+                self.stats.max_depth = self.stats.max_depth.max(tasks.len());
+                let list = lists.last_mut().expect("the caller's list is never popped");
+                result = match node {
+                    N::Terminal(matcher) => self.terminal(matcher.as_ref(), pos, list),
+                    N::Sequence(parts) => match parts.first() {
+                        Some(first) => {
+                            tasks.push(Task::Sequence {
+                                parts,
+                                i: 0,
+                                pos,
+                                end: pos,
+                                before: pos,
+                                trivia: list.len()..list.len(),
+                                mark: list.len(),
+                                verbatim,
+                                retry: false,
+                            });
+                            next = Some((first, pos, verbatim));
+                            continue;
+                        }
+                        None => Some(pos),
+                    },
+                    N::Choice(alternatives) => match alternatives.first() {
+                        Some(first) => {
+                            tasks.push(Task::Choice {
+                                alternatives,
+                                i: 0,
+                                pos,
+                                mark: self.staging.len(),
+                                verbatim,
+                            });
+                            next = Some((first, pos, verbatim));
+                            continue;
+                        }
+                        None => None,
+                    },
+                    N::Reference(idx) => match self.enter_rule(*idx, pos, verbatim) {
+                        Ok(task) => {
+                            next = Some((task.node, pos, task.key.2));
+                            tasks.push(Task::Rule(task));
+                            lists.push(Vec::new());
+                            continue;
+                        }
+                        Err(green) => green.map(|id| {
+                            list.push(id);
+                            pos + self.staging.get_node(id).width
+                        }),
+                    },
+                    N::Verbatim(inner) => {
+                        next = Some((inner, pos, true));
+                        continue;
+                    }
+                    N::Placeholder => None,
+                };
+            }
+            // Hand the result to the nodes waiting on it, until one of
+            // them has another part to evaluate.
+            while next.is_none() {
+                let Some(task) = tasks.pop() else {
+                    *children = lists.pop().expect("the caller's list is never popped");
+                    return result;
+                };
+                match self.resume(task, result, &mut tasks, &mut lists) {
+                    Flow::Eval(node, pos, verbatim) => next = Some((node, pos, verbatim)),
+                    Flow::Done(done) => result = done,
+                }
+            }
+        }
+    }
+
+    /// Continues `task` now that the part it waited on gave `result`.
+    fn resume(
+        &mut self,
+        task: Task<'a>,
+        result: Option<usize>,
+        tasks: &mut Vec<Task<'a>>,
+        lists: &mut Vec<Vec<GreenId>>,
+    ) -> Flow<'a> {
+        match task {
+            Task::Sequence {
+                parts,
+                i,
+                pos,
+                end,
+                before,
+                trivia,
+                mark,
+                verbatim,
+                retry,
+            } => {
+                let children = lists.last_mut().expect("a sequence runs in some list");
+                let end = match result {
+                    Some(next) if retry => next,
+                    // Trivia before an element that matched nothing is left
+                    // for whatever comes next, so it isn't trapped inside a
+                    // rule that ends with an empty optional.
+                    Some(next) if next == end => {
+                        children.drain(trivia.clone());
+                        before
+                    }
+                    Some(next) => next,
+                    None if !retry
+                        && before > pos
+                        && !self.probing
+                        && self.recover_at.contains(&end) =>
+                    {
+                        let part = &parts[i];
+                        let error = Tag::Error(self.skipped(part, end));
+                        match self.resync(part, parts.get(i + 1), end, verbatim) {
+                            Some((resume, retry)) => {
+                                children.push(self.token(error, end..resume));
+                                if retry {
+                                    tasks.push(Task::Sequence {
+                                        parts,
+                                        i,
+                                        pos,
+                                        end: resume,
+                                        before,
+                                        trivia,
+                                        mark,
+                                        verbatim,
+                                        retry,
+                                    });
+                                    return Flow::Eval(part, resume, verbatim);
+                                }
+                                resume
+                            }
+                            // Nothing to resume at: the rest is missing.
+                            None => {
+                                children.drain(trivia);
+                                children.push(self.token(error, before..before));
+                                return Flow::Done(Some(before));
+                            }
+                        }
+                    }
+                    None => {
+                        children.truncate(mark);
+                        return Flow::Done(None);
+                    }
+                };
+                let Some(part) = parts.get(i + 1) else {
+                    return Flow::Done(Some(end));
+                };
+                let start = children.len();
+                let next = self.skip_trivia(end, verbatim, children);
+                tasks.push(Task::Sequence {
+                    parts,
+                    i: i + 1,
+                    pos,
+                    end: next,
+                    before: end,
+                    trivia: start..children.len(),
+                    mark,
+                    verbatim,
+                    retry: false,
+                });
+                Flow::Eval(part, next, verbatim)
+            }
+            Task::Choice {
+                alternatives,
+                i,
+                pos,
+                mark,
+                verbatim,
+            } => {
+                if result.is_none() {
+                    self.discard(mark);
+                }
+                match alternatives.get(i + 1) {
+                    Some(alt) if result.is_none() => {
+                        self.stats.backtracks += 1;
+                        tasks.push(Task::Choice {
+                            alternatives,
+                            i: i + 1,
+                            pos,
+                            mark,
+                            verbatim,
+                        });
+                        Flow::Eval(alt, pos, verbatim)
+                    }
+                    _ => Flow::Done(result),
+                }
+            }
+            Task::Rule(task) => {
+                let children = lists.pop().expect("a rule pushes its own list");
+                let pos = task.pos;
+                let green = match self.exit_rule(task, children, result) {
+                    Ok(green) => green,
+                    Err(task) => {
+                        let (node, verbatim) = (task.node, task.key.2);
+                        tasks.push(Task::Rule(task));
+                        lists.push(Vec::new());
+                        return Flow::Eval(node, pos, verbatim);
+                    }
+                };
+                let list = lists.last_mut().expect("the caller's list is never popped");
+                Flow::Done(green.map(|id| {
+                    list.push(id);
+                    pos + self.staging.get_node(id).width
+                }))
+            }
+        }
+    }
+
+    /// Runs a terminal at `pos`, adding a leaf for what it consumed.
+    fn terminal(
+        &mut self,
+        matcher: &'a dyn Matcher,
+        pos: usize,
+        children: &mut Vec<GreenId>,
+    ) -> Option<usize> {
+        self.read(matcher, pos);
+        if self.past_end(matcher, pos) {
+            return None;
+        }
+        let Some(width) = self.input.try_match(matcher, pos) else {
+            if !self.probing {
+                self.fail(pos, matcher);
+            }
+            return None;
+        };
+        if width > 0 {
+            children.push(self.token(Tag::Terminal, pos..pos + width));
+        }
+        Some(pos + width)
+    }
+
+    /// Finds where to resume after `part` failed at `pos`: the first
+    /// position from there where `part` matches, paired with `true`, or
+    /// where `next` does, leaving `part` missing.
+    fn resync(
+        &mut self,
+        part: &'a NormalizedNode,
+        next: Option<&'a NormalizedNode>,
+        pos: usize,
+        verbatim: bool,
+    ) -> Option<(usize, bool)> {
+        let input = self.input;
+        (pos..=input.len())
+            .filter(|&at| input.is_boundary(at))
+            .find_map(|at| {
+                if self.probe(part, at, verbatim).is_some() {
+                    Some((at, true))
+                } else if next.is_some_and(|next| self.probe(next, at, verbatim).is_some()) {
+                    Some((at, false))
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Evaluates `node` at `pos` without building anything or recovering,
+    /// to see whether it matches there.
+    fn probe(&mut self, node: &'a NormalizedNode, pos: usize, verbatim: bool) -> Option<usize> {
+        let reach = self.reach;
+        let probing = std::mem::replace(&mut self.probing, true);
+        let mark = self.staging.len();
+        let end = self.eval(node, pos, verbatim, &mut Vec::new());
+        self.discard(mark);
+        (self.reach, self.probing) = (reach, probing);
+        end
+    }
+
+    /// Starts evaluating rule `idx` at `pos`. Gives the node right away if
+    /// the result is already known, or `None` if the rule can't be
+    /// evaluated there.
+    fn enter_rule(
+        &mut self,
+        idx: RuleId,
+        pos: usize,
+        verbatim: bool,
+    ) -> Result<RuleTask<'a>, Option<GreenId>> {
+        let grammar = self.grammar;
+        let rule = grammar.rule(idx).ok_or(None)?;
+        let key = (idx, pos, verbatim || rule.token);
+        // A probe doesn't recover, so it can't use results that may have.
+        let reusable = self.reusable.get(&key).filter(|entry| {
+            !self.probing || !self.recover_at.iter().any(|&at| reads(&key, entry, at))
+        });
+        if let Some(&entry) = reusable {
+            self.reach = self.reach.max(entry.reach);
+            self.sizes.insert(entry.green, entry.size);
+            if entry.carried {
+                self.reused.insert((pos, entry.green), entry.size);
+            }
+            if !self.probing {
+                self.failed_at = self.failed_at.max(entry.failed);
+                self.hidden = self.hidden.max(entry.failed);
+            }
+            return Err(Some(entry.green));
+        }
+        let memoize = self.options.memoize && self.recover_at.is_empty();
+        if memoize {
+            self.stats.memo_lookups += 1;
+            if let Some(&entry) = self.cache.get(&key) {
+                self.stats.memo_hits += 1;
+                self.reach = self.reach.max(entry.reach);
+                self.note_failed(entry.failed);
+                return Err(Some(entry.green));
+            }
+            if let Some(&(reach, failed)) = self.failed.get(&key) {
+                self.stats.memo_hits += 1;
+                self.reach = self.reach.max(reach);
+                self.note_failed(failed);
+                return Err(None);
+            }
+        }
+        if let Some(seed) = self.seeds.get_mut(&key) {
+            seed.used = true;
+            return Err(seed.green);
+        }
+        if !self.active.insert(key) {
+            return Err(None);
+        }
+        if self.left_recursive[idx.index()] {
+            let seed = Seed {
+                green: None,
+                used: false,
+            };
+            self.seeds.insert(key, seed);
+        }
+        Ok(RuleTask {
+            node: &rule.node,
+            pos,
+            key,
+            token: rule.token,
+            memoize,
+            outer_reach: std::mem::replace(&mut self.reach, pos),
+            outer_failed: self.failed_at.take(),
+            outer_rules: self.enter(idx, pos),
+        })
+    }
+
+    /// Finishes a rule evaluation that ended at `end`, building its node
+    /// from `children`. Token rules keep no children.
+    ///
+    /// A left-recursive rule is grown from a seed: its left-recursive calls
+    /// first fail, and each time the evaluation then matches further than
+    /// before, the result becomes the seed those calls return and the rule
+    /// is evaluated again, given back as `Err`. So `1+2+3` against
+    /// `expr ::= expr '+' num | num` nests as `(1+2)+3`.
+    fn exit_rule(
+        &mut self,
+        task: RuleTask<'a>,
+        children: Vec<GreenId>,
+        end: Option<usize>,
+    ) -> Result<Option<GreenId>, RuleTask<'a>> {
+        let RuleTask { pos, key, .. } = task;
+        let mut green = end.map(|end| {
+            let (green, size) = if task.token {
+                (self.token(Tag::Rule(key.0), pos..end), 1)
+            } else if self.grammar.rule(key.0).is_some_and(|rule| rule.fold) {
+                self.fold(key.0, children)
+            } else {
+                let sizes = children
+                    .iter()
+                    .map(|child| self.sizes.get(child).unwrap_or(&1));
+                let size = 1 + sizes.sum::<usize>();
+                (self.alloc(Tag::Rule(key.0), children, end - pos), size)
+            };
+            self.sizes.insert(green, size);
+            green
+        });
+        if green.is_some() {
+            self.pinned = self.staging.len();
+        }
+        if let Some(seed) = self.seeds.remove(&key) {
+            let width = |id: Option<GreenId>| id.map(|id| self.staging.get_node(id).width);
+            if seed.used && width(green) > width(seed.green) {
+                let used = false;
+                self.seeds.insert(key, Seed { green, used });
+                return Err(task);
+            }
+            if seed.green.is_some() {
+                green = seed.green;
+            }
+        }
+
+        self.rules = task.outer_rules;
+        self.active.remove(&key);
+        let reach = self.reach;
+        self.reach = task.outer_reach.max(reach);
+        let failed = self.failed_at;
+        self.failed_at = task.outer_failed.max(failed);
+        // Failures met while probing go unrecorded, so neither are the
+        // results.
+        let Some(green) = green else {
+            if task.memoize && !self.probing {
+                self.failed.insert(key, (reach, failed));
+            }
+            return Ok(None);
+        };
+        if !self.probing {
+            let size = self.sizes[&green];
+            let carried = false;
+            let entry = CacheEntry {
+                green,
+                reach,
+                size,
+                failed,
+                carried,
+            };
+            self.cache.insert(key, entry);
+        }
+        Ok(Some(green))
+    }
+
+    /// Folds the match of an operator rule of a precedence table, given by
+    /// its `children`, into a node per operator, left to right. The
+    /// children up to the rule's tail are the first step, and so are those
+    /// of each tail up to the tail under it; every later step makes a node
+    /// of the one before it and its own children. A match that comes to a
+    /// single node is that node, so operands standing alone get none.
+    fn fold(&mut self, idx: RuleId, children: Vec<GreenId>) -> (GreenId, usize) {
+        let tail = match self.grammar.rule(idx).map(|rule| &rule.node) {
+            Some(NormalizedNode::Sequence(parts)) => match parts.last() {
+                Some(NormalizedNode::Reference(tail)) => Some(Tag::Rule(*tail)),
+                _ => None,
+            },
+            _ => None,
+        };
+        let mut steps = Vec::new();
+        let mut rest = children;
+        while let Some(&last) = rest.last()
+            && tail.as_ref() == Some(&self.staging.get_node(last).tag)
+        {
+            rest.pop();
+            let inner = self.staging.get_node(last).children.clone();
+            steps.push(std::mem::replace(&mut rest, inner));
+        }
+        steps.push(rest);
+
+        let mut steps = steps.into_iter().filter(|step| !step.is_empty());
+        let mut folded = steps.next().unwrap_or_default();
+        for step in steps {
+            folded.extend(step);
+            folded = vec![self.branch(idx, folded)];
+        }
+        match folded[..] {
+            [only] if self.staging.get_node(only).tag != Tag::Trivia => {
+                (only, self.sizes.get(&only).copied().unwrap_or(1))
+            }
+            _ => {
+                let green = self.branch(idx, folded);
+                (green, self.sizes[&green])
+            }
+        }
+    }
+
+    /// A node of rule `idx` over `children`, with its size recorded.
+    fn branch(&mut self, idx: RuleId, children: Vec<GreenId>) -> GreenId {
+        let width = (children.iter())
+            .map(|&child| self.staging.get_node(child).width)
+            .sum();
+        let size = 1
+            + (children.iter())
+                .map(|child| self.sizes.get(child).unwrap_or(&1))
+                .sum::<usize>();
+        let green = self.alloc(Tag::Rule(idx), children, width);
+        self.sizes.insert(green, size);
+        green
+    }
+
+    /// Pushes `rule`, entered at `pos`, on the rule stack, returning the
+    /// stack as it was.
+    fn enter(&mut self, rule: RuleId, pos: usize) -> Option<Rc<Frame>> {
+        let outer = self.rules.take();
+        let label = match self.grammar.rule(rule).and_then(|rule| rule.label()) {
+            Some(label) => Some((label, pos)),
+            None => outer.as_ref().and_then(|frame| frame.label),
+        };
+        self.rules = Some(Rc::new(Frame {
+            rule,
+            label,
+            outer: outer.clone(),
+        }));
+        outer
+    }
+
+    /// The label of the innermost labeled rule being evaluated, if nothing
+    /// but trivia of it matched before `pos`.
+    fn label_at(&self, pos: usize) -> Option<&'static str> {
+        let (label, mut at) = self.rules.as_ref()?.label?;
+        if let Some(trivia) = self.grammar.trivia() {
+            while at < pos {
+                match self.input.try_match(trivia, at) {
+                    Some(width) if width > 0 => at += width,
+                    _ => break,
+                }
+            }
+        }
+        (at == pos).then_some(label)
+    }
+
+    /// Records `matcher` failing at `pos`.
+    fn fail(&mut self, pos: usize, matcher: &'a dyn Matcher) {
+        self.note_failed(Some(pos));
+        let label = match pos >= self.failure.pos {
+            true => self.label_at(pos),
+            false => None,
+        };
+        self.failure.record(pos, matcher, label, &self.rules);
+    }
+
+    /// Counts a failure at `failed`, if any, towards the innermost rule
+    /// being evaluated, unless probing.
+    fn note_failed(&mut self, failed: Option<usize>) {
+        if !self.probing {
+            self.failed_at = self.failed_at.max(failed);
+        }
+    }
+
+    /// The error for text skipped at `pos` in place of `node`, with the
+    /// label of the innermost labeled rule starting there: one `node`
+    /// starts with, or else the one being evaluated.
+    fn skipped(&self, node: &NormalizedNode, pos: usize) -> GrammarError {
+        let leading_rule = |error: &GrammarError| match error {
+            GrammarError::RuleMismatch { expected } => Some(*expected),
+            _ => None,
+        };
+        let error = expected(node);
+        let mut label = None;
+        let mut seen = HashSet::new();
+        let mut next = leading_rule(&error);
+        while let Some(idx) = next.filter(|&idx| seen.insert(idx)) {
+            let Some(rule) = self.grammar.rule(idx) else {
+                break;
+            };
+            label = rule.label().or(label);
+            next = leading_rule(&expected(&rule.node));
+        }
+        match (label.or_else(|| self.label_at(pos)), error) {
+            (None, error) => error,
+            (label, GrammarError::TokenMismatch { expected, .. }) => {
+                GrammarError::TokenMismatch { expected, label }
+            }
+            (label, GrammarError::RuleMismatch { expected }) => GrammarError::TokenMismatch {
+                expected: self.grammar.rule_name(expected).unwrap_or("?").to_string(),
+                label,
+            },
+            (_, error) => error,
+        }
+    }
+
+    /// Consumes trivia at `pos` until the grammar's trivia matcher stops
+    /// making progress, recording each match as a trivia leaf.
+    fn skip_trivia(
+        &mut self,
+        mut pos: usize,
+        verbatim: bool,
+        children: &mut Vec<GreenId>,
+    ) -> usize {
+        let Some(trivia) = self.grammar.trivia().filter(|_| !verbatim) else {
+            return pos;
+        };
+        loop {
+            self.read(trivia, pos);
+            if self.past_end(trivia, pos) {
+                return pos;
+            }
+            match self.input.try_match(trivia, pos) {
+                Some(width) if width > 0 => {
+                    children.push(self.token(Tag::Trivia, pos..pos + width));
+                    pos += width;
+                }
+                _ => return pos,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Engine, ParseOptions};
+    use crate::grammar::Grammar;
+    use crate::grammar_dsl::*;
+    use crate::parser::{Edit, ParserError, ParserResult, ParserState};
+    use crate::r;
+    use crate::tree::{GreenId, Tag, TreeAlloc};
+    use crate::utils::Span;
+    use crate::words::{Identifier, Matcher, Whitespace};
+    use std::time::Duration;
+
+    fn number() -> GrammarNode {
+        t(('0'..='9').times(1..))
+    }
+
+    fn expr() -> GrammarNode {
+        r!(term) + opt(t('+') + r!(expr))
+    }
+
+    fn term() -> GrammarNode {
+        r!(atom) + opt(t('*') + r!(term))
+    }
+
+    fn atom() -> GrammarNode {
+        token(r!(number)) | (t('(') + r!(expr) + t(')')) | token(r!(name))
+    }
+
+    fn name() -> GrammarNode {
+        t(Identifier)
+    }
+
+    fn program() -> GrammarNode {
+        r!(stmt) + opt(r!(program))
+    }
+
+    fn stmt() -> GrammarNode {
+        token(r!(name)) + t('=') + r!(expr) + t(';')
+    }
+
+    // Each level tries the next three times before settling on it, for
+    // backtracking that grows exponentially with depth.
+    fn l0() -> GrammarNode {
+        (r!(l1) + t('x')) | (r!(l1) + t('y')) | r!(l1)
+    }
+
+    fn l1() -> GrammarNode {
+        (r!(l2) + t('x')) | (r!(l2) + t('y')) | r!(l2)
+    }
+
+    fn l2() -> GrammarNode {
+        (r!(l3) + t('x')) | (r!(l3) + t('y')) | r!(l3)
+    }
+
+    fn l3() -> GrammarNode {
+        (r!(l4) + t('x')) | (r!(l4) + t('y')) | r!(l4)
+    }
+
+    fn l4() -> GrammarNode {
+        (r!(l5) + t('x')) | (r!(l5) + t('y')) | r!(l5)
+    }
+
+    fn l5() -> GrammarNode {
+        t('a')
+    }
+
+    /// Renders the tree as `rule@start..end(children)`, with terminals as
+    /// their quoted text, trivia as `~` and errors as `!` before the text
+    /// they cover, checking that children cover
+    /// their parent. Synthetic rules are left out, their children in their
+    /// place.
+    fn shape(state: &ParserState, text: &str, id: GreenId, offset: usize) -> String {
+        let node = state.arena().get_node(id);
+        let end = offset + node.width;
+        match &node.tag {
+            Tag::Rule(idx) => {
+                let rule = state.grammar().rule(*idx).unwrap();
+                let name = rule.name;
+                let mut at = offset;
+                let children: Vec<String> = node
+                    .children
+                    .iter()
+                    .map(|&child| {
+                        let rendered = shape(state, text, child, at);
+                        at += state.arena().get_node(child).width;
+                        rendered
+                    })
+                    .filter(|rendered| !rendered.is_empty())
+                    .collect();
+                if rule.is_synthetic() {
+                    children.join(" ")
+                } else if children.is_empty() {
+                    format!("{name}@{offset}..{end}")
+                } else {
+                    assert_eq!(at, end, "children of {name} must cover it");
+                    format!("{name}@{offset}..{end}({})", children.join(" "))
+                }
+            }
+            Tag::Terminal => format!("{:?}", &text[offset..end]),
+            Tag::Trivia => "~".to_string(),
+            Tag::Error(_) => format!("!{:?}", &text[offset..end]),
+        }
+    }
+
+    fn parse(grammar: &Grammar, text: &str) -> Result<String, ParserError> {
+        let state = ParserState::new(grammar.clone()).with_text(text);
+        match state.parse_full() {
+            ParserResult::Complete(root) => {
+                assert_eq!(root.offset, 0);
+                Ok(shape(&state, text, root.green, 0))
+            }
+            ParserResult::Incomplete(error) => Err(error),
+            ParserResult::Recovered { .. } => unreachable!("parse_full doesn't recover"),
+        }
+    }
+
+    #[test]
+    fn test_parse_arithmetic() {
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        assert_eq!(
+            parse(&grammar, "12+3*4").unwrap(),
+            "START@0..6(expr@0..6(\
+                term@0..2(atom@0..2(number@0..2)) \
+                \"+\" \
+                expr@3..6(term@3..6(atom@3..4(number@3..4) \"*\" term@5..6(atom@5..6(number@5..6))))))"
+        );
+        assert_eq!(
+            parse(&grammar, "(1)").unwrap(),
+            "START@0..3(expr@0..3(term@0..3(atom@0..3(\"(\" \
+                expr@1..2(term@1..2(atom@1..2(number@1..2))) \")\"))))"
+        );
+    }
+
+    #[test]
+    fn test_parse_incomplete() {
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        for (text, offset) in [("1+", 2), ("", 0), ("1+2)", 3), ("*", 0)] {
+            match parse(&grammar, text) {
+                Err(ParserError::SyntaxError {
+                    offset: at,
+                    line_col,
+                    ..
+                }) => {
+                    assert_eq!(at, offset, "{text:?}");
+                    assert_eq!(line_col.col, offset + 1);
+                }
+                other => panic!("{text:?} parsed as {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_farthest_failure() {
+        let grammar = Grammar::try_from(r!(expr)).unwrap().with_trivia(' ');
+        let Err(ParserError::SyntaxError {
+            offset,
+            expected,
+            rules,
+            ..
+        }) = parse(&grammar, "(1 + 2")
+        else {
+            panic!("\"(1 + 2\" parsed");
+        };
+        assert_eq!(offset, 6);
+        assert_eq!(expected, ["')'", "'*'", "'+'"]);
+        let names: Vec<_> = rules
+            .iter()
+            .map(|&idx| grammar.rule(idx).unwrap().name)
+            .collect();
+        // The first terminal tried at the end is `*`, in the term of `2`.
+        assert_eq!(
+            names,
+            [
+                "START", "expr", "term", "atom", "expr", "expr_opt", "expr", "term", "term_opt"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_trivia() {
+        let grammar = Grammar::try_from(r!(expr)).unwrap().with_trivia(' ');
+        assert_eq!(
+            parse(&grammar, " 1 + 23 ").unwrap(),
+            "START@0..8(~ expr@1..7(term@1..2(atom@1..2(number@1..2)) ~ \"+\" ~ \
+                expr@5..7(term@5..7(atom@5..7(number@5..7)))) ~)"
+        );
+        assert!(parse(&grammar, "1 + 2 3").is_err());
+    }
+
+    #[test]
+    fn test_parse_left_recursion() {
+        fn sum() -> GrammarNode {
+            (r!(sum) + t('+') + r!(product)) | r!(product)
+        }
+
+        fn product() -> GrammarNode {
+            (r!(product) + t('*') + t(('0'..='9').times(1..))) | t(('0'..='9').times(1..))
+        }
+
+        let grammar = Grammar::try_from_unsimplified(r!(sum)).unwrap();
+        assert_eq!(
+            parse(&grammar, "1+2*3+4").unwrap(),
+            "START@0..7(sum@0..7(\
+                sum@0..5(sum@0..1(product@0..1(\"1\")) \"+\" \
+                    product@2..5(product@2..3(\"2\") \"*\" \"3\")) \
+                \"+\" product@6..7(\"4\")))"
+        );
+        assert_eq!(
+            parse(&grammar, "12").unwrap(),
+            "START@0..2(sum@0..2(product@0..2(\"12\")))"
+        );
+        assert!(parse(&grammar, "1+").is_err());
+    }
+
+    fn statements() -> GrammarNode {
+        many(t('x') + t(';'))
+    }
+
+    #[test]
+    fn test_budget_exceeded_recovering() {
+        let grammar = Grammar::try_from(r!(statements)).unwrap();
+        let text = format!("{}x{}", "x;".repeat(200), "x;".repeat(200));
+        let mut state = ParserState::new(grammar)
+            .with_options(ParseOptions {
+                max_steps: Some(2000),
+                ..ParseOptions::default()
+            })
+            .with_text(&text);
+        // The first pass stops at the error within the budget, but
+        // recovering goes on over the rest of the text and doesn't.
+        assert!(matches!(
+            state.parse_full(),
+            ParserResult::Incomplete(ParserError::SyntaxError { .. })
+        ));
+        assert!(matches!(
+            state.parse(),
+            ParserResult::Incomplete(ParserError::BudgetExceeded { steps: 2001, .. })
+        ));
+        assert!(state.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_recover_bad_statement() {
+        let grammar = Grammar::try_from(r!(program))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let text = "a = 1;\nb = 2 2;\nc = 3;\n";
+        let mut state = ParserState::new(grammar).with_text(text);
+        assert!(matches!(state.parse(), ParserResult::Recovered { .. }));
+        let tree = shape(&state, text, state.ast().green, 0);
+        assert_eq!(str::matches(&tree, '!').count(), 1, "{tree}");
+        assert_eq!(
+            tree,
+            "START@0..23(program@0..22(\
+                stmt@0..6(name@0..1 ~ \"=\" ~ expr@4..5(term@4..5(atom@4..5(number@4..5))) \";\") ~ \
+                program@7..22(\
+                    stmt@7..15(name@7..8 ~ \"=\" ~ \
+                        expr@11..12(term@11..12(atom@11..12(number@11..12))) ~ !\"2\" \";\") ~ \
+                    program@16..22(stmt@16..22(name@16..17 ~ \"=\" ~ \
+                        expr@20..21(term@20..21(atom@20..21(number@20..21))) \";\")))) ~)"
+        );
+    }
+
+    fn pairs() -> GrammarNode {
+        many((t("ab") + t('c')) | (t('a') + t('b') + t('d')))
+    }
+
+    #[test]
+    fn test_discard_failed_alternatives() {
+        let grammar = Grammar::try_from(r!(pairs)).unwrap();
+        let arena = TreeAlloc::new();
+        let mut engine = Engine::new(&grammar, &arena, "abdabdabc");
+        let root = engine.parse_full().ok().unwrap();
+        // The "ab" tokens of the first alternative failing twice were
+        // never promoted.
+        let mut seen = std::collections::HashSet::new();
+        let mut stack = vec![root];
+        while let Some(id) = stack.pop() {
+            if seen.insert(id) {
+                stack.extend(&arena.get_node(id).children);
+            }
+        }
+        assert_eq!(arena.len(), seen.len());
+        assert_eq!(engine.stats().nodes_allocated, arena.len() as u64);
+    }
+
+    #[test]
+    fn test_memoize_common_prefixes() {
+        let grammar = Grammar::try_from_unsimplified(r!(l0)).unwrap();
+        let arena = TreeAlloc::new();
+        let steps = |memoize| {
+            let options = ParseOptions {
+                memoize,
+                ..ParseOptions::default()
+            };
+            let mut engine = Engine::new(&grammar, &arena, "a").with_options(options);
+            let root = engine.parse_full().ok().unwrap();
+            (root, engine.steps)
+        };
+        let (plain, plain_steps) = steps(false);
+        let (memoized, memoized_steps) = steps(true);
+        assert_eq!(plain, memoized);
+        assert!(plain_steps > 3usize.pow(5) as u64, "{plain_steps}");
+        assert!(memoized_steps < 100, "{memoized_steps}");
+    }
+
+    #[test]
+    fn test_parse_deep_nesting() {
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        let depth = 100_000;
+        let text = format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        let state = ParserState::new(grammar).with_text(text.as_str());
+        let ParserResult::Complete(root) = state.parse_full() else {
+            panic!("nested parens didn't parse");
+        };
+        assert_eq!(state.arena().get_node(root.green).width, text.len());
+    }
+
+    #[test]
+    fn test_budget_exceeded() {
+        let grammar = Grammar::try_from_unsimplified(r!(l0)).unwrap();
+        let mut state = ParserState::new(grammar).with_text("ax");
+        assert!(matches!(state.parse(), ParserResult::Complete(_)));
+        let tree = state.ast().green;
+
+        let mut state = state
+            .with_options(ParseOptions {
+                max_steps: Some(50),
+                ..ParseOptions::default()
+            })
+            .with_text("a");
+        match state.parse() {
+            ParserResult::Incomplete(ParserError::BudgetExceeded { steps, .. }) => {
+                assert_eq!(steps, 51)
+            }
+            _ => panic!("parse stayed within 50 steps"),
+        }
+        assert_eq!(state.ast().green, tree);
+        // A broken text isn't recovered from either.
+        state = state.with_text("b");
+        assert!(matches!(
+            state.parse(),
+            ParserResult::Incomplete(ParserError::BudgetExceeded { .. })
+        ));
+        assert_eq!(state.ast().green, tree);
+        assert!(state.diagnostics().is_empty());
+
+        let mut state = state
+            .with_options(ParseOptions {
+                max_duration: Some(Duration::ZERO),
+                ..ParseOptions::default()
+            })
+            .with_text("a");
+        assert!(matches!(
+            state.parse(),
+            ParserResult::Incomplete(ParserError::BudgetExceeded { steps: 1024, .. })
+        ));
+        assert_eq!(state.ast().green, tree);
+    }
+
+    /// Applies `edit` to the text and reparses, checking the result against
+    /// a full parse of the same text.
+    fn reparse(state: &mut ParserState, edit: Edit) -> ParserResult {
+        state.apply(&edit).unwrap();
+        let result = state.reparse_applied(&edit);
+        match (&result, state.parse_full()) {
+            (ParserResult::Complete(incremental), ParserResult::Complete(full)) => {
+                assert_eq!(incremental.green, full.green, "after {edit:?}");
+            }
+            (
+                ParserResult::Recovered { .. } | ParserResult::Incomplete(_),
+                ParserResult::Incomplete(_),
+            ) => {}
+            _ => panic!("reparse and full parse disagree after {edit:?}"),
+        }
+        result
+    }
+
+    fn update(start: usize, end: usize, new_text: &str) -> Edit {
+        Edit::Update {
+            span: Span::new(start, end),
+            new_text: new_text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_reparse_matches_full_parse() {
+        for memoize in [false, true] {
+            let grammar = Grammar::try_from(r!(program))
+                .unwrap()
+                .with_trivia(Whitespace::new());
+            let mut state = ParserState::new(grammar)
+                .with_options(ParseOptions {
+                    memoize,
+                    ..ParseOptions::default()
+                })
+                .with_text("a = 1;\nb = (a + 2) * 3;\nc = b;\n");
+            assert!(matches!(state.parse(), ParserResult::Complete(_)));
+            let edits = [
+                update(4, 5, "42"),
+                Edit::Insert {
+                    position: 0,
+                    new_text: "z = 0; ".to_string(),
+                },
+                // `b = (a + 2) * 3;` loses its closing paren, then gets it back.
+                update(25, 26, ""),
+                Edit::Insert {
+                    position: 25,
+                    new_text: ")".to_string(),
+                },
+                // Joins two statements onto one line.
+                Edit::Delete {
+                    span: Span::new(14, 15),
+                },
+                // An identifier grows into the statement after it.
+                update(7, 8, "ab"),
+                Edit::Insert {
+                    position: 38,
+                    new_text: "d = c * c;".to_string(),
+                },
+                // Renames `c` where it's defined, then where it's used, and
+                // spaces out the first statement.
+                Edit::Batch(vec![
+                    update(32, 33, "cc"),
+                    update(43, 48, "cc * cc"),
+                    Edit::Insert {
+                        position: 1,
+                        new_text: "  ".to_string(),
+                    },
+                ]),
+            ];
+            for edit in edits {
+                reparse(&mut state, edit);
+            }
+            assert_eq!(
+                state.text(),
+                "z   = 0; ab = 42;b = (a + 2) * 3;\ncc = b;d = cc * cc;\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_reparse_reuses_most_nodes() {
+        let grammar = Grammar::try_from(r!(program))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let text: String = (0..10_000)
+            .map(|i| format!("x{i} = (x + {i}) * 2 + y;\n"))
+            .collect();
+        let middle = text.find("x5000 = (x + ").unwrap() + "x5000 = (x + ".len();
+        let mut state = ParserState::new(grammar).with_text(text);
+        assert!(matches!(state.parse(), ParserResult::Complete(_)));
+        assert_eq!(state.reuse_stats().reused, 0);
+
+        let result = reparse(&mut state, update(middle, middle + 4, "7"));
+        assert!(matches!(result, ParserResult::Complete(_)));
+        let stats = state.reuse_stats();
+        assert!(
+            stats.reused * 100 > stats.nodes * 90,
+            "reused {} of {} nodes",
+            stats.reused,
+            stats.nodes
+        );
+    }
+
+    #[test]
+    fn test_reparse_with_error_reuses_most_nodes() {
+        let grammar = Grammar::try_from(r!(program))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let mut text: String = (0..400).map(|i| format!("x{i} = {i} + y;\n")).collect();
+        text.insert_str(text.find(";\nx10 ").unwrap(), " 2");
+        let far = text.find("x300 = ").unwrap() + "x300 = ".len();
+        let mut state = ParserState::new(grammar.clone()).with_text(text);
+        assert!(matches!(state.parse(), ParserResult::Recovered { .. }));
+
+        for edit in [update(far, far + 4, "7"), update(far + 2, far + 2, " * 3")] {
+            let result = reparse(&mut state, edit);
+            assert!(matches!(result, ParserResult::Recovered { .. }));
+            let stats = state.reuse_stats();
+            assert!(
+                stats.reused * 100 > stats.nodes * 90,
+                "reused {} of {} nodes",
+                stats.reused,
+                stats.nodes
+            );
+            // The same tree, errors and all, as recovering from scratch.
+            let mut fresh = ParserState::new(grammar.clone()).with_text(state.text());
+            assert!(matches!(fresh.parse(), ParserResult::Recovered { .. }));
+            assert_eq!(
+                shape(&state, &state.text(), state.ast().green, 0),
+                shape(&fresh, &fresh.text(), fresh.ast().green, 0)
+            );
+            assert_eq!(state.diagnostics(), fresh.diagnostics());
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! Document text kept in chunks, so an edit copies the chunks it touches
+//! rather than the whole text.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::utils::Span;
+
+/// Chunks are split once they grow past twice this many bytes.
+const CHUNK_LEN: usize = 1024;
+
+/// Text stored as a sequence of chunks, each split at char boundaries.
+/// Clones share the chunks.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Rope {
+    chunks: Vec<Arc<str>>,
+    /// The offset each chunk ends at.
+    ends: Vec<usize>,
+}
+
+impl Rope {
+    pub(crate) fn len(&self) -> usize {
+        self.ends.last().copied().unwrap_or(0)
+    }
+
+    pub(crate) fn is_char_boundary(&self, offset: usize) -> bool {
+        match self.chunk_at(offset) {
+            Some((i, start)) => self.chunks[i].is_char_boundary(offset - start),
+            None => offset == 0,
+        }
+    }
+
+    /// The text of `span`, which must be in bounds and on char boundaries.
+    pub(crate) fn slice(&self, span: Span) -> String {
+        let mut text = String::with_capacity(span.len());
+        let Some((first, mut start)) = self.chunk_at(span.start) else {
+            return text;
+        };
+        for chunk in &self.chunks[first..] {
+            if start >= span.end {
+                break;
+            }
+            let from = span.start.saturating_sub(start);
+            let to = (span.end - start).min(chunk.len());
+            text.push_str(&chunk[from..to]);
+            start += chunk.len();
+        }
+        text
+    }
+
+    /// Replaces `span`, which must be in bounds and on char boundaries,
+    /// with `new_text`.
+    pub(crate) fn replace(&mut self, span: Span, new_text: &str) {
+        let Some((first, start)) = self.chunk_at(span.start) else {
+            self.splice(0..0, 0, new_text.to_string());
+            return;
+        };
+        let (last, last_start) = self.chunk_at(span.end).unwrap_or((first, start));
+        let mut text = String::with_capacity(span.len() + new_text.len() + CHUNK_LEN);
+        text.push_str(&self.chunks[first][..span.start - start]);
+        text.push_str(new_text);
+        text.push_str(&self.chunks[last][span.end - last_start..]);
+        self.splice(first..last + 1, start, text);
+    }
+
+    /// The chunk holding `offset` and the offset it starts at. An offset
+    /// between two chunks is the end of the first.
+    fn chunk_at(&self, offset: usize) -> Option<(usize, usize)> {
+        let i = self
+            .ends
+            .partition_point(|&end| end < offset)
+            .min(self.chunks.len().checked_sub(1)?);
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        Some((i, start))
+    }
+
+    /// Replaces the chunks in `range`, which start at `start`, with
+    /// `text` split into chunks.
+    fn splice(&mut self, range: std::ops::Range<usize>, start: usize, text: String) {
+        let mut pieces = Vec::new();
+        let mut rest = text.as_str();
+        while rest.len() > 2 * CHUNK_LEN {
+            let mut at = CHUNK_LEN;
+            while !rest.is_char_boundary(at) {
+                at += 1;
+            }
+            pieces.push(Arc::from(&rest[..at]));
+            rest = &rest[at..];
+        }
+        if !rest.is_empty() {
+            pieces.push(Arc::from(rest));
+        }
+        let from = range.start;
+        self.chunks.splice(range, pieces);
+        self.ends.truncate(from);
+        let mut end = start;
+        self.ends.extend(self.chunks[from..].iter().map(|chunk| {
+            end += chunk.len();
+            end
+        }));
+    }
+}
+
+impl From<&str> for Rope {
+    fn from(text: &str) -> Self {
+        let mut rope = Rope::default();
+        rope.splice(0..0, 0, text.to_string());
+        rope
+    }
+}
+
+impl fmt::Display for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.chunks.iter().try_for_each(|chunk| f.write_str(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_inserts() {
+        let mut text = "abcdéfgh\n".repeat(100_000);
+        let mut rope = Rope::from(text.as_str());
+        assert_eq!(rope.len(), text.len());
+        // A fixed LCG, so the positions are the same every run.
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        for i in 0..10_000 {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            let mut at = (seed >> 33) as usize % (text.len() + 1);
+            while !text.is_char_boundary(at) {
+                at -= 1;
+            }
+            assert!(rope.is_char_boundary(at));
+            let new_text = if i % 2 == 0 { "x" } else { "ü" };
+            text.insert_str(at, new_text);
+            rope.replace(Span::new(at, at), new_text);
+        }
+        assert_eq!(rope.len(), text.len());
+        assert_eq!(rope.to_string(), text);
+        assert!(!rope.is_char_boundary(5));
+        assert_eq!(rope.slice(Span::new(3000, 9000)), text[3000..9000]);
+    }
+
+    #[test]
+    fn test_replace_across_chunks() {
+        let mut text = "0123456789".repeat(1000);
+        let mut rope = Rope::from(text.as_str());
+        for (start, end, new_text) in [(5, 4000, "-"), (0, 2, ""), (10, 10, "ab")] {
+            text.replace_range(start..end, new_text);
+            rope.replace(Span::new(start, end), new_text);
+            assert_eq!(rope.to_string(), text);
+        }
+        rope.replace(Span::new(0, rope.len()), "");
+        assert_eq!((rope.len(), rope.to_string()), (0, String::new()));
+        assert!(rope.is_char_boundary(0));
+        rope.replace(Span::new(0, 0), "é");
+        assert_eq!(rope.slice(Span::new(0, 2)), "é");
+    }
+}
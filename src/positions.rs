@@ -0,0 +1,185 @@
+//! LSP-style (line, character) positions, counted in a negotiated encoding,
+//! and their conversion to the byte offsets [`Edit`] and [`Span`] use.
+//!
+//! [`Edit`]: crate::parser::Edit
+//! [`Span`]: crate::utils::Span
+
+use crate::utils::{LineIndex, floor_char_boundary};
+
+/// The units a [`Position`]'s `character` counts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PositionEncoding {
+    /// Bytes.
+    Utf8,
+    /// UTF-16 code units, so chars outside the BMP count twice. The LSP
+    /// default.
+    #[default]
+    Utf16,
+    /// Chars.
+    Utf32,
+}
+
+impl PositionEncoding {
+    pub(crate) fn len(self, c: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => c.len_utf8(),
+            PositionEncoding::Utf16 => c.len_utf16(),
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+}
+
+/// A 0-based line and character offset into that line, as in LSP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+impl Position {
+    pub fn new(line: usize, character: usize) -> Self {
+        Position { line, character }
+    }
+}
+
+/// Converts between [`Position`]s and byte offsets in one text. Building
+/// it takes a pass over the text; each conversion after that searches the
+/// line starts and walks the one line.
+#[derive(Debug, Clone)]
+pub struct PositionIndex<'a> {
+    text: &'a str,
+    lines: LineIndex,
+    encoding: PositionEncoding,
+}
+
+impl<'a> PositionIndex<'a> {
+    pub fn new(text: &'a str, encoding: PositionEncoding) -> Self {
+        PositionIndex {
+            text,
+            lines: LineIndex::new(text),
+            encoding,
+        }
+    }
+
+    pub fn encoding(&self) -> PositionEncoding {
+        self.encoding
+    }
+
+    /// The byte offset of `position`. A character past the end of its
+    /// line means the end, as in LSP. `None` if the line doesn't exist or
+    /// the character falls inside a char.
+    pub fn offset(&self, position: Position) -> Option<usize> {
+        let (start, line) = self.line(position.line)?;
+        let mut units = 0;
+        for (at, c) in line.char_indices() {
+            match units.cmp(&position.character) {
+                std::cmp::Ordering::Less => units += self.encoding.len(c),
+                std::cmp::Ordering::Equal => return Some(start + at),
+                std::cmp::Ordering::Greater => return None,
+            }
+        }
+        (units <= position.character).then_some(start + line.len())
+    }
+
+    /// The position of byte `offset`. Offsets inside a char or past the
+    /// end are clamped to the preceding boundary.
+    pub fn position(&self, offset: usize) -> Position {
+        let offset = floor_char_boundary(self.text, offset);
+        let line = self.lines.line_of(offset);
+        let start = self.lines.line_start(line).unwrap_or(0);
+        let character = self.text[start..offset]
+            .chars()
+            .map(|c| self.encoding.len(c))
+            .sum();
+        Position { line, character }
+    }
+
+    /// The start offset and text of `line`, without its line break.
+    fn line(&self, line: usize) -> Option<(usize, &'a str)> {
+        let start = self.lines.line_start(line)?;
+        let end = self
+            .lines
+            .line_start(line + 1)
+            .map_or(self.text.len(), |next| next - 1);
+        let text = &self.text[start..end];
+        Some((start, text.strip_suffix('\r').unwrap_or(text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::grammar::Grammar;
+    use crate::grammar_dsl::*;
+    use crate::parser::{Edit, Parser};
+
+    #[test]
+    fn test_emoji_line() {
+        // `😀` is 4 bytes, 2 UTF-16 units and 1 char.
+        let text = "let a = \"😀\";\nb";
+        let close = text.find("\";").unwrap();
+        let cases = [
+            (PositionEncoding::Utf8, 13),
+            (PositionEncoding::Utf16, 11),
+            (PositionEncoding::Utf32, 10),
+        ];
+        for (encoding, character) in cases {
+            let index = PositionIndex::new(text, encoding);
+            let position = Position::new(0, character);
+            assert_eq!(index.offset(position), Some(close), "{encoding:?}");
+            assert_eq!(index.position(close), position, "{encoding:?}");
+        }
+
+        let index = PositionIndex::new(text, PositionEncoding::Utf16);
+        // Between the two halves of the surrogate pair.
+        assert_eq!(index.offset(Position::new(0, 10)), None);
+        // Past the end of a line is its end.
+        assert_eq!(index.offset(Position::new(0, 99)), Some(close + 2));
+        assert_eq!(index.offset(Position::new(1, 1)), Some(text.len()));
+        assert_eq!(index.offset(Position::new(2, 0)), None);
+    }
+
+    #[test]
+    fn test_multi_line_edits() {
+        let (sender, receiver) = mpsc::channel();
+        let parser = Parser::new(Grammar::try_from(t("")).unwrap(), receiver);
+        let text = "fn main() {\r\n    say(\"👋\");\r\n}\n";
+        sender
+            .send(Edit::Insert {
+                position: 0,
+                new_text: text.to_string(),
+            })
+            .unwrap();
+        parser.receive_edits().unwrap();
+        let edits = [
+            // Replace from inside the emoji's line through the next line.
+            ((1, 11), (2, 1), " 🌍\");\n"),
+            // Insert after the emoji, then delete the first line.
+            ((1, 11), (1, 11), "!"),
+            ((0, 0), (1, 0), ""),
+        ];
+        for ((line, character), (end_line, end_character), new_text) in edits {
+            let text = parser.state().text();
+            let index = PositionIndex::new(&text, PositionEncoding::Utf16);
+            let start = Position::new(line, character);
+            let end = Position::new(end_line, end_character);
+            let edit = Edit::from_lsp_range(&index, start, end, new_text).unwrap();
+            sender.send(edit).unwrap();
+            parser.receive_edits().unwrap();
+        }
+        let text = parser.state().text();
+        assert_eq!(text, "    say(\"👋! 🌍\");\n\n");
+
+        let index = PositionIndex::new(&text, PositionEncoding::Utf16);
+        let edit = Edit::from_lsp_position(&index, Position::new(1, 0), "}").unwrap();
+        assert_eq!(
+            edit,
+            Edit::Insert {
+                position: text.len() - 1,
+                new_text: "}".to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! Checks that a grammar, the parser and the trees it builds agree, for
+//! grammar authors' test suites. Behind the `testing` feature.
+
+use std::fmt;
+
+use crate::grammar::{
+    EvaluationError, GenLimits, Grammar, GrammarDiagnostic, Severity, SplitMix64,
+};
+use crate::parser::{ParserError, ParserState};
+use crate::tree::{GreenId, Tag, TreeAlloc, TreeInvariantError};
+use crate::utils::floor_char_boundary;
+
+/// How far around a divergence [`Divergence`] shows the text, in bytes.
+const CONTEXT: usize = 24;
+
+/// Why [`check_roundtrip`] failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoundtripFailure {
+    /// The input doesn't match the grammar.
+    Parse(ParserError),
+    /// The tree is malformed.
+    Invariant(TreeInvariantError),
+    /// The texts of the tree's tokens and trivia don't add up to the input.
+    /// `expected` is the input from the divergence on, `found` the tree's
+    /// text.
+    Text(Divergence),
+    /// Parsing the text again built a different tree. `expected` and
+    /// `found` describe the first nodes that differ.
+    Reparse(Divergence),
+}
+
+/// Where two things first differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Byte offset into the input.
+    pub offset: usize,
+    /// The input just before `offset`.
+    pub context: String,
+    pub expected: String,
+    pub found: String,
+}
+
+impl fmt::Display for RoundtripFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoundtripFailure::Parse(error) => write!(f, "the input doesn't parse: {error}"),
+            RoundtripFailure::Invariant(error) => write!(f, "the tree is malformed: {error}"),
+            RoundtripFailure::Text(at) => write!(
+                f,
+                "the tree's text differs from the input at byte {} after {:?}: expected {:?}, found {:?}",
+                at.offset, at.context, at.expected, at.found
+            ),
+            RoundtripFailure::Reparse(at) => write!(
+                f,
+                "reparsing built a different tree at byte {} after {:?}: expected {}, found {}",
+                at.offset, at.context, at.expected, at.found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RoundtripFailure {}
+
+/// Why [`check_grammar`] failed.
+#[derive(Debug, Clone)]
+pub enum GrammarCheckFailure {
+    /// [`Grammar::validate`] reported errors; warnings are let through.
+    Invalid(Vec<GrammarDiagnostic>),
+    /// Left recursion [`Grammar::eliminate_left_recursion`] can't rewrite.
+    LeftRecursion(EvaluationError),
+    /// [`Grammar::generate`] can't make sentences of the grammar.
+    NoSentences,
+    /// A generated sentence didn't round-trip.
+    Roundtrip {
+        input: String,
+        failure: RoundtripFailure,
+    },
+}
+
+impl fmt::Display for GrammarCheckFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrammarCheckFailure::Invalid(diagnostics) => {
+                write!(f, "the grammar is invalid:")?;
+                for diagnostic in diagnostics {
+                    write!(f, " rule `{}`: {};", diagnostic.rule, diagnostic.error)?;
+                }
+                Ok(())
+            }
+            GrammarCheckFailure::LeftRecursion(error) => write!(f, "{error}"),
+            GrammarCheckFailure::NoSentences => {
+                write!(f, "no sentence can be generated from the grammar")
+            }
+            GrammarCheckFailure::Roundtrip { input, failure } => {
+                write!(f, "generated sentence {input:?}: {failure}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GrammarCheckFailure {}
+
+/// Parses `input` with `grammar` and checks the result: that it parsed,
+/// that the tree is well-formed, that its tokens and trivia spell out
+/// `input` byte for byte, and that parsing that text again builds the same
+/// tree.
+pub fn check_roundtrip(grammar: &Grammar, input: &str) -> Result<(), RoundtripFailure> {
+    let mut state = ParserState::new(grammar.clone()).with_text(input);
+    if let Some(error) = state.parse().error() {
+        return Err(RoundtripFailure::Parse(error.clone()));
+    }
+    let (alloc, root) = (state.arena(), state.ast().green);
+    alloc
+        .check_invariants(root)
+        .map_err(RoundtripFailure::Invariant)?;
+
+    let rendered = alloc.render(root);
+    if rendered != input {
+        let offset = (input.bytes().zip(rendered.bytes()))
+            .take_while(|(a, b)| a == b)
+            .count();
+        let offset = floor_char_boundary(input, offset);
+        return Err(RoundtripFailure::Text(Divergence {
+            offset,
+            context: before(input, offset),
+            expected: after(input, offset),
+            found: after(&rendered, offset),
+        }));
+    }
+
+    let mut again = ParserState::new(grammar.clone()).with_text(&rendered);
+    if let Some(error) = again.parse().error() {
+        return Err(RoundtripFailure::Parse(error.clone()));
+    }
+    let (again_alloc, again_root) = (again.arena(), again.ast().green);
+    match first_difference(alloc, root, again_alloc, again_root) {
+        None => Ok(()),
+        Some((offset, expected, found)) => Err(RoundtripFailure::Reparse(Divergence {
+            offset,
+            context: before(input, offset),
+            expected: describe(alloc, expected, grammar),
+            found: describe(again_alloc, found, grammar),
+        })),
+    }
+}
+
+/// Checks `grammar` as a whole: that [`Grammar::validate`] finds no errors,
+/// that its left recursion, if any, is of a kind that can be rewritten,
+/// and that `samples` sentences from [`Grammar::generate`] pass
+/// [`check_roundtrip`]. The sentences come from fixed seeds, so a failure
+/// reproduces.
+///
+/// A generated sentence that doesn't parse often means an ordered choice
+/// whose earlier alternative shadows a later one.
+pub fn check_grammar(grammar: &Grammar, samples: usize) -> Result<(), GrammarCheckFailure> {
+    let errors: Vec<_> = (grammar.validate().into_iter())
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .collect();
+    if !errors.is_empty() {
+        return Err(GrammarCheckFailure::Invalid(errors));
+    }
+    grammar
+        .clone()
+        .eliminate_left_recursion()
+        .map_err(GrammarCheckFailure::LeftRecursion)?;
+
+    for seed in 0..samples as u64 {
+        let input = grammar
+            .generate(&mut SplitMix64::new(seed), GenLimits::default())
+            .ok_or(GrammarCheckFailure::NoSentences)?;
+        if let Err(failure) = check_roundtrip(grammar, &input) {
+            return Err(GrammarCheckFailure::Roundtrip { input, failure });
+        }
+    }
+    Ok(())
+}
+
+/// The offset and the nodes where the trees at `a` and `b` first differ, in
+/// text order.
+fn first_difference(
+    a_alloc: &TreeAlloc,
+    a: GreenId,
+    b_alloc: &TreeAlloc,
+    b: GreenId,
+) -> Option<(usize, GreenId, GreenId)> {
+    let mut stack = vec![(a, b, 0)];
+    while let Some((a, b, offset)) = stack.pop() {
+        let (a_node, b_node) = (a_alloc.get_node(a), b_alloc.get_node(b));
+        if (&a_node.tag, a_node.width, &a_node.text) != (&b_node.tag, b_node.width, &b_node.text)
+            || a_node.children.len() != b_node.children.len()
+        {
+            return Some((offset, a, b));
+        }
+        let mut at = offset;
+        let mut children = Vec::with_capacity(a_node.children.len());
+        for (&a, &b) in a_node.children.iter().zip(&b_node.children) {
+            children.push((a, b, at));
+            at += a_alloc.get_node(a).width;
+        }
+        stack.extend(children.into_iter().rev());
+    }
+    None
+}
+
+fn describe(alloc: &TreeAlloc, id: GreenId, grammar: &Grammar) -> String {
+    let node = alloc.get_node(id);
+    let kind = match &node.tag {
+        Tag::Rule(idx) => format!("rule `{}`", grammar.rule_name(*idx).unwrap_or("?")),
+        Tag::Terminal => String::from("token"),
+        Tag::Trivia => String::from("trivia"),
+        Tag::Error(error) => format!("error ({error})"),
+    };
+    let children = node.children.len();
+    match &node.text {
+        Some(text) => format!("{kind} {text:?}"),
+        None => format!("{kind} {} bytes long with {children} children", node.width),
+    }
+}
+
+fn before(text: &str, offset: usize) -> String {
+    let start = floor_char_boundary(text, offset.saturating_sub(CONTEXT));
+    text[start..offset].to_string()
+}
+
+fn after(text: &str, offset: usize) -> String {
+    let end = floor_char_boundary(text, offset + CONTEXT);
+    text[offset..end].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar_dsl::*;
+    use crate::r;
+    use crate::words::{Identifier, Whitespace};
+
+    fn list() -> GrammarNode {
+        t('[') + opt(r!(items)) + t(']')
+    }
+
+    fn items() -> GrammarNode {
+        r!(item) + opt(t(',') + r!(items))
+    }
+
+    fn item() -> GrammarNode {
+        token(r!(name)) | r!(list)
+    }
+
+    fn name() -> GrammarNode {
+        t(Identifier)
+    }
+
+    #[test]
+    fn test_check_roundtrip() {
+        let grammar = Grammar::try_from(r!(list))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        assert_eq!(check_roundtrip(&grammar, "[a, [b,c], []]"), Ok(()));
+        assert_eq!(
+            check_grammar(&grammar, 50).map_err(|e| e.to_string()),
+            Ok(())
+        );
+
+        let Err(RoundtripFailure::Parse(error)) = check_roundtrip(&grammar, "[a b]") else {
+            panic!("a missing comma parsed");
+        };
+        assert!(matches!(error, ParserError::SyntaxError { offset: 3, .. }));
+    }
+
+    #[test]
+    fn test_check_grammar_shadowed_alternative() {
+        // `a` always wins, so the `ab` sentences never parse.
+        let grammar = Grammar::try_from(t("a") | t("ab")).unwrap();
+        let failure = check_grammar(&grammar, 20).unwrap_err();
+        let GrammarCheckFailure::Roundtrip { input, failure } = failure else {
+            panic!("{failure}");
+        };
+        assert_eq!(input, "ab");
+        assert!(matches!(failure, RoundtripFailure::Parse(_)));
+    }
+
+    #[test]
+    fn test_first_difference() {
+        let grammar = Grammar::try_from(r!(list)).unwrap();
+        let parsed = |text: &str| {
+            let mut state = ParserState::new(grammar.clone()).with_text(text);
+            assert!(state.parse().is_complete());
+            state
+        };
+        let (a, b) = (parsed("[a,[b]]"), parsed("[a,[c]]"));
+        let (offset, expected, found) =
+            first_difference(a.arena(), a.ast().green, b.arena(), b.ast().green).unwrap();
+        assert_eq!(offset, 4);
+        assert_eq!(describe(a.arena(), expected, &grammar), "rule `name` \"b\"");
+        assert_eq!(describe(b.arena(), found, &grammar), "rule `name` \"c\"");
+        let c = parsed("[a,[b]]");
+        assert_eq!(
+            first_difference(a.arena(), a.ast().green, c.arena(), c.ast().green),
+            None
+        );
+    }
+}
@@ -1,45 +1,1175 @@
-use std::collections::HashMap;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::ops;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 use dashmap::DashMap;
+use parking_lot::RwLock;
 
-use crate::grammar::GrammarError;
+use crate::grammar::{Grammar, GrammarError, Rule, RuleId};
+use crate::json::{self, Json};
+use crate::parser::ParserState;
 use crate::utils::Span;
 
-type GreenId = usize;
+pub type GreenId = usize;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Tag {
-    Rule(usize),
+    Rule(RuleId),
+    /// A leaf for the text a terminal matched.
+    Terminal,
+    /// A leaf for text skipped by the grammar's trivia matcher.
+    Trivia,
     Error(GrammarError),
 }
 
+/// A green node placed in the text: where it starts, and the nodes it is
+/// nested in. Clones share the parent chain, as do the siblings one
+/// [`RedNode::children`] gives, so placing a node costs the same at any
+/// depth.
+#[derive(Debug, Clone)]
 pub struct RedNode {
-    pub parent: Option<Box<RedNode>>,
+    pub parent: Option<Arc<RedNode>>,
     pub offset: usize,
     pub green: GreenId,
+    /// Which of its parent's children this is; 0 for a root.
+    pub index: usize,
+}
+
+impl RedNode {
+    /// The root of the tree `green` heads, at offset 0.
+    pub fn root(green: GreenId) -> Self {
+        RedNode {
+            parent: None,
+            offset: 0,
+            green,
+            index: 0,
+        }
+    }
+
+    /// The green node this places, from the arena it was built in.
+    pub fn green<'a>(&self, alloc: &'a TreeAlloc) -> &'a GreenNode {
+        alloc.get_node(self.green)
+    }
+
+    /// The text this covers, `offset` to `offset` plus the green node's
+    /// width.
+    pub fn span(&self, alloc: &TreeAlloc) -> Span {
+        Span::new_len(self.offset, self.green(alloc).width)
+    }
+
+    /// The text this covers in `state`'s current text. A node only
+    /// describes the text of the parse it came from: once the text is
+    /// edited, its span may cover other text, or run past the end, in
+    /// which case it is cut short. Take a [`ParserState::snapshot`] to keep
+    /// the text a tree was parsed from.
+    pub fn text(&self, state: &ParserState) -> String {
+        state.text_in(self.span(state.arena()))
+    }
+
+    pub fn child_count(&self, alloc: &TreeAlloc) -> usize {
+        self.green(alloc).children.len()
+    }
+
+    /// The `i`th child, starting where its preceding siblings end.
+    pub fn nth_child(&self, alloc: &TreeAlloc, i: usize) -> Option<RedNode> {
+        self.children(alloc).nth(i)
+    }
+
+    /// The children in text order, each starting where the one before it
+    /// ends.
+    pub fn children<'a>(&'a self, alloc: &'a TreeAlloc) -> impl Iterator<Item = RedNode> + 'a {
+        let mut offset = self.offset;
+        let parent = Arc::new(self.clone());
+        let children = self.green(alloc).children.iter().enumerate();
+        children.map(move |(index, &green)| {
+            let child = RedNode {
+                parent: Some(parent.clone()),
+                offset,
+                green,
+                index,
+            };
+            offset += alloc.get_node(green).width;
+            child
+        })
+    }
+
+    /// The children in text order, with the nodes of synthetic rules
+    /// replaced by their own children, at any depth: the children as the
+    /// grammar was written, before `opt` and repetitions got rules of their
+    /// own. See [`Rule::is_synthetic`].
+    ///
+    /// [`Rule::is_synthetic`]: crate::grammar::Rule::is_synthetic
+    pub fn transparent_children<'a>(
+        &self,
+        alloc: &'a TreeAlloc,
+        grammar: &'a Grammar,
+    ) -> impl Iterator<Item = RedNode> + 'a {
+        let mut stack: Vec<RedNode> = self.children(alloc).collect();
+        stack.reverse();
+        std::iter::from_fn(move || {
+            loop {
+                let node = stack.pop()?;
+                let synthetic = match &node.green(alloc).tag {
+                    Tag::Rule(idx) => grammar.rule(*idx).is_some_and(Rule::is_synthetic),
+                    _ => false,
+                };
+                if !synthetic {
+                    return Some(node);
+                }
+                let children: Vec<_> = node.children(alloc).collect();
+                stack.extend(children.into_iter().rev());
+            }
+        })
+    }
+
+    /// The deepest node under this one, or this one, whose span holds
+    /// `offset`. Spans hold their start but not their end, so an offset
+    /// where two nodes meet goes to the one starting there; at the end of
+    /// this node's span, it goes to the last node ending there. Nodes
+    /// covering no text are passed over. Offsets outside the span are
+    /// clamped to it.
+    pub fn covering_node(&self, alloc: &TreeAlloc, offset: usize) -> RedNode {
+        let end = self.span(alloc).end;
+        let offset = offset.clamp(self.offset, end);
+        let mut node = self.clone();
+        loop {
+            let child = if offset < end {
+                node.find_child(alloc, |span| span.start <= offset && offset < span.end)
+            } else {
+                node.find_child(alloc, |span| span.end == end)
+            };
+            match child {
+                Some(child) => node = child,
+                None => return node,
+            }
+        }
+    }
+
+    /// The smallest node under this one, or this one, whose span holds all
+    /// of `span`. An empty span goes where [`RedNode::covering_node`] puts
+    /// its offset.
+    pub fn covering_node_for_span(&self, alloc: &TreeAlloc, span: Span) -> RedNode {
+        if span.is_empty() {
+            return self.covering_node(alloc, span.start);
+        }
+        let mut node = self.clone();
+        while let Some(child) = node.find_child(alloc, |child| {
+            child.start <= span.start && span.end <= child.end
+        }) {
+            node = child;
+        }
+        node
+    }
+
+    /// The first child covering some text whose span satisfies `pick`,
+    /// found from the green widths without placing the others.
+    fn find_child(&self, alloc: &TreeAlloc, pick: impl Fn(Span) -> bool) -> Option<RedNode> {
+        let mut offset = self.offset;
+        for (index, &green) in self.green(alloc).children.iter().enumerate() {
+            let width = alloc.get_node(green).width;
+            if width > 0 && pick(Span::new_len(offset, width)) {
+                return Some(RedNode {
+                    parent: Some(Arc::new(self.clone())),
+                    offset,
+                    green,
+                    index,
+                });
+            }
+            offset += width;
+        }
+        None
+    }
+
+    /// The trivia before this node's first token that belongs to it: the
+    /// trivia leaves since the previous token, less the ones that are that
+    /// token's trailing trivia.
+    pub fn leading_trivia(&self, alloc: &TreeAlloc) -> Vec<RedNode> {
+        let (mut trivia, token) = self.trivia_run(alloc, RedNode::prev_token);
+        trivia.reverse();
+        if token {
+            let trailing = trailing_len(alloc, &trivia);
+            trivia.drain(..trailing);
+        }
+        trivia
+    }
+
+    /// The trivia after this node's last token that belongs to it: the
+    /// trivia leaves after it up to and including the first one holding a
+    /// line break, or all of them if no token follows.
+    ///
+    /// Trivia is kept in the tree as leaves of its own, so it isn't part
+    /// of a token's span; see [`RedNode::full_span`].
+    pub fn trailing_trivia(&self, alloc: &TreeAlloc) -> Vec<RedNode> {
+        let (mut trivia, token) = self.trivia_run(alloc, RedNode::next_token);
+        if token {
+            trivia.truncate(trailing_len(alloc, &trivia));
+        }
+        trivia
+    }
+
+    /// The span of this node with its leading and trailing trivia.
+    pub fn full_span(&self, alloc: &TreeAlloc) -> Span {
+        let span = self.span(alloc);
+        let start = self
+            .leading_trivia(alloc)
+            .first()
+            .map_or(span.start, |node| node.offset);
+        let end = self
+            .trailing_trivia(alloc)
+            .last()
+            .map_or(span.end, |node| node.span(alloc).end);
+        Span::new(start, end)
+    }
+
+    /// The trivia leaves met stepping from this node with `step`, nearest
+    /// first, and whether a token other than trivia was met after them.
+    fn trivia_run(
+        &self,
+        alloc: &TreeAlloc,
+        step: fn(&RedNode, &TreeAlloc) -> Option<RedNode>,
+    ) -> (Vec<RedNode>, bool) {
+        let mut trivia = Vec::new();
+        let mut at = step(self, alloc);
+        while let Some(leaf) = at {
+            if leaf.green(alloc).tag != Tag::Trivia {
+                return (trivia, true);
+            }
+            at = step(&leaf, alloc);
+            trivia.push(leaf);
+        }
+        (trivia, false)
+    }
+
+    pub fn first_child(&self, alloc: &TreeAlloc) -> Option<RedNode> {
+        self.children(alloc).next()
+    }
+
+    pub fn last_child(&self, alloc: &TreeAlloc) -> Option<RedNode> {
+        let children = &self.green(alloc).children;
+        let index = children.len().checked_sub(1)?;
+        let green = children[index];
+        let span = self.span(alloc);
+        Some(RedNode {
+            parent: Some(Arc::new(self.clone())),
+            offset: span.end - alloc.get_node(green).width,
+            green,
+            index,
+        })
+    }
+
+    /// The child of the same parent after this one, starting where this
+    /// one ends.
+    pub fn next_sibling(&self, alloc: &TreeAlloc) -> Option<RedNode> {
+        let parent = self.parent.as_ref()?;
+        let index = self.index + 1;
+        let &green = parent.green(alloc).children.get(index)?;
+        Some(RedNode {
+            parent: Some(parent.clone()),
+            offset: self.span(alloc).end,
+            green,
+            index,
+        })
+    }
+
+    /// The child of the same parent before this one, ending where this
+    /// one starts.
+    pub fn prev_sibling(&self, alloc: &TreeAlloc) -> Option<RedNode> {
+        let parent = self.parent.as_ref()?;
+        let index = self.index.checked_sub(1)?;
+        let green = parent.green(alloc).children[index];
+        Some(RedNode {
+            parent: Some(parent.clone()),
+            offset: self.offset - alloc.get_node(green).width,
+            green,
+            index,
+        })
+    }
+
+    /// The first leaf after this node that covers some text, trivia
+    /// included, looking into the subtrees that follow. Stepping from
+    /// token to token goes through the whole text.
+    pub fn next_token(&self, alloc: &TreeAlloc) -> Option<RedNode> {
+        let mut node = self.clone();
+        loop {
+            let mut sibling = node.next_sibling(alloc);
+            while let Some(next) = sibling {
+                if next.green(alloc).width > 0 {
+                    let mut leaf = next;
+                    while let Some(first) = leaf.find_child(alloc, |_| true) {
+                        leaf = first;
+                    }
+                    return Some(leaf);
+                }
+                sibling = next.next_sibling(alloc);
+            }
+            node = RedNode::clone(node.parent.as_ref()?);
+        }
+    }
+
+    /// The last leaf before this node that covers some text, like
+    /// [`RedNode::next_token`] backwards.
+    pub fn prev_token(&self, alloc: &TreeAlloc) -> Option<RedNode> {
+        let mut node = self.clone();
+        loop {
+            let mut sibling = node.prev_sibling(alloc);
+            while let Some(prev) = sibling {
+                if prev.green(alloc).width > 0 {
+                    return Some(prev.last_leaf(alloc));
+                }
+                sibling = prev.prev_sibling(alloc);
+            }
+            node = RedNode::clone(node.parent.as_ref()?);
+        }
+    }
+
+    /// The last leaf under this node that covers some text, which the
+    /// node must do itself.
+    fn last_leaf(&self, alloc: &TreeAlloc) -> RedNode {
+        let mut leaf = self.clone();
+        let mut child = leaf.last_child(alloc);
+        while let Some(last) = child {
+            if last.green(alloc).width > 0 {
+                child = last.last_child(alloc);
+                leaf = last;
+            } else {
+                child = last.prev_sibling(alloc);
+            }
+        }
+        leaf
+    }
+
+    /// The root of a tree like this one's but with `green` in place of
+    /// this node. Only the nodes from here up are rebuilt, with their
+    /// widths adjusted; everything else is shared with the old tree.
+    pub fn replace_with(&self, alloc: &TreeAlloc, green: GreenId) -> GreenId {
+        let mut green = green;
+        let mut node = self;
+        while let Some(parent) = node.parent.as_deref() {
+            green = alloc.replace_child(parent.green, node.index, green);
+            node = parent;
+        }
+        green
+    }
+
+    /// The nodes this is nested in, innermost first.
+    pub fn ancestors(&self) -> impl Iterator<Item = &RedNode> {
+        std::iter::successors(self.parent.as_deref(), |node| node.parent.as_deref())
+    }
+
+    /// This node and every node under it, in preorder: each node before
+    /// its children, so starts never decrease.
+    pub fn descendants<'a>(&self, alloc: &'a TreeAlloc) -> impl Iterator<Item = RedNode> + 'a {
+        self.walk(alloc).filter_map(|event| match event {
+            WalkEvent::Enter(node) => Some(node),
+            WalkEvent::Leave(_) => None,
+        })
+    }
+
+    /// [`RedNode::descendants`] whose tag `pred` accepts.
+    pub fn matching_descendants<'a>(
+        &self,
+        alloc: &'a TreeAlloc,
+        pred: impl Fn(&Tag) -> bool + 'a,
+    ) -> impl Iterator<Item = RedNode> + 'a {
+        self.descendants(alloc)
+            .filter(move |node| pred(&node.green(alloc).tag))
+    }
+
+    /// Like [`RedNode::matching_descendants`], but only nodes intersecting
+    /// `range`, as [`Span::intersects`] has it. Subtrees outside it aren't
+    /// looked into.
+    pub fn matching_descendants_in<'a>(
+        &self,
+        alloc: &'a TreeAlloc,
+        range: Span,
+        pred: impl Fn(&Tag) -> bool + 'a,
+    ) -> impl Iterator<Item = RedNode> + 'a {
+        let mut stack = vec![self.clone()];
+        std::iter::from_fn(move || {
+            loop {
+                let node = stack.pop()?;
+                if !node.span(alloc).intersects(range) {
+                    continue;
+                }
+                let children: Vec<_> = node.children(alloc).collect();
+                stack.extend(children.into_iter().rev());
+                if pred(&node.green(alloc).tag) {
+                    return Some(node);
+                }
+            }
+        })
+    }
+
+    /// The nodes of `rule` under this one, or this one, in text order.
+    /// Empty if `grammar` has no such rule.
+    pub fn find_all<'r>(
+        &self,
+        alloc: &TreeAlloc,
+        grammar: &Grammar,
+        rule: impl Into<RuleRef<'r>>,
+    ) -> Vec<RedNode> {
+        self.find_all_in(alloc, grammar, rule, self.span(alloc))
+    }
+
+    /// Like [`RedNode::find_all`], but only nodes intersecting `range`.
+    pub fn find_all_in<'r>(
+        &self,
+        alloc: &TreeAlloc,
+        grammar: &Grammar,
+        rule: impl Into<RuleRef<'r>>,
+        range: Span,
+    ) -> Vec<RedNode> {
+        let idx = match rule.into() {
+            RuleRef::Id(id) => id,
+            RuleRef::Name(name) => match grammar.rule_index(name) {
+                Some(idx) => idx,
+                None => return Vec::new(),
+            },
+        };
+        self.matching_descendants_in(alloc, range, |tag| *tag == Tag::Rule(idx))
+            .collect()
+    }
+
+    /// What the node is, with rule names from `grammar`.
+    pub fn kind(&self, alloc: &TreeAlloc, grammar: &Grammar) -> NodeKind {
+        match &self.green(alloc).tag {
+            Tag::Rule(idx) => NodeKind::Rule(grammar.rule_name(*idx).unwrap_or("?")),
+            Tag::Error(error) => NodeKind::Error(error.clone()),
+            Tag::Terminal | Tag::Trivia => NodeKind::Token,
+        }
+    }
+
+    /// [`RedNode::descendants`] paired with their spans.
+    pub fn descendants_with_spans<'a>(
+        &self,
+        alloc: &'a TreeAlloc,
+    ) -> impl Iterator<Item = (RedNode, Span)> + 'a {
+        self.descendants(alloc).map(|node| {
+            let span = node.span(alloc);
+            (node, span)
+        })
+    }
+
+    /// Enters and leaves this node and every node under it, in text order.
+    /// Leave events alone give postorder. The walk keeps its own stack, so
+    /// the depth of the tree is bounded only by memory.
+    pub fn walk<'a>(&self, alloc: &'a TreeAlloc) -> impl Iterator<Item = WalkEvent> + 'a {
+        let mut stack = vec![WalkEvent::Enter(self.clone())];
+        std::iter::from_fn(move || {
+            let event = stack.pop()?;
+            if let WalkEvent::Enter(node) = &event {
+                stack.push(WalkEvent::Leave(node.clone()));
+                let children: Vec<_> = node.children(alloc).collect();
+                stack.extend(children.into_iter().rev().map(WalkEvent::Enter));
+            }
+            Some(event)
+        })
+    }
+}
+
+impl Drop for RedNode {
+    /// Unlinks the parents this was the last holder of one by one, so a
+    /// deep chain doesn't drop recursively.
+    fn drop(&mut self) {
+        let mut parent = self.parent.take();
+        while let Some(node) = parent {
+            parent = match Arc::try_unwrap(node) {
+                Ok(mut node) => node.parent.take(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+/// How many of `trivia`, which follows a token, trail it: up to and
+/// including the first piece holding a line break.
+fn trailing_len(alloc: &TreeAlloc, trivia: &[RedNode]) -> usize {
+    trivia
+        .iter()
+        .position(|node| {
+            let text = node.green(alloc).text.as_deref();
+            text.is_some_and(|text| text.contains('\n'))
+        })
+        .map_or(trivia.len(), |i| i + 1)
+}
+
+/// A rule to [`RedNode::find_all`], by name or by id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleRef<'a> {
+    Name(&'a str),
+    Id(RuleId),
+}
+
+impl<'a> From<&'a str> for RuleRef<'a> {
+    fn from(name: &'a str) -> Self {
+        RuleRef::Name(name)
+    }
+}
+
+impl From<RuleId> for RuleRef<'_> {
+    fn from(id: RuleId) -> Self {
+        RuleRef::Id(id)
+    }
+}
+
+/// What a node is, from [`RedNode::kind`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeKind {
+    /// A node of the named rule, token rules included.
+    Rule(&'static str),
+    Error(GrammarError),
+    /// A terminal or trivia leaf.
+    Token,
+}
+
+/// A step of [`RedNode::walk`].
+#[derive(Debug, Clone)]
+pub enum WalkEvent {
+    /// The node is reached, before any node under it.
+    Enter(RedNode),
+    /// Every node under the node has been left.
+    Leave(RedNode),
+}
+
+/// What [`walk`] does after a [`TreeVisitor`] hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisitFlow {
+    Continue,
+    /// Goes on past the node's children without visiting them. The same as
+    /// `Continue` from [`TreeVisitor::leave_node`].
+    SkipChildren,
+    /// Stops the walk, leaving no more nodes.
+    Stop,
+}
+
+/// Hooks [`walk`] calls on each node of a tree, both doing nothing unless
+/// overridden.
+pub trait TreeVisitor {
+    fn enter_node(&mut self, _node: &RedNode, _tag: &Tag, _span: Span) -> VisitFlow {
+        VisitFlow::Continue
+    }
+
+    /// Called after the node's children, or right after
+    /// [`TreeVisitor::enter_node`] if they were skipped.
+    fn leave_node(&mut self, _node: &RedNode, _tag: &Tag, _span: Span) -> VisitFlow {
+        VisitFlow::Continue
+    }
+}
+
+/// Visits the tree under `root` in preorder, without recursing, calling
+/// `visitor` on entering and leaving each node.
+pub fn walk(root: &RedNode, alloc: &TreeAlloc, visitor: &mut impl TreeVisitor) {
+    let mut stack = vec![WalkEvent::Enter(root.clone())];
+    while let Some(event) = stack.pop() {
+        let (WalkEvent::Enter(node) | WalkEvent::Leave(node)) = &event;
+        let (tag, span) = (&node.green(alloc).tag, node.span(alloc));
+        let flow = match &event {
+            WalkEvent::Enter(_) => visitor.enter_node(node, tag, span),
+            WalkEvent::Leave(_) => visitor.leave_node(node, tag, span),
+        };
+        match (flow, event) {
+            (VisitFlow::Stop, _) => return,
+            (flow, WalkEvent::Enter(node)) => {
+                let children: Vec<_> = match flow {
+                    VisitFlow::Continue => node.children(alloc).collect(),
+                    _ => Vec::new(),
+                };
+                stack.push(WalkEvent::Leave(node));
+                stack.extend(children.into_iter().rev().map(WalkEvent::Enter));
+            }
+            (_, WalkEvent::Leave(_)) => {}
+        }
+    }
+}
+
+/// A [`TreeVisitor`] collecting the spans of the nodes of each rule, in
+/// text order.
+#[derive(Debug, Clone, Default)]
+pub struct RuleSpans {
+    pub spans: HashMap<RuleId, Vec<Span>>,
+}
+
+impl TreeVisitor for RuleSpans {
+    fn enter_node(&mut self, _node: &RedNode, tag: &Tag, span: Span) -> VisitFlow {
+        if let Tag::Rule(idx) = tag {
+            self.spans.entry(*idx).or_default().push(span);
+        }
+        VisitFlow::Continue
+    }
+}
+
+/// A tree shown with the names of its rules from the grammar, one node per
+/// line indented by depth, rather than as bare [`Tag`]s. `Debug` shows the
+/// same.
+pub struct GrammarResolvedTree<'a> {
+    root: &'a RedNode,
+    grammar: &'a Grammar,
+    alloc: &'a TreeAlloc,
+}
+
+impl<'a> GrammarResolvedTree<'a> {
+    pub fn new(root: &'a RedNode, grammar: &'a Grammar, alloc: &'a TreeAlloc) -> Self {
+        Self {
+            root,
+            grammar,
+            alloc,
+        }
+    }
+
+    fn label(&self, tag: &Tag) -> String {
+        let name = |idx| {
+            self.grammar
+                .rule_name(idx)
+                .map_or(idx.to_string(), String::from)
+        };
+        match tag {
+            Tag::Rule(idx) => name(*idx),
+            Tag::Terminal => "Terminal".into(),
+            Tag::Trivia => "Trivia".into(),
+            Tag::Error(GrammarError::RuleMismatch { expected }) => {
+                format!("ERROR(expected {})", name(*expected))
+            }
+            Tag::Error(error) => format!("ERROR({error})"),
+        }
+    }
+}
+
+impl fmt::Display for GrammarResolvedTree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut depth = 0;
+        for event in self.root.walk(self.alloc) {
+            let node = match event {
+                WalkEvent::Enter(node) => node,
+                WalkEvent::Leave(_) => {
+                    depth -= 1;
+                    continue;
+                }
+            };
+            let green = node.green(self.alloc);
+            let span = node.span(self.alloc);
+            let indent = "  ".repeat(depth);
+            write!(
+                f,
+                "{indent}{} {}..{}",
+                self.label(&green.tag),
+                span.start,
+                span.end
+            )?;
+            match &green.text {
+                Some(text) => writeln!(f, " {text:?}")?,
+                None => writeln!(f)?,
+            }
+            depth += 1;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for GrammarResolvedTree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// How [`render_sexpr`] lays out a tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SexprOptions {
+    /// One node per line, indented by depth, rather than all on one line.
+    pub pretty: bool,
+    /// Follow each node with its span, as `@start..end`.
+    pub spans: bool,
+    /// Leave out the nodes of synthetic rules, with their children in
+    /// their place, as [`RedNode::transparent_children`] does.
+    pub transparent: bool,
+}
+
+/// The tree under `root`, parsed from `text`, as an S-expression: rules as
+/// `(name child ...)` with names from `grammar`, error nodes as
+/// `(ERROR ...)` and tokens as their quoted text. Trivia is left out.
+pub fn render_sexpr(
+    root: &RedNode,
+    alloc: &TreeAlloc,
+    grammar: &Grammar,
+    text: &str,
+    options: SexprOptions,
+) -> String {
+    let mut out = String::new();
+    let mut depth = 0;
+    for event in root.walk(alloc) {
+        let (WalkEvent::Enter(node) | WalkEvent::Leave(node)) = &event;
+        let green = node.green(alloc);
+        let branch = matches!(green.tag, Tag::Rule(_)) || !green.children.is_empty();
+        let hidden = match green.tag {
+            Tag::Rule(idx) => {
+                options.transparent && grammar.rule(idx).is_some_and(Rule::is_synthetic)
+            }
+            Tag::Trivia => true,
+            _ => false,
+        };
+        if hidden {
+            continue;
+        }
+        if let WalkEvent::Leave(_) = event {
+            if branch {
+                out.push(')');
+                depth -= 1;
+            }
+            continue;
+        }
+        if options.pretty && !out.is_empty() {
+            out.push('\n');
+            out += &"  ".repeat(depth);
+        } else if !out.is_empty() && !out.ends_with('(') {
+            out.push(' ');
+        }
+        let span = node.span(alloc);
+        let at = match options.spans {
+            true => format!("@{}..{}", span.start, span.end),
+            false => String::new(),
+        };
+        let slice = text.get(span.start..span.end).unwrap_or_default();
+        match &green.tag {
+            Tag::Rule(idx) => {
+                let name = grammar.rule_name(*idx).unwrap_or("?");
+                out += &format!("({name}{at}");
+            }
+            Tag::Error(_) if branch => out += &format!("(ERROR{at}"),
+            Tag::Error(_) => out += &format!("(ERROR{at} {slice:?})"),
+            Tag::Terminal | Tag::Trivia => out += &format!("{slice:?}{at}"),
+        }
+        if branch {
+            depth += 1;
+        }
+    }
+    out
+}
+
+/// The tree under `root`, parsed from `text`, as JSON. Each node is an
+/// object with its `id`, a `kind` of `rule`, `token`, `trivia` or `error`,
+/// a `name` for rules and errors, its `span`, the `text` of leaves and its
+/// `children`. Nodes shared in the arena appear wherever they are in the
+/// tree, with the same `id`.
+pub fn to_json(root: &RedNode, alloc: &TreeAlloc, grammar: &Grammar, text: &str) -> Json {
+    // The fields of each node entered but not left, `children` last.
+    let mut stack: Vec<Vec<(String, Json)>> = Vec::new();
+    for event in root.walk(alloc) {
+        match event {
+            WalkEvent::Enter(node) => {
+                let mut fields = json_fields(&node, alloc, grammar, text);
+                fields.push(("children".into(), Json::Array(Vec::new())));
+                stack.push(fields);
+            }
+            WalkEvent::Leave(_) => {
+                let node = Json::Object(stack.pop().expect("entered before left"));
+                match stack.last_mut().and_then(|parent| parent.last_mut()) {
+                    Some((_, Json::Array(siblings))) => siblings.push(node),
+                    _ => return node,
+                }
+            }
+        }
+    }
+    unreachable!("the walk leaves the root last")
+}
+
+/// Writes what [`to_json`] gives to `out`, without building it first.
+pub fn write_json(
+    root: &RedNode,
+    alloc: &TreeAlloc,
+    grammar: &Grammar,
+    text: &str,
+    out: &mut impl Write,
+) -> fmt::Result {
+    let mut first = true;
+    for event in root.walk(alloc) {
+        match event {
+            WalkEvent::Enter(node) => {
+                if !first {
+                    out.write_char(',')?;
+                }
+                out.write_char('{')?;
+                for (key, value) in json_fields(&node, alloc, grammar, text) {
+                    json::write_escaped(out, &key)?;
+                    write!(out, ":{value},")?;
+                }
+                out.write_str("\"children\":[")?;
+                first = true;
+                continue;
+            }
+            WalkEvent::Leave(_) => out.write_str("]}")?,
+        }
+        first = false;
+    }
+    Ok(())
+}
+
+/// The fields of `node` in [`to_json`], all but its children.
+fn json_fields(
+    node: &RedNode,
+    alloc: &TreeAlloc,
+    grammar: &Grammar,
+    text: &str,
+) -> Vec<(String, Json)> {
+    let green = node.green(alloc);
+    let span = node.span(alloc);
+    let name = |name: &str| Some(Json::String(name.into()));
+    let (kind, name) = match &green.tag {
+        Tag::Rule(idx) => ("rule", name(grammar.rule_name(*idx).unwrap_or("?"))),
+        Tag::Terminal => ("token", None),
+        Tag::Trivia => ("trivia", None),
+        Tag::Error(GrammarError::Placeholder) => ("error", name("placeholder")),
+        Tag::Error(GrammarError::RuleMismatch { .. }) => ("error", name("rule_mismatch")),
+        Tag::Error(GrammarError::TokenMismatch { .. }) => ("error", name("token_mismatch")),
+    };
+    let mut fields = vec![
+        ("id".into(), Json::Int(node.green as i64)),
+        ("kind".into(), Json::String(kind.into())),
+    ];
+    fields.extend(name.map(|name| ("name".into(), name)));
+    fields.push((
+        "span".into(),
+        Json::Array(vec![
+            Json::Int(span.start as i64),
+            Json::Int(span.end as i64),
+        ]),
+    ));
+    if green.children.is_empty() {
+        let leaf = text.get(span.start..span.end).unwrap_or_default();
+        fields.push(("text".into(), Json::String(leaf.into())));
+    }
+    fields
+}
+
+/// What happened to a subtree between two trees, in a [`TreeDiff`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiffKind {
+    /// Only in the new tree.
+    Inserted,
+    /// Only in the old tree.
+    Deleted,
+    /// In both with the same tag, but not the same below.
+    Replaced,
+    /// In both the same, at another offset.
+    Moved,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DiffEntry {
+    pub kind: DiffKind,
+    /// `None` if inserted.
+    pub old_span: Option<Span>,
+    /// `None` if deleted.
+    pub new_span: Option<Span>,
+    pub tag: Tag,
+}
+
+/// The subtrees that differ between two trees, from [`diff`], in text
+/// order, parents before children.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeDiff {
+    pub entries: Vec<DiffEntry>,
+}
+
+impl TreeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// A step of [`diff`]: comparing two nodes, or listing an entry found.
+enum DiffStep {
+    Compare(RedNode, RedNode),
+    Emit(DiffEntry),
+}
+
+/// How the tree under `new_root` differs from the one under `old_root`.
+///
+/// Nodes with the same [`GreenId`] are the same all the way down, so only
+/// the parts of the trees that differ are walked. Children are matched up
+/// by id from either end; those left between are paired in order while
+/// their tags agree and compared further, the rest deleted and inserted. A
+/// replaced node is listed along with what changed below it, so an edit
+/// to a token gives the token and each of its ancestors.
+pub fn diff(old_root: &RedNode, new_root: &RedNode, alloc: &TreeAlloc) -> TreeDiff {
+    let entry = |kind, old: Option<&RedNode>, new: Option<&RedNode>| DiffEntry {
+        kind,
+        old_span: old.map(|node| node.span(alloc)),
+        new_span: new.map(|node| node.span(alloc)),
+        tag: new
+            .or(old)
+            .expect("one side is there")
+            .green(alloc)
+            .tag
+            .clone(),
+    };
+    let mut entries = Vec::new();
+    let mut stack = vec![DiffStep::Compare(old_root.clone(), new_root.clone())];
+    while let Some(step) = stack.pop() {
+        let (old, new) = match step {
+            DiffStep::Compare(old, new) => (old, new),
+            DiffStep::Emit(entry) => {
+                entries.push(entry);
+                continue;
+            }
+        };
+        if old.green == new.green {
+            if old.offset != new.offset {
+                entries.push(entry(DiffKind::Moved, Some(&old), Some(&new)));
+            }
+            continue;
+        }
+        if old.green(alloc).tag != new.green(alloc).tag {
+            entries.push(entry(DiffKind::Deleted, Some(&old), None));
+            entries.push(entry(DiffKind::Inserted, None, Some(&new)));
+            continue;
+        }
+        entries.push(entry(DiffKind::Replaced, Some(&old), Some(&new)));
+
+        let old_children: Vec<_> = old.children(alloc).collect();
+        let new_children: Vec<_> = new.children(alloc).collect();
+        let same = |(a, b): (&RedNode, &RedNode)| a.green == b.green;
+        let pairs = old_children.iter().zip(&new_children);
+        let prefix = pairs.take_while(|&pair| same(pair)).count();
+        let pairs = old_children[prefix..]
+            .iter()
+            .rev()
+            .zip(new_children[prefix..].iter().rev());
+        let suffix = pairs.take_while(|&pair| same(pair)).count();
+        let old_middle = &old_children[prefix..old_children.len() - suffix];
+        let new_middle = &new_children[prefix..new_children.len() - suffix];
+        let paired = old_middle.iter().zip(new_middle);
+        let paired = paired
+            .take_while(|(a, b)| a.green(alloc).tag == b.green(alloc).tag)
+            .count();
+
+        // Steps in text order, pushed last first to be taken first.
+        let mut steps = Vec::new();
+        let pairs = old_children.iter().zip(&new_children);
+        let compare = |(a, b): (&RedNode, &RedNode)| DiffStep::Compare(a.clone(), b.clone());
+        steps.extend(pairs.take(prefix + paired).map(compare));
+        for old in &old_middle[paired..] {
+            steps.push(DiffStep::Emit(entry(DiffKind::Deleted, Some(old), None)));
+        }
+        for new in &new_middle[paired..] {
+            steps.push(DiffStep::Emit(entry(DiffKind::Inserted, None, Some(new))));
+        }
+        let old_suffix = &old_children[old_children.len() - suffix..];
+        let new_suffix = &new_children[new_children.len() - suffix..];
+        steps.extend(old_suffix.iter().zip(new_suffix).map(compare));
+        stack.extend(steps.into_iter().rev());
+    }
+    TreeDiff { entries }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct GreenNode {
     pub tag: Tag,
+    /// Length in input units: bytes for text and binary input, tokens for
+    /// token streams.
     pub width: usize,
     pub children: Vec<GreenId>,
+    /// The text a token covers, for leaves built with
+    /// [`TreeAlloc::alloc_token`]; `None` for other nodes.
+    pub text: Option<Arc<str>>,
 }
 
-pub(crate) struct TreeAlloc {
+pub struct TreeAlloc {
     nodes: boxcar::Vec<GreenNode>,
-    dedup: DashMap<u64, Vec<usize>>,
+    /// One copy of each token text, shared by every token holding it.
+    strings: DashMap<Arc<str>, ()>,
+    /// Nodes by a 128-bit hash, wide enough that two different nodes
+    /// sharing one is left to chance and only counted.
+    dedup: Box<[DedupShard]>,
+    /// Entries `dedup` stops growing at.
+    dedup_capacity: usize,
+    dedup_entries: AtomicUsize,
+}
+
+/// How many parts the dedup index is split into, by the top bits of the
+/// node hashes, so threads allocating different nodes rarely wait on
+/// each other.
+const DEDUP_SHARDS: usize = 64;
+
+/// One part of the dedup index, aligned to keep its lock and counters off
+/// the cache lines of the others.
+#[repr(align(128))]
+#[derive(Default)]
+struct DedupShard {
+    map: RwLock<HashMap<u128, GreenId, BuildHasherDefault<Prehashed>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    collisions: AtomicU64,
+}
+
+/// Hashes a `u128` that is already a hash to part of itself rather than
+/// hashing it again.
+#[derive(Default)]
+struct Prehashed(u64);
+
+impl Hasher for Prehashed {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ byte as u64;
+        }
+    }
+
+    fn write_u128(&mut self, n: u128) {
+        self.0 = n as u64;
+    }
+}
+
+/// How far a [`TreeAlloc`] had got, from [`TreeAlloc::begin`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocMark {
+    nodes: usize,
+}
+
+/// A way a green tree is malformed, from [`TreeAlloc::check_invariants`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeInvariantError {
+    /// The node's width isn't the sum of its children's, or the length of
+    /// its text for a token.
+    WidthMismatch {
+        node: GreenId,
+        expected: usize,
+        actual: usize,
+    },
+    /// The node has a child id that isn't in the arena.
+    DanglingChild { node: GreenId, child: GreenId },
+    /// The node is under itself.
+    Cycle { node: GreenId },
+}
+
+impl fmt::Display for TreeInvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeInvariantError::WidthMismatch {
+                node,
+                expected,
+                actual,
+            } => write!(f, "node {node} has width {actual}, expected {expected}"),
+            TreeInvariantError::DanglingChild { node, child } => {
+                write!(f, "node {node} has child {child}, which isn't in the arena")
+            }
+            TreeInvariantError::Cycle { node } => write!(f, "node {node} is under itself"),
+        }
+    }
+}
+
+impl std::error::Error for TreeInvariantError {}
+
+/// Roughly how much memory a [`TreeAlloc`] takes, from
+/// [`TreeAlloc::memory_usage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    pub nodes: usize,
+    /// The nodes with their children lists.
+    pub node_bytes: usize,
+    /// Distinct token texts, each stored once.
+    pub interned_strings: usize,
+    pub string_bytes: usize,
+    pub dedup_entries: usize,
+    pub dedup_bytes: usize,
+}
+
+impl AllocStats {
+    pub fn total_bytes(&self) -> usize {
+        self.node_bytes + self.string_bytes + self.dedup_bytes
+    }
+}
+
+/// What the dedup index of a [`TreeAlloc`] has done, from
+/// [`TreeAlloc::dedup_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Nodes indexed.
+    pub entries: usize,
+    /// Nodes found already in the arena.
+    pub hits: u64,
+    /// Nodes added to the arena, whether indexed or not.
+    pub misses: u64,
+    /// Nodes added because a different node had the same hash.
+    pub collisions: u64,
+}
+
+impl Default for TreeAlloc {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TreeAlloc {
     pub fn new() -> Self {
         Self {
             nodes: boxcar::Vec::new(),
-            dedup: DashMap::new(),
+            strings: DashMap::new(),
+            dedup: (0..DEDUP_SHARDS).map(|_| DedupShard::default()).collect(),
+            dedup_capacity: usize::MAX,
+            dedup_entries: AtomicUsize::new(0),
+        }
+    }
+
+    /// Stops indexing nodes for reuse once `capacity` are, bounding the
+    /// memory the index takes. Nodes after that are added anew each time,
+    /// so equal nodes may have different ids.
+    pub fn with_dedup_capacity(mut self, capacity: usize) -> Self {
+        self.dedup_capacity = capacity;
+        self
+    }
+
+    /// Counts the memory the nodes and the dedup index take, walking every
+    /// node. Allocator overhead is left out.
+    pub fn memory_usage(&self) -> AllocStats {
+        let node_bytes = (self.nodes.iter())
+            .map(|(_, node)| {
+                let children = node.children.capacity() * size_of::<GreenId>();
+                let error = match &node.tag {
+                    Tag::Error(GrammarError::TokenMismatch { expected, .. }) => expected.capacity(),
+                    _ => 0,
+                };
+                size_of::<GreenNode>() + children + error
+            })
+            .sum();
+        let string_bytes = (self.strings.iter())
+            .map(|entry| size_of::<(Arc<str>, ())>() + entry.key().len())
+            .sum();
+        let maps = self.dedup.iter().map(|shard| shard.map.read());
+        let (dedup_entries, dedup_capacity) = maps.fold((0, 0), |(len, capacity), map| {
+            (len + map.len(), capacity + map.capacity())
+        });
+        AllocStats {
+            nodes: self.len(),
+            node_bytes,
+            interned_strings: self.interned_strings(),
+            string_bytes,
+            dedup_entries,
+            dedup_bytes: dedup_capacity * size_of::<(u128, GreenId)>(),
+        }
+    }
+
+    /// How many distinct token texts the arena holds.
+    pub fn interned_strings(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn dedup_stats(&self) -> DedupStats {
+        let sum = |count: fn(&DedupShard) -> &AtomicU64| {
+            self.dedup
+                .iter()
+                .map(|shard| count(shard).load(Ordering::Relaxed))
+                .sum()
+        };
+        DedupStats {
+            entries: self.dedup_entries.load(Ordering::Relaxed),
+            hits: sum(|shard| &shard.hits),
+            misses: sum(|shard| &shard.misses),
+            collisions: sum(|shard| &shard.collisions),
         }
     }
 
@@ -47,32 +1177,1502 @@ impl TreeAlloc {
         &self.nodes[id]
     }
 
+    /// How many nodes the arena holds.
+    pub fn len(&self) -> usize {
+        self.nodes.count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn alloc(&self, tag: Tag, children: Vec<GreenId>, width: usize) -> GreenId {
-        let node = GreenNode {
+        self.intern(tag, children, width).0
+    }
+
+    /// Like [`TreeAlloc::alloc`], also telling whether the node is new
+    /// rather than one already in the arena.
+    pub fn intern(&self, tag: Tag, children: Vec<GreenId>, width: usize) -> (GreenId, bool) {
+        self.insert(GreenNode {
             tag,
             children,
             width,
+            text: None,
+        })
+    }
+
+    /// Allocates a leaf holding the text it covers, so the tree can be
+    /// rendered without the document. Identical tokens share a node, and
+    /// equal texts one allocation.
+    pub fn alloc_token(&self, tag: Tag, text: &str) -> GreenId {
+        self.intern_token(tag, text).0
+    }
+
+    /// Like [`TreeAlloc::alloc_token`], also telling whether the node is
+    /// new rather than one already in the arena.
+    pub fn intern_token(&self, tag: Tag, text: &str) -> (GreenId, bool) {
+        self.insert(GreenNode {
+            tag,
+            children: vec![],
+            width: text.len(),
+            text: Some(self.intern_str(text)),
+        })
+    }
+
+    /// The arena's copy of `text`, made if it has none.
+    fn intern_str(&self, text: &str) -> Arc<str> {
+        if let Some(entry) = self.strings.get(text) {
+            return entry.key().clone();
+        }
+        let entry = self.strings.entry(Arc::from(text)).or_insert(());
+        entry.key().clone()
+    }
+
+    fn insert(&self, node: GreenNode) -> (GreenId, bool) {
+        let half = |seed: u64| {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            node.hash(&mut hasher);
+            hasher.finish() as u128
         };
+        let hash = half(0) << 64 | half(1);
 
-        let mut hasher = DefaultHasher::new();
-        node.hash(&mut hasher);
-        let hash = hasher.finish();
+        let shard = &self.dedup[(hash >> (128 - DEDUP_SHARDS.ilog2())) as usize];
 
-        if let Some(indices) = self.dedup.get(&hash) {
-            for &idx in indices.iter() {
-                if self.nodes[idx] == node {
-                    return idx;
-                }
+        // Nodes already there, the common case when reparsing, only need
+        // the shard read.
+        if let Some(&idx) = shard.map.read().get(&hash) {
+            return self.found(shard, idx, node);
+        }
+        let mut map = shard.map.write();
+        let vacant = match map.entry(hash) {
+            Entry::Occupied(entry) => {
+                let idx = *entry.get();
+                drop(map);
+                return self.found(shard, idx, node);
             }
+            Entry::Vacant(entry) => entry,
+        };
+        shard.misses.fetch_add(1, Ordering::Relaxed);
+        let idx = self.nodes.push(node);
+        let entries = self
+            .dedup_entries
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < self.dedup_capacity).then_some(n + 1)
+            });
+        if entries.is_ok() {
+            vacant.insert(idx);
         }
+        (idx, true)
+    }
 
-        let idx = self.nodes.count();
-        self.nodes.push(node);
-        self.dedup.entry(hash).or_default().push(idx);
-        idx
+    /// [`TreeAlloc::insert`] once the index has `idx` for the hash of
+    /// `node`.
+    fn found(&self, shard: &DedupShard, idx: GreenId, node: GreenNode) -> (GreenId, bool) {
+        if self.nodes[idx] == node {
+            shard.hits.fetch_add(1, Ordering::Relaxed);
+            return (idx, false);
+        }
+        shard.collisions.fetch_add(1, Ordering::Relaxed);
+        shard.misses.fetch_add(1, Ordering::Relaxed);
+        (self.nodes.push(node), true)
     }
 
     pub fn new_placeholder(&self, width: usize) -> GreenId {
         self.alloc(Tag::Error(GrammarError::Placeholder), vec![], width)
     }
+
+    /// A copy of `parent` with its `index`th child replaced by `child`, the
+    /// width adjusted to match. The other children are shared, and if the
+    /// result is a node already in the arena its id is returned.
+    ///
+    /// # Panics
+    ///
+    /// If `parent` has no `index`th child.
+    pub fn replace_child(&self, parent: GreenId, index: usize, child: GreenId) -> GreenId {
+        self.splice_child(parent, index..index + 1, Some(child))
+    }
+
+    /// A copy of `parent` with `child` inserted before its `index`th child,
+    /// or after the last if `index` is the child count, like
+    /// [`TreeAlloc::replace_child`].
+    ///
+    /// # Panics
+    ///
+    /// If `index` is past the child count.
+    pub fn insert_child(&self, parent: GreenId, index: usize, child: GreenId) -> GreenId {
+        self.splice_child(parent, index..index, Some(child))
+    }
+
+    /// A copy of `parent` without its `index`th child, like
+    /// [`TreeAlloc::replace_child`].
+    ///
+    /// # Panics
+    ///
+    /// If `parent` has no `index`th child.
+    pub fn remove_child(&self, parent: GreenId, index: usize) -> GreenId {
+        self.splice_child(parent, index..index + 1, None)
+    }
+
+    fn splice_child(
+        &self,
+        parent: GreenId,
+        range: std::ops::Range<usize>,
+        child: Option<GreenId>,
+    ) -> GreenId {
+        let node = self.get_node(parent);
+        let mut children = node.children.clone();
+        let removed: usize = children
+            .splice(range, child)
+            .map(|id| self.get_node(id).width)
+            .sum();
+        let added = child.map_or(0, |id| self.get_node(id).width);
+        self.alloc(node.tag.clone(), children, node.width - removed + added)
+    }
+
+    /// Marks the arena as it is now, for [`TreeAlloc::rollback`] to go
+    /// back to.
+    pub fn begin(&self) -> AllocMark {
+        AllocMark { nodes: self.len() }
+    }
+
+    /// Drops the nodes allocated since `mark`, returning how many. Their
+    /// ids will be given to the next nodes allocated, so none of them may
+    /// still be held: not in a tree kept, a parse cache or a snapshot.
+    /// Taking the arena mutably keeps other threads from allocating
+    /// meanwhile, but the ids are the caller's to account for.
+    ///
+    /// Rebuilds the arena's storage, so it costs as much as compacting.
+    pub fn rollback(&mut self, mark: AllocMark) -> usize {
+        let count = self.len();
+        if mark.nodes >= count {
+            return 0;
+        }
+        let nodes = std::mem::take(&mut self.nodes);
+        self.nodes = nodes.into_iter().take(mark.nodes).collect();
+        let mut entries = 0;
+        for shard in self.dedup.iter_mut() {
+            let map = shard.map.get_mut();
+            map.retain(|_, &mut id| id < mark.nodes);
+            entries += map.len();
+        }
+        *self.dedup_entries.get_mut() = entries;
+        // Texts only the interner still holds were the dropped nodes'.
+        self.strings.retain(|text, _| Arc::strong_count(text) > 1);
+        count - mark.nodes
+    }
+
+    /// Checks the tree under `root` is well formed: every child is in the
+    /// arena, no node is under itself, and each node's width is the sum of
+    /// its children's, or its text's length for tokens. Nodes shared in the
+    /// tree are checked once. A `root` not in the arena is reported as its
+    /// own dangling child.
+    pub fn check_invariants(&self, root: GreenId) -> Result<(), TreeInvariantError> {
+        let count = self.len();
+        if root >= count {
+            return Err(TreeInvariantError::DanglingChild {
+                node: root,
+                child: root,
+            });
+        }
+        // Nodes whose children are being checked, and nodes done.
+        let mut open = HashSet::new();
+        let mut done = HashSet::new();
+        let mut stack = vec![(root, false)];
+        while let Some((id, children_done)) = stack.pop() {
+            let node = self.get_node(id);
+            if children_done {
+                let expected = match &node.text {
+                    Some(text) if node.children.is_empty() => text.len(),
+                    None if node.children.is_empty() => node.width,
+                    _ => node
+                        .children
+                        .iter()
+                        .map(|&child| self.get_node(child).width)
+                        .sum(),
+                };
+                if expected != node.width {
+                    return Err(TreeInvariantError::WidthMismatch {
+                        node: id,
+                        expected,
+                        actual: node.width,
+                    });
+                }
+                open.remove(&id);
+                done.insert(id);
+                continue;
+            }
+            if done.contains(&id) {
+                continue;
+            }
+            open.insert(id);
+            stack.push((id, true));
+            for &child in &node.children {
+                if child >= count {
+                    return Err(TreeInvariantError::DanglingChild { node: id, child });
+                }
+                if open.contains(&child) {
+                    return Err(TreeInvariantError::Cycle { node: child });
+                }
+                stack.push((child, false));
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the trees under `roots` into a new arena, leaving behind the
+    /// nodes nothing kept refers to. Returns it with where each node kept
+    /// ended up.
+    pub fn compact(&self, roots: &[GreenId]) -> (TreeAlloc, CompactionMap) {
+        let fresh = TreeAlloc::new().with_dedup_capacity(self.dedup_capacity);
+        let mut map = HashMap::new();
+        // Children are copied before their parent, `true` marking a node
+        // whose children are done.
+        let mut stack: Vec<_> = roots.iter().map(|&root| (root, false)).collect();
+        while let Some((id, done)) = stack.pop() {
+            if map.contains_key(&id) {
+                continue;
+            }
+            let node = self.get_node(id);
+            if !done {
+                stack.push((id, true));
+                stack.extend(node.children.iter().map(|&child| (child, false)));
+                continue;
+            }
+            let copy = GreenNode {
+                tag: node.tag.clone(),
+                children: node.children.iter().map(|child| map[child]).collect(),
+                width: node.width,
+                text: node.text.as_deref().map(|text| fresh.intern_str(text)),
+            };
+            map.insert(id, fresh.insert(copy).0);
+        }
+        (fresh, CompactionMap(map))
+    }
+
+    /// Drops every node not under `roots`, like [`TreeAlloc::compact`] in
+    /// place. Ids from before are only good through the map returned.
+    pub fn collect(&mut self, roots: &[GreenId]) -> CompactionMap {
+        let (fresh, map) = self.compact(roots);
+        *self = fresh;
+        map
+    }
+
+    /// The texts of the tokens under `id`, in order. For a tree the parser
+    /// built this is the text it parsed.
+    pub fn render(&self, id: GreenId) -> String {
+        let mut text = String::new();
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let node = self.get_node(id);
+            if let Some(token) = &node.text {
+                text.push_str(token);
+            }
+            stack.extend(node.children.iter().rev());
+        }
+        text
+    }
+}
+
+/// Where [`TreeAlloc::compact`] put the nodes it kept.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionMap(HashMap<GreenId, GreenId>);
+
+impl CompactionMap {
+    /// The new id of node `old`, or `None` if it wasn't kept.
+    pub fn get(&self, old: GreenId) -> Option<GreenId> {
+        self.0.get(&old).copied()
+    }
+
+    /// How many nodes were kept.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Nodes built speculatively over a [`TreeAlloc`], which gets only those
+/// [`Staging::promote`]d. The parse engine stages what it builds, truncates
+/// the staging when a choice alternative fails, and promotes the tree it
+/// keeps, so alternatives given up on never reach the shared arena.
+///
+/// Staged ids have the top bit set, so they can't be taken for arena ids.
+/// A staged node's children may be of either kind.
+pub(crate) struct Staging<'a> {
+    arena: &'a TreeAlloc,
+    nodes: Vec<GreenNode>,
+    /// The arena ids of the staged nodes promoted so far.
+    promoted: HashMap<GreenId, GreenId>,
+    /// Promoted nodes the arena didn't have, and ones it did.
+    pub(crate) allocated: u64,
+    pub(crate) deduplicated: u64,
+}
+
+impl<'a> Staging<'a> {
+    const STAGED: GreenId = 1 << (GreenId::BITS - 1);
+
+    pub(crate) fn new(arena: &'a TreeAlloc) -> Self {
+        Staging {
+            arena,
+            nodes: Vec::new(),
+            promoted: HashMap::new(),
+            allocated: 0,
+            deduplicated: 0,
+        }
+    }
+
+    pub(crate) fn is_staged(id: GreenId) -> bool {
+        id & Self::STAGED != 0
+    }
+
+    /// The node `id` stands for, staged or in the arena.
+    pub(crate) fn get_node(&self, id: GreenId) -> &GreenNode {
+        match Self::is_staged(id) {
+            true => &self.nodes[id & !Self::STAGED],
+            false => self.arena.get_node(id),
+        }
+    }
+
+    pub(crate) fn alloc(&mut self, tag: Tag, children: Vec<GreenId>, width: usize) -> GreenId {
+        self.push(GreenNode {
+            tag,
+            children,
+            width,
+            text: None,
+        })
+    }
+
+    /// Stages a leaf holding `text`, like [`TreeAlloc::alloc_token`].
+    pub(crate) fn alloc_token(&mut self, tag: Tag, text: &str) -> GreenId {
+        self.push(GreenNode {
+            tag,
+            children: vec![],
+            width: text.len(),
+            text: Some(Arc::from(text)),
+        })
+    }
+
+    fn push(&mut self, node: GreenNode) -> GreenId {
+        self.nodes.push(node);
+        (self.nodes.len() - 1) | Self::STAGED
+    }
+
+    /// How many nodes are staged, a mark for [`Staging::truncate`].
+    pub(crate) fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Drops the nodes staged since `mark`, whose ids will be given to the
+    /// next ones staged. None of them may still be held.
+    pub(crate) fn truncate(&mut self, mark: usize) {
+        self.nodes.truncate(mark);
+    }
+
+    /// Interns `id` into the arena, along with every staged node under it,
+    /// and returns its arena id. Arena ids are returned as they are.
+    pub(crate) fn promote(&mut self, id: GreenId) -> GreenId {
+        let mut stack = vec![(id, false)];
+        while let Some((id, children_done)) = stack.pop() {
+            if !Self::is_staged(id) || self.promoted.contains_key(&id) {
+                continue;
+            }
+            let node = &self.nodes[id & !Self::STAGED];
+            if !children_done {
+                stack.push((id, true));
+                stack.extend(node.children.iter().rev().map(|&child| (child, false)));
+                continue;
+            }
+            let children = (node.children.iter())
+                .map(|child| self.promoted.get(child).copied().unwrap_or(*child))
+                .collect();
+            let (promoted, new) = match &node.text {
+                Some(text) => self.arena.intern_token(node.tag.clone(), text),
+                None => self.arena.intern(node.tag.clone(), children, node.width),
+            };
+            match new {
+                true => self.allocated += 1,
+                false => self.deduplicated += 1,
+            }
+            self.promoted.insert(id, promoted);
+        }
+        self.promoted(id).unwrap_or(id)
+    }
+
+    /// The arena id of `id`: itself if it's in the arena, or the id it was
+    /// promoted to, if it was.
+    pub(crate) fn promoted(&self, id: GreenId) -> Option<GreenId> {
+        match Self::is_staged(id) {
+            true => self.promoted.get(&id).copied(),
+            false => Some(id),
+        }
+    }
+}
+
+/// Builds a green tree top-down, for parsers that find a node's tag before
+/// its children. Nodes are hash-consed in the arena as they finish, so the
+/// ids are the same as building bottom-up with [`TreeAlloc::alloc`].
+pub struct GreenBuilder<'a> {
+    alloc: &'a TreeAlloc,
+    /// Nodes started but not finished, with where their children start in
+    /// `children`.
+    parents: Vec<(Tag, usize)>,
+    children: Vec<GreenId>,
+}
+
+/// A point among the children built so far, for
+/// [`GreenBuilder::start_node_at`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint(usize);
+
+impl<'a> GreenBuilder<'a> {
+    pub fn new(alloc: &'a TreeAlloc) -> Self {
+        GreenBuilder {
+            alloc,
+            parents: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    /// Starts a node; what is built until its [`GreenBuilder::finish_node`]
+    /// goes in it.
+    pub fn start_node(&mut self, tag: Tag) {
+        self.parents.push((tag, self.children.len()));
+    }
+
+    /// Adds a leaf holding `text`.
+    pub fn token(&mut self, tag: Tag, text: &str) {
+        let id = self.alloc.alloc_token(tag, text);
+        self.children.push(id);
+    }
+
+    /// Marks the current point, so a node started later can take in what
+    /// is built from here on.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint(self.children.len())
+    }
+
+    /// Starts a node holding what was built since `checkpoint`, as when a
+    /// binary expression turns out to start with the operand just parsed.
+    ///
+    /// # Panics
+    ///
+    /// If a node was started or finished since `checkpoint`.
+    pub fn start_node_at(&mut self, checkpoint: Checkpoint, tag: Tag) {
+        let start = self.parents.last().map_or(0, |&(_, start)| start);
+        assert!(
+            start <= checkpoint.0 && checkpoint.0 <= self.children.len(),
+            "checkpoint is outside the current node"
+        );
+        self.parents.push((tag, checkpoint.0));
+    }
+
+    /// Finishes the node started last, as wide as its children together.
+    ///
+    /// # Panics
+    ///
+    /// If no node is started.
+    pub fn finish_node(&mut self) -> GreenId {
+        let (tag, start) = self.parents.pop().expect("no node to finish");
+        let children = self.children.split_off(start);
+        let width = children
+            .iter()
+            .map(|&child| self.alloc.get_node(child).width)
+            .sum();
+        let id = self.alloc.alloc(tag, children, width);
+        self.children.push(id);
+        id
+    }
+
+    /// The root built.
+    ///
+    /// # Panics
+    ///
+    /// If a node is still unfinished, or there isn't exactly one root.
+    pub fn finish(self) -> GreenId {
+        assert!(self.parents.is_empty(), "unfinished node");
+        match self.children.as_slice() {
+            &[root] => root,
+            _ => panic!("expected one root, built {}", self.children.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::Grammar;
+    use crate::grammar_dsl::*;
+    use crate::r;
+    use crate::words::{BlockComment, Identifier, Matcher, Whitespace};
+
+    fn stmt() -> GrammarNode {
+        t(Identifier) + t('=') + t(('0'..='9').times(1..)) + t(';')
+    }
+
+    fn stmts() -> GrammarNode {
+        r!(stmt) + opt(r!(stmts))
+    }
+
+    fn expr() -> GrammarNode {
+        r!(term) + opt(t('+') + r!(expr))
+    }
+
+    fn term() -> GrammarNode {
+        t(('0'..='9').times(1..)) + opt(t('*') + r!(term))
+    }
+
+    #[test]
+    fn test_red_children() {
+        let alloc = TreeAlloc::new();
+        let leaf = |width| alloc.alloc(Tag::Terminal, vec![], width);
+        let (a, b, c) = (leaf(2), leaf(3), leaf(1));
+        let inner = alloc.alloc(Tag::Rule(RuleId::new(1)), vec![b, c], 4);
+        let root = alloc.alloc(Tag::Rule(RuleId::new(0)), vec![a, inner], 6);
+        let root = RedNode {
+            parent: None,
+            offset: 10,
+            green: root,
+            index: 0,
+        };
+
+        assert_eq!(root.child_count(&alloc), 2);
+        let offsets: Vec<_> = root.children(&alloc).map(|child| child.offset).collect();
+        assert_eq!(offsets, [10, 12]);
+        let inner = root.nth_child(&alloc, 1).unwrap();
+        assert_eq!(inner.green(&alloc).tag, Tag::Rule(RuleId::new(1)));
+        assert_eq!(inner.parent.as_ref().unwrap().green, root.green);
+        let spans: Vec<_> = inner
+            .children(&alloc)
+            .map(|child| (child.offset, child.offset + child.green(&alloc).width))
+            .collect();
+        assert_eq!(spans, [(12, 15), (15, 16)]);
+        assert!(root.nth_child(&alloc, 2).is_none());
+        assert_eq!(inner.nth_child(&alloc, 0).unwrap().child_count(&alloc), 0);
+    }
+
+    #[test]
+    fn test_traversal_order() {
+        let alloc = TreeAlloc::new();
+        let leaf = |width| alloc.alloc(Tag::Terminal, vec![], width);
+        let (a, b, c) = (leaf(2), leaf(3), leaf(1));
+        let inner = alloc.alloc(Tag::Rule(RuleId::new(1)), vec![b, c], 4);
+        let root = alloc.alloc(Tag::Rule(RuleId::new(0)), vec![a, inner], 6);
+        let root = RedNode {
+            parent: None,
+            offset: 0,
+            green: root,
+            index: 0,
+        };
+
+        let preorder: Vec<_> = root.descendants(&alloc).map(|node| node.green).collect();
+        assert_eq!(preorder, [root.green, a, inner, b, c]);
+        let spans: Vec<_> = root
+            .descendants_with_spans(&alloc)
+            .map(|(_, span)| (span.start, span.end))
+            .collect();
+        assert_eq!(spans, [(0, 6), (0, 2), (2, 6), (2, 5), (5, 6)]);
+        assert!(spans.is_sorted_by_key(|span| span.0));
+
+        let events: Vec<_> = root
+            .walk(&alloc)
+            .map(|event| match event {
+                WalkEvent::Enter(node) => (true, node.green),
+                WalkEvent::Leave(node) => (false, node.green),
+            })
+            .collect();
+        let postorder: Vec<_> = events
+            .iter()
+            .filter(|(enter, _)| !enter)
+            .map(|&(_, green)| green)
+            .collect();
+        assert_eq!(postorder, [a, b, c, inner, root.green]);
+        assert_eq!(events[..3], [(true, root.green), (true, a), (false, a)]);
+
+        let c = root.descendants(&alloc).last().unwrap();
+        let ancestors: Vec<_> = c.ancestors().map(|node| node.green).collect();
+        assert_eq!(ancestors, [inner, root.green]);
+        assert_eq!(root.ancestors().count(), 0);
+    }
+
+    #[test]
+    fn test_covering_node() {
+        fn expr() -> GrammarNode {
+            r!(term) + r!(rest)
+        }
+
+        fn rest() -> GrammarNode {
+            opt(t('+') + r!(expr))
+        }
+
+        fn term() -> GrammarNode {
+            t(('0'..='9').times(1..))
+        }
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        let expr = grammar.rule_index("expr").unwrap();
+        let rest = grammar.rule_index("rest").unwrap();
+        let mut state = ParserState::new(grammar).with_text("12+3");
+        assert!(state.parse().is_complete());
+        let (root, alloc) = (state.ast(), state.arena());
+        let covering = |offset| {
+            let node = root.covering_node(alloc, offset);
+            (node.span(alloc), node.text(&state))
+        };
+
+        assert_eq!(covering(1), (Span::new(0, 2), "12".to_string()));
+        // Between `12` and `+`, the node starting there wins.
+        assert_eq!(covering(2), (Span::new(2, 3), "+".to_string()));
+        // At the end, `3` rather than the empty `rest` after it.
+        assert_eq!(covering(4), (Span::new(3, 4), "3".to_string()));
+        assert_eq!(covering(99), covering(4));
+
+        let node = root.covering_node_for_span(alloc, Span::new(0, 3));
+        assert_eq!(node.green(alloc).tag, Tag::Rule(expr));
+        assert_eq!(node.span(alloc), Span::new(0, 4));
+        // The `opt` inside `rest`, which covers the same text.
+        let node = root.covering_node_for_span(alloc, Span::new(2, 4));
+        assert_eq!(node.text(&state), "+3");
+        assert_eq!(
+            node.parent.as_ref().unwrap().green(alloc).tag,
+            Tag::Rule(rest)
+        );
+        let node = root.covering_node_for_span(alloc, Span::new(3, 3));
+        assert_eq!(node.text(&state), "3");
+    }
+
+    #[test]
+    fn test_tokens_render_input() {
+        let grammar = Grammar::try_from(r!(stmts))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let text = "ab = 12;\n  cd=3 ;  ab = 12;\n";
+        let mut state = ParserState::new(grammar).with_text(text);
+        assert!(state.parse().is_complete());
+        let alloc = state.arena();
+        assert_eq!(alloc.render(state.ast().green), text);
+
+        // Identical tokens share a node.
+        let abs: Vec<_> = state
+            .ast()
+            .descendants(alloc)
+            .filter(|node| node.green(alloc).text.as_deref() == Some("ab"))
+            .map(|node| node.green)
+            .collect();
+        assert_eq!(abs.len(), 2);
+        assert_eq!(abs[0], abs[1]);
+        assert_ne!(
+            alloc.alloc_token(Tag::Terminal, "1"),
+            alloc.alloc_token(Tag::Terminal, "2")
+        );
+
+        state = state.with_text("ab = ;");
+        assert!(matches!(
+            state.parse(),
+            crate::parser::ParserResult::Recovered { .. }
+        ));
+        assert_eq!(state.arena().render(state.ast().green), "ab = ;");
+    }
+
+    #[test]
+    fn test_token_trivia() {
+        let grammar = Grammar::try_from(t(Identifier) + t('+') + t(Identifier))
+            .unwrap()
+            .with_trivia(Whitespace::new().or(BlockComment::new("/*", "*/")));
+        let texts = |state: &ParserState, nodes: Vec<RedNode>| -> Vec<String> {
+            nodes.iter().map(|node| node.text(state)).collect()
+        };
+
+        let mut state = ParserState::new(grammar.clone()).with_text("a /*c*/ + b");
+        assert!(state.parse().is_complete());
+        let alloc = state.arena();
+        let token = |text: &str| {
+            state
+                .ast()
+                .descendants(alloc)
+                .find(|node| node.green(alloc).text.as_deref() == Some(text))
+                .unwrap()
+        };
+        let (a, plus) = (token("a"), token("+"));
+        assert_eq!(texts(&state, a.trailing_trivia(alloc)), [" ", "/*c*/", " "]);
+        assert!(plus.leading_trivia(alloc).is_empty());
+        assert_eq!(a.full_span(alloc), Span::new(0, 8));
+        assert_eq!(alloc.render(state.ast().green), "a /*c*/ + b");
+
+        // After a line break, trivia leads the next token.
+        let text = " a\n /*d*/ + b \n/*e*/";
+        state = ParserState::new(grammar).with_text(text);
+        assert!(state.parse().is_complete());
+        let alloc = state.arena();
+        let token = |text: &str| {
+            state
+                .ast()
+                .descendants(alloc)
+                .find(|node| node.green(alloc).text.as_deref() == Some(text))
+                .unwrap()
+        };
+        let (a, plus, b) = (token("a"), token("+"), token("b"));
+        assert_eq!(texts(&state, a.leading_trivia(alloc)), [" "]);
+        assert_eq!(texts(&state, a.trailing_trivia(alloc)), ["\n "]);
+        assert_eq!(texts(&state, plus.leading_trivia(alloc)), ["/*d*/", " "]);
+        assert_eq!(texts(&state, b.trailing_trivia(alloc)), [" \n", "/*e*/"]);
+        assert_eq!(state.ast().full_span(alloc), Span::new(0, text.len()));
+        assert_eq!(alloc.render(state.ast().green), text);
+    }
+
+    #[test]
+    fn test_sibling_and_token_navigation() {
+        fn list() -> GrammarNode {
+            t('[') + t(Identifier) + t(',') + t(Identifier) + t(']')
+        }
+
+        let grammar = Grammar::try_from(r!(list) + opt(r!(list)))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let text = "[a, b]\n[cd,e ]";
+        let mut state = ParserState::new(grammar).with_text(text);
+        assert!(state.parse().is_complete());
+        let (root, alloc) = (state.ast(), state.arena());
+
+        let list = root.first_child(alloc).unwrap();
+        let expected: Vec<_> = list
+            .children(alloc)
+            .map(|child| (child.offset, child.text(&state)))
+            .collect();
+        assert_eq!(expected.len(), 6);
+        let mut forward = Vec::new();
+        let mut at = list.first_child(alloc);
+        while let Some(node) = at {
+            forward.push((node.offset, node.text(&state)));
+            at = node.next_sibling(alloc);
+        }
+        assert_eq!(forward, expected);
+        let mut backward = Vec::new();
+        let mut at = list.last_child(alloc);
+        while let Some(node) = at {
+            backward.push((node.offset, node.text(&state)));
+            at = node.prev_sibling(alloc);
+        }
+        backward.reverse();
+        assert_eq!(backward, expected);
+        assert!(root.next_sibling(alloc).is_none());
+
+        let mut tokens = Vec::new();
+        let mut at = Some(root.covering_node(alloc, 0));
+        while let Some(token) = at {
+            tokens.push(token.text(&state));
+            at = token.next_token(alloc);
+        }
+        assert_eq!(tokens.concat(), text);
+        assert_eq!(tokens[..4], ["[", "a", ",", " "]);
+        let mut at = Some(root.covering_node(alloc, text.len()));
+        while let Some(token) = at {
+            assert_eq!(token.text(&state), tokens.pop().unwrap());
+            at = token.prev_token(alloc);
+        }
+        assert!(tokens.is_empty());
+    }
+
+    #[test]
+    fn test_deep_tree_shares_parents() {
+        const DEPTH: usize = 100_000;
+        let alloc = TreeAlloc::new();
+        let mut green = alloc.alloc_token(Tag::Terminal, "x");
+        for _ in 0..DEPTH {
+            let pair = vec![green, alloc.alloc_token(Tag::Terminal, "y")];
+            green = alloc.alloc(
+                Tag::Rule(RuleId::new(1)),
+                pair,
+                alloc.get_node(green).width + 1,
+            );
+        }
+        let root = RedNode::root(green);
+
+        let mut nodes = 0;
+        for node in root.descendants(&alloc) {
+            nodes += 1;
+            let children: Vec<_> = node.children(&alloc).collect();
+            if let [first, second] = children.as_slice() {
+                let parent = first.parent.as_ref().unwrap();
+                assert!(Arc::ptr_eq(parent, second.parent.as_ref().unwrap()));
+                if let Some(grandchild) = first.first_child(&alloc) {
+                    let grandparent = grandchild.parent.as_ref().unwrap().parent.as_ref();
+                    assert!(Arc::ptr_eq(grandparent.unwrap(), parent));
+                }
+            }
+        }
+        assert_eq!(nodes, 2 * DEPTH + 1);
+        let deepest = root.covering_node(&alloc, 0);
+        assert_eq!(deepest.ancestors().count(), DEPTH);
+        let sibling = deepest.next_sibling(&alloc).unwrap();
+        assert!(Arc::ptr_eq(
+            sibling.parent.as_ref().unwrap(),
+            deepest.parent.as_ref().unwrap()
+        ));
+        assert_eq!(sibling.offset, 1);
+        // Dropping the last node of a chain this deep must not overflow.
+        drop(deepest);
+    }
+
+    #[test]
+    fn test_builder_matches_alloc() {
+        let alloc = TreeAlloc::new();
+        let (expr, number) = (Tag::Rule(RuleId::new(1)), Tag::Rule(RuleId::new(2)));
+
+        // `1+2*3`, wrapping each operand once its operator shows up.
+        let mut builder = GreenBuilder::new(&alloc);
+        builder.start_node(Tag::Rule(RuleId::new(0)));
+        let sum = builder.checkpoint();
+        builder.start_node(number.clone());
+        builder.token(Tag::Terminal, "1");
+        builder.finish_node();
+        builder.start_node_at(sum, expr.clone());
+        builder.token(Tag::Terminal, "+");
+        let product = builder.checkpoint();
+        builder.start_node(number.clone());
+        builder.token(Tag::Terminal, "2");
+        builder.finish_node();
+        builder.start_node_at(product, expr.clone());
+        builder.token(Tag::Terminal, "*");
+        builder.start_node(number.clone());
+        builder.token(Tag::Terminal, "3");
+        let three = builder.finish_node();
+        builder.finish_node();
+        builder.finish_node();
+        builder.finish_node();
+        let built = builder.finish();
+
+        let token = |text| alloc.alloc_token(Tag::Terminal, text);
+        let num = |text| alloc.alloc(number.clone(), vec![token(text)], 1);
+        let product = alloc.alloc(expr.clone(), vec![num("2"), token("*"), num("3")], 3);
+        let sum = alloc.alloc(expr, vec![num("1"), token("+"), product], 5);
+        let root = alloc.alloc(Tag::Rule(RuleId::new(0)), vec![sum], 5);
+        assert_eq!(built, root);
+        assert_eq!(three, num("3"));
+        assert_eq!(alloc.render(built), "1+2*3");
+    }
+
+    #[test]
+    fn test_replace_child() {
+        let alloc = TreeAlloc::new();
+        let token = |text| alloc.alloc_token(Tag::Terminal, text);
+        let row: Vec<_> = ["a", "b", "c", "d"].into_iter().map(token).collect();
+        let inner = alloc.alloc(Tag::Rule(RuleId::new(2)), row.clone(), 4);
+        let wide = vec![token("x"), inner, token("y")];
+        let root = RedNode::root(alloc.alloc(Tag::Rule(RuleId::new(1)), wide.clone(), 6));
+
+        let c = root.covering_node(&alloc, 3);
+        assert_eq!(alloc.render(c.green), "c");
+        let new_root = c.replace_with(&alloc, token("ccc"));
+        assert_ne!(new_root, root.green);
+        assert_eq!(alloc.render(new_root), "xabcccdy");
+        assert_eq!(alloc.get_node(new_root).width, 8);
+        let children = &alloc.get_node(new_root).children;
+        assert_eq!((children[0], children[2]), (wide[0], wide[2]));
+        let new_inner = &alloc.get_node(children[1]).children;
+        assert_eq!(new_inner[..2], row[..2]);
+        assert_eq!(new_inner[3], row[3]);
+
+        // Putting the old leaf back gives the old tree.
+        let back = RedNode::root(new_root).covering_node(&alloc, 3);
+        assert_eq!(back.replace_with(&alloc, row[2]), root.green);
+
+        let inserted = alloc.insert_child(inner, 4, token("e"));
+        assert_eq!(alloc.render(inserted), "abcde");
+        assert_eq!(alloc.get_node(inserted).width, 5);
+        assert_eq!(alloc.remove_child(inserted, 4), inner);
+        assert_eq!(alloc.render(alloc.remove_child(inner, 0)), "bcd");
+    }
+
+    /// Whether `a` in `a_alloc` and `b` in `b_alloc` are the same tree.
+    fn same_tree(a_alloc: &TreeAlloc, a: GreenId, b_alloc: &TreeAlloc, b: GreenId) -> bool {
+        let (a, b) = (a_alloc.get_node(a), b_alloc.get_node(b));
+        (&a.tag, a.width, &a.text) == (&b.tag, b.width, &b.text)
+            && a.children.len() == b.children.len()
+            && (a.children.iter().zip(&b.children))
+                .all(|(&a, &b)| same_tree(a_alloc, a, b_alloc, b))
+    }
+
+    #[test]
+    fn test_collect_unreachable() {
+        let mut alloc = TreeAlloc::new();
+        let tree = |alloc: &TreeAlloc, n: usize| {
+            let mut builder = GreenBuilder::new(alloc);
+            builder.start_node(Tag::Rule(RuleId::new(0)));
+            for i in 0..n {
+                builder.start_node(Tag::Rule(RuleId::new(1)));
+                builder.token(Tag::Terminal, &format!("{n}.{i}"));
+                builder.token(Tag::Trivia, " ");
+                builder.finish_node();
+            }
+            builder.finish_node();
+            builder.finish()
+        };
+        let roots: Vec<_> = (0..50).map(|n| tree(&alloc, n)).collect();
+        let kept = roots[20];
+        let reference = TreeAlloc::new();
+        let expected = tree(&reference, 20);
+        let before = alloc.len();
+
+        let map = alloc.collect(&[kept]);
+        assert!(alloc.len() < before / 10);
+        assert_eq!(alloc.len(), map.len());
+        let root = map.get(kept).unwrap();
+        assert!(same_tree(&alloc, root, &reference, expected));
+        assert_eq!(map.get(roots[49]), None);
+        // Dedup still finds the nodes kept.
+        assert_eq!(tree(&alloc, 20), root);
+        assert_eq!(alloc.len(), map.len());
+    }
+
+    #[test]
+    fn test_dedup_capacity() {
+        let alloc = TreeAlloc::new().with_dedup_capacity(2);
+        let a = alloc.alloc_token(Tag::Terminal, "a");
+        let b = alloc.alloc_token(Tag::Terminal, "b");
+        let c = alloc.alloc_token(Tag::Terminal, "c");
+        assert_eq!(alloc.alloc_token(Tag::Terminal, "a"), a);
+        assert_eq!(alloc.alloc_token(Tag::Terminal, "b"), b);
+        let again = alloc.alloc_token(Tag::Terminal, "c");
+        assert_ne!(again, c);
+        assert_eq!(alloc.get_node(again), alloc.get_node(c));
+        let stats = alloc.dedup_stats();
+        assert_eq!(
+            stats,
+            DedupStats {
+                entries: 2,
+                hits: 2,
+                misses: 4,
+                collisions: 0,
+            }
+        );
+
+        // Building over the cap gives the same tree, just shared less.
+        let build = |alloc: &TreeAlloc| {
+            let mut builder = GreenBuilder::new(alloc);
+            builder.start_node(Tag::Rule(RuleId::new(0)));
+            for i in 0..20 {
+                builder.start_node(Tag::Rule(RuleId::new(1)));
+                builder.token(Tag::Terminal, ["x", "y"][i % 2]);
+                builder.finish_node();
+            }
+            builder.finish_node();
+            builder.finish()
+        };
+        let shared = TreeAlloc::new();
+        let capped = TreeAlloc::new().with_dedup_capacity(3);
+        let (left, right) = (build(&shared), build(&capped));
+        assert!(same_tree(&shared, left, &capped, right));
+        assert!(capped.len() > shared.len());
+        assert_eq!(capped.dedup_stats().entries, 3);
+    }
+
+    #[test]
+    fn test_render_sexpr() {
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        let render = |text: &str, options| {
+            let mut state = ParserState::new(grammar.clone()).with_text(text);
+            state.parse();
+            render_sexpr(state.ast(), state.arena(), &grammar, text, options)
+        };
+        let compact = SexprOptions {
+            transparent: true,
+            ..SexprOptions::default()
+        };
+        let pretty = SexprOptions {
+            pretty: true,
+            spans: true,
+            transparent: true,
+        };
+        assert_eq!(
+            render("1+2*3", compact),
+            r#"(START (expr (term "1") "+" (expr (term "2" "*" (term "3")))))"#
+        );
+        assert_eq!(
+            render("1+2*3", SexprOptions::default()),
+            r#"(START (expr (term "1" (term_opt)) (expr_opt "+" (expr (term "2" (term_opt "*" (term "3" (term_opt)))) (expr_opt)))))"#
+        );
+        assert_eq!(
+            render("1+2*3", pretty),
+            r#"(START@0..5
+  (expr@0..5
+    (term@0..1
+      "1"@0..1)
+    "+"@1..2
+    (expr@2..5
+      (term@2..5
+        "2"@2..3
+        "*"@3..4
+        (term@4..5
+          "3"@4..5)))))"#
+        );
+        assert_eq!(
+            render("1+*3", compact),
+            r#"(START (expr (term "1") "+" (ERROR "*") (expr (term "3"))))"#
+        );
+        assert_eq!(
+            render("1+*3", pretty),
+            r#"(START@0..4
+  (expr@0..4
+    (term@0..1
+      "1"@0..1)
+    "+"@1..2
+    (ERROR@2..3 "*")
+    (expr@3..4
+      (term@3..4
+        "3"@3..4))))"#
+        );
+    }
+
+    #[test]
+    fn test_json_export() {
+        let grammar = Grammar::try_from(r!(expr))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let text = "1 + *3";
+        let mut state = ParserState::new(grammar.clone()).with_text(text);
+        state.parse();
+        let json = to_json(state.ast(), state.arena(), &grammar, text);
+        // Nodes shared in the arena, like the spaces here, keep their id.
+        assert_eq!(
+            json.to_string(),
+            concat!(
+                r#"{"id":14,"kind":"rule","name":"START","span":[0,6],"children":["#,
+                r#"{"id":13,"kind":"rule","name":"expr","span":[0,6],"children":["#,
+                r#"{"id":3,"kind":"rule","name":"term","span":[0,1],"children":["#,
+                r#"{"id":2,"kind":"token","span":[0,1],"text":"1","children":[]},"#,
+                r#"{"id":1,"kind":"rule","name":"term_opt","span":[1,1],"text":"","children":[]}]},"#,
+                r#"{"id":9,"kind":"trivia","span":[1,2],"text":" ","children":[]},"#,
+                r#"{"id":12,"kind":"rule","name":"expr_opt","span":[2,6],"children":["#,
+                r#"{"id":10,"kind":"token","span":[2,3],"text":"+","children":[]},"#,
+                r#"{"id":9,"kind":"trivia","span":[3,4],"text":" ","children":[]},"#,
+                r#"{"id":11,"kind":"error","name":"rule_mismatch","span":[4,5],"text":"*","#,
+                r#""children":[]},"#,
+                r#"{"id":8,"kind":"rule","name":"expr","span":[5,6],"children":["#,
+                r#"{"id":7,"kind":"rule","name":"term","span":[5,6],"children":["#,
+                r#"{"id":6,"kind":"token","span":[5,6],"text":"3","children":[]},"#,
+                r#"{"id":1,"kind":"rule","name":"term_opt","span":[6,6],"text":"","children":[]}]},"#,
+                r#"{"id":4,"kind":"rule","name":"expr_opt","span":[6,6],"text":"","children":[]}]}]}]}]}"#,
+            )
+        );
+        let mut streamed = String::new();
+        write_json(state.ast(), state.arena(), &grammar, text, &mut streamed).unwrap();
+        assert_eq!(streamed, json.to_string());
+    }
+
+    #[test]
+    fn test_diff() {
+        use DiffKind::*;
+        let grammar = Grammar::try_from(r!(stmts)).unwrap();
+        let mut state = ParserState::new(grammar.clone()).with_text("a=1;b=2;c=3;");
+        assert!(state.parse().is_complete());
+        let old = state.snapshot();
+        let mut diff_to = |text: &str| {
+            assert!(state.set_text(text).is_complete());
+            let changes = diff(old.ast(), state.ast(), state.arena());
+            let span = |span: Option<Span>| span.map(|span| (span.start, span.end));
+            (changes.entries.into_iter())
+                .map(|entry| {
+                    (
+                        entry.kind,
+                        span(entry.old_span),
+                        span(entry.new_span),
+                        entry.tag,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let [start, stmts, more, stmt] = ["START", "stmts", "stmts_opt", "stmt"]
+            .map(|name| Tag::Rule(grammar.rule_index(name).unwrap()));
+
+        // The token edited and each of its ancestors.
+        assert_eq!(
+            diff_to("a=1;b=5;c=3;"),
+            [
+                (Replaced, Some((0, 12)), Some((0, 12)), start.clone()),
+                (Replaced, Some((0, 12)), Some((0, 12)), stmts.clone()),
+                (Replaced, Some((4, 12)), Some((4, 12)), more.clone()),
+                (Replaced, Some((4, 12)), Some((4, 12)), stmts.clone()),
+                (Replaced, Some((4, 8)), Some((4, 8)), stmt.clone()),
+                (Replaced, Some((6, 7)), Some((6, 7)), Tag::Terminal),
+            ]
+        );
+        // What comes after a longer token moves, but isn't looked into.
+        assert_eq!(
+            diff_to("a=1;b=55;c=3;"),
+            [
+                (Replaced, Some((0, 12)), Some((0, 13)), start.clone()),
+                (Replaced, Some((0, 12)), Some((0, 13)), stmts.clone()),
+                (Replaced, Some((4, 12)), Some((4, 13)), more.clone()),
+                (Replaced, Some((4, 12)), Some((4, 13)), stmts.clone()),
+                (Replaced, Some((4, 8)), Some((4, 9)), stmt.clone()),
+                (Replaced, Some((6, 7)), Some((6, 8)), Tag::Terminal),
+                (Moved, Some((7, 8)), Some((8, 9)), Tag::Terminal),
+                (Moved, Some((8, 12)), Some((9, 13)), more.clone()),
+            ]
+        );
+        assert_eq!(
+            diff_to("a=1;c=3;"),
+            [
+                (Replaced, Some((0, 12)), Some((0, 8)), start),
+                (Replaced, Some((0, 12)), Some((0, 8)), stmts.clone()),
+                (Replaced, Some((4, 12)), Some((4, 8)), more.clone()),
+                (Replaced, Some((4, 12)), Some((4, 8)), stmts.clone()),
+                (Replaced, Some((4, 8)), Some((4, 8)), stmt),
+                (Replaced, Some((4, 5)), Some((4, 5)), Tag::Terminal),
+                (Replaced, Some((6, 7)), Some((6, 7)), Tag::Terminal),
+                (Replaced, Some((8, 12)), Some((8, 8)), more),
+                (Deleted, Some((8, 12)), None, stmts),
+            ]
+        );
+        assert!(diff(old.ast(), old.ast(), old.arena()).is_empty());
+    }
+
+    #[test]
+    fn test_visitor() {
+        /// Names of the nodes entered, and left as `/name`.
+        struct Trace<'a> {
+            grammar: &'a Grammar,
+            seen: Vec<String>,
+            skip: Option<RuleId>,
+        }
+
+        impl Trace<'_> {
+            fn name(&self, tag: &Tag) -> String {
+                match tag {
+                    Tag::Rule(idx) => self.grammar.rule(*idx).unwrap().name.into(),
+                    Tag::Error(_) => "ERROR".into(),
+                    _ => "token".into(),
+                }
+            }
+        }
+
+        impl TreeVisitor for Trace<'_> {
+            fn enter_node(&mut self, _node: &RedNode, tag: &Tag, _span: Span) -> VisitFlow {
+                self.seen.push(self.name(tag));
+                match tag {
+                    Tag::Error(_) => VisitFlow::Stop,
+                    Tag::Rule(idx) if Some(*idx) == self.skip => VisitFlow::SkipChildren,
+                    _ => VisitFlow::Continue,
+                }
+            }
+
+            fn leave_node(&mut self, _node: &RedNode, tag: &Tag, _span: Span) -> VisitFlow {
+                self.seen.push(format!("/{}", self.name(tag)));
+                VisitFlow::Continue
+            }
+        }
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        let trace = |text: &str, skip: Option<&str>| {
+            let mut state = ParserState::new(grammar.clone()).with_text(text);
+            state.parse();
+            let mut trace = Trace {
+                grammar: &grammar,
+                seen: Vec::new(),
+                skip: skip.map(|name| grammar.rule_index(name).unwrap()),
+            };
+            walk(state.ast(), state.arena(), &mut trace);
+            trace.seen.join(" ")
+        };
+
+        assert_eq!(
+            trace("1+*3", None),
+            "START expr term token /token term_opt /term_opt /term expr_opt token /token ERROR"
+        );
+        assert_eq!(
+            trace("1+2*3", Some("term")),
+            "START expr term /term expr_opt token /token expr term /term expr_opt /expr_opt /expr /expr_opt /expr /START"
+        );
+
+        let mut state = ParserState::new(grammar.clone()).with_text("1+2*3");
+        state.parse();
+        let mut spans = RuleSpans::default();
+        walk(state.ast(), state.arena(), &mut spans);
+        let term = grammar.rule_index("term").unwrap();
+        assert_eq!(
+            spans.spans[&term],
+            [Span::new(0, 1), Span::new(2, 5), Span::new(4, 5)]
+        );
+    }
+
+    #[test]
+    fn test_grammar_resolved_tree() {
+        let grammar = Grammar::try_from(r!(expr))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let mut state = ParserState::new(grammar.clone()).with_text("1 *+3");
+        state.parse();
+        let (root, alloc) = (state.ast(), state.arena());
+
+        let mut raw = String::new();
+        for node in root.descendants(alloc) {
+            let depth = node.ancestors().count();
+            let span = node.span(alloc);
+            let tag = &node.green(alloc).tag;
+            raw += &format!(
+                "{}{tag:?} {}..{}\n",
+                "  ".repeat(depth),
+                span.start,
+                span.end
+            );
+        }
+        assert_eq!(
+            raw,
+            "\
+Rule(0) 0..5
+  Rule(1) 0..5
+    Rule(2) 0..5
+      Terminal 0..1
+      Trivia 1..2
+      Rule(3) 2..5
+        Terminal 2..3
+        Error(RuleMismatch { expected: 2 }) 3..4
+        Rule(2) 4..5
+          Terminal 4..5
+          Rule(3) 5..5
+    Rule(4) 5..5
+"
+        );
+        assert_eq!(
+            GrammarResolvedTree::new(root, &grammar, alloc).to_string(),
+            "\
+START 0..5
+  expr 0..5
+    term 0..5
+      Terminal 0..1 \"1\"
+      Trivia 1..2 \" \"
+      term_opt 2..5
+        Terminal 2..3 \"*\"
+        ERROR(expected term) 3..4 \"+\"
+        term 4..5
+          Terminal 4..5 \"3\"
+          term_opt 5..5
+    expr_opt 5..5
+"
+        );
+
+        state.set_text("1+2 x");
+        let tree = GrammarResolvedTree::new(state.ast(), &grammar, state.arena());
+        let last = format!("{tree:?}").lines().last().map(String::from);
+        assert_eq!(last.as_deref(), Some("  ERROR(expected EOF) 4..5 \"x\""));
+    }
+
+    #[test]
+    fn test_find_all() {
+        let grammar = Grammar::try_from(r!(stmts))
+            .unwrap()
+            .with_trivia(Whitespace::new());
+        let text = "a = 1;\nb = 22;\nc = 333;\n";
+        let mut state = ParserState::new(grammar.clone()).with_text(text);
+        assert!(state.parse().is_complete());
+        let (root, alloc) = (state.ast(), state.arena());
+        let spans =
+            |nodes: Vec<RedNode>| -> Vec<_> { nodes.iter().map(|node| node.span(alloc)).collect() };
+
+        let stmt = grammar.rule_index("stmt").unwrap();
+        let all = [Span::new(0, 6), Span::new(7, 14), Span::new(15, 23)];
+        assert_eq!(spans(root.find_all(alloc, &grammar, "stmt")), all);
+        assert_eq!(spans(root.find_all(alloc, &grammar, stmt)), all);
+        assert_eq!(
+            spans(root.find_all_in(alloc, &grammar, "stmt", Span::new(10, 16))),
+            all[1..]
+        );
+        assert_eq!(
+            spans(root.find_all_in(alloc, &grammar, "stmt", Span::new(6, 7))),
+            []
+        );
+        assert!(root.find_all(alloc, &grammar, "missing").is_empty());
+
+        let first = root.find_all(alloc, &grammar, "stmt").remove(0);
+        assert_eq!(first.kind(alloc, &grammar), NodeKind::Rule("stmt"));
+        let token = first.first_child(alloc).unwrap();
+        assert_eq!(token.kind(alloc, &grammar), NodeKind::Token);
+
+        state.set_text("a = 1;\nb = ;\n");
+        let (root, alloc) = (state.ast(), state.arena());
+        let errors: Vec<_> = root
+            .matching_descendants(alloc, |tag| matches!(tag, Tag::Error(_)))
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0].kind(alloc, &grammar),
+            NodeKind::Error(GrammarError::TokenMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        let mut alloc = TreeAlloc::new();
+        let tokens = |alloc: &TreeAlloc, range: std::ops::Range<usize>| -> Vec<_> {
+            range
+                .map(|i| alloc.alloc_token(Tag::Terminal, &format!("{i:06}")))
+                .collect()
+        };
+        assert_eq!(alloc.memory_usage().total_bytes(), 0);
+        let first = tokens(&alloc, 0..1000);
+        let half = alloc.memory_usage();
+        let second = tokens(&alloc, 1000..2000);
+        let full = alloc.memory_usage();
+        assert_eq!((half.nodes, full.nodes), (1000, 2000));
+        assert_eq!(full.dedup_entries, 2000);
+        // The index grows in steps, the nodes and texts evenly.
+        assert_eq!(full.node_bytes, 2 * half.node_bytes);
+        assert_eq!(full.string_bytes, 2 * half.string_bytes);
+        let ratio = full.total_bytes() as f64 / half.total_bytes() as f64;
+        assert!((1.5..2.5).contains(&ratio), "{ratio}");
+
+        // Allocating nodes already there takes nothing more.
+        tokens(&alloc, 0..1000);
+        assert_eq!(alloc.memory_usage(), full);
+
+        let root = alloc.alloc(Tag::Rule(RuleId::new(0)), first, 6000);
+        alloc.alloc(Tag::Rule(RuleId::new(0)), second, 6000);
+        let kept = alloc.collect(&[root]);
+        let collected = alloc.memory_usage();
+        assert_eq!(collected.nodes, kept.len());
+        assert!(collected.total_bytes() < full.total_bytes());
+        assert!(collected.total_bytes() > half.total_bytes());
+    }
+
+    #[test]
+    fn test_interned_token_text() {
+        let alloc = TreeAlloc::new();
+        let ids: std::collections::HashSet<_> = (0..1000)
+            .map(|_| alloc.alloc_token(Tag::Terminal, "ident"))
+            .collect();
+        assert_eq!(ids.len(), 1);
+        assert_eq!(alloc.interned_strings(), 1);
+
+        // Tokens of other kinds get their own node, but share the text.
+        let trivia = alloc.alloc_token(Tag::Trivia, "ident");
+        assert_eq!(alloc.interned_strings(), 1);
+        let id = *ids.iter().next().unwrap();
+        let (a, b) = (alloc.get_node(id), alloc.get_node(trivia));
+        assert!(Arc::ptr_eq(
+            a.text.as_ref().unwrap(),
+            b.text.as_ref().unwrap()
+        ));
+        assert_eq!(alloc.memory_usage().interned_strings, 1);
+    }
+
+    #[test]
+    fn test_alloc_from_threads() {
+        const THREADS: usize = 4;
+        const ALLOCS: usize = 100_000;
+        const DISTINCT: usize = 20_000;
+        let alloc = TreeAlloc::new();
+        let existing = alloc.alloc_token(Tag::Terminal, "0");
+        let ids: Vec<Vec<GreenId>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..THREADS)
+                .map(|thread| {
+                    let alloc = &alloc;
+                    scope.spawn(move || {
+                        // Each thread goes through the same nodes from a
+                        // different place, so they race for each one.
+                        let start = thread * DISTINCT / THREADS;
+                        let mut ids = vec![0; ALLOCS];
+                        for i in (0..ALLOCS).map(|i| (start + i) % ALLOCS) {
+                            let text = (i % DISTINCT).to_string();
+                            let token = alloc.alloc_token(Tag::Terminal, &text);
+                            ids[i] =
+                                alloc.alloc(Tag::Rule(RuleId::new(0)), vec![token], text.len());
+                        }
+                        ids
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+
+        assert!(ids.iter().all(|other| *other == ids[0]));
+        assert_eq!(alloc.len(), 2 * DISTINCT);
+        assert_eq!(alloc.get_node(ids[0][0]).children, [existing]);
+        let stats = alloc.dedup_stats();
+        assert_eq!(stats.misses, 2 * DISTINCT as u64);
+        assert_eq!(stats.hits + stats.misses, (2 * THREADS * ALLOCS + 1) as u64);
+        assert_eq!(stats.entries, 2 * DISTINCT);
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let alloc = TreeAlloc::new();
+        let a = alloc.alloc_token(Tag::Terminal, "ab");
+        let b = alloc.alloc_token(Tag::Terminal, "c");
+        let good = alloc.alloc(Tag::Rule(RuleId::new(1)), vec![a, b, a], 5);
+        let root = alloc.alloc(Tag::Rule(RuleId::new(0)), vec![good, b], 6);
+        assert_eq!(alloc.check_invariants(root), Ok(()));
+
+        let wide = alloc.alloc(Tag::Rule(RuleId::new(1)), vec![a, b], 4);
+        let root = alloc.alloc(Tag::Rule(RuleId::new(0)), vec![good, wide], 9);
+        assert_eq!(
+            alloc.check_invariants(root),
+            Err(TreeInvariantError::WidthMismatch {
+                node: wide,
+                expected: 3,
+                actual: 4,
+            })
+        );
+        let short = alloc.insert(GreenNode {
+            tag: Tag::Terminal,
+            children: vec![],
+            width: 1,
+            text: Some("xy".into()),
+        });
+        assert_eq!(
+            alloc.check_invariants(short.0),
+            Err(TreeInvariantError::WidthMismatch {
+                node: short.0,
+                expected: 2,
+                actual: 1,
+            })
+        );
+
+        let dangling = alloc.alloc(Tag::Rule(RuleId::new(1)), vec![a, 1000], 2);
+        assert_eq!(
+            alloc.check_invariants(dangling),
+            Err(TreeInvariantError::DanglingChild {
+                node: dangling,
+                child: 1000,
+            })
+        );
+        // A node naming the id the next one gets as its child.
+        let next = alloc.len() + 1;
+        let first = alloc.alloc(Tag::Rule(RuleId::new(1)), vec![next], 0);
+        let second = alloc.alloc(Tag::Rule(RuleId::new(2)), vec![first], 0);
+        assert_eq!(second, next);
+        assert_eq!(
+            alloc.check_invariants(second),
+            Err(TreeInvariantError::Cycle { node: second })
+        );
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut alloc = TreeAlloc::new();
+        let a = alloc.alloc_token(Tag::Terminal, "a");
+        let kept = alloc.alloc(Tag::Rule(RuleId::new(0)), vec![a], 1);
+        let mark = alloc.begin();
+        let len = alloc.len();
+
+        // An alternative tried and given up on.
+        let b = alloc.alloc_token(Tag::Terminal, "bb");
+        let tried = alloc.alloc(Tag::Rule(RuleId::new(1)), vec![a, b], 3);
+        assert_eq!(alloc.alloc(Tag::Rule(RuleId::new(1)), vec![a, b], 3), tried);
+        assert_eq!(alloc.rollback(mark), 2);
+        assert_eq!(alloc.len(), len);
+        assert_eq!(alloc.interned_strings(), 1);
+        assert_eq!(alloc.dedup_stats().entries, len);
+        assert_eq!(alloc.rollback(mark), 0);
+
+        // What was there before is still found, what was dropped is built
+        // again under the freed ids.
+        assert_eq!(alloc.alloc(Tag::Rule(RuleId::new(0)), vec![a], 1), kept);
+        let c = alloc.alloc_token(Tag::Terminal, "c");
+        assert_eq!(c, b);
+        assert_eq!(alloc.get_node(c).text.as_deref(), Some("c"));
+        assert_eq!(alloc.check_invariants(kept), Ok(()));
+    }
 }
@@ -22,6 +22,18 @@ impl Span {
     pub fn len(&self) -> usize {
         self.end - self.start
     }
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+    /// Whether the spans share some text, or one is empty and lies within
+    /// the other, ends included.
+    pub fn intersects(&self, other: Span) -> bool {
+        if self.is_empty() || other.is_empty() {
+            self.start <= other.end && other.start <= self.end
+        } else {
+            self.start < other.end && other.start < self.end
+        }
+    }
 }
 
 impl ops::Add for Span {
@@ -35,6 +47,118 @@ impl ops::Add for Span {
     }
 }
 
+/// A 1-based line and column. Columns count chars, not bytes, and a
+/// `\r\n` pair ends a single line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LineCol {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl std::fmt::Display for LineCol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// Line start offsets of a text, for repeated offset-to-line lookups.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(text: &str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex { starts }
+    }
+
+    /// The position of byte `offset` in `text`, which must be the text the
+    /// index was built from. Offsets inside a char or past the end are
+    /// clamped to the preceding boundary.
+    pub fn line_col(&self, text: &str, offset: usize) -> LineCol {
+        let offset = floor_char_boundary(text, offset);
+        let line = self.line_of(offset);
+        let line_text = &text[self.starts[line]..offset];
+        LineCol {
+            line: line + 1,
+            col: line_text.chars().count() + 1,
+        }
+    }
+
+    /// The 0-based line byte `offset` is on.
+    pub fn line_of(&self, offset: usize) -> usize {
+        self.starts.partition_point(|&start| start <= offset) - 1
+    }
+
+    /// The byte offset 0-based `line` starts at, if the text has it.
+    pub fn line_start(&self, line: usize) -> Option<usize> {
+        self.starts.get(line).copied()
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.starts.len()
+    }
+
+    /// Updates the index for `span` of the text being replaced with
+    /// `new_text`. Only the starts after `span.start` change.
+    pub fn edit(&mut self, span: Span, new_text: &str) {
+        let first = self.starts.partition_point(|&start| start <= span.start);
+        let removed = self.starts[first..].partition_point(|&start| start <= span.end);
+        let inserted = new_text
+            .match_indices('\n')
+            .map(|(i, _)| span.start + i + 1);
+        let shifted = self.starts[first + removed..]
+            .iter()
+            .map(|&start| start - span.end + span.start + new_text.len());
+        let tail: Vec<usize> = inserted.chain(shifted).collect();
+        self.starts.truncate(first);
+        self.starts.extend(tail);
+    }
+}
+
+impl Default for LineIndex {
+    fn default() -> Self {
+        LineIndex::new("")
+    }
+}
+
+/// The position of byte `offset` in `text`, computed by scanning the
+/// prefix. Use [`LineIndex`] for many lookups in the same text.
+pub fn line_col(text: &str, offset: usize) -> LineCol {
+    let prefix = &text[..floor_char_boundary(text, offset)];
+    let line_start = prefix.rfind('\n').map_or(0, |i| i + 1);
+    LineCol {
+        line: prefix.matches('\n').count() + 1,
+        col: prefix[line_start..].chars().count() + 1,
+    }
+}
+
+/// The byte offset of the char at `index` in `text`, or of its end if
+/// `index` is the number of chars. `None` past that.
+pub fn char_to_byte(text: &str, index: usize) -> Option<usize> {
+    text.char_indices()
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(text.len()))
+        .nth(index)
+}
+
+/// The index of the char at byte `offset` in `text`. Offsets inside a char
+/// or past the end are clamped to the preceding boundary.
+pub fn byte_to_char(text: &str, offset: usize) -> usize {
+    text[..floor_char_boundary(text, offset)].chars().count()
+}
+
+pub(crate) fn floor_char_boundary(text: &str, offset: usize) -> usize {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Range {
     pub start: usize,
@@ -63,6 +187,58 @@ impl From<Range> for ops::RangeFrom<usize> {
     }
 }
 
-pub(crate) trait Unzip<A, B> {
-    fn unzip2(self) -> (Vec<A>, Vec<B>);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_col() {
+        let text = "ab\r\nçd\n\nend";
+        let index = LineIndex::new(text);
+        let cases = [
+            (0, (1, 1)),
+            // The `\r` of a CRLF pair is the last column of its line.
+            (2, (1, 3)),
+            (3, (1, 4)),
+            (4, (2, 1)),
+            // `ç` is two bytes but one column.
+            (6, (2, 2)),
+            (5, (2, 1)),
+            (8, (3, 1)),
+            (9, (4, 1)),
+            (text.len(), (4, 4)),
+            (text.len() + 5, (4, 4)),
+        ];
+        for (offset, (line, col)) in cases {
+            let expected = LineCol { line, col };
+            assert_eq!(line_col(text, offset), expected, "offset {offset}");
+            assert_eq!(index.line_col(text, offset), expected, "offset {offset}");
+        }
+        assert_eq!(line_col("", 0).to_string(), "1:1");
+    }
+
+    #[test]
+    fn test_line_index_edit() {
+        let mut text = "a\nbc\r\nd\n".to_string();
+        let mut index = LineIndex::new(&text);
+        let edits = [(1, 1, "\n\nx"), (0, 6, ""), (2, 3, "\r\n"), (0, 0, "")];
+        for (start, end, new_text) in edits {
+            index.edit(Span::new(start, end), new_text);
+            text.replace_range(start..end, new_text);
+            assert_eq!(index, LineIndex::new(&text), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn test_char_offsets() {
+        let text = "aé😀b";
+        let cases = [(0, 0), (1, 1), (2, 3), (3, 7), (4, 8)];
+        for (index, offset) in cases {
+            assert_eq!(char_to_byte(text, index), Some(offset));
+            assert_eq!(byte_to_char(text, offset), index);
+        }
+        assert_eq!(char_to_byte(text, 5), None);
+        assert_eq!(byte_to_char(text, 5), 2);
+        assert_eq!(byte_to_char(text, 99), 4);
+    }
 }
@@ -1,65 +1,677 @@
-use std::{
-    collections::btree_map::Range,
-    fmt::Debug,
-    ops::{self, Index, IndexMut},
+use std::{fmt::Debug, ops};
+
+use crate::{
+    grammar::leak_name,
+    utils::{LineCol, Span, line_col},
 };
 
-use crate::utils::Span;
+#[cfg(feature = "byte-input")]
+mod bytes;
+#[cfg(feature = "regex")]
+mod regex;
+mod stream;
+mod tokens;
+#[cfg(feature = "unicode")]
+mod xid;
+
+#[cfg(feature = "byte-input")]
+pub use bytes::{AnyBytes, LengthPrefixed};
+#[cfg(feature = "regex")]
+pub use regex::{Regex, RegexError};
+pub use stream::{Token, TokenExact, TokenInput, TokenKind, TokenState, TokenWhere};
+pub use tokens::{
+    BlockComment, Float, Identifier, Integer, LineComment, QuotedString, Until, Whitespace,
+};
 
 #[derive(Debug, Clone)]
 pub struct EndOfInput;
 #[derive(Debug, Clone)]
 pub struct StartOfInput;
+/// Zero-width; succeeds at the start of input or right after a `\n`.
+#[derive(Debug, Clone)]
+pub struct StartOfLine;
+/// Zero-width; succeeds before a `\n`, a `\r\n` or at the end of input.
+#[derive(Debug, Clone)]
+pub struct EndOfLine;
+/// Either matcher, tried in order. Built by [`Matcher::or`].
 #[derive(Debug, Clone)]
 pub struct Alternative<T, U>(T, U);
+/// One matcher after the other. Built by [`Matcher::then`].
 #[derive(Debug, Clone)]
 pub struct Sequence<T, U>(T, U);
+/// Greedy repetition within a count range. Built by [`Matcher::times`].
 #[derive(Debug, Clone)]
 pub struct Repeat<T, R: ops::RangeBounds<usize>>(T, R);
+/// Repeats as few times as possible. On its own it matches the minimum;
+/// followed by something in a [`Sequence`], it takes one more repetition
+/// at a time until the follower matches.
+#[derive(Debug, Clone)]
+pub struct LazyRepeat<T, R: ops::RangeBounds<usize>>(T, R);
 
-pub trait Lexical<T>
-where
-    Self: IntoIterator<Item = T>
-        + Index<usize, Output = T>
-        + Index<ops::Range<usize>, Output = [T]>
-        + IndexMut<usize, Output = T>
-        + IndexMut<ops::Range<usize>, Output = [T]>,
-    for<'a> &'a Self: IntoIterator<Item = &'a T>,
-    T: Clone + PartialEq + Eq,
-{
-    fn len(&self) -> usize;
+/// Matches the longest of a set of keywords. A keyword ending in an
+/// identifier character only matches when not followed by another one, so
+/// `in` does not match the start of `int`.
+#[derive(Debug, Clone)]
+pub struct Keywords {
+    /// Sorted for binary search.
+    words: Vec<String>,
+    /// Distinct keyword lengths in bytes, longest first.
+    lengths: Vec<usize>,
+}
+
+/// Matches one character satisfying `pred`. The name is shown in place of
+/// the predicate wherever the grammar is printed.
+#[derive(Debug, Clone, Copy)]
+pub struct CharClass {
+    pub name: &'static str,
+    pub pred: fn(char) -> bool,
+}
+
+pub const ALPHA: CharClass = class("alpha", char::is_alphabetic);
+pub const ALPHANUMERIC: CharClass = class("alphanumeric", char::is_alphanumeric);
+pub const WHITESPACE: CharClass = class("whitespace", char::is_whitespace);
+pub const HEX_DIGIT: CharClass = class("hex_digit", |c| c.is_ascii_hexdigit());
+pub const IDENT_START: CharClass = class("ident_start", is_ident_start);
+pub const IDENT_CONTINUE: CharClass = class("ident_continue", is_ident_continue);
+
+/// The built-in classes, which are the ones that can be serialized.
+pub const CHAR_CLASSES: [CharClass; 6] = [
+    ALPHA,
+    ALPHANUMERIC,
+    WHITESPACE,
+    HEX_DIGIT,
+    IDENT_START,
+    IDENT_CONTINUE,
+];
+
+pub const fn class(name: &'static str, pred: fn(char) -> bool) -> CharClass {
+    CharClass { name, pred }
+}
+
+/// Matches any single character.
+#[derive(Debug, Clone)]
+pub struct AnyChar;
+
+/// Matches any single character not in the set.
+#[derive(Debug, Clone)]
+pub struct NoneOf {
+    /// Sorted and deduplicated.
+    chars: Vec<char>,
+}
+
+/// Matches a single character in the set. An empty set matches nothing.
+#[derive(Debug, Clone)]
+pub struct OneOf {
+    /// Sorted and deduplicated.
+    chars: Vec<char>,
+}
+
+/// Things that can be turned into a set of characters: strings, char
+/// slices and arrays.
+pub trait IntoCharSet {
+    fn into_chars(self) -> Vec<char>;
+}
+
+impl IntoCharSet for &str {
+    fn into_chars(self) -> Vec<char> {
+        self.chars().collect()
+    }
+}
+
+impl IntoCharSet for String {
+    fn into_chars(self) -> Vec<char> {
+        self.chars().collect()
+    }
+}
+
+impl IntoCharSet for &[char] {
+    fn into_chars(self) -> Vec<char> {
+        self.to_vec()
+    }
+}
+
+impl<const N: usize> IntoCharSet for [char; N] {
+    fn into_chars(self) -> Vec<char> {
+        self.to_vec()
+    }
+}
+
+impl IntoCharSet for Vec<char> {
+    fn into_chars(self) -> Vec<char> {
+        self
+    }
+}
+
+fn char_set(chars: impl IntoCharSet) -> Vec<char> {
+    let mut chars = chars.into_chars();
+    chars.sort_unstable();
+    chars.dedup();
+    chars
+}
+
+/// Renders a set in bracket notation, escaping the characters that are
+/// special inside brackets.
+fn display_set(chars: &[char], negated: bool) -> String {
+    let mut out = String::from(if negated { "[^" } else { "[" });
+    for &c in chars {
+        match c {
+            ']' | '\\' | '^' | '-' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out.push(']');
+    out
+}
+
+pub fn one_of(chars: impl IntoCharSet) -> OneOf {
+    OneOf {
+        chars: char_set(chars),
+    }
+}
+
+pub fn none_of(chars: impl IntoCharSet) -> NoneOf {
+    NoneOf {
+        chars: char_set(chars),
+    }
+}
+
+/// A sequence of lexical units (chars, bytes or tokens) addressed by
+/// [`Span`]s of unit indices.
+pub trait Lexical<T> {
+    fn as_slice(&self) -> &[T];
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn span(&self) -> Span {
         Span {
             start: 0,
             end: self.len(),
         }
     }
+    fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.as_slice().iter()
+    }
+    /// # Panics
+    ///
+    /// If `span` is out of bounds; see [`Lexical::get`].
     fn slice(&self, span: Span) -> &[T] {
-        &self[span.start..span.end]
+        &self.as_slice()[span.start..span.end]
     }
+    /// The units in `span`, or `None` if it is out of bounds or reversed.
+    fn get(&self, span: Span) -> Option<&[T]> {
+        self.as_slice().get(span.start..span.end)
+    }
+}
+
+pub trait LexicalMut<T>: Lexical<T> {
+    fn as_mut_slice(&mut self) -> &mut [T];
     fn slice_mut(&mut self, span: Span) -> &mut [T] {
-        &mut self[span.start..span.end]
+        &mut self.as_mut_slice()[span.start..span.end]
+    }
+    fn get_mut(&mut self, span: Span) -> Option<&mut [T]> {
+        self.as_mut_slice().get_mut(span.start..span.end)
     }
 }
 
-impl<T: Clone + PartialEq + Eq> Lexical<T> for Vec<T> {
-    fn len(&self) -> usize {
-        self.len()
+impl<T> Lexical<T> for Vec<T> {
+    fn as_slice(&self) -> &[T] {
+        self
+    }
+}
+
+impl<T> LexicalMut<T> for Vec<T> {
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        self
+    }
+}
+
+impl<T> Lexical<T> for Box<[T]> {
+    fn as_slice(&self) -> &[T] {
+        self
+    }
+}
+
+impl<T> LexicalMut<T> for Box<[T]> {
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        self
+    }
+}
+
+impl<T, const N: usize> Lexical<T> for [T; N] {
+    fn as_slice(&self) -> &[T] {
+        self
+    }
+}
+
+impl<T, const N: usize> LexicalMut<T> for [T; N] {
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        self
+    }
+}
+
+impl<T> Lexical<T> for &[T] {
+    fn as_slice(&self) -> &[T] {
+        self
+    }
+}
+
+impl<T> Lexical<T> for &mut [T] {
+    fn as_slice(&self) -> &[T] {
+        self
+    }
+}
+
+impl<T> LexicalMut<T> for &mut [T] {
+    fn as_mut_slice(&mut self) -> &mut [T] {
+        self
+    }
+}
+
+/// Closed description of the built-in matchers, used to store and restore
+/// grammars. Custom matchers have no spec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TerminalSpec {
+    Str(String),
+    Char(char),
+    StartOfInput,
+    EndOfInput,
+    StartOfLine,
+    EndOfLine,
+    Alternative(Box<TerminalSpec>, Box<TerminalSpec>),
+    Sequence(Box<TerminalSpec>, Box<TerminalSpec>),
+    /// `max` is inclusive; `None` is unbounded.
+    Repeat {
+        inner: Box<TerminalSpec>,
+        min: usize,
+        max: Option<usize>,
+    },
+    LazyRepeat {
+        inner: Box<TerminalSpec>,
+        min: usize,
+        max: Option<usize>,
+    },
+    Keywords(Vec<String>),
+    #[cfg(feature = "byte-input")]
+    Byte(u8),
+    #[cfg(feature = "byte-input")]
+    ByteString(Vec<u8>),
+    #[cfg(feature = "byte-input")]
+    AnyBytes(usize),
+    /// Header width in bytes: 1, 2 or 4.
+    #[cfg(feature = "byte-input")]
+    LengthPrefixed {
+        width: usize,
+        big_endian: bool,
+    },
+    /// Inclusive character range.
+    CharRange(char, char),
+    /// One of the built-in [`CHAR_CLASSES`], by name.
+    CharClass(String),
+    AnyChar,
+    NoneOf(Vec<char>),
+    OneOf(Vec<char>),
+    Integer {
+        allow_sign: bool,
+        allow_underscores: bool,
+        radix: u32,
+    },
+    Float {
+        allow_exponent: bool,
+        require_fraction: bool,
+    },
+    QuotedString {
+        quote: char,
+        escape: Option<char>,
+        allow_newlines: bool,
+    },
+    LineComment(String),
+    BlockComment {
+        open: String,
+        close: String,
+        nested: bool,
+        allow_unterminated: bool,
+    },
+    Identifier,
+    Whitespace {
+        include_newlines: bool,
+        min: usize,
+    },
+    Until {
+        delimiter: String,
+        inclusive: bool,
+        or_end_of_input: bool,
+    },
+    /// Pattern of a [`Regex`]; only available with the `regex` feature.
+    #[cfg(feature = "regex")]
+    Regex(String),
+}
+
+impl TerminalSpec {
+    pub fn into_matcher(self) -> Box<dyn Matcher> {
+        use std::ops::Bound;
+        match self {
+            TerminalSpec::Str(text) => Box::new(text),
+            TerminalSpec::Char(c) => Box::new(c),
+            TerminalSpec::StartOfInput => Box::new(StartOfInput),
+            TerminalSpec::EndOfInput => Box::new(EndOfInput),
+            TerminalSpec::StartOfLine => Box::new(StartOfLine),
+            TerminalSpec::EndOfLine => Box::new(EndOfLine),
+            TerminalSpec::Alternative(a, b) => {
+                Box::new(Alternative(a.into_matcher(), b.into_matcher()))
+            }
+            TerminalSpec::Sequence(a, b) => Box::new(Sequence(a.into_matcher(), b.into_matcher())),
+            TerminalSpec::Repeat { inner, min, max } => Box::new(Repeat(
+                inner.into_matcher(),
+                (
+                    Bound::Included(min),
+                    max.map_or(Bound::Unbounded, Bound::Included),
+                ),
+            )),
+            TerminalSpec::LazyRepeat { inner, min, max } => Box::new(LazyRepeat(
+                inner.into_matcher(),
+                (
+                    Bound::Included(min),
+                    max.map_or(Bound::Unbounded, Bound::Included),
+                ),
+            )),
+            #[cfg(feature = "byte-input")]
+            TerminalSpec::Byte(b) => Box::new(b),
+            #[cfg(feature = "byte-input")]
+            TerminalSpec::ByteString(bytes) => Box::new(bytes),
+            #[cfg(feature = "byte-input")]
+            TerminalSpec::AnyBytes(n) => Box::new(AnyBytes(n)),
+            #[cfg(feature = "byte-input")]
+            TerminalSpec::LengthPrefixed { width, big_endian } => Box::new(
+                LengthPrefixed::new(width, big_endian)
+                    .unwrap_or_else(|| panic!("invalid length prefix width {}", width)),
+            ),
+            TerminalSpec::Keywords(words) => Box::new(Keywords::new(words)),
+            TerminalSpec::CharRange(start, end) => Box::new(start..=end),
+            TerminalSpec::AnyChar => Box::new(AnyChar),
+            TerminalSpec::NoneOf(chars) => Box::new(none_of(chars)),
+            TerminalSpec::OneOf(chars) => Box::new(one_of(chars)),
+            TerminalSpec::Integer {
+                allow_sign,
+                allow_underscores,
+                radix,
+            } => {
+                let mut integer = Integer::with_radix(radix);
+                integer.allow_sign = allow_sign;
+                integer.allow_underscores = allow_underscores;
+                Box::new(integer)
+            }
+            TerminalSpec::Float {
+                allow_exponent,
+                require_fraction,
+            } => Box::new(Float {
+                allow_exponent,
+                require_fraction,
+            }),
+            TerminalSpec::QuotedString {
+                quote,
+                escape,
+                allow_newlines,
+            } => Box::new(QuotedString {
+                quote,
+                escape,
+                allow_newlines,
+            }),
+            TerminalSpec::LineComment(prefix) => Box::new(LineComment(leak_name(prefix))),
+            TerminalSpec::BlockComment {
+                open,
+                close,
+                nested,
+                allow_unterminated,
+            } => {
+                let mut comment = BlockComment::new(leak_name(open), leak_name(close));
+                comment.nested = nested;
+                comment.allow_unterminated = allow_unterminated;
+                Box::new(comment)
+            }
+            TerminalSpec::Identifier => Box::new(Identifier),
+            TerminalSpec::Whitespace {
+                include_newlines,
+                min,
+            } => Box::new(Whitespace {
+                include_newlines,
+                min,
+            }),
+            TerminalSpec::Until {
+                delimiter,
+                inclusive,
+                or_end_of_input,
+            } => {
+                let mut until = Until::new(delimiter);
+                until.inclusive = inclusive;
+                until.or_end_of_input = or_end_of_input;
+                Box::new(until)
+            }
+            #[cfg(feature = "regex")]
+            TerminalSpec::Regex(pattern) => Box::new(
+                Regex::new(&pattern).unwrap_or_else(|e| panic!("invalid regex spec: {}", e)),
+            ),
+            TerminalSpec::CharClass(name) => Box::new(
+                CharClass::builtin(&name).unwrap_or_else(|| panic!("unknown char class {}", name)),
+            ),
+        }
     }
 }
 
+/// The input and current byte position a [`Matcher`] runs against.
 pub struct State<'a> {
     input: &'a str,
     position: usize,
 }
 
-pub trait Matcher: Debug {
-    fn matches(&self, state: &mut State) -> bool;
+/// A saved [`State`] position, taken by [`State::checkpoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    position: usize,
+}
+
+impl<'a> State<'a> {
+    pub fn new(input: &'a str) -> Self {
+        State::at(input, 0)
+    }
+
+    /// A state positioned at byte offset `position` of `input`.
+    pub fn at(input: &'a str, position: usize) -> Self {
+        State { input, position }
+    }
+
+    pub fn input(&self) -> &'a str {
+        self.input
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Moves the position forward by `len` bytes.
+    pub fn advance(&mut self, len: usize) {
+        self.position += len;
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            position: self.position,
+        }
+    }
+
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        self.position = checkpoint.position;
+    }
+
+    /// The unconsumed input. Empty if the position is out of bounds or not
+    /// on a char boundary, so byte arithmetic never panics here.
+    pub fn rest(&self) -> &'a str {
+        self.input.get(self.position..).unwrap_or("")
+    }
+
+    /// The 1-based line and column of the current position, computed from
+    /// the input prefix on demand.
+    pub fn line_col(&self) -> (usize, usize) {
+        let LineCol { line, col } = line_col(self.input, self.position);
+        (line, col)
+    }
+}
+
+/// How tightly a matcher's [`Matcher::display`] binds, so composite
+/// displays know when to parenthesize their parts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Precedence {
+    Alternative,
+    Sequence,
+    Atom,
+}
+
+/// Runs `matcher` at the start of `input`, returning the number of bytes it
+/// consumed.
+pub fn match_str(matcher: &(impl Matcher + ?Sized), input: &str) -> Option<usize> {
+    matcher.try_match(input, 0)
+}
+
+/// Displays `matcher`, parenthesized if it binds looser than `min`.
+pub(crate) fn display_at(matcher: &(impl Matcher + ?Sized), min: Precedence) -> String {
+    if matcher.precedence() < min {
+        format!("({})", matcher.display())
+    } else {
+        matcher.display()
+    }
+}
+
+/// The end of the char at `at`, or one past the end of input.
+pub(crate) fn next_char_end(input: &str, at: usize) -> usize {
+    at + input
+        .get(at..)
+        .and_then(|rest| rest.chars().next())
+        .map_or(1, char::len_utf8)
+}
+
+/// The later of two [`Matcher::lookahead_end`]s, unknown if either is.
+pub(crate) fn max_reach(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    Some(a?.max(b?))
+}
+
+/// A terminal, implemented by [`Matcher::try_match`], which says how much
+/// of the input it consumes without changing anything, so a failed match
+/// needs no cleanup. [`Matcher::matches`] adapts it to a [`State`]:
+///
+/// ```
+/// use tree_editor::words::{Matcher, State, match_str};
+///
+/// /// A run of one or more `'a'`s followed by `'b'`.
+/// #[derive(Debug)]
+/// struct AsThenB;
+///
+/// impl Matcher for AsThenB {
+///     fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+///         let rest = input.get(at..)?;
+///         let run = rest.len() - rest.trim_start_matches('a').len();
+///         (run > 0 && rest[run..].starts_with('b')).then_some(run + 1)
+///     }
+///
+///     fn is_nullable(&self) -> bool {
+///         false
+///     }
+/// }
+///
+/// assert_eq!(match_str(&AsThenB, "aab!"), Some(3));
+/// assert_eq!(match_str(&AsThenB, "aa!"), None);
+/// let mut state = State::new("aac");
+/// assert!(!AsThenB.matches(&mut state));
+/// assert_eq!(state.position(), 0);
+/// ```
+pub trait Matcher: Debug + Send + Sync {
+    /// Matches at byte offset `at` of `input`, returning the number of bytes
+    /// consumed. Nothing is mutated, so a failed match needs no cleanup.
+    fn try_match(&self, input: &str, at: usize) -> Option<usize>;
+
+    /// Matches at byte offset `at` of binary input. By default the matcher
+    /// runs on the valid UTF-8 that follows `at`, which is what text
+    /// matchers embedded in a binary grammar need; byte matchers override it.
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        let rest = input.get(at..)?;
+        let valid = match std::str::from_utf8(rest) {
+            Ok(text) => text,
+            Err(e) => std::str::from_utf8(&rest[..e.valid_up_to()]).unwrap_or_default(),
+        };
+        self.try_match(valid, 0)
+    }
+
+    /// Matches at token index `at` of a token stream, returning the number
+    /// of tokens consumed. Text matchers never match tokens.
+    fn try_match_tokens(&self, input: &dyn TokenInput, at: usize) -> Option<usize> {
+        let _ = (input, at);
+        None
+    }
+
+    /// The end of the input this matcher reads when run at `at`, whether
+    /// or not it matches. Looking for more input at the end counts as
+    /// reading byte `input.len()`. Incremental reparsing reuses results
+    /// whose reads all end before an edit, so this must never undercount;
+    /// `None`, the default, means unknown and is always safe.
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        let _ = (input, at);
+        None
+    }
+
+    /// Matches at `state.position()` through [`Matcher::try_match`],
+    /// advancing past the match on success and leaving the position
+    /// unchanged on failure.
+    fn matches(&self, state: &mut State) -> bool {
+        match self.try_match(state.input(), state.position()) {
+            Some(len) => {
+                state.advance(len);
+                true
+            }
+            None => false,
+        }
+    }
     fn display(&self) -> String {
         String::from("<terminal>")
     }
+    /// The exact text this matcher accepts, for matchers that match one fixed
+    /// literal. Used when exporting grammars to other notations.
+    fn literal(&self) -> Option<String> {
+        None
+    }
+    /// Some text this matcher accepts, for generating sentences from a
+    /// grammar: a member for ranges and sets, the shortest form for
+    /// repetitions and the empty string for zero-width matchers. By default
+    /// the [`Matcher::literal`], so matchers without one give `None`.
+    fn sample(&self) -> Option<String> {
+        self.literal()
+    }
     fn is_nullable(&self) -> bool;
 
+    /// The precedence of [`Matcher::display`]'s output.
+    fn precedence(&self) -> Precedence {
+        Precedence::Atom
+    }
+
+    /// Matches `self` and then `next` from where it stopped. Matchers that
+    /// can stop at more than one place try them in turn until `next`
+    /// succeeds; by default only the position from `matches` is tried.
+    fn matches_then(&self, state: &mut State, next: &dyn Fn(&mut State) -> bool) -> bool {
+        self.matches(state) && next(state)
+    }
+
+    /// Describes this matcher for serialization, if it is a built-in one.
+    fn spec(&self) -> Option<TerminalSpec> {
+        None
+    }
+
     fn is_consuming(&self) -> bool {
         !self.is_nullable()
     }
@@ -87,74 +699,356 @@ pub trait Matcher: Debug {
     {
         Repeat(self, range)
     }
+
+    fn times_lazy<R>(self, range: R) -> LazyRepeat<Self, R>
+    where
+        Self: Sized,
+        R: ops::RangeBounds<usize>,
+    {
+        LazyRepeat(self, range)
+    }
 }
 
 impl Matcher for &str {
-    fn matches(&self, state: &mut State) -> bool {
-        let end_pos = state.position + self.len();
-        if end_pos <= state.input.len() && &state.input[state.position..end_pos] == *self {
-            state.position = end_pos;
-            true
-        } else {
-            false
-        }
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        input.get(at..)?.starts_with(*self).then_some(self.len())
+    }
+
+    fn lookahead_end(&self, _input: &str, at: usize) -> Option<usize> {
+        Some(at + self.len())
+    }
+
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        input
+            .get(at..)?
+            .starts_with(self.as_bytes())
+            .then_some(self.len())
     }
 
     fn display(&self) -> String {
         format!("\"{}\"", self)
     }
 
+    fn literal(&self) -> Option<String> {
+        Some(self.to_string())
+    }
+
     fn is_nullable(&self) -> bool {
-        self.len() == 0
+        self.is_empty()
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Str(self.to_string()))
+    }
+}
+
+impl Matcher for String {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        self.as_str().try_match(input, at)
+    }
+
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        self.as_str().lookahead_end(input, at)
+    }
+
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        self.as_str().try_match_bytes(input, at)
+    }
+
+    fn display(&self) -> String {
+        Matcher::display(&self.as_str())
+    }
+
+    fn literal(&self) -> Option<String> {
+        Some(self.clone())
+    }
+
+    fn is_nullable(&self) -> bool {
+        self.is_empty()
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Str(self.clone()))
     }
 }
 
 impl Matcher for char {
-    fn matches(&self, state: &mut State) -> bool {
-        if let Some(next_char) = state.input[state.position..].chars().next() {
-            if next_char == *self {
-                state.position += next_char.len_utf8();
-                return true;
-            }
-        }
-        false
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        input
+            .get(at..)?
+            .starts_with(*self)
+            .then_some(self.len_utf8())
+    }
+
+    fn lookahead_end(&self, _input: &str, at: usize) -> Option<usize> {
+        Some(at + self.len_utf8())
     }
 
     fn display(&self) -> String {
         format!("'{}'", self)
     }
 
+    fn literal(&self) -> Option<String> {
+        Some(self.to_string())
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Char(*self))
+    }
+}
+
+impl Matcher for ops::RangeInclusive<char> {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let c = input.get(at..)?.chars().next()?;
+        (self.contains(&c)).then_some(c.len_utf8())
+    }
+
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        Some(next_char_end(input, at))
+    }
+
+    fn display(&self) -> String {
+        format!("['{}'-'{}']", self.start(), self.end())
+    }
+    fn sample(&self) -> Option<String> {
+        (!self.is_empty()).then(|| self.start().to_string())
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::CharRange(*self.start(), *self.end()))
+    }
+}
+
+impl Matcher for AnyChar {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let c = input.get(at..)?.chars().next()?;
+        Some(c.len_utf8())
+    }
+
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        Some(next_char_end(input, at))
+    }
+
+    fn display(&self) -> String {
+        String::from(".")
+    }
+    fn sample(&self) -> Option<String> {
+        Some(String::from("a"))
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::AnyChar)
+    }
+}
+
+impl OneOf {
+    pub fn chars(&self) -> &[char] {
+        &self.chars
+    }
+}
+
+impl Matcher for OneOf {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let c = input.get(at..)?.chars().next()?;
+        (self.chars.binary_search(&c).is_ok()).then_some(c.len_utf8())
+    }
+
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        Some(next_char_end(input, at))
+    }
+
+    fn display(&self) -> String {
+        display_set(&self.chars, false)
+    }
+    fn sample(&self) -> Option<String> {
+        self.chars.first().map(char::to_string)
+    }
+
     fn is_nullable(&self) -> bool {
         false
     }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::OneOf(self.chars.clone()))
+    }
+}
+
+impl NoneOf {
+    pub fn chars(&self) -> &[char] {
+        &self.chars
+    }
+}
+
+impl Matcher for NoneOf {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let c = input.get(at..)?.chars().next()?;
+        (self.chars.binary_search(&c).is_err()).then_some(c.len_utf8())
+    }
+
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        Some(next_char_end(input, at))
+    }
+
+    fn display(&self) -> String {
+        display_set(&self.chars, true)
+    }
+    fn sample(&self) -> Option<String> {
+        (('a'..='z').chain('0'..='9'))
+            .find(|c| self.chars.binary_search(c).is_err())
+            .map(String::from)
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::NoneOf(self.chars.clone()))
+    }
 }
 
 impl Matcher for EndOfInput {
-    fn matches(&self, state: &mut State) -> bool {
-        state.position >= state.input.len()
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        (at >= input.len()).then_some(0)
+    }
+
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        (at >= input.len()).then_some(0)
+    }
+
+    fn try_match_tokens(&self, input: &dyn TokenInput, at: usize) -> Option<usize> {
+        (at >= input.len()).then_some(0)
+    }
+
+    fn lookahead_end(&self, _input: &str, at: usize) -> Option<usize> {
+        Some(at + 1)
     }
 
     fn display(&self) -> String {
         String::from("EOF")
     }
+    fn sample(&self) -> Option<String> {
+        Some(String::new())
+    }
 
     fn is_nullable(&self) -> bool {
         true
     }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::EndOfInput)
+    }
 }
 
 impl Matcher for StartOfInput {
-    fn matches(&self, state: &mut State) -> bool {
-        state.position == 0
+    fn try_match(&self, _input: &str, at: usize) -> Option<usize> {
+        (at == 0).then_some(0)
+    }
+
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, _input: &[u8], at: usize) -> Option<usize> {
+        (at == 0).then_some(0)
+    }
+
+    fn try_match_tokens(&self, _input: &dyn TokenInput, at: usize) -> Option<usize> {
+        (at == 0).then_some(0)
+    }
+
+    fn lookahead_end(&self, _input: &str, at: usize) -> Option<usize> {
+        Some(at)
     }
 
     fn display(&self) -> String {
         String::from("SOF")
     }
+    fn sample(&self) -> Option<String> {
+        Some(String::new())
+    }
+
+    fn is_nullable(&self) -> bool {
+        true
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::StartOfInput)
+    }
+}
+
+impl Matcher for StartOfLine {
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        (at == 0 || input.get(at - 1) == Some(&b'\n')).then_some(0)
+    }
+
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        (at == 0 || input.get(..at).is_some_and(|before| before.ends_with('\n'))).then_some(0)
+    }
+
+    fn lookahead_end(&self, _input: &str, at: usize) -> Option<usize> {
+        Some(at)
+    }
+
+    fn display(&self) -> String {
+        String::from("BOL")
+    }
+    fn sample(&self) -> Option<String> {
+        Some(String::new())
+    }
+
+    fn is_nullable(&self) -> bool {
+        true
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::StartOfLine)
+    }
+}
+
+impl Matcher for EndOfLine {
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        let rest = input.get(at..)?;
+        (rest.is_empty() || rest.starts_with(b"\n") || rest.starts_with(b"\r\n")).then_some(0)
+    }
+
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let rest = input.get(at..)?;
+        (rest.is_empty() || rest.starts_with('\n') || rest.starts_with("\r\n")).then_some(0)
+    }
+
+    fn lookahead_end(&self, _input: &str, at: usize) -> Option<usize> {
+        Some(at + 2)
+    }
+
+    fn display(&self) -> String {
+        String::from("EOL")
+    }
+    fn sample(&self) -> Option<String> {
+        Some(String::new())
+    }
 
     fn is_nullable(&self) -> bool {
         true
     }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::EndOfLine)
+    }
 }
 
 impl<T, U> Matcher for Alternative<T, U>
@@ -162,18 +1056,52 @@ where
     T: Matcher,
     U: Matcher,
 {
-    fn matches(&self, state: &mut State) -> bool {
-        let original_position = state.position;
-        if self.0.matches(state) {
-            true
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        self.0
+            .try_match(input, at)
+            .or_else(|| self.1.try_match(input, at))
+    }
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        self.0
+            .try_match_bytes(input, at)
+            .or_else(|| self.1.try_match_bytes(input, at))
+    }
+    fn try_match_tokens(&self, input: &dyn TokenInput, at: usize) -> Option<usize> {
+        self.0
+            .try_match_tokens(input, at)
+            .or_else(|| self.1.try_match_tokens(input, at))
+    }
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        let first = self.0.lookahead_end(input, at);
+        if self.0.try_match(input, at).is_some() {
+            first
         } else {
-            state.position = original_position;
-            self.1.matches(state)
+            max_reach(first, self.1.lookahead_end(input, at))
         }
     }
+    fn display(&self) -> String {
+        format!(
+            "{} | {}",
+            display_at(&self.0, Precedence::Alternative),
+            display_at(&self.1, Precedence::Alternative)
+        )
+    }
+    fn sample(&self) -> Option<String> {
+        self.0.sample().or_else(|| self.1.sample())
+    }
+    fn precedence(&self) -> Precedence {
+        Precedence::Alternative
+    }
     fn is_nullable(&self) -> bool {
         self.0.is_nullable() || self.1.is_nullable()
     }
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Alternative(
+            Box::new(self.0.spec()?),
+            Box::new(self.1.spec()?),
+        ))
+    }
 }
 
 impl<T, U> Matcher for Sequence<T, U>
@@ -181,57 +1109,751 @@ where
     T: Matcher,
     U: Matcher,
 {
-    fn matches(&self, state: &mut State) -> bool {
-        self.0.matches(state) && self.1.matches(state)
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let mut state = State::at(input, at);
+        self.matches_then(&mut state, &|_| true)
+            .then(|| state.position() - at)
+    }
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        let first = self.0.try_match_bytes(input, at)?;
+        let second = self.1.try_match_bytes(input, at + first)?;
+        Some(first + second)
+    }
+    fn try_match_tokens(&self, input: &dyn TokenInput, at: usize) -> Option<usize> {
+        let first = self.0.try_match_tokens(input, at)?;
+        let second = self.1.try_match_tokens(input, at + first)?;
+        Some(first + second)
+    }
+    fn matches_then(&self, state: &mut State, next: &dyn Fn(&mut State) -> bool) -> bool {
+        let start = state.checkpoint();
+        if self
+            .0
+            .matches_then(state, &|state| self.1.matches_then(state, next))
+        {
+            true
+        } else {
+            state.restore(start);
+            false
+        }
+    }
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        let first = self.0.lookahead_end(input, at);
+        match self.0.try_match(input, at) {
+            Some(len) => max_reach(first, self.1.lookahead_end(input, at + len)),
+            None => first,
+        }
+    }
+    fn display(&self) -> String {
+        format!(
+            "{} {}",
+            display_at(&self.0, Precedence::Sequence),
+            display_at(&self.1, Precedence::Sequence)
+        )
+    }
+    fn sample(&self) -> Option<String> {
+        Some(self.0.sample()? + &self.1.sample()?)
+    }
+    fn precedence(&self) -> Precedence {
+        Precedence::Sequence
     }
     fn is_nullable(&self) -> bool {
         self.0.is_nullable() && self.1.is_nullable()
     }
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Sequence(
+            Box::new(self.0.spec()?),
+            Box::new(self.1.spec()?),
+        ))
+    }
+}
+
+/// Normalizes a repetition range to inclusive `(min, max)` bounds, with
+/// `None` for an unbounded maximum. Returns `None` for a range that admits
+/// no count at all: `..0`, `0..0` and reversed ranges never match, while
+/// `..=0` matches exactly zero repetitions.
+fn repeat_bounds(range: &impl ops::RangeBounds<usize>) -> Option<(usize, Option<usize>)> {
+    use std::ops::Bound;
+
+    let min = match range.start_bound() {
+        Bound::Included(&n) => n,
+        Bound::Excluded(&n) => n.checked_add(1)?,
+        Bound::Unbounded => 0,
+    };
+    let max = match range.end_bound() {
+        Bound::Included(&n) => Some(n),
+        Bound::Excluded(&n) => Some(n.checked_sub(1)?),
+        Bound::Unbounded => None,
+    };
+    match max {
+        Some(max) if max < min => None,
+        _ => Some((min, max)),
+    }
+}
+
+/// Renders `inner` with a postfix repetition operator.
+fn display_repeat(inner: &impl Matcher, bounds: Option<(usize, Option<usize>)>) -> String {
+    let operator = match bounds {
+        Some((0, None)) => "*".to_string(),
+        Some((1, None)) => "+".to_string(),
+        Some((0, Some(1))) => "?".to_string(),
+        Some((min, None)) => format!("{{{},}}", min),
+        Some((min, Some(max))) if min == max => format!("{{{}}}", min),
+        Some((min, Some(max))) => format!("{{{},{}}}", min, max),
+        // An empty range; rendered the way it is serialized.
+        None => "{1,0}".to_string(),
+    };
+    format!("{}{}", display_at(inner, Precedence::Atom), operator)
+}
+
+/// Applies `step` as many times as `bounds` allow, returning the total
+/// length if at least the minimum matched.
+fn repeat_greedy(
+    (min, max): (usize, Option<usize>),
+    at: usize,
+    step: impl Fn(usize) -> Option<usize>,
+) -> Option<usize> {
+    let mut end = at;
+    let mut count = 0;
+
+    while max.is_none_or(|max| count < max) {
+        let Some(len) = step(end) else {
+            break;
+        };
+        count += 1;
+        end += len;
+        if len == 0 {
+            break;
+        }
+    }
+
+    (count >= min).then_some(end - at)
 }
 
+/// Repeats greedily. A zero-width match of the inner matcher ends the
+/// repetition and counts once, so a nullable inner matcher can't loop
+/// forever and only satisfies a minimum of at most one.
 impl<R, T> Matcher for Repeat<T, R>
 where
     T: Matcher,
-    R: ops::RangeBounds<usize> + Debug,
+    R: ops::RangeBounds<usize> + Debug + Send + Sync,
 {
-    fn matches(&self, state: &mut State) -> bool {
-        use std::ops::Bound;
-
-        let min = match self.1.start_bound() {
-            Bound::Included(&n) => n,
-            Bound::Excluded(&n) => n + 1,
-            Bound::Unbounded => 0,
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        repeat_greedy(repeat_bounds(&self.1)?, at, |end| {
+            self.0.try_match(input, end)
+        })
+    }
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        repeat_greedy(repeat_bounds(&self.1)?, at, |end| {
+            self.0.try_match_bytes(input, end)
+        })
+    }
+    fn try_match_tokens(&self, input: &dyn TokenInput, at: usize) -> Option<usize> {
+        repeat_greedy(repeat_bounds(&self.1)?, at, |end| {
+            self.0.try_match_tokens(input, end)
+        })
+    }
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        let Some(bounds) = repeat_bounds(&self.1) else {
+            return Some(at);
         };
+        let reach = std::cell::Cell::new(Some(at));
+        repeat_greedy(bounds, at, |end| {
+            reach.set(max_reach(reach.get(), self.0.lookahead_end(input, end)));
+            self.0.try_match(input, end)
+        });
+        reach.get()
+    }
+    fn display(&self) -> String {
+        display_repeat(&self.0, repeat_bounds(&self.1))
+    }
+    fn sample(&self) -> Option<String> {
+        let (min, _) = repeat_bounds(&self.1)?;
+        Some(self.0.sample()?.repeat(min))
+    }
+    fn is_nullable(&self) -> bool {
+        match repeat_bounds(&self.1) {
+            Some((min, _)) => min == 0 || (min == 1 && self.0.is_nullable()),
+            None => false,
+        }
+    }
+    fn spec(&self) -> Option<TerminalSpec> {
+        let (min, max) = repeat_bounds(&self.1).unwrap_or((1, Some(0)));
+        Some(TerminalSpec::Repeat {
+            inner: Box::new(self.0.spec()?),
+            min,
+            max,
+        })
+    }
+}
 
-        let max = match self.1.end_bound() {
-            Bound::Included(&n) => n,
-            Bound::Excluded(&n) => n.saturating_sub(1),
-            Bound::Unbounded => usize::MAX,
+impl<R, T> Matcher for LazyRepeat<T, R>
+where
+    T: Matcher,
+    R: ops::RangeBounds<usize> + Debug + Send + Sync,
+{
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let mut state = State::at(input, at);
+        (self.matches_then(&mut state, &|_| true)).then(|| state.position() - at)
+    }
+    /// Binary and token input have no follower-aware matching, so only the minimum is
+    /// taken.
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        let (min, _) = repeat_bounds(&self.1)?;
+        repeat_greedy((min, Some(min)), at, |end| {
+            self.0.try_match_bytes(input, end)
+        })
+    }
+    fn try_match_tokens(&self, input: &dyn TokenInput, at: usize) -> Option<usize> {
+        let (min, _) = repeat_bounds(&self.1)?;
+        repeat_greedy((min, Some(min)), at, |end| {
+            self.0.try_match_tokens(input, end)
+        })
+    }
+    fn matches_then(&self, state: &mut State, next: &dyn Fn(&mut State) -> bool) -> bool {
+        let Some((min, max)) = repeat_bounds(&self.1) else {
+            return false;
         };
-
-        let original_position = state.position;
+        let start = state.checkpoint();
         let mut count = 0;
-
-        while count < max && self.0.matches(state) {
+        // As in `Repeat`, a zero-width repetition is the last one tried.
+        let mut stalled = false;
+        loop {
+            if count >= min {
+                let here = state.checkpoint();
+                if next(state) {
+                    return true;
+                }
+                state.restore(here);
+            }
+            if stalled || max.is_some_and(|max| count >= max) {
+                break;
+            }
+            let before = state.checkpoint();
+            if !self.0.matches(state) {
+                break;
+            }
             count += 1;
+            stalled = state.checkpoint() == before;
+        }
+        state.restore(start);
+        false
+    }
+    fn display(&self) -> String {
+        format!("{}?", display_repeat(&self.0, repeat_bounds(&self.1)))
+    }
+    fn sample(&self) -> Option<String> {
+        let (min, _) = repeat_bounds(&self.1)?;
+        Some(self.0.sample()?.repeat(min))
+    }
+    fn is_nullable(&self) -> bool {
+        match repeat_bounds(&self.1) {
+            Some((min, _)) => min == 0 || (min == 1 && self.0.is_nullable()),
+            None => false,
         }
+    }
+    fn spec(&self) -> Option<TerminalSpec> {
+        let (min, max) = repeat_bounds(&self.1).unwrap_or((1, Some(0)));
+        Some(TerminalSpec::LazyRepeat {
+            inner: Box::new(self.0.spec()?),
+            min,
+            max,
+        })
+    }
+}
 
-        if count >= min && count <= max {
-            true
-        } else {
-            state.position = original_position;
-            false
+pub(crate) fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+pub(crate) fn is_ident_continue(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl CharClass {
+    pub fn builtin(name: &str) -> Option<CharClass> {
+        CHAR_CLASSES.into_iter().find(|class| class.name == name)
+    }
+}
+
+impl Matcher for CharClass {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let c = input.get(at..)?.chars().next()?;
+        ((self.pred)(c)).then_some(c.len_utf8())
+    }
+
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        Some(next_char_end(input, at))
+    }
+
+    fn display(&self) -> String {
+        format!("<{}>", self.name)
+    }
+    fn sample(&self) -> Option<String> {
+        (('a'..='z')
+            .chain('A'..='Z')
+            .chain('0'..='9')
+            .chain([' ', '_']))
+        .find(|&c| (self.pred)(c))
+        .map(String::from)
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    /// Classes are serialized by name, so a custom class only round-trips
+    /// if it doesn't reuse a built-in name.
+    fn spec(&self) -> Option<TerminalSpec> {
+        CharClass::builtin(self.name).map(|class| TerminalSpec::CharClass(class.name.to_string()))
+    }
+}
+
+impl Keywords {
+    pub fn new<S: Into<String>>(words: impl IntoIterator<Item = S>) -> Self {
+        let mut words: Vec<String> = words.into_iter().map(Into::into).collect();
+        words.sort();
+        words.dedup();
+        let mut lengths: Vec<usize> = words.iter().map(String::len).collect();
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        lengths.dedup();
+        Keywords { words, lengths }
+    }
+
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+}
+
+impl Matcher for Keywords {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let rest = input.get(at..)?;
+        for &len in self.lengths.iter() {
+            let Some(candidate) = rest.get(..len) else {
+                continue;
+            };
+            if self
+                .words
+                .binary_search_by(|w| w.as_str().cmp(candidate))
+                .is_err()
+            {
+                continue;
+            }
+            let at_boundary = !candidate.ends_with(is_ident_continue)
+                || !rest[len..].starts_with(is_ident_continue);
+            if at_boundary {
+                return Some(len);
+            }
         }
+        None
+    }
+
+    /// The longest candidate, then one char for the word-boundary check.
+    fn lookahead_end(&self, _input: &str, at: usize) -> Option<usize> {
+        let longest = self.lengths.first().copied().unwrap_or(0);
+        Some(at + longest + 4)
+    }
+
+    fn display(&self) -> String {
+        let words: Vec<String> = self.words.iter().map(|w| format!("\"{}\"", w)).collect();
+        format!("({})", words.join(" | "))
     }
+    fn sample(&self) -> Option<String> {
+        self.words.first().cloned()
+    }
+
     fn is_nullable(&self) -> bool {
-        use std::ops::Bound;
+        self.words.iter().any(String::is_empty)
+    }
 
-        let min = match self.1.start_bound() {
-            Bound::Included(&n) => n,
-            Bound::Excluded(&n) => n + 1,
-            Bound::Unbounded => 0,
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Keywords(self.words.clone()))
+    }
+}
+
+impl<M: Matcher + ?Sized> Matcher for Box<M> {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        (**self).try_match(input, at)
+    }
+    #[cfg(feature = "byte-input")]
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        (**self).try_match_bytes(input, at)
+    }
+    fn try_match_tokens(&self, input: &dyn TokenInput, at: usize) -> Option<usize> {
+        (**self).try_match_tokens(input, at)
+    }
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        (**self).lookahead_end(input, at)
+    }
+    fn matches(&self, state: &mut State) -> bool {
+        (**self).matches(state)
+    }
+    fn matches_then(&self, state: &mut State, next: &dyn Fn(&mut State) -> bool) -> bool {
+        (**self).matches_then(state, next)
+    }
+    fn display(&self) -> String {
+        (**self).display()
+    }
+    fn sample(&self) -> Option<String> {
+        (**self).sample()
+    }
+    fn precedence(&self) -> Precedence {
+        (**self).precedence()
+    }
+    fn literal(&self) -> Option<String> {
+        (**self).literal()
+    }
+    fn is_nullable(&self) -> bool {
+        (**self).is_nullable()
+    }
+    fn spec(&self) -> Option<TerminalSpec> {
+        (**self).spec()
+    }
+    fn is_consuming(&self) -> bool {
+        (**self).is_consuming()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `matcher` at the start of `input`, returning the end position on success.
+    fn run(matcher: &impl Matcher, input: &str) -> Option<usize> {
+        let mut state = State { input, position: 0 };
+        matcher.matches(&mut state).then_some(state.position)
+    }
+
+    #[test]
+    fn test_str_char_boundaries() {
+        // "ab" would end inside the two-byte `é` if sliced by length.
+        assert_eq!(run(&"ab", "é"), None);
+        assert_eq!(run(&"a", "é"), None);
+        assert_eq!(run(&"xyz", "x😀"), None);
+        assert_eq!(run(&"x😀", "x😀!"), Some(5));
+        assert_eq!(run(&'a', "é"), None);
+        let mut state = State {
+            input: "é",
+            position: 1,
+        };
+        assert!(!Matcher::matches(&"a", &mut state));
+        assert!(!'a'.matches(&mut state));
+        assert!(!AnyChar.matches(&mut state));
+        assert!(!StartOfLine.matches(&mut state));
+    }
+
+    #[test]
+    fn test_random_patterns_never_panic() {
+        const ALPHABET: [char; 8] = ['a', 'é', '😀', '\n', 'b', '語', ' ', '\u{301}'];
+        let mut seed: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut next = move |bound: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % bound as u64) as usize
         };
+        let mut random_string =
+            |len: usize| -> String { (0..len).map(|_| ALPHABET[next(ALPHABET.len())]).collect() };
+        for _ in 0..2000 {
+            let input = random_string(6);
+            let pattern = random_string(3);
+            for position in 0..=input.len() + 1 {
+                let mut state = State {
+                    input: &input,
+                    position,
+                };
+                if Matcher::matches(&pattern.as_str(), &mut state) {
+                    assert!(input[position..].starts_with(&pattern));
+                    assert_eq!(state.position, position + pattern.len());
+                }
+                let first = pattern.chars().next().unwrap();
+                state.position = position;
+                first.matches(&mut state);
+            }
+        }
+    }
+
+    #[test]
+    fn test_repeat_bounds() {
+        assert_eq!(run(&'a'.times(..), "aaab"), Some(3));
+        assert_eq!(run(&'a'.times(2..=3), "aaaa"), Some(3));
+        assert_eq!(run(&'a'.times(2..), "ab"), None);
+        assert_eq!(run(&'a'.times(..2), "aaa"), Some(1));
+        assert_eq!(run(&'a'.times(..=0), "aaa"), Some(0));
+        assert!('a'.times(..=0).is_nullable());
+        // Empty ranges admit no repetition count and never match.
+        assert_eq!(run(&'a'.times(..0), "aaa"), None);
+        assert_eq!(run(&'a'.times(0..0), ""), None);
+        assert!(!'a'.times(..0).is_nullable());
+    }
+
+    #[test]
+    fn test_repeat_nullable_inner() {
+        assert_eq!(run(&"".times(1..), "abc"), Some(0));
+        assert_eq!(run(&"".times(..), ""), Some(0));
+        assert_eq!(run(&"".times(2..), "abc"), None);
+        assert_eq!(run(&'a'.or("").times(1..), "aab"), Some(2));
+        assert_eq!(run(&"ab".then('c').times(..), "abcabd"), Some(3));
+        assert!("".times(1..).is_nullable());
+        assert!(!"".times(2..).is_nullable());
+    }
+
+    #[test]
+    fn test_lazy_repeat() {
+        let body = AnyChar.times_lazy(..).then("*/");
+        assert_eq!(run(&body, " a * b */ c */"), Some(9));
+        assert_eq!(run(&body, "*/"), Some(2));
+        let mut state = State {
+            input: "/* never closed *",
+            position: 2,
+        };
+        assert!(!body.matches(&mut state));
+        assert_eq!(state.position, 2);
+
+        // The lazy repeat backtracks through a nested sequence.
+        let comment = "/*".then(AnyChar.times_lazy(..).then("*/").then(EndOfInput));
+        assert_eq!(run(&comment, "/* a */ b */"), Some(12));
+        assert_eq!(run(&comment, "/* a */ b"), None);
+
+        assert_eq!(run(&'a'.times_lazy(1..), "aaa"), Some(1));
+        assert_eq!(run(&'a'.times_lazy(..=2).then('b'), "aaab"), None);
+        assert_eq!(run(&"".times_lazy(..).then('b'), "b"), Some(1));
+        assert!(AnyChar.times_lazy(..).is_nullable());
+    }
+
+    #[test]
+    fn test_try_match() {
+        assert_eq!("ab".try_match("xab", 1), Some(2));
+        assert_eq!("ab".try_match("xab", 0), None);
+        assert_eq!('é'.try_match("é", 0), Some(2));
+        assert_eq!(EndOfInput.try_match("ab", 2), Some(0));
+        assert_eq!(StartOfInput.try_match("ab", 1), None);
+        assert_eq!('a'.or("bc").try_match("bcd", 0), Some(2));
+        assert_eq!('a'.then("bc").try_match("abd", 0), None);
+        assert_eq!('a'.times(1..).try_match("baab", 1), Some(2));
+        // Matchers implementing only `matches` get `try_match` for free.
+        assert_eq!(AnyChar.try_match("xy", 1), Some(1));
+
+        let mut state = State {
+            input: "abd",
+            position: 0,
+        };
+        assert!(!'a'.then("bc").matches(&mut state));
+        assert_eq!(state.position, 0);
+    }
 
-        min == 0 || self.0.is_nullable()
+    #[test]
+    fn test_sequence_restores_position() {
+        let pair = 'a'.then('b');
+        let mut state = State {
+            input: "abac",
+            position: 0,
+        };
+        assert!(pair.clone().times(..).matches(&mut state));
+        assert_eq!(state.position, 2);
+        assert!(!pair.clone().times(2..).matches(&mut state));
+        assert_eq!(state.position, 2);
+        assert!(!pair.matches(&mut state));
+        assert_eq!(state.position, 2);
+        assert_eq!(run(&pair.times(..).then("ac"), "abac"), Some(4));
+    }
+
+    #[test]
+    fn test_composite_display() {
+        assert_eq!("a".then('b').display(), r#""a" 'b'"#);
+        assert_eq!("a".or('b').then('c').display(), r#"("a" | 'b') 'c'"#);
+        assert_eq!("a".then('b').or('c').display(), r#""a" 'b' | 'c'"#);
+        assert_eq!('a'.or('b').or('c').display(), "'a' | 'b' | 'c'");
+        assert_eq!("a".then('b').times(1..).display(), r#"("a" 'b')+"#);
+        assert_eq!('a'.times(..).display(), "'a'*");
+        assert_eq!('a'.times(..=1).display(), "'a'?");
+        assert_eq!('a'.times(2..).display(), "'a'{2,}");
+        assert_eq!('a'.times(3..=3).display(), "'a'{3}");
+        assert_eq!('a'.times(2..5).display(), "'a'{2,4}");
+        assert_eq!('a'.times(..0).display(), "'a'{1,0}");
+        assert_eq!('a'.times(..).times(1..).display(), "'a'*+");
+        assert_eq!(AnyChar.times_lazy(..).then("*/").display(), r#".*? "*/""#);
+    }
+
+    #[test]
+    fn test_lookahead_end() {
+        let input = "ab1 é";
+        assert_eq!("ab".lookahead_end(input, 0), Some(2));
+        assert_eq!('x'.lookahead_end(input, 0), Some(1));
+        // A single-char matcher reads one whole char, or notices the end.
+        assert_eq!(ALPHA.lookahead_end(input, 4), Some(6));
+        assert_eq!(ALPHA.lookahead_end(input, 6), Some(7));
+        // A greedy repeat reads the char that stopped it.
+        assert_eq!(ALPHA.times(..).lookahead_end(input, 0), Some(3));
+        // Failed alternatives and the rest of a sequence count too.
+        assert_eq!(("abc".or("a")).lookahead_end(input, 0), Some(3));
+        assert_eq!(("a".then("b1 ")).lookahead_end(input, 0), Some(4));
+        assert_eq!(EndOfInput.lookahead_end(input, 2), Some(3));
+        assert_eq!("a".then("b".times_lazy(..)).lookahead_end(input, 0), None);
+    }
+
+    #[test]
+    fn test_state_line_col() {
+        let input = "a\r\né = 1\n";
+        let at = |position| State::at(input, position).line_col();
+        assert_eq!(at(0), (1, 1));
+        assert_eq!(at(3), (2, 1));
+        // Past the two-byte `é`, the column moves by one.
+        assert_eq!(at(5), (2, 2));
+        assert_eq!(at(input.len()), (3, 1));
+    }
+
+    #[test]
+    fn test_lexical() {
+        let boxed: Box<[u32]> = vec![1, 2, 3].into_boxed_slice();
+        assert_eq!(Lexical::len(&boxed), 3);
+        assert_eq!(boxed.span(), Span::new(0, 3));
+        assert_eq!(boxed.slice(Span::new(1, 3)), [2, 3]);
+        assert_eq!(boxed.get(Span::new(2, 4)), None);
+        assert_eq!(boxed.get(Span::new(2, 1)), None);
+        assert_eq!(Lexical::iter(&boxed).sum::<u32>(), 6);
+
+        let borrowed: &[char] = &['a', 'b'];
+        assert_eq!(Lexical::get(&borrowed, Span::new(0, 1)), Some(&['a'][..]));
+        assert!(!Lexical::is_empty(&borrowed));
+        let empty: &[char] = &[];
+        assert!(Lexical::is_empty(&empty));
+        assert_eq!(Lexical::get(&empty, Span::empty()), Some(&[][..]));
+
+        let mut tokens = vec!["a", "b", "c"];
+        tokens.slice_mut(Span::new(0, 2)).reverse();
+        assert_eq!(tokens, ["b", "a", "c"]);
+        assert!(LexicalMut::get_mut(&mut tokens, Span::new(1, 9)).is_none());
+        let mut array = [1u8, 2];
+        array.as_mut_slice()[0] = 7;
+        assert_eq!(Lexical::as_slice(&array), [7, 2]);
+    }
+
+    #[test]
+    fn test_keywords() {
+        let keywords = Keywords::new(["if", "in", "int", "+", "+="]);
+        assert_eq!(run(&keywords, "in x"), Some(2));
+        assert_eq!(run(&keywords, "int x"), Some(3));
+        assert_eq!(run(&keywords, "inx"), None);
+        assert_eq!(run(&keywords, "int_"), None);
+        assert_eq!(run(&keywords, "in"), Some(2));
+        assert_eq!(run(&keywords, "i"), None);
+        assert_eq!(run(&keywords, ""), None);
+        assert_eq!(run(&keywords, "+=x"), Some(2));
+        assert_eq!(run(&keywords, "+x"), Some(1));
+        assert_eq!(run(&keywords, "if("), Some(2));
+        assert_eq!(keywords.display(), r#"("+" | "+=" | "if" | "in" | "int")"#);
+        assert!(!keywords.is_nullable());
+    }
+
+    #[test]
+    fn test_char_class() {
+        let digit = class("digit", char::is_numeric);
+        assert_eq!(run(&digit, "42"), Some(1));
+        assert_eq!(run(&digit, "x"), None);
+        assert_eq!(run(&digit, ""), None);
+        assert_eq!(digit.display(), "<digit>");
+        assert_eq!(digit.spec(), None);
+
+        assert_eq!(run(&ALPHA, "éa"), Some(2));
+        assert_eq!(run(&IDENT_START, "_1"), Some(1));
+        assert_eq!(run(&IDENT_START, "1_"), None);
+        assert_eq!(run(&IDENT_CONTINUE, "1_"), Some(1));
+        assert_eq!(run(&HEX_DIGIT, "fG"), Some(1));
+        assert_eq!(run(&HEX_DIGIT, "G"), None);
+        assert_eq!(run(&WHITESPACE, "\u{3000}"), Some(3));
+        assert_eq!(
+            WHITESPACE.spec(),
+            Some(TerminalSpec::CharClass("whitespace".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_any_char() {
+        assert_eq!(run(&AnyChar, "ab"), Some(1));
+        assert_eq!(run(&AnyChar, "語x"), Some(3));
+        assert_eq!(run(&AnyChar, ""), None);
+        let mut state = State {
+            input: "a",
+            position: 1,
+        };
+        assert!(!AnyChar.matches(&mut state));
+        assert_eq!(AnyChar.display(), ".");
+        assert!(!AnyChar.is_nullable());
+
+        let not_quote = none_of(['"', '\\', '»']);
+        assert_eq!(run(&not_quote, "a\""), Some(1));
+        assert_eq!(run(&not_quote, "«"), Some(2));
+        assert_eq!(run(&not_quote, "»"), None);
+        assert_eq!(run(&not_quote, "\""), None);
+        assert_eq!(run(&not_quote, "\\n"), None);
+        assert_eq!(run(&not_quote, ""), None);
+        assert_eq!(not_quote.display(), r#"[^"\\»]"#);
+    }
+
+    #[test]
+    fn test_one_of() {
+        let ops = one_of("+-*/");
+        assert_eq!(run(&ops, "*2"), Some(1));
+        assert_eq!(run(&ops, "2"), None);
+        assert_eq!(run(&ops, ""), None);
+        assert_eq!(ops.display(), r"[*+\-/]");
+        assert!(!ops.is_nullable());
+
+        let arrows = one_of(['→', '←', '→']);
+        assert_eq!(arrows.chars(), ['←', '→']);
+        assert_eq!(run(&arrows, "→x"), Some(3));
+        assert_eq!(run(&one_of(&['a', 'b'][..]), "b"), Some(1));
+        assert_eq!(run(&one_of(String::from("]^")), "^"), Some(1));
+        assert_eq!(one_of("]^\\").display(), r"[\\\]\^]");
+
+        let empty = one_of("");
+        assert_eq!(run(&empty, "a"), None);
+        assert_eq!(empty.display(), "[]");
+    }
+
+    #[test]
+    fn test_line_anchors() {
+        let input = "a\r\nb\nc";
+        let at = |matcher: &dyn Matcher, position| {
+            let mut state = State { input, position };
+            let matched = matcher.matches(&mut state);
+            assert_eq!(state.position, position);
+            matched
+        };
+        let starts: Vec<usize> = (0..=input.len()).filter(|&i| at(&StartOfLine, i)).collect();
+        let ends: Vec<usize> = (0..=input.len()).filter(|&i| at(&EndOfLine, i)).collect();
+        assert_eq!(starts, [0, 3, 5]);
+        assert_eq!(ends, [1, 2, 4, 6]);
+        assert_eq!(run(&EndOfLine, ""), Some(0));
+        assert_eq!(run(&StartOfLine, ""), Some(0));
+        assert!(StartOfLine.is_nullable() && EndOfLine.is_nullable());
+        assert_eq!(
+            (StartOfLine.display(), EndOfLine.display()),
+            ("BOL".into(), "EOL".into())
+        );
+    }
+
+    #[test]
+    fn test_char_range() {
+        let digits = '0'..='9';
+        assert_eq!(run(&digits, "7a"), Some(1));
+        assert_eq!(run(&digits, "a7"), None);
+        assert_eq!(run(&digits, ""), None);
+        assert_eq!(digits.display(), "['0'-'9']");
+        assert!(!digits.is_nullable());
+
+        let greek = 'α'..='ω';
+        assert_eq!(run(&greek, "λx"), Some(2));
+        assert_eq!(run(&greek, "Λ"), None);
+        let mut state = State {
+            input: "αβγ!",
+            position: 0,
+        };
+        while greek.matches(&mut state) {}
+        assert_eq!(state.position, 6);
+        assert_eq!(state.rest(), "!");
     }
 }
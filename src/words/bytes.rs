@@ -0,0 +1,319 @@
+//! Matchers over binary input, enabled by the `byte-input` feature.
+//!
+//! Byte matchers implement [`Matcher::try_match_bytes`]. Grammars of them
+//! parse with [`ParserState::parse_bytes`]. On text they match the UTF-8
+//! bytes of the input, so they also work in ordinary grammars.
+//!
+//! [`ParserState::parse_bytes`]: crate::parser::ParserState::parse_bytes
+
+use super::{Matcher, TerminalSpec};
+
+/// Consumes exactly `n` bytes, whatever they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnyBytes(pub usize);
+
+/// Consumes an unsigned length header followed by that many payload bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LengthPrefixed {
+    /// Header size in bytes: 1, 2 or 4.
+    width: usize,
+    big_endian: bool,
+}
+
+impl LengthPrefixed {
+    pub fn u8() -> Self {
+        LengthPrefixed {
+            width: 1,
+            big_endian: false,
+        }
+    }
+
+    pub fn u16_be() -> Self {
+        LengthPrefixed {
+            width: 2,
+            big_endian: true,
+        }
+    }
+
+    pub fn u16_le() -> Self {
+        LengthPrefixed {
+            width: 2,
+            big_endian: false,
+        }
+    }
+
+    pub fn u32_be() -> Self {
+        LengthPrefixed {
+            width: 4,
+            big_endian: true,
+        }
+    }
+
+    pub fn u32_le() -> Self {
+        LengthPrefixed {
+            width: 4,
+            big_endian: false,
+        }
+    }
+
+    /// Returns `None` unless `width` is 1, 2 or 4.
+    pub fn new(width: usize, big_endian: bool) -> Option<Self> {
+        matches!(width, 1 | 2 | 4).then_some(LengthPrefixed { width, big_endian })
+    }
+}
+
+fn display_bytes(bytes: &[u8]) -> String {
+    let escaped: String = bytes
+        .iter()
+        .flat_map(|&b| std::ascii::escape_default(b))
+        .map(char::from)
+        .collect();
+    format!("b\"{}\"", escaped)
+}
+
+impl Matcher for u8 {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        self.try_match_bytes(input.as_bytes(), at)
+    }
+
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        (input.get(at) == Some(self)).then_some(1)
+    }
+
+    fn display(&self) -> String {
+        format!("0x{:02X}", self)
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Byte(*self))
+    }
+}
+
+impl Matcher for &[u8] {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        self.try_match_bytes(input.as_bytes(), at)
+    }
+
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        input.get(at..)?.starts_with(self).then_some(self.len())
+    }
+
+    fn display(&self) -> String {
+        display_bytes(self)
+    }
+
+    fn is_nullable(&self) -> bool {
+        self.is_empty()
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::ByteString(self.to_vec()))
+    }
+}
+
+impl<const N: usize> Matcher for &[u8; N] {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        self.as_slice().try_match(input, at)
+    }
+
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        self.as_slice().try_match_bytes(input, at)
+    }
+
+    fn display(&self) -> String {
+        display_bytes(self.as_slice())
+    }
+
+    fn is_nullable(&self) -> bool {
+        N == 0
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        self.as_slice().spec()
+    }
+}
+
+impl Matcher for Vec<u8> {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        self.as_slice().try_match(input, at)
+    }
+
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        self.as_slice().try_match_bytes(input, at)
+    }
+
+    fn display(&self) -> String {
+        display_bytes(self)
+    }
+
+    fn is_nullable(&self) -> bool {
+        self.is_empty()
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        self.as_slice().spec()
+    }
+}
+
+impl Matcher for AnyBytes {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        self.try_match_bytes(input.as_bytes(), at)
+    }
+
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        (input.len().checked_sub(at)? >= self.0).then_some(self.0)
+    }
+
+    fn display(&self) -> String {
+        format!("BYTES({})", self.0)
+    }
+
+    fn is_nullable(&self) -> bool {
+        self.0 == 0
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::AnyBytes(self.0))
+    }
+}
+
+impl Matcher for LengthPrefixed {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        self.try_match_bytes(input.as_bytes(), at)
+    }
+
+    fn try_match_bytes(&self, input: &[u8], at: usize) -> Option<usize> {
+        let header = input.get(at..at.checked_add(self.width)?)?;
+        let fold = |len: usize, &b: &u8| (len << 8) | b as usize;
+        let len = if self.big_endian {
+            header.iter().fold(0, fold)
+        } else {
+            header.iter().rev().fold(0, fold)
+        };
+        let total = self.width.checked_add(len)?;
+        (input.len() - at >= total).then_some(total)
+    }
+
+    fn display(&self) -> String {
+        let endian = match (self.width, self.big_endian) {
+            (1, _) => "",
+            (_, true) => "be",
+            (_, false) => "le",
+        };
+        format!("LENGTH_PREFIXED(u{}{})", self.width * 8, endian)
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::LengthPrefixed {
+            width: self.width,
+            big_endian: self.big_endian,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::Grammar;
+    use crate::grammar_dsl::*;
+    use crate::parser::{ParserError, ParserResult, ParserState};
+    use crate::r;
+    use crate::tree::{Tag, WalkEvent};
+
+    #[test]
+    fn test_byte_matchers() {
+        let input: &[u8] = &[0xFF, b'a', b'b', 0x00];
+        assert_eq!(0xFFu8.try_match_bytes(input, 0), Some(1));
+        assert_eq!(0xFFu8.try_match_bytes(input, 1), None);
+        assert_eq!(b"ab".try_match_bytes(input, 1), Some(2));
+        assert_eq!(AnyBytes(3).try_match_bytes(input, 1), Some(3));
+        assert_eq!(AnyBytes(4).try_match_bytes(input, 1), None);
+        assert_eq!(AnyBytes(0).try_match_bytes(input, 9), None);
+        // Text matchers run on the valid UTF-8 that follows the position.
+        assert_eq!("ab".try_match_bytes(input, 1), Some(2));
+        assert_eq!('a'.try_match_bytes(input, 0), None);
+        assert_eq!(super::super::EndOfInput.try_match_bytes(input, 1), None);
+        assert_eq!(b"\x00ab".as_slice().display(), r#"b"\x00ab""#);
+        assert_eq!(0x0Au8.display(), "0x0A");
+    }
+
+    #[test]
+    fn test_length_prefixed_record() {
+        // magic, version, u16 big-endian length, payload, terminator
+        fn record() -> GrammarNode {
+            t(b"RC") + t(0x01u8) + t(LengthPrefixed::u16_be()) + t(0x00u8)
+        }
+
+        let grammar = Grammar::try_from(some(r!(record))).unwrap();
+        let mut state = ParserState::new(grammar);
+        let input: &[u8] = b"RC\x01\x00\x03\xFFab\x00RC\x01\x00\x00\x00";
+        let ParserResult::Complete(root) = state.parse_bytes(input) else {
+            panic!("the records don't parse");
+        };
+        // The records and their parts, by span.
+        let spans: Vec<_> = (root.walk(state.arena()))
+            .filter_map(|event| match event {
+                WalkEvent::Enter(node) => Some(node),
+                WalkEvent::Leave(_) => None,
+            })
+            .filter(|node| match node.green(state.arena()).tag {
+                Tag::Rule(idx) => state.grammar().rule_name(idx) == Some("record"),
+                _ => true,
+            })
+            .map(|node| node.span(state.arena()))
+            .map(|span| (span.start, span.end))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (0, 9),
+                (0, 2),
+                (2, 3),
+                (3, 8),
+                (8, 9),
+                (9, 15),
+                (9, 11),
+                (11, 12),
+                (12, 14),
+                (14, 15)
+            ]
+        );
+
+        // A length running past the end of the input.
+        match state.parse_bytes(b"RC\x01\x00\x05ab\x00") {
+            ParserResult::Incomplete(ParserError::SyntaxError { offset, .. }) => {
+                assert_eq!(offset, 3)
+            }
+            _ => panic!("the truncated record parses"),
+        }
+
+        assert_eq!(
+            LengthPrefixed::u16_be().try_match_bytes(b"\x00\x05ab", 0),
+            None
+        );
+        assert_eq!(
+            LengthPrefixed::u16_le().try_match_bytes(b"\x02\x00ab", 0),
+            Some(4)
+        );
+        assert_eq!(
+            LengthPrefixed::u32_le().try_match_bytes(b"\x00\x00", 0),
+            None
+        );
+
+        let grammar =
+            Grammar::try_from(t(b"RC") + t(0x01u8) + t(LengthPrefixed::u16_be()) + t(0x00u8))
+                .unwrap();
+        assert_eq!(
+            grammar.to_string().trim_end(),
+            r#"START ::= b"RC" 0x01 LENGTH_PREFIXED(u16be) 0x00"#
+        );
+    }
+}
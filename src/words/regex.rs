@@ -0,0 +1,515 @@
+//! A small backtracking regular expression matcher, enabled by the `regex`
+//! feature.
+//!
+//! The supported syntax is a subset of the `regex` crate's: literals, `.`,
+//! escapes (`\d \w \s` and their negations, `\n \t \r`, escaped
+//! metacharacters), bracket classes with ranges and negation, groups
+//! (`( )` and `(?: )`), alternation, the quantifiers `* + ? {n} {n,} {n,m}`
+//! with lazy `?` variants, and the anchors `^` and `$`. Alternatives are
+//! tried left to right, so the result matches the `regex` crate's
+//! leftmost-first semantics. `\d`, `\w` and `\s` are approximated with the
+//! `char::is_*` classification functions.
+//!
+//! Patterns are always anchored at the current position.
+
+use std::fmt;
+
+use super::{Matcher, TerminalSpec};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexError {
+    /// Byte offset in the pattern.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid pattern at byte {}: {}",
+            self.position, self.message
+        )
+    }
+}
+
+impl std::error::Error for RegexError {}
+
+/// Matches a regular expression anchored at the current position.
+#[derive(Debug, Clone)]
+pub struct Regex {
+    pattern: String,
+    node: Node,
+    nullable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Perl {
+    Digit,
+    Word,
+    Space,
+}
+
+impl Perl {
+    fn contains(self, c: char) -> bool {
+        match self {
+            Perl::Digit => c.is_numeric(),
+            Perl::Word => c.is_alphanumeric() || c == '_',
+            Perl::Space => c.is_whitespace(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Range(char, char),
+    Perl(Perl, bool),
+}
+
+impl ClassItem {
+    fn contains(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Range(lo, hi) => (lo..=hi).contains(&c),
+            ClassItem::Perl(perl, negated) => perl.contains(c) != negated,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    /// `.`, which does not match a newline.
+    Any,
+    Class {
+        items: Vec<ClassItem>,
+        negated: bool,
+    },
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, RegexError> {
+        let mut parser = PatternParser {
+            chars: pattern.char_indices().collect(),
+            index: 0,
+            len: pattern.len(),
+        };
+        let node = parser.alternation()?;
+        if parser.index < parser.chars.len() {
+            return Err(parser.error("unmatched `)`"));
+        }
+        let nullable = run(&node, "", 0).is_some();
+        Ok(Regex {
+            pattern: pattern.to_string(),
+            node,
+            nullable,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Returns the end of the match starting at `position`, if any.
+    pub fn match_at(&self, input: &str, position: usize) -> Option<usize> {
+        run(&self.node, input, position)
+    }
+}
+
+impl Matcher for Regex {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        Some(self.match_at(input, at)? - at)
+    }
+
+    fn display(&self) -> String {
+        format!("/{}/", self.pattern)
+    }
+
+    fn is_nullable(&self) -> bool {
+        self.nullable
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Regex(self.pattern.clone()))
+    }
+}
+
+fn run(node: &Node, input: &str, position: usize) -> Option<usize> {
+    input.get(position..)?;
+    let mut end = None;
+    match_node(node, input, position, &mut |p| {
+        end = Some(p);
+        true
+    });
+    end
+}
+
+/// Continuation-passing backtracking: `k` is called with each possible end
+/// position in preference order until it accepts one.
+fn match_node(node: &Node, input: &str, pos: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+    let next = input[pos..].chars().next();
+    let advance = |pred: bool, k: &mut dyn FnMut(usize) -> bool| match next {
+        Some(c) if pred => k(pos + c.len_utf8()),
+        _ => false,
+    };
+    match node {
+        Node::Char(expected) => advance(next == Some(*expected), k),
+        Node::Any => advance(next != Some('\n'), k),
+        Node::Class { items, negated } => {
+            let hit = next.is_some_and(|c| items.iter().any(|item| item.contains(c)) != *negated);
+            advance(hit, k)
+        }
+        Node::Start => pos == 0 && k(pos),
+        Node::End => pos == input.len() && k(pos),
+        Node::Concat(items) => match_sequence(items, input, pos, k),
+        Node::Alt(alternatives) => {
+            for alternative in alternatives {
+                if match_node(alternative, input, pos, k) {
+                    return true;
+                }
+            }
+            false
+        }
+        Node::Repeat {
+            node,
+            min,
+            max,
+            greedy,
+        } => match_repeat(node, (*min, *max, *greedy), 0, input, pos, k),
+    }
+}
+
+fn match_sequence(
+    items: &[Node],
+    input: &str,
+    pos: usize,
+    k: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    match items.split_first() {
+        None => k(pos),
+        Some((first, rest)) => match_node(first, input, pos, &mut |p| {
+            match_sequence(rest, input, p, k)
+        }),
+    }
+}
+
+fn match_repeat(
+    node: &Node,
+    bounds: (usize, Option<usize>, bool),
+    count: usize,
+    input: &str,
+    pos: usize,
+    k: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    let (min, max, greedy) = bounds;
+    if count < min {
+        return match_node(node, input, pos, &mut |p| {
+            match_repeat(node, bounds, count + 1, input, p, k)
+        });
+    }
+    let can_repeat = max.is_none_or(|max| count < max);
+    // Iterations past the minimum must consume input, or `(a*)*` would loop.
+    let again = |k: &mut dyn FnMut(usize) -> bool| {
+        can_repeat
+            && match_node(node, input, pos, &mut |p| {
+                p != pos && match_repeat(node, bounds, count + 1, input, p, k)
+            })
+    };
+    if !greedy && k(pos) {
+        return true;
+    }
+    again(k) || (greedy && k(pos))
+}
+
+struct PatternParser {
+    chars: Vec<(usize, char)>,
+    index: usize,
+    len: usize,
+}
+
+impl PatternParser {
+    fn error(&self, message: &str) -> RegexError {
+        RegexError {
+            position: self.chars.get(self.index).map_or(self.len, |&(i, _)| i),
+            message: message.to_string(),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).map(|&(_, c)| c)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.index += 1;
+        c
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.index += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Node, RegexError> {
+        let mut alternatives = vec![self.concat()?];
+        while self.eat('|') {
+            alternatives.push(self.concat()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Node::Alt(alternatives)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, RegexError> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            items.push(self.quantified(atom)?);
+        }
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Node::Concat(items)
+        })
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, RegexError> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => return self.counted(atom),
+            _ => return Ok(atom),
+        };
+        self.index += 1;
+        self.repeat(atom, min, max)
+    }
+
+    fn counted(&mut self, atom: Node) -> Result<Node, RegexError> {
+        self.index += 1;
+        let min = self
+            .number()?
+            .ok_or_else(|| self.error("expected a repetition count"))?;
+        let max = if self.eat(',') {
+            self.number()?
+        } else {
+            Some(min)
+        };
+        if !self.eat('}') {
+            return Err(self.error("expected `}`"));
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error("repetition range is reversed"));
+        }
+        self.repeat(atom, min, max)
+    }
+
+    fn number(&mut self) -> Result<Option<usize>, RegexError> {
+        let mut digits = String::new();
+        while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+            digits.push(c);
+            self.index += 1;
+        }
+        if digits.is_empty() {
+            return Ok(None);
+        }
+        digits
+            .parse()
+            .map(Some)
+            .map_err(|_| self.error("repetition count is too large"))
+    }
+
+    fn repeat(&mut self, atom: Node, min: usize, max: Option<usize>) -> Result<Node, RegexError> {
+        if matches!(atom, Node::Start | Node::End) {
+            return Err(self.error("an anchor cannot be repeated"));
+        }
+        let greedy = !self.eat('?');
+        Ok(Node::Repeat {
+            node: Box::new(atom),
+            min,
+            max,
+            greedy,
+        })
+    }
+
+    fn atom(&mut self) -> Result<Node, RegexError> {
+        let c = self.bump().expect("atom called at end of pattern");
+        Ok(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                if self.eat('?') && !self.eat(':') {
+                    return Err(self.error("only `(?:` groups are supported"));
+                }
+                let inner = self.alternation()?;
+                if !self.eat(')') {
+                    return Err(self.error("unclosed group"));
+                }
+                inner
+            }
+            '[' => self.class()?,
+            '\\' => match self.escape()? {
+                ClassItem::Range(c, _) => Node::Char(c),
+                perl => Node::Class {
+                    items: vec![perl],
+                    negated: false,
+                },
+            },
+            '*' | '+' | '?' | '{' => {
+                self.index -= 1;
+                return Err(self.error("quantifier with nothing to repeat"));
+            }
+            c => Node::Char(c),
+        })
+    }
+
+    /// Parses the escape after a backslash, as a single char or a class.
+    fn escape(&mut self) -> Result<ClassItem, RegexError> {
+        let Some(c) = self.bump() else {
+            return Err(self.error("trailing backslash"));
+        };
+        let single = |c| Ok(ClassItem::Range(c, c));
+        match c {
+            'd' => Ok(ClassItem::Perl(Perl::Digit, false)),
+            'D' => Ok(ClassItem::Perl(Perl::Digit, true)),
+            'w' => Ok(ClassItem::Perl(Perl::Word, false)),
+            'W' => Ok(ClassItem::Perl(Perl::Word, true)),
+            's' => Ok(ClassItem::Perl(Perl::Space, false)),
+            'S' => Ok(ClassItem::Perl(Perl::Space, true)),
+            'n' => single('\n'),
+            't' => single('\t'),
+            'r' => single('\r'),
+            c if c.is_ascii_punctuation() => single(c),
+            _ => {
+                self.index -= 1;
+                Err(self.error("unsupported escape"))
+            }
+        }
+    }
+
+    fn class(&mut self) -> Result<Node, RegexError> {
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let Some(c) = self.bump() else {
+                return Err(self.error("unclosed character class"));
+            };
+            let item = match c {
+                ']' if !first => break,
+                '\\' => self.escape()?,
+                c => ClassItem::Range(c, c),
+            };
+            first = false;
+            let item = match item {
+                ClassItem::Range(lo, _)
+                    if self.peek() == Some('-')
+                        && self
+                            .chars
+                            .get(self.index + 1)
+                            .is_some_and(|&(_, c)| c != ']') =>
+                {
+                    self.index += 1;
+                    let hi = match self.bump() {
+                        Some('\\') => match self.escape()? {
+                            ClassItem::Range(hi, _) => hi,
+                            ClassItem::Perl(..) => {
+                                return Err(self.error("invalid range end"));
+                            }
+                        },
+                        Some(hi) => hi,
+                        None => return Err(self.error("unclosed character class")),
+                    };
+                    if hi < lo {
+                        return Err(self.error("character range is reversed"));
+                    }
+                    ClassItem::Range(lo, hi)
+                }
+                item => item,
+            };
+            items.push(item);
+        }
+        Ok(Node::Class { items, negated })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::words::State;
+
+    fn find(pattern: &str, input: &str) -> Option<usize> {
+        Regex::new(pattern).unwrap().match_at(input, 0)
+    }
+
+    #[test]
+    fn test_regex_float() {
+        let float = r"[+-]?(\d+\.\d*|\.\d+)([eE][+-]?\d+)?";
+        assert_eq!(find(float, "3.14 rest"), Some(4));
+        assert_eq!(find(float, "-.5e10;"), Some(6));
+        assert_eq!(find(float, "1.e-3"), Some(5));
+        assert_eq!(find(float, "1e3"), None);
+        assert_eq!(find(float, "."), None);
+        let regex = Regex::new(float).unwrap();
+        assert!(!regex.is_nullable());
+        assert_eq!(regex.display(), format!("/{}/", float));
+    }
+
+    #[test]
+    fn test_regex_nullable() {
+        assert!(Regex::new("a*").unwrap().is_nullable());
+        assert!(Regex::new("(?:ab)?|c").unwrap().is_nullable());
+        assert!(Regex::new("^$").unwrap().is_nullable());
+        assert!(!Regex::new("a+").unwrap().is_nullable());
+        assert_eq!(find("(a*)*b", "aaab"), Some(4));
+        assert_eq!(find("a*?", "aaa"), Some(0));
+        assert_eq!(find("a{2,3}", "aaaa"), Some(3));
+    }
+
+    #[test]
+    fn test_regex_anchored() {
+        assert_eq!(find(r"\d+", "abc123"), None);
+        let regex = Regex::new(r"\d+").unwrap();
+        assert_eq!(regex.match_at("abc123x", 3), Some(6));
+        let mut state = State {
+            input: "ab12",
+            position: 1,
+        };
+        assert!(!regex.matches(&mut state));
+        assert_eq!(state.position, 1);
+        state.position = 2;
+        assert!(regex.matches(&mut state));
+        assert_eq!(state.position, 4);
+        assert_eq!(Regex::new("^a").unwrap().match_at("aa", 1), None);
+        assert_eq!(find("[^α-ω]+", "abγ"), Some(2));
+    }
+
+    #[test]
+    fn test_regex_errors() {
+        assert_eq!(Regex::new("(ab").unwrap_err().message, "unclosed group");
+        assert_eq!(Regex::new("ab)").unwrap_err().position, 2);
+        assert_eq!(Regex::new("*a").unwrap_err().position, 0);
+        assert!(Regex::new("[z-a]").is_err());
+        assert!(Regex::new("a{3,1}").is_err());
+        assert!(Regex::new(r"\q").is_err());
+    }
+}
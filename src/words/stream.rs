@@ -0,0 +1,318 @@
+//! Matching over token streams produced by an external lexer.
+//!
+//! Token terminals implement [`Matcher::try_match_tokens`]. The input is
+//! seen through the type-erased [`TokenInput`], so a terminal such as
+//! `TokenKind(Kind::Ident)` works without naming the token type. Grammars of
+//! them parse with [`ParserState::parse_tokens`], where widths count tokens
+//! rather than bytes.
+//!
+//! [`ParserState::parse_tokens`]: crate::parser::ParserState::parse_tokens
+
+use std::any::Any;
+use std::fmt::Debug;
+
+use super::{Lexical, Matcher};
+
+/// A token with a kind, as produced by a lexer.
+pub trait Token: 'static {
+    type Kind: PartialEq + Debug + 'static;
+    fn kind(&self) -> Self::Kind;
+}
+
+/// A token sequence seen without its concrete token type.
+pub trait TokenInput {
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// The token at `at`, for downcasting to the concrete type.
+    fn token(&self, at: usize) -> Option<&dyn Any>;
+    /// Whether the token at `at` has `kind`, which must be the token's
+    /// `Kind` type to ever match.
+    fn kind_is(&self, at: usize, kind: &dyn Any) -> bool;
+}
+
+impl<T: Token> TokenInput for &[T] {
+    fn len(&self) -> usize {
+        <[T]>::len(self)
+    }
+
+    fn token(&self, at: usize) -> Option<&dyn Any> {
+        <[T]>::get(self, at).map(|token| token as &dyn Any)
+    }
+
+    fn kind_is(&self, at: usize, kind: &dyn Any) -> bool {
+        match (<[T]>::get(self, at), kind.downcast_ref::<T::Kind>()) {
+            (Some(token), Some(kind)) => token.kind() == *kind,
+            _ => false,
+        }
+    }
+}
+
+impl<T: Token> TokenInput for Vec<T> {
+    fn len(&self) -> usize {
+        <[T]>::len(self)
+    }
+
+    fn token(&self, at: usize) -> Option<&dyn Any> {
+        <[T]>::get(self, at).map(|token| token as &dyn Any)
+    }
+
+    fn kind_is(&self, at: usize, kind: &dyn Any) -> bool {
+        self.as_slice().kind_is(at, kind)
+    }
+}
+
+/// Matches a single token of the given kind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenKind<K>(pub K);
+
+/// Matches a single token equal to the given one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenExact<T>(pub T);
+
+/// Matches a single token satisfying `pred`, shown by `name`.
+#[derive(Clone, Copy)]
+pub struct TokenWhere<T> {
+    pub name: &'static str,
+    pub pred: fn(&T) -> bool,
+}
+
+impl<K: PartialEq + Debug + Send + Sync + 'static> Matcher for TokenKind<K> {
+    fn try_match(&self, _input: &str, _at: usize) -> Option<usize> {
+        None
+    }
+
+    fn try_match_tokens(&self, input: &dyn TokenInput, at: usize) -> Option<usize> {
+        input.kind_is(at, &self.0).then_some(1)
+    }
+
+    fn display(&self) -> String {
+        format!("{:?}", self.0)
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+}
+
+impl<T: PartialEq + Debug + Send + Sync + 'static> Matcher for TokenExact<T> {
+    fn try_match(&self, _input: &str, _at: usize) -> Option<usize> {
+        None
+    }
+
+    fn try_match_tokens(&self, input: &dyn TokenInput, at: usize) -> Option<usize> {
+        let token = input.token(at)?.downcast_ref::<T>()?;
+        (*token == self.0).then_some(1)
+    }
+
+    fn display(&self) -> String {
+        format!("{:?}", self.0)
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+}
+
+impl<T: 'static> Debug for TokenWhere<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenWhere")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<T: 'static> Matcher for TokenWhere<T> {
+    fn try_match(&self, _input: &str, _at: usize) -> Option<usize> {
+        None
+    }
+
+    fn try_match_tokens(&self, input: &dyn TokenInput, at: usize) -> Option<usize> {
+        let token = input.token(at)?.downcast_ref::<T>()?;
+        (self.pred)(token).then_some(1)
+    }
+
+    fn display(&self) -> String {
+        format!("<{}>", self.name)
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+}
+
+/// A position in a token sequence, the token-stream counterpart of
+/// [`super::State`].
+pub struct TokenState<'a, T> {
+    tokens: &'a [T],
+    position: usize,
+}
+
+impl<'a, T: Token> TokenState<'a, T> {
+    pub fn new(tokens: &'a impl Lexical<T>) -> Self {
+        TokenState {
+            tokens: tokens.as_slice(),
+            position: 0,
+        }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    pub fn peek(&self) -> Option<&'a T> {
+        self.tokens.get(self.position)
+    }
+
+    /// Runs `matcher` at the current position, advancing past the tokens
+    /// it consumed on success.
+    pub fn matches(&mut self, matcher: &(impl Matcher + ?Sized)) -> bool {
+        match matcher.try_match_tokens(&self.tokens, self.position) {
+            Some(len) => {
+                self.position += len;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::Grammar;
+    use crate::grammar_dsl::*;
+    use crate::parser::{ParserError, ParserResult, ParserState};
+    use crate::r;
+    use crate::tree::{Tag, WalkEvent};
+    use crate::utils::Span;
+    use crate::words::EndOfInput;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Kind {
+        Num,
+        Plus,
+        Star,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Tok {
+        kind: Kind,
+        text: &'static str,
+    }
+
+    impl Token for Tok {
+        type Kind = Kind;
+        fn kind(&self) -> Kind {
+            self.kind
+        }
+    }
+
+    fn lex(text: &'static str) -> Vec<Tok> {
+        text.split(' ')
+            .map(|text| Tok {
+                kind: match text {
+                    "+" => Kind::Plus,
+                    "*" => Kind::Star,
+                    _ => Kind::Num,
+                },
+                text,
+            })
+            .collect()
+    }
+
+    /// The tree from `state`'s last parse of `tokens`, as nested rule
+    /// names around the text of the tokens they matched.
+    fn render(state: &ParserState, tokens: &[Tok]) -> String {
+        let mut out = String::new();
+        for event in state.ast().walk(state.arena()) {
+            match event {
+                WalkEvent::Enter(node) => {
+                    let span = node.span(state.arena());
+                    match node.green(state.arena()).tag {
+                        Tag::Rule(idx) => {
+                            out += &format!(" ({}", state.grammar().rule_name(idx).unwrap())
+                        }
+                        _ => out += &format!(" {}", tokens[span.start].text),
+                    }
+                }
+                WalkEvent::Leave(node) => {
+                    if let Tag::Rule(_) = node.green(state.arena()).tag {
+                        out += ")";
+                    }
+                }
+            }
+        }
+        out.trim_start().to_string()
+    }
+
+    #[test]
+    fn test_token_matchers() {
+        let tokens = lex("1 + 2");
+        let mut state = TokenState::new(&tokens);
+        assert!(!state.matches(&TokenKind(Kind::Plus)));
+        assert!(state.matches(&TokenKind(Kind::Num)));
+        assert!(state.matches(&TokenExact(Tok {
+            kind: Kind::Plus,
+            text: "+"
+        })));
+        let two = TokenWhere::<Tok> {
+            name: "two",
+            pred: |tok| tok.text == "2",
+        };
+        assert!(state.matches(&two));
+        assert!(state.is_at_end());
+        assert!(state.matches(&EndOfInput));
+        assert_eq!(state.position(), 3);
+
+        assert_eq!(TokenKind(3u8).try_match_tokens(&tokens, 0), None);
+        assert_eq!(TokenKind(Kind::Num).try_match(" 1", 0), None);
+        assert_eq!(two.display(), "<two>");
+        assert_eq!(TokenKind(Kind::Num).display(), "Num");
+    }
+
+    #[test]
+    fn test_token_grammar() {
+        fn expr() -> GrammarNode {
+            r!(term) + opt(tok(Kind::Plus) + r!(expr))
+        }
+
+        fn term() -> GrammarNode {
+            tok(Kind::Num) + opt(tok(Kind::Star) + r!(term))
+        }
+
+        let grammar = Grammar::try_from(r!(expr)).unwrap();
+        let mut state = ParserState::new(grammar);
+        let tokens = lex("1 + 2");
+        let ParserResult::Complete(root) = state.parse_tokens(&tokens) else {
+            panic!("\"1 + 2\" doesn't parse");
+        };
+        assert_eq!(root.span(state.arena()), Span::new(0, 3));
+        assert_eq!(
+            render(&state, &tokens),
+            "(START (expr (term 1 (term_opt)) (expr_opt + (expr (term 2 (term_opt)) (expr_opt)))))"
+        );
+
+        let tokens = lex("1 * 2 + 3");
+        assert!(state.parse_tokens(&tokens).is_complete());
+        assert_eq!(
+            render(&state, &tokens),
+            "(START (expr (term 1 (term_opt * (term 2 (term_opt)))) \
+             (expr_opt + (expr (term 3 (term_opt)) (expr_opt)))))"
+        );
+
+        for (text, at) in [("1 +", 2), ("+ 1", 0), ("1 2", 1)] {
+            match state.parse_tokens(&lex(text)) {
+                ParserResult::Incomplete(ParserError::SyntaxError { offset, .. }) => {
+                    assert_eq!(offset, at, "{text:?}")
+                }
+                other => panic!("{text:?} parsed as {:?}", other.error()),
+            }
+        }
+    }
+}
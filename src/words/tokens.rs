@@ -0,0 +1,810 @@
+//! Matchers that scan a whole token at once: delimited content, numbers,
+//! strings, comments and whitespace.
+
+use super::{Matcher, TerminalSpec, next_char_end};
+
+/// Consumes everything up to the first occurrence of a delimiter, stopping
+/// before it or, if inclusive, after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Until {
+    delimiter: String,
+    pub(super) inclusive: bool,
+    pub(super) or_end_of_input: bool,
+}
+
+impl Until {
+    /// # Panics
+    ///
+    /// If `delimiter` is empty.
+    pub fn new(delimiter: impl Into<String>) -> Self {
+        let delimiter = delimiter.into();
+        assert!(!delimiter.is_empty(), "Until delimiter must not be empty");
+        Until {
+            delimiter,
+            inclusive: false,
+            or_end_of_input: false,
+        }
+    }
+
+    /// Also consume the delimiter.
+    pub fn inclusive(mut self) -> Self {
+        self.inclusive = true;
+        self
+    }
+
+    /// Treat the end of input as an implicit delimiter instead of failing.
+    pub fn or_end_of_input(mut self) -> Self {
+        self.or_end_of_input = true;
+        self
+    }
+
+    pub fn delimiter(&self) -> &str {
+        &self.delimiter
+    }
+}
+
+impl Matcher for Until {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let rest = input.get(at..)?;
+        match rest.find(self.delimiter.as_str()) {
+            Some(offset) if self.inclusive => Some(offset + self.delimiter.len()),
+            Some(offset) => Some(offset),
+            None if self.or_end_of_input => Some(rest.len()),
+            None => None,
+        }
+    }
+
+    fn display(&self) -> String {
+        let mut display = format!("UNTIL({:?}", self.delimiter);
+        if self.inclusive {
+            display.push_str(", inclusive");
+        }
+        if self.or_end_of_input {
+            display.push_str(", EOF");
+        }
+        display.push(')');
+        display
+    }
+    fn sample(&self) -> Option<String> {
+        Some(match self.inclusive {
+            true => self.delimiter.clone(),
+            false => String::new(),
+        })
+    }
+
+    fn is_nullable(&self) -> bool {
+        !self.inclusive
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Until {
+            delimiter: self.delimiter.clone(),
+            inclusive: self.inclusive,
+            or_end_of_input: self.or_end_of_input,
+        })
+    }
+}
+
+/// Consumes a maximal integer literal in the given radix, with optional
+/// sign and `_` separators. Separators must sit between digits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Integer {
+    pub(super) allow_sign: bool,
+    pub(super) allow_underscores: bool,
+    pub(super) radix: u32,
+}
+
+impl Default for Integer {
+    fn default() -> Self {
+        Integer::with_radix(10)
+    }
+}
+
+impl Integer {
+    /// # Panics
+    ///
+    /// If `radix` is not in `2..=36`.
+    pub fn with_radix(radix: u32) -> Self {
+        assert!((2..=36).contains(&radix), "radix must be in 2..=36");
+        Integer {
+            allow_sign: false,
+            allow_underscores: false,
+            radix,
+        }
+    }
+
+    /// Accept a leading `+` or `-`.
+    pub fn signed(mut self) -> Self {
+        self.allow_sign = true;
+        self
+    }
+
+    /// Accept `_` between digits, as in `1_000_000`.
+    pub fn underscores(mut self) -> Self {
+        self.allow_underscores = true;
+        self
+    }
+
+    pub fn radix(&self) -> u32 {
+        self.radix
+    }
+}
+
+/// Returns the end of the digit run starting at `pos`, or `pos` if there
+/// is none. Underscores are only taken when a digit follows them.
+fn scan_digits(input: &str, pos: usize, radix: u32, underscores: bool) -> usize {
+    let mut end = pos;
+    let mut seen_digit = false;
+    for (offset, c) in input.get(pos..).unwrap_or("").char_indices() {
+        if c.is_digit(radix) {
+            seen_digit = true;
+            end = pos + offset + c.len_utf8();
+        } else if !(c == '_' && underscores && seen_digit) {
+            break;
+        }
+    }
+    end
+}
+
+impl Matcher for Integer {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let mut pos = at;
+        if self.allow_sign && input.get(at..)?.starts_with(['+', '-']) {
+            pos += 1;
+        }
+        let end = scan_digits(input, pos, self.radix, self.allow_underscores);
+        (end > pos).then_some(end - at)
+    }
+
+    fn display(&self) -> String {
+        match self.radix {
+            10 => String::from("INTEGER"),
+            radix => format!("INTEGER({})", radix),
+        }
+    }
+    fn sample(&self) -> Option<String> {
+        Some(String::from("0"))
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Integer {
+            allow_sign: self.allow_sign,
+            allow_underscores: self.allow_underscores,
+            radix: self.radix,
+        })
+    }
+}
+
+/// Consumes a maximal decimal floating-point literal: digits, an optional
+/// fraction and an optional exponent. By default a bare integer or `1.`
+/// also matches; a `.` is not consumed when followed by another `.` or an
+/// identifier, so `1..2` and `1.max(2)` are left alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Float {
+    pub(super) allow_exponent: bool,
+    pub(super) require_fraction: bool,
+}
+
+impl Default for Float {
+    fn default() -> Self {
+        Float {
+            allow_exponent: true,
+            require_fraction: false,
+        }
+    }
+}
+
+impl Float {
+    pub fn new() -> Self {
+        Float::default()
+    }
+
+    /// Reject exponents such as `1e10`.
+    pub fn no_exponent(mut self) -> Self {
+        self.allow_exponent = false;
+        self
+    }
+
+    /// Only match literals with digits after the `.`.
+    pub fn require_fraction(mut self) -> Self {
+        self.require_fraction = true;
+        self
+    }
+}
+
+impl Matcher for Float {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let mut end = scan_digits(input, at, 10, false);
+        if end == at {
+            return None;
+        }
+        let mut has_fraction = false;
+        if input[end..].starts_with('.') {
+            let fraction_end = scan_digits(input, end + 1, 10, false);
+            if fraction_end > end + 1 {
+                has_fraction = true;
+                end = fraction_end;
+            } else if !self.require_fraction
+                && !input[end + 1..].starts_with(|c: char| c == '.' || super::is_ident_start(c))
+            {
+                end += 1;
+            }
+        }
+        if self.require_fraction && !has_fraction {
+            return None;
+        }
+        if self.allow_exponent && input[end..].starts_with(['e', 'E']) {
+            let mut exponent = end + 1;
+            if input[exponent..].starts_with(['+', '-']) {
+                exponent += 1;
+            }
+            let exponent_end = scan_digits(input, exponent, 10, false);
+            if exponent_end > exponent {
+                end = exponent_end;
+            }
+        }
+        Some(end - at)
+    }
+
+    fn display(&self) -> String {
+        String::from("FLOAT")
+    }
+    fn sample(&self) -> Option<String> {
+        Some(String::from("0.0"))
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Float {
+            allow_exponent: self.allow_exponent,
+            require_fraction: self.require_fraction,
+        })
+    }
+}
+
+/// Consumes a quoted literal, quotes included. The escape character makes
+/// the following character content, so an escaped quote does not close
+/// the literal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotedString {
+    pub(super) quote: char,
+    pub(super) escape: Option<char>,
+    pub(super) allow_newlines: bool,
+}
+
+impl QuotedString {
+    /// A single-line literal delimited by `quote`, with `\` escapes.
+    pub fn new(quote: char) -> Self {
+        QuotedString {
+            quote,
+            escape: Some('\\'),
+            allow_newlines: false,
+        }
+    }
+
+    /// Use a different escape character, or none.
+    pub fn escape(mut self, escape: Option<char>) -> Self {
+        self.escape = escape;
+        self
+    }
+
+    /// Allow unescaped line breaks inside the literal.
+    pub fn allow_newlines(mut self) -> Self {
+        self.allow_newlines = true;
+        self
+    }
+}
+
+impl Matcher for QuotedString {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let rest = input.get(at..)?;
+        if !rest.starts_with(self.quote) {
+            return None;
+        }
+        let mut chars = rest.char_indices().skip(1);
+        while let Some((offset, c)) = chars.next() {
+            if Some(c) == self.escape {
+                chars.next()?;
+            } else if c == self.quote {
+                return Some(offset + c.len_utf8());
+            } else if c == '\n' && !self.allow_newlines {
+                return None;
+            }
+        }
+        None
+    }
+
+    fn display(&self) -> String {
+        format!("STRING({:?})", self.quote)
+    }
+    fn sample(&self) -> Option<String> {
+        Some(format!("{0}{0}", self.quote))
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::QuotedString {
+            quote: self.quote,
+            escape: self.escape,
+            allow_newlines: self.allow_newlines,
+        })
+    }
+}
+
+/// Consumes a comment from its prefix up to, but not including, the next
+/// `\n` or the end of input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineComment(pub &'static str);
+
+impl Matcher for LineComment {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let rest = input.get(at..)?;
+        if !rest.starts_with(self.0) {
+            return None;
+        }
+        Some(rest.find('\n').unwrap_or(rest.len()))
+    }
+
+    /// The prefix, then the rest of the line and the char that ends it.
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        let end = match self.try_match(input, at) {
+            Some(len) => next_char_end(input, at + len),
+            None => at,
+        };
+        Some(end.max(at + self.0.len()))
+    }
+
+    fn display(&self) -> String {
+        format!("LINE_COMMENT({:?})", self.0)
+    }
+    fn sample(&self) -> Option<String> {
+        Some(self.0.to_string())
+    }
+
+    fn is_nullable(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::LineComment(self.0.to_string()))
+    }
+}
+
+/// Consumes a comment from `open` to the matching `close`. Nested comments
+/// count depth; an unterminated comment fails unless allowed, in which case
+/// it runs to the end of input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockComment {
+    pub(super) open: &'static str,
+    pub(super) close: &'static str,
+    pub(super) nested: bool,
+    pub(super) allow_unterminated: bool,
+}
+
+impl BlockComment {
+    /// # Panics
+    ///
+    /// If either delimiter is empty.
+    pub fn new(open: &'static str, close: &'static str) -> Self {
+        assert!(
+            !open.is_empty() && !close.is_empty(),
+            "comment delimiters must not be empty"
+        );
+        BlockComment {
+            open,
+            close,
+            nested: false,
+            allow_unterminated: false,
+        }
+    }
+
+    pub fn nested(mut self) -> Self {
+        self.nested = true;
+        self
+    }
+
+    /// Accept a comment that is still open at the end of input, as editors
+    /// do while it is being typed.
+    pub fn allow_unterminated(mut self) -> Self {
+        self.allow_unterminated = true;
+        self
+    }
+}
+
+impl Matcher for BlockComment {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        if !input.get(at..)?.starts_with(self.open) {
+            return None;
+        }
+        let mut pos = at + self.open.len();
+        let mut depth = 1;
+        while pos < input.len() {
+            let rest = &input[pos..];
+            if rest.starts_with(self.close) {
+                pos += self.close.len();
+                depth -= 1;
+                if depth == 0 {
+                    return Some(pos - at);
+                }
+            } else if self.nested && rest.starts_with(self.open) {
+                pos += self.open.len();
+                depth += 1;
+            } else {
+                pos += rest.chars().next().map_or(1, char::len_utf8);
+            }
+        }
+        self.allow_unterminated.then(|| input.len() - at)
+    }
+
+    fn display(&self) -> String {
+        format!("BLOCK_COMMENT({:?}, {:?})", self.open, self.close)
+    }
+    fn sample(&self) -> Option<String> {
+        Some(format!("{}{}", self.open, self.close))
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::BlockComment {
+            open: self.open.to_string(),
+            close: self.close.to_string(),
+            nested: self.nested,
+            allow_unterminated: self.allow_unterminated,
+        })
+    }
+}
+
+/// Consumes a maximal run of whitespace, optionally stopping at line
+/// breaks, and fails if the run is shorter than `min` characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Whitespace {
+    pub(super) include_newlines: bool,
+    pub(super) min: usize,
+}
+
+impl Default for Whitespace {
+    fn default() -> Self {
+        Whitespace::new()
+    }
+}
+
+impl Whitespace {
+    /// At least one whitespace character, line breaks included.
+    pub fn new() -> Self {
+        Whitespace {
+            include_newlines: true,
+            min: 1,
+        }
+    }
+
+    /// Stop at `\n` and `\r`, for languages where line breaks matter.
+    pub fn horizontal(mut self) -> Self {
+        self.include_newlines = false;
+        self
+    }
+
+    /// Require at least `min` characters; `0` makes the matcher nullable.
+    pub fn at_least(mut self, min: usize) -> Self {
+        self.min = min;
+        self
+    }
+}
+
+impl Matcher for Whitespace {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let rest = input.get(at..)?;
+        let mut count = 0;
+        let mut len = 0;
+        for c in rest.chars() {
+            if !c.is_whitespace() || (!self.include_newlines && matches!(c, '\n' | '\r')) {
+                break;
+            }
+            count += 1;
+            len += c.len_utf8();
+        }
+        (count >= self.min).then_some(len)
+    }
+
+    /// The whole run, even one too short to match, then the char that
+    /// ended it.
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        let run = Whitespace {
+            min: 0,
+            ..self.clone()
+        };
+        let len = run.try_match(input, at).unwrap_or(0);
+        Some(next_char_end(input, at + len))
+    }
+
+    fn display(&self) -> String {
+        let name = if self.include_newlines { "WS" } else { "HWS" };
+        match self.min {
+            0 => name.to_string(),
+            1 => format!("{}+", name),
+            min => format!("{}{{{},}}", name, min),
+        }
+    }
+    fn sample(&self) -> Option<String> {
+        Some(" ".repeat(self.min.max(1)))
+    }
+
+    fn is_nullable(&self) -> bool {
+        self.min == 0
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Whitespace {
+            include_newlines: self.include_newlines,
+            min: self.min,
+        })
+    }
+}
+
+/// Consumes an identifier: a start character or `_`, then any number of
+/// continue characters. With the `unicode` feature these follow UAX #31
+/// closely: letters start one, and letters, digits, combining marks and
+/// connector punctuation continue it. Without it only ASCII letters and
+/// digits count.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identifier;
+
+#[cfg(feature = "unicode")]
+fn is_xid_start(c: char) -> bool {
+    c.is_alphabetic()
+}
+
+#[cfg(feature = "unicode")]
+fn is_xid_continue(c: char) -> bool {
+    c.is_alphanumeric() || super::xid::is_continue_mark(c)
+}
+
+#[cfg(not(feature = "unicode"))]
+fn is_xid_start(c: char) -> bool {
+    c.is_ascii_alphabetic()
+}
+
+#[cfg(not(feature = "unicode"))]
+fn is_xid_continue(c: char) -> bool {
+    c.is_ascii_alphanumeric()
+}
+
+impl Matcher for Identifier {
+    fn try_match(&self, input: &str, at: usize) -> Option<usize> {
+        let rest = input.get(at..)?;
+        let mut chars = rest.char_indices();
+        match chars.next() {
+            Some((_, c)) if c == '_' || is_xid_start(c) => {}
+            _ => return None,
+        }
+        let len = chars
+            .find(|&(_, c)| !(c == '_' || is_xid_continue(c)))
+            .map_or(rest.len(), |(offset, _)| offset);
+        Some(len)
+    }
+
+    /// The match, then the char that ended it.
+    fn lookahead_end(&self, input: &str, at: usize) -> Option<usize> {
+        let len = self.try_match(input, at).unwrap_or(0);
+        Some(next_char_end(input, at + len))
+    }
+
+    fn display(&self) -> String {
+        String::from("IDENT")
+    }
+    fn sample(&self) -> Option<String> {
+        Some(String::from("x"))
+    }
+
+    fn is_nullable(&self) -> bool {
+        false
+    }
+
+    fn spec(&self) -> Option<TerminalSpec> {
+        Some(TerminalSpec::Identifier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::words::State;
+
+    fn run(matcher: &impl Matcher, input: &str, position: usize) -> Option<usize> {
+        let mut state = State { input, position };
+        matcher.matches(&mut state).then_some(state.position)
+    }
+
+    #[test]
+    fn test_until() {
+        let body = Until::new("*/");
+        let comment = "/* é ∗ */ x";
+        assert_eq!(run(&body, comment, 2), Some(10));
+        assert_eq!(run(&body.clone().inclusive(), comment, 2), Some(12));
+        assert_eq!(run(&body, "*/", 0), Some(0));
+        assert_eq!(run(&body.clone().inclusive(), "*/", 0), Some(2));
+
+        let open = "/* never closed";
+        assert_eq!(run(&body, open, 2), None);
+        assert_eq!(run(&body.clone().or_end_of_input(), open, 2), Some(15));
+        assert_eq!(
+            body.clone().inclusive().or_end_of_input().display(),
+            r#"UNTIL("*/", inclusive, EOF)"#
+        );
+        assert!(body.is_nullable());
+        assert!(!body.inclusive().is_nullable());
+    }
+
+    #[test]
+    fn test_integer() {
+        let decimal = Integer::default();
+        assert_eq!(run(&decimal, "123abc", 0), Some(3));
+        assert_eq!(run(&decimal, "-1", 0), None);
+        assert_eq!(run(&decimal.clone().signed(), "-1", 0), Some(2));
+        assert_eq!(run(&decimal.clone().signed(), "-x", 0), None);
+        assert_eq!(run(&decimal, "1_000_000", 0), Some(1));
+        let grouped = decimal.clone().underscores();
+        assert_eq!(run(&grouped, "1_000_000;", 0), Some(9));
+        assert_eq!(run(&grouped, "1__0", 0), Some(4));
+        assert_eq!(run(&grouped, "1_ ", 0), Some(1));
+        assert_eq!(run(&grouped, "_1", 0), None);
+
+        let hex = Integer::with_radix(16);
+        assert_eq!(run(&hex, "0x1f", 2), Some(4));
+        assert_eq!(run(&hex, "DEADbeefg", 0), Some(8));
+        assert_eq!(hex.display(), "INTEGER(16)");
+        assert_eq!(decimal.display(), "INTEGER");
+    }
+
+    #[test]
+    fn test_float() {
+        let float = Float::new();
+        assert_eq!(run(&float, "1.5+", 0), Some(3));
+        assert_eq!(run(&float, "1. ", 0), Some(2));
+        assert_eq!(run(&float, "1..2", 0), Some(1));
+        assert_eq!(run(&float, "1.max(2)", 0), Some(1));
+        assert_eq!(run(&float, "12", 0), Some(2));
+        assert_eq!(run(&float, "1e10", 0), Some(4));
+        assert_eq!(run(&float, "2.5E-3)", 0), Some(6));
+        assert_eq!(run(&float, "1e", 0), Some(1));
+        assert_eq!(run(&float, "1e+", 0), Some(1));
+        assert_eq!(run(&float, "1.5e+x", 0), Some(3));
+        assert_eq!(run(&float, ".5", 0), None);
+        assert_eq!(run(&float.clone().no_exponent(), "1e5", 0), Some(1));
+
+        let strict = Float::new().require_fraction();
+        assert_eq!(run(&strict, "1.5", 0), Some(3));
+        assert_eq!(run(&strict, "1.", 0), None);
+        assert_eq!(run(&strict, "1e5", 0), None);
+        let mut state = State {
+            input: "7.",
+            position: 0,
+        };
+        assert!(!strict.matches(&mut state));
+        assert_eq!(state.position, 0);
+        assert_eq!(float.display(), "FLOAT");
+    }
+
+    #[test]
+    fn test_quoted_string() {
+        let string = QuotedString::new('"');
+        assert_eq!(run(&string, r#""a\"b" rest"#, 0), Some(6));
+        assert_eq!(run(&string, r#""""#, 0), Some(2));
+        assert_eq!(run(&string, r#""\\""#, 0), Some(4));
+        assert_eq!(run(&string, "\"日本\"", 0), Some(8));
+        assert_eq!(run(&string, r#""unterminated"#, 0), None);
+        assert_eq!(run(&string, r#""ends in \"#, 0), None);
+        assert_eq!(run(&string, "\"a\nb\"", 0), None);
+        assert_eq!(
+            run(&string.clone().allow_newlines(), "\"a\nb\"", 0),
+            Some(5)
+        );
+        assert_eq!(run(&string, "x\"\"", 0), None);
+        assert_eq!(string.display(), r#"STRING('"')"#);
+
+        let chars = QuotedString::new('\'').escape(None);
+        assert_eq!(run(&chars, "'x'", 0), Some(3));
+        assert_eq!(run(&chars, r"'\'", 0), Some(3));
+        let mut state = State {
+            input: "'open",
+            position: 0,
+        };
+        assert!(!chars.matches(&mut state));
+        assert_eq!(state.position, 0);
+    }
+
+    #[test]
+    fn test_line_comment() {
+        let comment = LineComment("//");
+        assert_eq!(run(&comment, "// note\nx", 0), Some(7));
+        assert_eq!(run(&comment, "x // end", 2), Some(8));
+        assert_eq!(run(&comment, "//", 0), Some(2));
+        assert_eq!(run(&comment, "/ x", 0), None);
+        assert_eq!(comment.display(), r#"LINE_COMMENT("//")"#);
+    }
+
+    #[test]
+    fn test_block_comment() {
+        let flat = BlockComment::new("/*", "*/");
+        let nested = flat.clone().nested();
+        let input = "/* a /* b */ c */d";
+        assert_eq!(run(&flat, input, 0), Some(12));
+        assert_eq!(run(&nested, input, 0), Some(17));
+        assert_eq!(run(&nested, "/*/**/*/", 0), Some(8));
+        assert_eq!(run(&flat, "/**/", 0), Some(4));
+        assert_eq!(run(&flat, "/*/", 0), None);
+
+        let mut state = State {
+            input: "x /* open /* */",
+            position: 2,
+        };
+        assert!(!nested.matches(&mut state));
+        assert_eq!(state.position, 2);
+        assert!(nested.clone().allow_unterminated().matches(&mut state));
+        assert_eq!(state.position, 15);
+        assert_eq!(flat.display(), r#"BLOCK_COMMENT("/*", "*/")"#);
+    }
+
+    #[test]
+    fn test_whitespace() {
+        let ws = Whitespace::new();
+        assert_eq!(run(&ws, " \t\u{00A0}\n x", 0), Some(6));
+        assert_eq!(run(&ws, "x", 0), None);
+        assert_eq!(run(&ws, "", 0), None);
+        assert!(!ws.is_nullable());
+        assert_eq!(ws.display(), "WS+");
+
+        let horizontal = Whitespace::new().horizontal();
+        assert_eq!(run(&horizontal, "\t \u{3000}\r\n", 0), Some(5));
+        assert_eq!(run(&horizontal, "\n", 0), None);
+        assert_eq!(horizontal.display(), "HWS+");
+
+        let optional = Whitespace::new().at_least(0);
+        assert_eq!(run(&optional, "x", 0), Some(0));
+        assert!(optional.is_nullable());
+        assert_eq!(optional.display(), "WS");
+        assert_eq!(run(&Whitespace::new().at_least(2), " x", 0), None);
+    }
+
+    #[test]
+    fn test_identifier() {
+        assert_eq!(run(&Identifier, "_foo bar", 0), Some(4));
+        assert_eq!(run(&Identifier, "x1+", 0), Some(2));
+        assert_eq!(run(&Identifier, "_", 0), Some(1));
+        assert_eq!(run(&Identifier, "1x", 0), None);
+        assert_eq!(run(&Identifier, "", 0), None);
+        assert_eq!(Identifier.display(), "IDENT");
+        if cfg!(feature = "unicode") {
+            assert_eq!(run(&Identifier, "变量 = 1", 0), Some(6));
+            assert_eq!(run(&Identifier, "x变", 0), Some(4));
+            // A combining acute accent, a virama and a vowel sign, and a
+            // connector.
+            assert_eq!(run(&Identifier, "e\u{301}x = 1", 0), Some(4));
+            assert_eq!(run(&Identifier, "नमस्ते = 1", 0), Some(18));
+            assert_eq!(run(&Identifier, "a\u{203F}b", 0), Some(5));
+            assert_eq!(run(&Identifier, "\u{301}x", 0), None);
+        } else {
+            assert_eq!(run(&Identifier, "变量", 0), None);
+            assert_eq!(run(&Identifier, "x变", 0), Some(1));
+            assert_eq!(run(&Identifier, "e\u{301}x", 0), Some(1));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_until_empty_delimiter() {
+        Until::new("");
+    }
+}